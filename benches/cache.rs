@@ -0,0 +1,104 @@
+//! Benchmarks `SectorCache` lookups and inserts under random access.
+
+extern crate criterion;
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group};
+
+use fs::fat::{
+    types::SectorIdx,
+    cache::{SectorCache, eviction_policies::UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED},
+};
+
+use generic_array::GenericArray;
+use storage_traits::{Storage, errors::{ReadError, WriteError}};
+use typenum::consts::{U512, U4096};
+
+const NUM_SECTORS: u64 = 100_000;
+const ACCESSES: u64 = 4_000;
+
+/// Storage that just hands out zeroed sectors so that we're (mostly) timing
+/// the cache.
+struct NullStorage;
+
+impl Storage for NullStorage {
+    type Word = u8;
+    type SECTOR_SIZE = U512;
+
+    type ReadErr = ();
+    type WriteErr = ();
+
+    fn capacity(&self) -> usize { NUM_SECTORS as usize }
+
+    fn read_sector(&mut self, _: usize, buffer: &mut GenericArray<u8, U512>) -> Result<(), ReadError<()>> {
+        buffer.iter_mut().for_each(|b| *b = 0);
+        Ok(())
+    }
+
+    fn write_sector(&mut self, _: usize, _: &GenericArray<u8, U512>) -> Result<(), WriteError<()>> {
+        Ok(())
+    }
+}
+
+/// xorshift; we just want sector indexes without any particular order.
+fn random_sectors(mut x: u64, modulus: u64) -> impl Iterator<Item = SectorIdx> {
+    core::iter::repeat_with(move || {
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        SectorIdx::new(x % modulus)
+    })
+}
+
+fn bench_random_access(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cache random access");
+    group.throughput(Throughput::Elements(ACCESSES));
+
+    let mut s = NullStorage;
+
+    // Every access is a miss that inserts into a cache that never fills up:
+    group.bench_function(BenchmarkId::new("inserts", ACCESSES), |b| b.iter(|| {
+        let mut cache = SectorCache::<_, U512, U4096>::new(
+            &s,
+            SectorIdx::new(NUM_SECTORS),
+            UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+        );
+
+        let c = cache.upgrade(&mut s);
+        for sector in random_sectors(12345, NUM_SECTORS).take(ACCESSES as usize) {
+            let _ = c.get(sector)[0];
+        }
+    }));
+
+    // Misses on a full cache; each one evicts:
+    let mut cache = SectorCache::<_, U512, U4096>::new(
+        &s,
+        SectorIdx::new(NUM_SECTORS),
+        UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+    );
+    let mut sectors = random_sectors(6789, NUM_SECTORS);
+
+    group.bench_function(BenchmarkId::new("evictions", ACCESSES), |b| b.iter(|| {
+        let c = cache.upgrade(&mut s);
+        for sector in sectors.by_ref().take(ACCESSES as usize) {
+            let _ = c.get(sector)[0];
+        }
+    }));
+}
+
+criterion_group!(benches, bench_random_access);
+
+fn main() {
+    // The caches are big and live on the stack.
+    std::thread::Builder::new()
+        .stack_size(1024 * 1024 * 64)
+        .spawn(|| {
+            benches();
+
+            criterion::Criterion::default()
+                .configure_from_args()
+                .final_summary();
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+}
@@ -21,6 +21,7 @@ use fs::{
             DirIter, State,
         },
         table::FatEntry,
+        types::ClusterOffset,
     }
 };
 
@@ -65,8 +66,10 @@ fn bench_read_speed(c: &mut Criterion) {
 
         group.throughput(Throughput::Elements(file_size as u64));
 
+        f.cache.reset_stats();
+
         group.bench_with_input(
-            BenchmarkId::new("file read speed", file_size),
+            BenchmarkId::new("file read speed (byte at a time)", file_size),
             &entry,
             |b, p| b.iter(|| {
                 let mut c = p.cluster_idx();
@@ -81,14 +84,9 @@ fn bench_read_speed(c: &mut Criterion) {
                         c = fe.trace(&mut f, &mut s).next().unwrap().next;
                     }
 
-                    // Assumes contiguous clusters for the moment..
-                    // TODO: when offset is a multiple of the cluster size, update
-                    // the cluster...
-                    let (_, offs) = f.cluster_to_sector(c, offset);
-
                     let mut buf = [0];
                     let fe = FatEntry::from(c);
-                    fe.upgrade(&mut f, &mut s).read(offs as u32, &mut buf).unwrap();
+                    fe.upgrade(&mut f, &mut s).read(ClusterOffset::new(offset), &mut buf).unwrap();
 
                     checksum = checksum.wrapping_add(buf[0] as u64);
 
@@ -100,10 +98,107 @@ fn bench_read_speed(c: &mut Criterion) {
                 }
             })
         );
+
+        // Same thing but with one `read_at` call; this skips re-tracing the
+        // chain for every byte and copies whole sectors out of the cache.
+        let file = entry.clone().into_file().unwrap();
+        let mut buf = vec![0; file_size as usize];
+
+        group.bench_with_input(
+            BenchmarkId::new("file read speed (read_at)", file_size),
+            &file,
+            |b, p| b.iter(|| {
+                p.upgrade(&mut f, &mut s).read_at(0, &mut buf).unwrap();
+
+                let checksum = buf.iter().fold(0u64, |c, b| c.wrapping_add(*b as u64));
+                if checksum % 56789 == 6 {
+                    println!("{}", checksum & 7);
+                }
+            })
+        );
+
+        // Try shrinking the cache size (the `U1000000` above) to see how this
+        // changes:
+        let stats = f.cache.stats();
+        println!("{}: {} hits, {} misses, {} evictions (hit ratio: {:.4})",
+            path,
+            stats.hits,
+            stats.misses,
+            stats.evictions,
+            stats.hit_ratio().unwrap_or(0.0),
+        );
     }
 }
 
-criterion_group!(benches, bench_read_speed);
+/// Reads a run of sectors in one `FatFs::read` call (which goes through
+/// `get_range`) and then the same sectors one `FatFs::read` call at a time.
+fn bench_sequential_read(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sequential read");
+
+    let mut s = FileBackedStorage::from_file(IMG_FILE_PATH).unwrap();
+    let g = Gpt::read_gpt(&mut s).unwrap();
+    let p = g.get_partition_entry(&mut s, 0).unwrap();
+
+    let mut f = FatFs::<_, U128, _>::mount(&mut s, &p,
+        UnmodifiedFirst::<LeastRecentlyAccessed>::default(),
+    ).unwrap();
+
+    // Wherever the biggest file starts; we're reading sectors, not the file.
+    let (_, entry) = f.lookup_path(&mut s, b"/5M").unwrap();
+    let (start, _) = f.cluster_to_sector(entry.cluster_idx(), ClusterOffset::new(0));
+
+    for &sectors in [8usize, 64, 1024].iter() {
+        let mut buf = vec![0; sectors * 512];
+        group.throughput(Throughput::Bytes(buf.len() as u64));
+
+        group.bench_with_input(BenchmarkId::new("one read", sectors), &sectors, |b, _| b.iter(|| {
+            f.read(&mut s, start, 0, &mut buf).unwrap();
+        }));
+
+        group.bench_with_input(BenchmarkId::new("a read per sector", sectors), &sectors, |b, _| b.iter(|| {
+            for (i, chunk) in buf.chunks_mut(512).enumerate() {
+                f.read(&mut s, start.checked_add(i as u64).unwrap(), 0, chunk).unwrap();
+            }
+        }));
+    }
+}
+
+fn bench_dir_listing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("directory listing");
+
+    let mut s = FileBackedStorage::from_file(IMG_FILE_PATH).unwrap();
+    let g = Gpt::read_gpt(&mut s).unwrap();
+    let p = g.get_partition_entry(&mut s, 0).unwrap();
+
+    let mut f = FatFs::<_, U128, _>::mount(&mut s, &p,
+        UnmodifiedFirst::<LeastRecentlyAccessed>::default(),
+    ).unwrap();
+    let root = f.root_dir_cluster_num;
+
+    // Every entry gets copied out and decoded:
+    group.bench_function("owned entries", |b| b.iter(|| {
+        DirIter::from_cluster(root, &mut f, &mut s)
+            .filter(|(_, e)| e.state() == State::Exists)
+            .map(|(_, e)| e.file_size as u64)
+            .sum::<u64>()
+    }));
+
+    // Only the bytes we look at get decoded:
+    group.bench_function("borrowed entries", |b| b.iter(|| {
+        let mut d = DirIter::from_cluster(root, &mut f, &mut s);
+        let mut total = 0u64;
+
+        while let Some(size) = d.next_with(|_, e| {
+            if e.state() == State::Exists { e.file_size() as u64 } else { 0 }
+        }) {
+            total += size;
+        }
+
+        total
+    }));
+}
+
+criterion_group!(benches, bench_read_speed, bench_sequential_read, bench_dir_listing);
 // criterion_main!(benches);
 
 fn main() {
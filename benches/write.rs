@@ -0,0 +1,96 @@
+//! Measures how fast large writes go through `FatFs::write` and
+//! `FatFs::write_iter` (and into the cache).
+
+extern crate criterion;
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group};
+
+use fs::{
+    gpt::PartitionEntry,
+    fat::{
+        FatFs,
+        types::SectorIdx,
+        cache::eviction_policies::UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+    },
+    storage::StorageExt,
+};
+
+use generic_array::GenericArray;
+use storage_traits::{Storage, errors::{ReadError, WriteError}};
+use typenum::consts::{U512, U128};
+
+const NUM_SECTORS: usize = 1 + 4096;
+
+/// Where the writes start; well past the metadata.
+const START: u64 = 2048;
+
+/// A `Vec` of sectors; we don't want to time a real disk.
+struct VecStorage(Vec<u8>);
+
+impl Storage for VecStorage {
+    type Word = u8;
+    type SECTOR_SIZE = U512;
+
+    type ReadErr = ();
+    type WriteErr = ();
+
+    fn capacity(&self) -> usize { NUM_SECTORS }
+
+    fn read_sector(&mut self, sector_idx: usize, buffer: &mut GenericArray<u8, U512>) -> Result<(), ReadError<()>> {
+        buffer.copy_from_slice(&self.0[(sector_idx * 512)..((sector_idx + 1) * 512)]);
+        Ok(())
+    }
+
+    fn write_sector(&mut self, sector_idx: usize, words: &GenericArray<u8, U512>) -> Result<(), WriteError<()>> {
+        self.0[(sector_idx * 512)..((sector_idx + 1) * 512)].copy_from_slice(words);
+        Ok(())
+    }
+}
+
+impl StorageExt for VecStorage { }
+
+fn bench_write_speed(c: &mut Criterion) {
+    let mut group = c.benchmark_group("write speed");
+
+    let mut s = VecStorage(vec![0; NUM_SECTORS * 512]);
+    let p = PartitionEntry::fat("BENCH", 1, NUM_SECTORS as u64 - 1);
+    let mut f = FatFs::<_, U128, _>::format(&mut s, &p, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    for len in [4 * 1024, 1024 * 1024].iter() {
+        let data: Vec<u8> = (0..*len).map(|i| i as u8).collect();
+        group.throughput(Throughput::Bytes(*len as u64));
+
+        // Starts partway into a sector so the first and last sectors are only
+        // partially written.
+        group.bench_with_input(BenchmarkId::new("write", len), &data, |b, d| b.iter(|| {
+            f.write(&mut s, SectorIdx::new(START), 100, d).unwrap();
+            f.cache.flush(&mut s).unwrap();
+        }));
+
+        // An iterator whose length isn't known up-front:
+        group.bench_with_input(BenchmarkId::new("write_iter", len), &data, |b, d| b.iter(|| {
+            f.write_iter(&mut s, SectorIdx::new(START), 100, d.iter().cloned().filter(|_| true)).unwrap();
+            f.cache.flush(&mut s).unwrap();
+        }));
+    }
+
+    f.unmount(&mut s).unwrap();
+}
+
+criterion_group!(benches, bench_write_speed);
+
+fn main() {
+    // The cache lives on the stack.
+    std::thread::Builder::new()
+        .stack_size(1024 * 1024 * 64)
+        .spawn(|| {
+            benches();
+
+            criterion::Criterion::default()
+                .configure_from_args()
+                .final_summary();
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+}
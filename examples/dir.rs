@@ -1,62 +1,71 @@
+// Lists the root directory of the FAT volume in the first partition of the
+// GPT disk image passed in (i.e. a copy of an SD card) or, without one, of the
+// golden volume that's built into the crate (see `fs::test_util`).
+//
 // Requires the `no_std` feature to be disabled so that `File`s implement the
-// `Storage` trait.
+// `Storage` trait and the `test-util` feature for the golden volume.
 //
-// Run with --no-default-features.
+// Run with --no-default-features --features test-util [-- <image>].
 
-use fs::gpt::{Gpt, PartitionEntry};
+use fs::gpt::Gpt;
 use fs::fat::{FatFs,
-    types::SectorIdx,
-    cache::eviction_policies::{
-        UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
-        LeastRecentlyAccessed,
-        UnmodifiedFirst,
+    cache::{
+        CacheEntry,
+        IndexLink,
+        EvictionPolicy,
+        eviction_policies::{LeastRecentlyAccessed, UnmodifiedFirst},
     },
     dir::{DirIter, State},
 };
+use fs::test_util::golden_volume;
+use fs::util::BitMapLen;
 
 use storage_traits::{FileBackedStorage, Storage};
-use generic_array::GenericArray;
-use typenum::consts::{U512, U32};
-
-const FILE_PATH: &'static str = "assets/gpt.img";
+use generic_array::{ArrayLength, GenericArray};
+use typenum::consts::U32;
 
-const SD_CARD_PATH: &'static str = "/dev/mmcblk0";
-const SD_CARD_SIZE: usize = 31_449_088;
+use std::cell::RefCell;
 
 fn main() {
-    // let mut f = FileBackedStorage::from_file(FILE_PATH).unwrap();
-    let mut s = FileBackedStorage::from_file_with_explicit_size(SD_CARD_PATH, SD_CARD_SIZE).unwrap();
+    match std::env::args().nth(1) {
+        Some(path) => {
+            let mut s = FileBackedStorage::from_file(&path).unwrap();
 
-    let g = Gpt::read_gpt(&mut s).unwrap();
-    let p = g.get_partition_entry(&mut s, 0).unwrap();
+            let g = Gpt::read_gpt(&mut s).unwrap();
+            let p = g.get_partition_entry(&mut s, 0).unwrap();
 
-    let mut f = FatFs::<_, U32, _>::mount(&mut s, &p,
-        UnmodifiedFirst::<LeastRecentlyAccessed>::default(),
-    ).unwrap();
+            println!("{:#?}", g);
+            println!("{:#?}", p);
 
-    println!("{:#?}", g);
-    println!("{:#?}", p);
+            let mut f = FatFs::<_, U32, _>::mount(&mut s, &p,
+                UnmodifiedFirst::<LeastRecentlyAccessed>::default(),
+            ).unwrap();
+
+            list_root_dir(&mut f, &mut s);
+        },
+        None => {
+            let (mut s, mut f) = golden_volume();
+            list_root_dir(&mut f, &mut s);
+        },
+    }
+}
 
+fn list_root_dir<S, CS, Ev>(f: &mut FatFs<S, CS, Ev>, s: &mut S)
+where
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
+    CS: ArrayLength<CacheEntry>,
+    CS: ArrayLength<IndexLink>,
+    CS: BitMapLen,
+    Ev: EvictionPolicy,
+{
     println!("{:#?}", f);
-    println!("{:#?}", f.get_boot_sect(&mut s));
+    println!("{:#?}", f.get_boot_sect(s));
     println!("{:?}", f.root_dir_cluster_num);
 
-    for dir in DirIter::from_cluster(f.root_dir_cluster_num, &mut f, &mut s) {
+    for (_, dir) in DirIter::from_cluster(f.root_dir_cluster_num, f, s) {
         if let State::Exists = dir.state() {
-            // println!("{:#?}", dir);
-            println!("{:#?}.{:#?}", dir.file_name, dir.file_ext);
+            println!("{}", dir);
         }
     }
-
-    // println!("{:?}", f);
 }
-
-// fn recur(d: DirEntry, prefix: String) {
-//     for dir in i {
-//         if let State::Exists = dir.state() {
-//             println!("{}{:#?}.{:#?}", prefix, dir.name, dir.ext);
-
-//             if let Some(i) = dir.into_dir_iter()
-//         }
-//     }
-// }
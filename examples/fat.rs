@@ -1,60 +1,81 @@
+// Dumps the boot sector and the first few sectors of the FAT volume in the
+// first partition of the GPT disk image passed in (i.e. a copy of an SD card)
+// or, without one, of the golden volume that's built into the crate (see
+// `fs::test_util`).
+//
 // Requires the `no_std` feature to be disabled so that `File`s implement the
-// `Storage` trait.
+// `Storage` trait and the `test-util` feature for the golden volume.
 //
-// Run with --no-default-features.
+// Run with --no-default-features --features test-util [-- <image>].
 
-use fs::gpt::{Gpt, PartitionEntry};
+use fs::gpt::Gpt;
 use fs::fat::{FatFs,
-    types::SectorIdx,
-    cache::eviction_policies::{
-        UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
-        LeastRecentlyAccessed,
-        UnmodifiedFirst,
-    }
+    cache::{
+        CacheEntry,
+        IndexLink,
+        EvictionPolicy,
+        eviction_policies::{LeastRecentlyAccessed, UnmodifiedFirst},
+    },
 };
+use fs::test_util::golden_volume;
+use fs::util::BitMapLen;
 
 use storage_traits::{FileBackedStorage, Storage};
-use generic_array::GenericArray;
-use typenum::consts::{U512, U32};
+use generic_array::{ArrayLength, GenericArray};
+use typenum::consts::U32;
 
-const FILE_PATH: &'static str = "assets/fat.img.mut";
+use std::cell::RefCell;
 
-const SD_CARD_PATH: &'static str = "/dev/mmcblk0";
-const SD_CARD_SIZE: usize = 31_449_088;
+/// How many sectors (from the start of the volume) to dump.
+const SECTORS: usize = 8;
 
 fn main() {
-    // let mut s = FileBackedStorage::from_file(FILE_PATH).unwrap();
-    let mut s = FileBackedStorage::from_file_with_explicit_size(SD_CARD_PATH, SD_CARD_SIZE).unwrap();
+    match std::env::args().nth(1) {
+        Some(path) => {
+            let mut s = FileBackedStorage::from_file(&path).unwrap();
+
+            let g = Gpt::read_gpt(&mut s).unwrap();
+            let p = g.get_partition_entry(&mut s, 0).unwrap();
 
-    let g = Gpt::read_gpt(&mut s).unwrap();
-    let p = g.get_partition_entry(&mut s, 0).unwrap();
+            println!("{:#?}", g);
+            println!("{:#?}", p);
 
-    let mut f = FatFs::<_, U32, _>::mount(
-        &mut s,
-        &p,
-        UnmodifiedFirst::<LeastRecentlyAccessed>::default(),
-    ).unwrap();
+            let mut f = FatFs::<_, U32, _>::mount(
+                &mut s,
+                &p,
+                UnmodifiedFirst::<LeastRecentlyAccessed>::default(),
+            ).unwrap();
 
-    println!("{:#?}", g);
-    println!("{:#?}", p);
+            dump(&mut f, &mut s);
+        },
+        None => {
+            let (mut s, mut f) = golden_volume();
+            dump(&mut f, &mut s);
+        },
+    }
+}
 
-    println!("{:#?}", f.get_boot_sect(&mut s));
+fn dump<S, CS, Ev>(f: &mut FatFs<S, CS, Ev>, s: &mut S)
+where
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
+    CS: ArrayLength<CacheEntry>,
+    CS: ArrayLength<IndexLink>,
+    CS: BitMapLen,
+    Ev: EvictionPolicy,
+{
+    println!("{:#?}", f.get_boot_sect(s));
     println!("{:#?}", f);
 
     let mut buf = [0u8; 512];
 
-    for i in 2048..2150 {
-        f.read(&mut s, SectorIdx::new(i as u64), 0, &mut buf);
+    for sector in f.valid_sector_range().take(SECTORS) {
+        let i = *sector.inner();
+        f.read(s, sector, 0, &mut buf).unwrap();
 
         println!("{} → ({:#6X}):", i, i * 512);
         for (o, a) in buf.chunks(16).enumerate() {
-            match a {
-                a @ [_, _, _, _, _, _, _, _, _, _, _, _, _, _, _, _] =>
-                    println!("{:#6X}: {:2X?}", i * 512 + 16 * o, a),
-                _ => unreachable!(),
-            }
+            println!("{:#6X}: {:2X?}", i * 512 + 16 * (o as u64), a);
         }
     }
-
-    // println!("{:#?}", f.next_free_cluster(&mut s));
 }
@@ -1,22 +1,27 @@
+// Prints the GPT and its first partition entry from the disk image passed in
+// (i.e. a copy of an SD card). The golden volume in `fs::test_util` is a bare
+// volume without a partition table so there's nothing to show without an
+// image.
+//
 // Requires the `no_std` feature to be disabled so that `File`s implement the
 // `Storage` trait.
 //
-// Run with --no-default-features.
-
-use fs::gpt::{Gpt, PartitionEntry};
+// Run with --no-default-features -- <image>.
 
-use storage_traits::{FileBackedStorage, Storage};
-use generic_array::GenericArray;
-use typenum::consts::U512;
+use fs::gpt::Gpt;
 
-const FILE_PATH: &'static str = "assets/gpt.img";
-
-const SD_CARD_PATH: &'static str = "/dev/mmcblk0";
-const SD_CARD_SIZE: usize = 31_449_088;
+use storage_traits::FileBackedStorage;
 
 fn main() {
-    // let mut f = FileBackedStorage::from_file(FILE_PATH).unwrap();
-    let mut f = FileBackedStorage::from_file_with_explicit_size(SD_CARD_PATH, SD_CARD_SIZE).unwrap();
+    let path = match std::env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: gpt <image>");
+            std::process::exit(1);
+        },
+    };
+
+    let mut f = FileBackedStorage::from_file(&path).unwrap();
 
     let g = Gpt::read_gpt(&mut f).unwrap();
     let p = g.get_partition_entry(&mut f, 0).unwrap();
@@ -22,11 +22,15 @@ extern bool eFile_NewDir(
     uint16_t len
 );
 
+// Reads past the end of the file are cut short; `bytes_read` (which can be
+// NULL) is set to the number of bytes actually read.
 extern bool eFile_Read(
     char const* path,
     uint16_t len,
+    uint32_t offset,
     uint8_t buf[/*buf_len*/],
-    uint32_t buf_len
+    uint32_t buf_len,
+    uint32_t* bytes_read
 );
 
 extern bool eFile_ReadAll(
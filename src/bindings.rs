@@ -43,11 +43,14 @@ pub mod efile {
     use crate::fat::cache::eviction_policies::{LeastRecentlyAccessed, UnmodifiedFirst};
     use crate::fat::dir::{DirIter, State};
     use crate::fat::table::FatEntry;
+    use crate::fat::types::ClusterOffset;
 
     use typenum::consts::{U512, U32, U16, U8, U4};
 
     use core::slice::{from_raw_parts, from_raw_parts_mut};
 
+    // `mutex::Mutex` is picked by features; with `custom_mutex` these are
+    // locked by whatever was registered with `register_mutex!`.
     static STORAGE: Mutex<Option<EDiskStorage>> = Mutex::new(None);
     static FS: Mutex<Option<
         FatFs<EDiskStorage, U4, UnmodifiedFirst<LeastRecentlyAccessed>>
@@ -78,59 +81,49 @@ pub mod efile {
     pub extern "C" fn eFile_NewFile(path: *const u8, len: u16) -> bool {
         let path = unsafe { from_raw_parts(path, len as usize) };
 
-        todo!()
+        STORAGE.cs(|s| s.as_mut().map(|s| FS.cs(|f| f.as_mut().map(|f| {
+            f.create_file(s, path).is_ok()
+        })).unwrap_or(false)).unwrap_or(false))
     }
 
     #[no_mangle]
     pub extern "C" fn eFile_NewDir(path: *const u8, len: u16) -> bool {
         let path = unsafe { from_raw_parts(path, len as usize) };
 
-        todo!()
+        STORAGE.cs(|s| s.as_mut().map(|s| FS.cs(|f| f.as_mut().map(|f| {
+            f.create_dir(s, path).is_ok()
+        })).unwrap_or(false)).unwrap_or(false))
     }
 
+    /// Reads up to `buf_len` bytes starting `offset` bytes into the file.
+    ///
+    /// Reads that go past the end of the file are cut short; the number of
+    /// bytes actually read is written to `bytes_read` (if it isn't null).
+    /// Reading at the end of the file succeeds and reads nothing; reading past
+    /// it fails.
     #[no_mangle]
-    pub extern "C" fn eFile_Read(path: *const u8, len: u16, mut offset: u32, buf: *mut u8, buf_len: u32) -> bool {
+    pub extern "C" fn eFile_Read(
+        path: *const u8,
+        len: u16,
+        offset: u32,
+        buf: *mut u8,
+        buf_len: u32,
+        bytes_read: *mut u32,
+    ) -> bool {
         let path = unsafe { from_raw_parts(path, len as usize) };
-        let mut buf = unsafe { from_raw_parts_mut(buf, buf_len as usize) };
+        let buf = unsafe { from_raw_parts_mut(buf, buf_len as usize) };
+        let bytes_read = unsafe { bytes_read.as_mut() };
 
         STORAGE.cs(|s| s.as_mut().map(|s| FS.cs(|f| f.as_mut().map(|f| {
-            let bytes_in_a_cluster = f.bytes_in_a_cluster();
-
-            if let Ok((_, p)) = f.lookup_path(s, path) {
-                if !p.attributes.is_file() {
-                    false
-                } else {
-                    let mut c = p.cluster_idx();
-                    if offset + buf_len >= p.file_size {
-                        return false;
-                    }
-
-                    for b in buf.iter_mut() {
-                        while offset >= bytes_in_a_cluster {
-                            offset -= bytes_in_a_cluster;
-
-                            let fe = FatEntry::from(c);
-                            c = fe.trace(f, s).next().unwrap().next;
-                        }
-
-                        // Assumes contiguous clusters for the moment..
-                        // TODO: when offset is a multiple of the cluster size, update
-                        // the cluster...
-                        let (_, offs) = f.cluster_to_sector(c, offset);
-
-                        let mut buf = [0];
-                        let mut fe = FatEntry::from(c);
-                        fe.upgrade(f, s).read(offs as u32, &mut buf).unwrap();
-
-                        *b = buf[0];
-
-                        offset += 1;
+            match f.read_file(s, path, offset, buf) {
+                Ok(len) => {
+                    if let Some(bytes_read) = bytes_read {
+                        *bytes_read = len as u32;
                     }
 
                     true
-                }
-            } else {
-                false
+                },
+                Err(_) => false,
             }
         })).unwrap_or(false)).unwrap_or(false))
     }
@@ -153,18 +146,19 @@ pub mod efile {
                         while offset >= bytes_in_a_cluster {
                             offset -= bytes_in_a_cluster;
 
+                            // The tracer starts with `c` itself.
                             let fe = FatEntry::from(c);
-                            c = fe.trace(f, s).next().unwrap().next;
+                            c = match fe.trace(f, s).nth(1) {
+                                Some(next) => next.next,
+                                None => return false,
+                            };
                         }
 
-                        // Assumes contiguous clusters for the moment..
-                        // TODO: when offset is a multiple of the cluster size, update
-                        // the cluster...
-                        let (_, offs) = f.cluster_to_sector(c, offset);
-
                         let mut buf = [0];
                         let mut fe = FatEntry::from(c);
-                        fe.upgrade(f, s).read(offs as u32, &mut buf).unwrap();
+                        if fe.upgrade(f, s).read(ClusterOffset::new(offset), &mut buf).is_err() {
+                            return false;
+                        }
 
                         func(buf[0]);
 
@@ -182,8 +176,17 @@ pub mod efile {
     #[no_mangle]
     pub extern "C" fn eFile_Append(path: *const u8, len: u16, buf: *const u8, buf_len: u32) -> bool {
         let path = unsafe { from_raw_parts(path, len as usize) };
+        let buf = unsafe { from_raw_parts(buf, buf_len as usize) };
+
+        STORAGE.cs(|s| s.as_mut().map(|s| FS.cs(|f| f.as_mut().map(|f| {
+            let size = match f.lookup_path(s, path) {
+                Ok((_, entry)) => entry.file_size,
+                Err(_) => return false,
+            };
 
-        todo!()
+            // This grows the file's cluster chain and updates its size:
+            f.write_file(s, path, size, buf).is_ok()
+        })).unwrap_or(false)).unwrap_or(false))
     }
 
     #[no_mangle]
@@ -207,7 +210,9 @@ pub mod efile {
 
         STORAGE.cs(|s| s.as_mut().map(|s| FS.cs(|f| f.as_mut().map(|f| {
             if let Ok((_, de)) = f.lookup_path(s, path) {
-                for (_, dir) in DirIter::from_cluster(de.cluster_idx(), f, s) {
+                let mut iter = DirIter::from_cluster(de.cluster_idx(), f, s);
+
+                for (_, dir) in iter.by_ref() {
                     if let State::Exists = dir.state() {
                         func(
                             dir.file_name.0.as_ptr(),
@@ -215,7 +220,8 @@ pub mod efile {
                         )
                     }
                 }
-                true
+
+                iter.error().is_none()
             } else {
                 false
             }
@@ -231,10 +237,20 @@ pub mod efile {
 }
 
 pub mod edisk {
+    use crate::storage::{ClassifyError, EraseError, StorageExt, StorageKind};
+
     use storage_traits::{Storage, errors::{ReadError, WriteError}};
     use generic_array::GenericArray;
     use typenum::consts::U512;
 
+    use core::ops::Range;
+
+    /// Most sectors `EDiskStorage::erase_range` will zero in one `eDisk_Write` call.
+    pub const ERASE_CHUNK_SECTORS: usize = 64;
+
+    // In flash (or .rodata) rather than on the stack.
+    static ZEROS: [u8; ERASE_CHUNK_SECTORS * 512] = [0; ERASE_CHUNK_SECTORS * 512];
+
     #[repr(C)]
     pub struct EDiskStorage {
         pub drive_num: u8,
@@ -259,6 +275,16 @@ pub mod edisk {
         ResParErr = 4,
     }
 
+    impl ClassifyError for DResult {
+        fn kind(&self) -> StorageKind {
+            match self {
+                DResult::ResNotRdy => StorageKind::Retryable,
+                DResult::ResWrPrt => StorageKind::WriteProtected,
+                DResult::ResOk | DResult::ResError | DResult::ResParErr => StorageKind::Fatal,
+            }
+        }
+    }
+
     extern "C" {
         fn eDisk_Read(drv: u8, buff: *mut u8, sector: u32, count: u32) -> DResult;
         fn eDisk_ReadBlock(buff: *mut u8, sector: u32) -> DResult;
@@ -324,4 +350,64 @@ pub mod edisk {
             }
         }
     }
+
+    impl StorageExt for EDiskStorage {
+        // `eDisk_Write` can do a run of sectors in one call. Reads stay on the
+        // default impl: the cache loads sectors one at a time into slots that
+        // aren't contiguous, so there's no caller for a multi-sector read.
+
+        fn write_sectors(
+            &mut self,
+            start: usize,
+            buffers: &[GenericArray<u8, U512>],
+        ) -> Result<(), WriteError<DResult>> {
+            let end = (start as u64) + (buffers.len() as u64);
+            if end > self.size_in_sectors {
+                return Err(WriteError::OutOfRange {
+                    requested_offset: end as usize - 1,
+                    max_offset: self.size_in_sectors as usize,
+                });
+            }
+
+            if buffers.is_empty() { return Ok(()); }
+
+            match unsafe { eDisk_Write(
+                self.drive_num,
+                buffers.as_ptr() as *const u8,
+                start as u32,
+                buffers.len() as u32,
+            ) } {
+                DResult::ResOk => Ok(()),
+                e => Err(WriteError::Other(e))
+            }
+        }
+
+        fn erase_range(&mut self, range: Range<usize>) -> Result<(), EraseError<DResult>> {
+            let (mut start, end) = (range.start as u64, range.end as u64);
+            if end > self.size_in_sectors {
+                return Err(WriteError::OutOfRange {
+                    requested_offset: end as usize - 1,
+                    max_offset: self.size_in_sectors as usize,
+                }.into());
+            }
+
+            while start < end {
+                let count = core::cmp::min(end - start, ERASE_CHUNK_SECTORS as u64);
+
+                match unsafe { eDisk_Write(
+                    self.drive_num,
+                    ZEROS.as_ptr(),
+                    start as u32,
+                    count as u32,
+                ) } {
+                    DResult::ResOk => {},
+                    e => return Err(WriteError::Other(e).into()),
+                }
+
+                start += count;
+            }
+
+            Ok(())
+        }
+    }
 }
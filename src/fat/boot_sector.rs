@@ -2,21 +2,22 @@
 //!
 //! Majority of the docs here are sourced from [this page](https://en.wikipedia.org/wiki/Design_of_the_FAT_file_system).
 
-// We only support the FAT32 variants so expect 25 byte DOS 3.31 BIOS Parameter
-// Blocks (BPBs) with the extensions (?).
-
-// Another TODO: relax the 512B sector size restriction in this file.
+// We read the FAT32 Extended BIOS Parameter Block. FAT12 and FAT16 volumes
+// share everything up to offset 0x024 with it but put different things after
+// that, so the FAT32 specific fields are garbage for those volumes.
 
 use super::types::SectorIdx;
+use super::table::FatType;
 
-use generic_array::GenericArray;
-use typenum::consts::U512;
+use generic_array::{ArrayLength, GenericArray};
 
 use core::convert::TryInto;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BootSector {
     // Offset: 0x003
+    #[cfg_attr(feature = "serde", serde(with = "crate::util::serde_text"))]
     pub oem_name: [u8; 8],
 
     pub bpb: BiosParameterBlock,
@@ -32,22 +33,143 @@ impl BootSector {
         }
     }
 
-    pub fn read(sector: &GenericArray<u8, U512>) -> Self {
+    pub fn read<N: ArrayLength<u8>>(sector: &GenericArray<u8, N>) -> Self {
         Self {
             oem_name: sector.as_slice()[3..(3 + 8)].try_into().unwrap(),
             bpb: BiosParameterBlock::read(sector),
         }
     }
 
-    pub fn write(&self, sector: &mut GenericArray<u8, U512>) {
-        // TODO!
-        todo!()
+    /// Writes the jump instruction, the OEM name, the BPB, the extended boot
+    /// signature and the fields after it (where they go depends on the FAT
+    /// type), and the boot sector signature. Everything else (i.e. the boot
+    /// code) is left alone.
+    pub fn write<N: ArrayLength<u8>>(&self, sector: &mut GenericArray<u8, N>) {
+        let fat_type = self.fat_type();
+        let sig = Self::extended_boot_sig_offset(fat_type);
+
+        self.bpb.write(sector);
+
+        let sector = sector.as_mut_slice();
+
+        // A short jump past the BPB, followed by a NOP.
+        sector[0..3].copy_from_slice(&[0xEB, (sig + 24 - 2) as u8, 0x90]);
+        sector[3..(3 + 8)].copy_from_slice(&self.oem_name);
+
+        sector[sig - 2] = self.bpb.phys_drive_number;
+        sector[sig] = EXTENDED_BOOT_SIG;
+        sector[(sig + 1)..(sig + 5)].copy_from_slice(&self.bpb.volume_id.to_le_bytes());
+        sector[(sig + 5)..(sig + 5 + 11)].copy_from_slice(&self.bpb.volume_label);
+        sector[(sig + 16)..(sig + 16 + 8)].copy_from_slice(&self.bpb.file_system_type);
+
+        sector[0x1FE] = 0x55;
+        sector[0x1FF] = 0xAA;
+    }
+
+    /// Picks a layout for a new volume of `total_sectors` sectors starting at
+    /// `starting_lba`.
+    ///
+    /// Volumes of 260 MiB or more get FAT32 (32 reserved sectors with the
+    /// FSInfo sector at 1 and a backup boot sector at 6, and the root
+    /// directory in cluster 2); smaller ones
+    /// get FAT12 or FAT16 (depending on the cluster count) with a 512 entry
+    /// root directory. Either way there are two FATs and cluster sizes roughly
+    /// follow what Microsoft's formatter picks.
+    ///
+    /// `None` if the volume is too small to hold any clusters.
+    pub fn for_volume(starting_lba: u32, total_sectors: u32, sector_size: u16) -> Option<BootSector> {
+        const ROOT_DIR_ENTRIES: u16 = 512;
+        const NUM_FATS: u8 = 2;
+
+        let bytes = (total_sectors as u64) * (sector_size as u64);
+        let fat32 = bytes >= 260 * 1024 * 1024;
+
+        let sectors_per_cluster = if fat32 {
+            let cluster_size: u32 = match bytes >> 30 {
+                0..=7 => 4 * 1024,
+                8..=15 => 8 * 1024,
+                16..=31 => 16 * 1024,
+                _ => 32 * 1024,
+            };
+
+            core::cmp::max(cluster_size / (sector_size as u32), 1) as u8
+        } else {
+            // Small enough that the volume can't end up with too many clusters
+            // for FAT16.
+            let mut spc: u8 = 1;
+            while total_sectors / (spc as u32) >= 65525 && spc < 128 {
+                spc *= 2;
+            }
+
+            spc
+        };
+
+        let (reserved, root_dir_entries) = if fat32 { (32, 0) } else { (1, ROOT_DIR_ENTRIES) };
+        let root_dir_sectors = ((root_dir_entries as u32) * 32 + (sector_size as u32) - 1) / (sector_size as u32);
+
+        // Bigger FATs leave less room for clusters which need smaller FATs, so
+        // we go until the FAT is big enough for the clusters that are left.
+        let mut fat_size: u32 = 1;
+        let fat_type = loop {
+            let metadata = (reserved as u32) + (NUM_FATS as u32) * fat_size + root_dir_sectors;
+            let clusters = total_sectors.checked_sub(metadata)? / (sectors_per_cluster as u32);
+            if clusters == 0 { return None; }
+
+            let fat_type = FatType::from_cluster_count(clusters);
+            let fat_bits = ((clusters as u64) + 2) * (fat_type.bits_per_entry() as u64);
+            let needed = ((fat_bits + (sector_size as u64) * 8 - 1) / ((sector_size as u64) * 8)) as u32;
+
+            if needed <= fat_size { break fat_type; }
+            fat_size = needed;
+        };
+
+        // The layout has to match the type the cluster count gives us.
+        if fat32 != (fat_type == FatType::Fat32) {
+            return None;
+        }
+
+        let small_total = if fat32 || total_sectors > 0xFFFF { 0 } else { total_sectors as u16 };
+
+        Some(Self {
+            oem_name: *b"r3-fatfs",
+            bpb: BiosParameterBlock {
+                bytes_per_logical_sector: sector_size,
+                logical_sectors_per_cluster: sectors_per_cluster,
+                num_reserved_logical_sectors: reserved,
+                num_file_alloc_tables: NUM_FATS,
+                max_root_dir_entries: root_dir_entries,
+                total_logical_sectors: small_total,
+                media_descriptor: 0xF8,
+                logical_sectors_per_fat: if fat32 { 0 } else { fat_size as u16 },
+
+                phys_sectors_per_track: 0x0010,
+                num_heads: 0x0004,
+                hidden_preceeding_sectors: starting_lba,
+                total_logical_sectors_extended: if small_total == 0 { total_sectors } else { 0 },
+                logical_sectors_per_fat_extended: if fat32 { fat_size } else { 0 },
+                drive_desc_mirroring_flags: 0,
+                version: 0x0000,
+                root_dir_cluster_num: if fat32 { 2 } else { 0 },
+                fs_info_logical_sector_num: if fat32 { 1 } else { 0 },
+                boot_sector_backup_logical_sector_start_num: if fat32 { BACKUP_BOOT_SECTOR } else { 0 },
+
+                phys_drive_number: 0x80,
+                volume_id: 0x00,
+                volume_label: *b"NO NAME    ",
+                file_system_type: match fat_type {
+                    FatType::Fat12 => *b"FAT12   ",
+                    FatType::Fat16 => *b"FAT16   ",
+                    FatType::Fat32 => *b"FAT32   ",
+                },
+            },
+        })
     }
 }
 
 // FAT32 Extended BIOS Parameter Block (includes DOS 3.31 BPB which includes the
 // DOS 2.0 BPB).
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BiosParameterBlock {
     // From the DOS 2.0 BPB:
 
@@ -172,9 +294,11 @@ pub struct BiosParameterBlock {
     pub volume_id: u32,
 
     // Offset = 0x047; def = blanks (0x20)
+    #[cfg_attr(feature = "serde", serde(with = "crate::util::serde_text"))]
     pub volume_label: [u8; 11],
 
     // Offset = 0x052; def = ["FAT32   "]
+    #[cfg_attr(feature = "serde", serde(with = "crate::util::serde_text"))]
     pub file_system_type: [u8; 8],
 }
 
@@ -221,7 +345,7 @@ impl BiosParameterBlock {
         }
     }
 
-    pub fn read(sector: &GenericArray<u8, U512>) -> Self {
+    pub fn read<N: ArrayLength<u8>>(sector: &GenericArray<u8, N>) -> Self {
         let sector = sector.as_slice();
 
         macro_rules! e {
@@ -267,17 +391,236 @@ impl BiosParameterBlock {
         }
     }
 
-    pub fn write(&self, sector: &mut GenericArray<u8, U512>) {
-        // TODO!
-        todo!()
+    /// Writes the DOS 3.31 BPB and, for FAT32 style BPBs (ones that don't
+    /// use the 16-bit FAT size), the FAT32 fields that follow it.
+    ///
+    /// The fields after that (`phys_drive_number` onwards) live in different
+    /// places for FAT12/16 and FAT32; `BootSector::write` takes care of them.
+    pub fn write<N: ArrayLength<u8>>(&self, sector: &mut GenericArray<u8, N>) {
+        let sector = sector.as_mut_slice();
+
+        macro_rules! e {
+            ($offset:literal, $val:expr) => {{
+                let bytes = $val.to_le_bytes();
+                sector[$offset..($offset + bytes.len())].copy_from_slice(&bytes);
+            }};
+        }
+
+        e!(0x00B, self.bytes_per_logical_sector);
+        e!(0x00D, self.logical_sectors_per_cluster);
+        e!(0x00E, self.num_reserved_logical_sectors);
+        e!(0x010, self.num_file_alloc_tables);
+        e!(0x011, self.max_root_dir_entries);
+        e!(0x013, self.total_logical_sectors);
+        e!(0x015, self.media_descriptor);
+        e!(0x016, self.logical_sectors_per_fat);
+
+        e!(0x018, self.phys_sectors_per_track);
+        e!(0x01A, self.num_heads);
+        e!(0x01C, self.hidden_preceeding_sectors);
+        e!(0x020, self.total_logical_sectors_extended);
+
+        if self.logical_sectors_per_fat == 0 {
+            e!(0x024, self.logical_sectors_per_fat_extended);
+            e!(0x028, self.drive_desc_mirroring_flags);
+            e!(0x02A, self.version);
+            e!(0x02C, self.root_dir_cluster_num);
+            e!(0x030, self.fs_info_logical_sector_num);
+            e!(0x032, self.boot_sector_backup_logical_sector_start_num);
+            sector[0x034..0x040].copy_from_slice(&[0; 12]);
+        }
+    }
+}
+
+/// Where FAT32 volumes usually keep a copy of their boot sector, relative to
+/// the start of the volume; `FatFs::mount` looks here when the boot sector is
+/// bad.
+pub const BACKUP_BOOT_SECTOR: u16 = 6;
+
+/// The value of the extended boot signature when the volume id, volume label,
+/// and file system type fields that follow it are present.
+pub const EXTENDED_BOOT_SIG: u8 = 0x29;
+
+impl BootSector {
+    /// Where the extended boot signature is; the volume id (4 bytes) and the
+    /// volume label (11 bytes) come right after it. FAT12/16 and FAT32 have it
+    /// in different places.
+    pub fn extended_boot_sig_offset(fat_type: FatType) -> usize {
+        match fat_type {
+            FatType::Fat12 | FatType::Fat16 => 0x026,
+            FatType::Fat32 => 0x042,
+        }
+    }
+
+    /// The volume label in the boot sector, if the extended boot signature
+    /// says it's there.
+    pub fn read_volume_label<N: ArrayLength<u8>>(
+        sector: &GenericArray<u8, N>,
+        fat_type: FatType,
+    ) -> Option<[u8; 11]> {
+        let sig = Self::extended_boot_sig_offset(fat_type);
+        let sector = sector.as_slice();
+
+        if sector[sig] == EXTENDED_BOOT_SIG {
+            Some(sector[(sig + 5)..(sig + 5 + 11)].try_into().unwrap())
+        } else {
+            None
+        }
+    }
+
+    /// The volume id (usually a serial number made from the time the volume
+    /// was formatted), if the extended boot signature says it's there.
+    pub fn read_volume_id<N: ArrayLength<u8>>(
+        sector: &GenericArray<u8, N>,
+        fat_type: FatType,
+    ) -> Option<u32> {
+        let sig = Self::extended_boot_sig_offset(fat_type);
+        let sector = sector.as_slice();
+
+        if sector[sig] == EXTENDED_BOOT_SIG {
+            Some(u32::from_le_bytes(sector[(sig + 1)..(sig + 5)].try_into().unwrap()))
+        } else {
+            None
+        }
+    }
+
+    /// The file system type string (i.e. `"FAT32   "`) in the boot sector, if
+    /// the extended boot signature says it's there. This is informational
+    /// only; it's not how the FAT type is determined.
+    pub fn read_file_system_type<N: ArrayLength<u8>>(
+        sector: &GenericArray<u8, N>,
+        fat_type: FatType,
+    ) -> Option<[u8; 8]> {
+        let sig = Self::extended_boot_sig_offset(fat_type);
+        let sector = sector.as_slice();
+
+        if sector[sig] == EXTENDED_BOOT_SIG {
+            Some(sector[(sig + 16)..(sig + 16 + 8)].try_into().unwrap())
+        } else {
+            None
+        }
     }
+
+    /// Writes `label` to the boot sector, setting the extended boot signature
+    /// if it wasn't already.
+    pub fn write_volume_label<N: ArrayLength<u8>>(
+        sector: &mut GenericArray<u8, N>,
+        fat_type: FatType,
+        label: &[u8; 11],
+    ) {
+        let sig = Self::extended_boot_sig_offset(fat_type);
+        let sector = sector.as_mut_slice();
+
+        sector[sig] = EXTENDED_BOOT_SIG;
+        sector[(sig + 5)..(sig + 5 + 11)].copy_from_slice(label);
+    }
+}
+
+/// The FAT32 FS Information Sector; it holds hints that save us from having to
+/// scan the FAT.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsInfo {
+    /// Last known number of free clusters; 0xFFFF_FFFF if unknown.
+    // Offset: 0x1E8
+    pub free_cluster_count: u32,
+
+    /// Cluster number of the most recently allocated cluster (where to start
+    /// looking for a free one); 0xFFFF_FFFF if unknown.
+    // Offset: 0x1EC
+    pub next_free_cluster: u32,
 }
 
-// TODO: FS Information Sector
+impl FsInfo {
+    pub const UNKNOWN: u32 = 0xFFFF_FFFF;
+
+    const LEAD_SIG: u32 = 0x4161_5252; // "RRaA"
+    const STRUCT_SIG: u32 = 0x6141_7272; // "rrAa"
+    const TRAIL_SIG: u32 = 0xAA55_0000;
+
+    /// `None` if the sector doesn't have the FS Information Sector's
+    /// signatures.
+    pub fn read<N: ArrayLength<u8>>(sector: &GenericArray<u8, N>) -> Option<Self> {
+        let sector = sector.as_slice();
+        let e = |offset: usize| u32::from_le_bytes(sector[offset..(offset + 4)].try_into().unwrap());
+
+        if sector.len() < 512 ||
+            e(0x000) != Self::LEAD_SIG ||
+            e(0x1E4) != Self::STRUCT_SIG ||
+            e(0x1FC) != Self::TRAIL_SIG {
+            return None;
+        }
+
+        Some(Self {
+            free_cluster_count: e(0x1E8),
+            next_free_cluster: e(0x1EC),
+        })
+    }
+
+    /// Writes the signatures and our two fields; the rest of the sector is
+    /// left alone.
+    pub fn write<N: ArrayLength<u8>>(&self, sector: &mut GenericArray<u8, N>) {
+        let sector = sector.as_mut_slice();
+        let mut e = |offset: usize, val: u32| sector[offset..(offset + 4)].copy_from_slice(&val.to_le_bytes());
+
+        e(0x000, Self::LEAD_SIG);
+        e(0x1E4, Self::STRUCT_SIG);
+        e(0x1E8, self.free_cluster_count);
+        e(0x1EC, self.next_free_cluster);
+        e(0x1FC, Self::TRAIL_SIG);
+    }
+}
 
 impl BootSector {
-    pub fn starting_fat_sector(&self) -> SectorIdx {
-        SectorIdx::new(/*(self.bpb.num_reserved_logical_sectors as u64)
-            +*/ self.bpb.hidden_preceeding_sectors as u64)
+    /// The first FAT comes right after the reserved sectors (which start with
+    /// this boot sector) so this is `volume_start` plus the number of reserved
+    /// sectors.
+    ///
+    /// `hidden_preceeding_sectors` is supposed to be `volume_start` but we
+    /// don't rely on it; lots of formatters leave it as 0 (i.e. for volumes
+    /// made in image files) and on partitioned disks it's sometimes relative
+    /// to an extended partition rather than the start of the disk.
+    pub fn starting_fat_sector(&self, volume_start: SectorIdx) -> SectorIdx {
+        SectorIdx::new(volume_start.inner() + (self.bpb.num_reserved_logical_sectors as u64))
+    }
+
+    /// FAT12/16 volumes use the DOS 2.0 field; FAT32 volumes set that to 0 and
+    /// use the extended one.
+    pub fn fat_size_in_sectors(&self) -> u32 {
+        match self.bpb.logical_sectors_per_fat {
+            0 => self.bpb.logical_sectors_per_fat_extended,
+            n => n as u32,
+        }
+    }
+
+    pub fn total_sectors(&self) -> u32 {
+        match self.bpb.total_logical_sectors {
+            0 => self.bpb.total_logical_sectors_extended,
+            n => n as u32,
+        }
+    }
+
+    /// Size of the fixed root directory region that FAT12/16 volumes have; 0
+    /// for FAT32.
+    pub fn root_dir_size_in_sectors(&self) -> u32 {
+        let bytes = (self.bpb.max_root_dir_entries as u32) * 32;
+        let sector_size = self.bpb.bytes_per_logical_sector as u32;
+
+        (bytes + sector_size.saturating_sub(1))
+            .checked_div(sector_size)
+            .unwrap_or(0)
+    }
+
+    pub fn cluster_count(&self) -> u32 {
+        let metadata_sectors = (self.bpb.num_reserved_logical_sectors as u32) +
+            (self.bpb.num_file_alloc_tables as u32) * self.fat_size_in_sectors() +
+            self.root_dir_size_in_sectors();
+
+        self.total_sectors().saturating_sub(metadata_sectors)
+            .checked_div(self.bpb.logical_sectors_per_cluster as u32)
+            .unwrap_or(0)
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        FatType::from_cluster_count(self.cluster_count())
     }
 }
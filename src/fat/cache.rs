@@ -1,18 +1,37 @@
 //! Home of the `SectorCache` type; that which all writes and reads to `Storage`
 //! flow through.
 
-use super::types::SectorIdx;
+use super::FatError;
+use super::types::{SectorIdx, SectorRange};
 use crate::util::{BitMap, BitMapLen};
 
 use storage_traits::Storage;
 use generic_array::{ArrayLength, GenericArray};
+use typenum::marker_traits::Unsigned;
 
-use core::cell::{RefCell, RefMut, Ref};
+use core::cell::{Cell, RefCell, RefMut, Ref};
 use core::cmp::Ordering;
 use core::marker::PhantomData;
-use core::ops::{Index, IndexMut};
+use core::ops::{Index, IndexMut, Range};
 use core::fmt::{self, Debug};
 
+/// `log::trace!` but only with the `cache-trace` feature enabled; the cache's
+/// hot paths don't pay for the checks otherwise.
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "cache-trace")]
+        { log::trace!($($arg)*); }
+    };
+}
+
+/// Like `trace!` but for `log::debug!`.
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "cache-trace")]
+        { log::debug!($($arg)*); }
+    };
+}
+
 /// Counter type with interior mutability that implements `Copy`
 /// (unlike `Cell<u64>`).
 ///
@@ -38,10 +57,10 @@ impl CopyCounter {
 
 #[derive(Debug, Clone, Copy)]
 pub enum CacheEntry {
-    /// Present but unmodified; can be freely evicted.
-    Resident { s: SectorIdx, arr_idx: usize, age: u64, last_accessed: CopyCounter },
+    /// Present but unmodified; can be freely evicted (unless pinned).
+    Resident { s: SectorIdx, arr_idx: usize, age: u64, last_accessed: CopyCounter, pinned: bool },
     /// Present and contains modifications.
-    Dirty { s: SectorIdx, arr_idx: usize, age: u64, last_accessed: CopyCounter },
+    Dirty { s: SectorIdx, arr_idx: usize, age: u64, last_accessed: CopyCounter, pinned: bool },
     /// Does not contain a sector.
     Free,
 }
@@ -53,20 +72,16 @@ impl CacheEntry {
 
         if *counter < age { log::warn!("Internal cache counter overflowed!"); }
 
-        Self::Resident { s: sector, arr_idx: idx, age, last_accessed: CopyCounter::new(0) }
-    }
-
-    fn new_for_lookup(s: SectorIdx) -> Self {
-        Self::Resident { s, arr_idx: 0, age: 0, last_accessed: CopyCounter::new(0) }
+        Self::Resident { s: sector, arr_idx: idx, age, last_accessed: CopyCounter::new(0), pinned: false }
     }
 
     /// Errors if the `CacheEntry` is `Free`, otherwise succeeds.
     /*pub */fn mark_as_dirty(&mut self) -> Result<(), ()> {
         use CacheEntry::*;
         *self = match *self {
-            Resident { s, arr_idx, age, last_accessed } |
-            Dirty { s, arr_idx, age, last_accessed } =>
-                Dirty { s, arr_idx, age, last_accessed },
+            Resident { s, arr_idx, age, last_accessed, pinned } |
+            Dirty { s, arr_idx, age, last_accessed, pinned } =>
+                Dirty { s, arr_idx, age, last_accessed, pinned },
             Free => return Err(()),
         };
 
@@ -77,8 +92,8 @@ impl CacheEntry {
     /*pub */fn mark_as_clean(&mut self) -> Result<(), ()> {
         use CacheEntry::*;
         *self = match *self {
-            Dirty { s, arr_idx, age, last_accessed } =>
-                Resident { s, arr_idx, age, last_accessed },
+            Dirty { s, arr_idx, age, last_accessed, pinned } =>
+                Resident { s, arr_idx, age, last_accessed, pinned },
 
             Resident { .. } | Free => return Err(()),
         };
@@ -90,6 +105,22 @@ impl CacheEntry {
         matches!(self, CacheEntry::Dirty { .. })
     }
 
+    /// Pinned entries are never picked for eviction.
+    pub fn is_pinned(&self) -> bool {
+        matches!(self, CacheEntry::Resident { pinned: true, .. } | CacheEntry::Dirty { pinned: true, .. })
+    }
+
+    /// Errors if the `CacheEntry` is `Free`.
+    /*pub */fn set_pinned(&mut self, pin: bool) -> Result<(), ()> {
+        use CacheEntry::*;
+        match self {
+            Resident { pinned, .. } | Dirty { pinned, .. } => *pinned = pin,
+            Free => return Err(()),
+        }
+
+        Ok(())
+    }
+
     /// `None` if the `CacheEntry` is `Free`; succeeds otherwise.
     /*pub */fn get_sector_idx(&self) -> Option<SectorIdx> {
         use CacheEntry::*;
@@ -192,22 +223,53 @@ impl Ord for CacheEntry {
 
 impl Default for CacheEntry { fn default() -> Self { CacheEntry::Free } }
 
+/// A link in the hash index `CacheTable` keeps alongside its entries: the
+/// position of an entry in the table or nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IndexLink(u32);
+
+impl IndexLink {
+    const NONE: Self = Self(u32::max_value());
+
+    fn to(pos: usize) -> Self {
+        debug_assert!(pos < (u32::max_value() as usize));
+        Self(pos as u32)
+    }
+
+    fn get(self) -> Option<usize> {
+        if self == Self::NONE { None } else { Some(self.0 as usize) }
+    }
+}
+
+impl Default for IndexLink { fn default() -> Self { IndexLink::NONE } }
+
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
 #[allow(non_camel_case_types)]
-pub struct CacheTable<SIZE: ArrayLength<CacheEntry>> {
-    // To help make cache lookups faster, we keep this in sorted order.
+pub struct CacheTable<SIZE: ArrayLength<CacheEntry> + ArrayLength<IndexLink>> {
+    // The entry for the sector in the `n`th slot of the sector cache lives at
+    // position `n` in this table; entries never move which makes inserts and
+    // removals O(1). Eviction policies sweep over this whole array, `Free`
+    // entries included.
     cache_entry_table: GenericArray<CacheEntry, SIZE>,
 
+    // To find entries by sector, we keep a chained hash index: `buckets` has
+    // the position of the first entry for each hash bucket and `chain` has
+    // the position of the entry after each entry in the same bucket.
+    //
+    // With as many buckets as there are entries, chains are short.
+    buckets: GenericArray<IndexLink, SIZE>,
+    chain: GenericArray<IndexLink, SIZE>,
+
     length: usize,
 }
 
-impl<S: ArrayLength<CacheEntry>> CacheTable<S> {
+impl<S: ArrayLength<CacheEntry> + ArrayLength<IndexLink>> CacheTable<S> {
     pub fn new() -> Self {
         Default::default()
     }
 
     pub fn capacity() -> usize {
-        S::to_usize()
+        <S as Unsigned>::to_usize()
     }
 
     pub fn len(&self) -> usize {
@@ -218,31 +280,44 @@ impl<S: ArrayLength<CacheEntry>> CacheTable<S> {
         Self::capacity() - self.len()
     }
 
+    fn bucket(s: SectorIdx) -> usize {
+        // Fibonacci hashing; sequential sectors end up spread out.
+        let hash = s.inner().wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32;
+        (hash as usize) % Self::capacity()
+    }
+
+    /// Where in the table the entry for a sector is, if it's present.
+    /*pub */fn position(&self, s: SectorIdx) -> Option<usize> {
+        if Self::capacity() == 0 { return None; }
+
+        let mut link = self.buckets[Self::bucket(s)];
+        while let Some(pos) = link.get() {
+            if self.cache_entry_table[pos].get_sector_idx() == Some(s) {
+                return Some(pos);
+            }
+
+            link = self.chain[pos];
+        }
+
+        None
+    }
+
     /*pub */fn get(&self, s: SectorIdx) -> Option<&CacheEntry> {
-        let entry = CacheEntry::new_for_lookup(s);
-        self.cache_entry_table
-            .as_slice()
-            .binary_search(&entry)
-            .ok()
-            .map(|idx| &self.cache_entry_table.as_slice()[idx])
+        self.position(s).map(move |pos| &self.cache_entry_table[pos])
     }
 
     /*pub */fn get_mut(&mut self, s: SectorIdx) -> Option<&mut CacheEntry> {
-        // Basically the same as the above save for the as_mut_slice calls.
-        // Blame the borrow checker for the asymmetry.
-
-        let entry = CacheEntry::new_for_lookup(s);
-        match self.cache_entry_table
-            .as_mut_slice()
-            .binary_search(&entry)
-            .ok() {
-            Some(idx) => Some(&mut self.cache_entry_table[idx]),
+        match self.position(s) {
+            Some(pos) => Some(&mut self.cache_entry_table[pos]),
             None => None,
         }
     }
 
     /// All newly inserted entries are marked as resident.
     ///
+    /// The entry is placed at the same position in the table as the sector's
+    /// slot in the cache (`idx`), which must be free.
+    ///
     /// Returns an `Err(Some(_))` if the table already contains an entry with
     /// the sector in question.
     ///
@@ -253,53 +328,30 @@ impl<S: ArrayLength<CacheEntry>> CacheTable<S> {
         idx: usize,
         counter: &mut u64,
     ) -> Result<&mut CacheEntry, Option<&mut CacheEntry>> {
-        let entry = CacheEntry::new(s, idx, counter);
-        match self.cache_entry_table.binary_search(&entry) {
-            // If the sector is already in the table, return it's entry:
-            Ok(idx) => {
-                Err(Some(&mut self.cache_entry_table.as_mut_slice()[idx]))
-            },
+        // If the sector is already in the table, return it's entry:
+        if let Some(pos) = self.position(s) {
+            return Err(Some(&mut self.cache_entry_table[pos]));
+        }
 
-            Err(idx) => {
-                // If it's not present, we were just told where to place this
-                // entry.
+        // Make sure we have room for it:
+        if self.free_entries() == 0 {
+            return Err(None);
+        }
 
-                // First let's make sure we have room for it:
-                if self.free_entries() == 0 {
-                    return Err(None);
-                }
+        // The bitmap in the sector cache should agree with us about which
+        // slots are free:
+        assert!(self.cache_entry_table[idx] == CacheEntry::Free);
 
-                // Just to be extra sure, double check that the last element
-                // really is free (since we're only adding one thing we only
-                // need to check the last element):
-                match self.cache_entry_table.as_slice().last() {
-                    Some(last) => {
-                        assert!(last == &CacheEntry::Free)
-                    },
-                    None => {
-                        // Zero does satisfy the `Unsigned` trait so it's
-                        // possible to construct an instance of this type with
-                        // SIZE = 0, but the above check (free_entries >= 1)
-                        // should catch this.
-                        unreachable!()
-                    },
-                }
+        // Put the entry in its place and make it the head of its bucket:
+        let bucket = Self::bucket(s);
+        self.chain[idx] = self.buckets[bucket];
+        self.buckets[bucket] = IndexLink::to(idx);
 
-                // Now, shift everything at and after the index we were told to
-                // insert into one place to the right. Note that we stop at
-                // self.length() because there's no reason we need to bother
-                // copying empty elements.
-                self.cache_entry_table.copy_within(idx..(self.length), idx + 1);
+        self.length += 1;
 
-                // Increment our length:
-                self.length += 1;
-
-                // And finally, put our new element into place and return it.
-                let slot = &mut self.cache_entry_table[idx];
-                *slot = entry;
-                Ok(slot)
-            }
-        }
+        let slot = &mut self.cache_entry_table[idx];
+        *slot = CacheEntry::new(s, idx, counter);
+        Ok(slot)
     }
 
     /// Tries to remove an entry with the given sector.
@@ -315,54 +367,44 @@ impl<S: ArrayLength<CacheEntry>> CacheTable<S> {
     ) -> Result<usize, Option<&mut CacheEntry>> {
         use CacheEntry::*;
 
-        let entry = CacheEntry::new_for_lookup(s);
-        match self.cache_entry_table.binary_search(&entry) {
-            Ok(idx) => {
-                match self.cache_entry_table[idx] {
-                    Resident { arr_idx, .. } => {
-                        // Move the remaining entries left one.
-                        //
-                        // | a | b | c | E | e | f | _ | _ | _ | _ |
-                        //                  \     /
-                        //                  copy to:
-                        //                     |
-                        //                 /---/
-                        //                 V
-                        //              /     \
-                        // | a | b | c | E | e | f | _ | _ | _ | _ |
-                        // | a | b | c | e | f | f | _ | _ | _ | _ |
-                        //
-                        // And then zero the last element:
-                        // | a | b | c | e | f | f | _ | _ | _ | _ |
-                        //
-                        //                   |
-                        //                   V
-                        //
-                        // | a | b | c | e | f | _ | _ | _ | _ | _ |
-                        //
-                        // This works even when there are no following entries.
-
-                        self.cache_entry_table
-                            .copy_within((idx + 1)..(self.length), idx);
-
-                        self.length -= 1;
-                        self.cache_entry_table[self.length] = CacheEntry::Free;
-
-                        Ok(arr_idx)
-                    },
-
-                    // If it's dirty, error:
-                    Dirty { .. } => Err(Some(&mut self.cache_entry_table[idx])),
-
-                    // This can't happen; lookup _can't_ return a Free sector.
-                    Free => unreachable!(),
+        let pos = match self.position(s) {
+            Some(pos) => pos,
+            // If a corresponding Entry is not present, error:
+            None => return Err(None),
+        };
+
+        match self.cache_entry_table[pos] {
+            Resident { arr_idx, .. } => {
+                // Unlink the entry from its bucket's chain:
+                let next = self.chain[pos];
+                let bucket = Self::bucket(s);
+
+                if self.buckets[bucket] == IndexLink::to(pos) {
+                    self.buckets[bucket] = next;
+                } else {
+                    let mut prev = self.buckets[bucket].get()
+                        .expect("entries are in their bucket's chain");
+
+                    while self.chain[prev] != IndexLink::to(pos) {
+                        prev = self.chain[prev].get()
+                            .expect("entries are in their bucket's chain");
+                    }
+
+                    self.chain[prev] = next;
                 }
+
+                self.chain[pos] = IndexLink::NONE;
+                self.cache_entry_table[pos] = CacheEntry::Free;
+                self.length -= 1;
+
+                Ok(arr_idx)
             },
 
-            Err(_) => {
-                // If a corresponding Entry is not present, error:
-                Err(None)
-            }
+            // If it's dirty, error:
+            Dirty { .. } => Err(Some(&mut self.cache_entry_table[pos])),
+
+            // This can't happen; lookup _can't_ return a Free sector.
+            Free => unreachable!(),
         }
     }
 
@@ -390,9 +432,14 @@ pub trait EvictionPolicy {
     /// This only takes &self to be object safe.
     fn compare(&self, a: &CacheEntry, b: &CacheEntry) -> Ordering;
 
-    /// Returns `None` if there are no elements in the array.
+    /// Returns `None` if there are no elements in the array that can be
+    /// evicted.
+    ///
+    /// Pinned entries must never be picked; implementors that override this
+    /// should skip them as well.
     fn pick_entry_to_evict<'arr>(&self, arr: &'arr mut [CacheEntry]) -> Option<&'arr mut CacheEntry> {
         arr.iter_mut()
+            .filter(|e| !e.is_pinned())
             .max_by(|a, b| self.compare(a, b))
     }
 }
@@ -552,6 +599,226 @@ pub mod eviction_policies {
     pub static MODIFIED_THEN_LEAST_RECENTLY_ACCESSED: DynEvictionPolicy =
         &ModifiedFirst::<LeastRecentlyAccessed>(LeastRecentlyAccessed);
 
+    // The combinators below are for building policies in user code; i.e.
+    // "data sectors before metadata sectors, then least recently accessed":
+    //
+    // ```rust,ignore
+    // Then(
+    //     ByKey(|e: &CacheEntry| matches!(e, Resident { s, .. } | Dirty { s, .. } if *s >= DATA_START)),
+    //     LeastRecentlyAccessed,
+    // )
+    // ```
+
+    /// Prefers the entry for which the function returns the greater key.
+    ///
+    /// As with the other policies `Free` entries are always picked first; the
+    /// function is only called on `Resident` and `Dirty` entries.
+    #[derive(Debug, Copy, Clone, Default)]
+    pub struct ByKey<F>(pub F);
+
+    impl<F, K> EvictionPolicy for ByKey<F>
+    where
+        F: Fn(&CacheEntry) -> K,
+        K: Ord,
+    {
+        fn compare(&self, a: &CacheEntry, b: &CacheEntry) -> Ordering {
+            match (a, b) {
+                (Free, Free) => Ordering::Equal,
+                (Free, _) => Ordering::Greater,
+                (_, Free) => Ordering::Less,
+
+                (a, b) => (self.0)(a).cmp(&(self.0)(b)),
+            }
+        }
+    }
+
+    /// Flips the preference of the inner policy, except that `Free` entries
+    /// are still picked first.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord,
+        Hash, Default
+    )]
+    pub struct Reversed<P: EvictionPolicy>(pub P);
+
+    impl<P: EvictionPolicy> EvictionPolicy for Reversed<P> {
+        fn compare(&self, a: &CacheEntry, b: &CacheEntry) -> Ordering {
+            match (a, b) {
+                (Free, Free) => Ordering::Equal,
+                (Free, _) => Ordering::Greater,
+                (_, Free) => Ordering::Less,
+
+                (a, b) => self.0.compare(a, b).reverse(),
+            }
+        }
+    }
+
+    /// Uses the second policy to break the first policy's ties, like
+    /// [`Ordering::then`].
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord,
+        Hash, Default
+    )]
+    pub struct Then<P: EvictionPolicy, Q: EvictionPolicy>(pub P, pub Q);
+
+    impl<P: EvictionPolicy, Q: EvictionPolicy> EvictionPolicy for Then<P, Q> {
+        fn compare(&self, a: &CacheEntry, b: &CacheEntry) -> Ordering {
+            self.0.compare(a, b).then_with(|| self.1.compare(a, b))
+        }
+    }
+}
+
+/// How writes to cached sectors make their way to `Storage`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum CacheMode {
+    /// Modified sectors are marked as dirty and are only written out when
+    /// they're evicted or when the cache is flushed.
+    WriteBack,
+    /// Modified sectors are written out as soon as the mutable borrow that
+    /// modified them ends. Entries stay `Resident` rather than `Dirty`.
+    ///
+    /// This trades throughput for durability: a power loss can't leave the
+    /// volume with modifications that only ever made it into the cache.
+    WriteThrough,
+}
+
+impl Default for CacheMode { fn default() -> Self { CacheMode::WriteBack } }
+
+/// Returned when some of the dirty sectors in a `SectorCache` couldn't be
+/// written out.
+///
+/// The sectors that failed are left dirty (so a later flush can retry them);
+/// `failed_sectors` lists them.
+#[allow(non_camel_case_types)]
+#[derive(Clone, PartialEq, Eq)]
+pub struct FlushError<CACHE_SIZE: ArrayLength<CacheEntry>> {
+    /// The entries that couldn't be written out, sorted by sector; the rest
+    /// are `Free`.
+    failed: GenericArray<CacheEntry, CACHE_SIZE>,
+    num_failed: usize,
+    /// The lowest numbered sector that was written but read back differently
+    /// (only checked when `SectorCache::set_verify_writes` is on). These are
+    /// in `failed_sectors` too.
+    pub verify_error: Option<VerifyError>,
+}
+
+#[allow(non_camel_case_types)]
+impl<CACHE_SIZE: ArrayLength<CacheEntry>> FlushError<CACHE_SIZE> {
+    fn new() -> Self {
+        Self { failed: Default::default(), num_failed: 0, verify_error: None }
+    }
+
+    fn push(&mut self, entry: CacheEntry, verify_error: Option<VerifyError>) {
+        self.failed[self.num_failed] = entry;
+        self.num_failed += 1;
+
+        self.verify_error = match (self.verify_error, verify_error) {
+            (Some(a), Some(b)) if b.sector < a.sector => Some(b),
+            (a, b) => a.or(b),
+        };
+    }
+
+    pub fn num_failed(&self) -> usize {
+        self.num_failed
+    }
+
+    /// The lowest numbered sector that couldn't be written.
+    pub fn first_failed(&self) -> SectorIdx {
+        self.failed_sectors().next().expect("a flush error has at least one failed sector")
+    }
+
+    /// Every sector that couldn't be written, in order.
+    pub fn failed_sectors<'a>(&'a self) -> impl Iterator<Item = SectorIdx> + 'a {
+        self.failed[..self.num_failed].iter().filter_map(CacheEntry::get_sector_idx)
+    }
+}
+
+// Not derived so that `CACHE_SIZE` doesn't have to be `Debug`.
+#[allow(non_camel_case_types)]
+impl<CACHE_SIZE: ArrayLength<CacheEntry>> Debug for FlushError<CACHE_SIZE> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FlushError")
+            .field("failed", &&self.failed[..self.num_failed])
+            .field("verify_error", &self.verify_error)
+            .finish()
+    }
+}
+
+/// A sector that didn't read back the same right after it was written out;
+/// see `SectorCache::set_verify_writes`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct VerifyError {
+    pub sector: SectorIdx,
+}
+
+/// Returned by `SectorCache::invalidate_all` when there are modified sectors
+/// that it couldn't drop.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct InvalidateError {
+    pub dirty_sectors: usize,
+}
+
+/// Why `write_out` failed.
+enum WriteOutError {
+    Storage(FatError),
+    Verify(VerifyError),
+}
+
+impl From<WriteOutError> for FatError {
+    fn from(err: WriteOutError) -> Self {
+        match err {
+            WriteOutError::Storage(err) => err,
+            // The data didn't make it to storage; as far as callers are
+            // concerned the write failed.
+            WriteOutError::Verify(_) => FatError::Io,
+        }
+    }
+}
+
+/// Writes `data` to `sector`. With `verify` set the sector is then read back
+/// and compared against `data`.
+fn write_out<S: Storage<Word = u8>>(
+    storage: &mut S,
+    sector: SectorIdx,
+    data: &GenericArray<u8, S::SECTOR_SIZE>,
+    verify: bool,
+) -> Result<(), WriteOutError> {
+    storage.write_sector(sector.idx(), data)
+        .map_err(|err| WriteOutError::Storage(err.into()))?;
+
+    if !verify { return Ok(()); }
+
+    let mut read_back = GenericArray::<u8, S::SECTOR_SIZE>::default();
+    storage.read_sector(sector.idx(), &mut read_back)
+        .map_err(|err| WriteOutError::Storage(err.into()))?;
+
+    if read_back == *data {
+        Ok(())
+    } else {
+        debug!("cache: sector {} read back differently after being written", sector.inner());
+        Err(WriteOutError::Verify(VerifyError { sector }))
+    }
+}
+
+/// Counters that describe how effective a `SectorCache` has been.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub struct CacheStats {
+    /// Lookups for sectors that were already in the cache.
+    pub hits: u64,
+    /// Lookups for sectors that had to be loaded from `Storage`.
+    pub misses: u64,
+    /// Entries that were evicted to make room for other sectors.
+    pub evictions: u64,
+}
+
+impl CacheStats {
+    /// `None` if there haven't been any lookups.
+    pub fn hit_ratio(&self) -> Option<f64> {
+        let total = self.hits + self.misses;
+
+        if total == 0 {
+            None
+        } else {
+            Some((self.hits as f64) / (total as f64))
+        }
+    }
 }
 
 #[allow(non_camel_case_types)]
@@ -561,6 +828,7 @@ where
     SECTOR_SIZE: ArrayLength<u8>,
     CACHE_SIZE_IN_SECTORS: ArrayLength<RefCell<GenericArray<u8, SECTOR_SIZE>>>,
     CACHE_SIZE_IN_SECTORS: ArrayLength<CacheEntry>,
+    CACHE_SIZE_IN_SECTORS: ArrayLength<IndexLink>,
     CACHE_SIZE_IN_SECTORS: BitMapLen,
     Eviction: EvictionPolicy,
 {
@@ -573,6 +841,15 @@ where
     eviction_policy: Eviction,
     counter: RefCell<u64>,
 
+    mode: CacheMode,
+    readahead_window: usize,
+    verify_writes: bool,
+    generation: u64,
+
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+
     _s: PhantomData<StorageImpl>,
 }
 
@@ -583,6 +860,7 @@ where
     SECT_SIZE: ArrayLength<u8>,
     CACHE_SIZE: ArrayLength<RefCell<GenericArray<u8, SECT_SIZE>>>,
     CACHE_SIZE: ArrayLength<CacheEntry>,
+    CACHE_SIZE: ArrayLength<IndexLink>,
     CACHE_SIZE: BitMapLen,
     Ev: EvictionPolicy,
 {
@@ -598,6 +876,7 @@ where
     SECT_SIZE: ArrayLength<u8>,
     CACHE_SIZE: ArrayLength<RefCell<GenericArray<u8, SECT_SIZE>>>,
     CACHE_SIZE: ArrayLength<CacheEntry>,
+    CACHE_SIZE: ArrayLength<IndexLink>,
     CACHE_SIZE: BitMapLen,
     Ev: EvictionPolicy,
 {
@@ -616,27 +895,231 @@ where
             eviction_policy: ev,
             counter: RefCell::new(0),
 
+            mode: CacheMode::WriteBack,
+            readahead_window: CACHE_SIZE::to_usize(),
+            verify_writes: false,
+            generation: 0,
+
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+
             _s: PhantomData,
         }
     }
 
-    /// Returns `Err` if there are no entries there to evict.
-    /*pub */fn evict_entry(&mut self, storage: &mut S) -> Result<(), ()> {
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+        }
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.hits = 0;
+        self.misses = 0;
+        self.evictions = 0;
+    }
+
+    pub fn mode(&self) -> CacheMode {
+        self.mode
+    }
+
+    /// Note that switching to `WriteThrough` does not write out sectors that
+    /// are already dirty; call `flush` for that.
+    pub fn set_mode(&mut self, mode: CacheMode) {
+        self.mode = mode
+    }
+
+    /// How many sectors past a miss `SectorCacheWithStorage::get_range` will
+    /// try to read ahead; also used by file reads to decide how much of the
+    /// next cluster to `prefetch`.
+    pub fn readahead_window(&self) -> usize {
+        self.readahead_window
+    }
+
+    /// Defaults to the size of the cache; 0 disables read ahead.
+    pub fn set_readahead_window(&mut self, sectors: usize) {
+        self.readahead_window = sectors
+    }
+
+    pub fn verify_writes(&self) -> bool {
+        self.verify_writes
+    }
+
+    /// When enabled every sector the cache writes out (on flushes, evictions,
+    /// and write through) is immediately read back and compared against what
+    /// was written; sectors that don't match are treated as failed writes and
+    /// stay dirty. `flush` reports them in `FlushError::verify_error`.
+    ///
+    /// This doubles the I/O for writes so it's off by default; it's meant for
+    /// tracking down storage that silently drops or corrupts writes.
+    pub fn set_verify_writes(&mut self, enable: bool) {
+        self.verify_writes = enable
+    }
+
+    /// How many times `invalidate_all` has been called; code that holds on to
+    /// things it read through the cache can compare this to tell whether the
+    /// media might have changed since.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Forgets every sector that hasn't been modified so that the next access
+    /// to each one reads it from the storage again. Call this when the media
+    /// might have changed out from under the cache (i.e. a card was removed
+    /// and put back). Unmodified pinned sectors are forgotten (and unpinned) too.
+    ///
+    /// Modified sectors belong to the old media so they can't be written out
+    /// to the new one, but they can't be silently thrown away either; they're
+    /// kept and this errors with how many there are. Flush before the media
+    /// goes away to avoid this.
+    ///
+    /// Bumps `generation` either way.
+    pub fn invalidate_all(&mut self) -> Result<(), InvalidateError> {
+        self.generation = self.generation.wrapping_add(1);
+
+        for pos in 0..CACHE_SIZE::to_usize() {
+            if let CacheEntry::Resident { s, .. } = self.cache_table.cache_entry_table[pos] {
+                let arr_idx = self.cache_table.remove(s).expect("to be able to remove clean entries");
+                self.cache_bitmap.set(arr_idx, false).unwrap();
+            }
+        }
+
+        debug!("cache: invalidated; now on generation {}", self.generation);
+
+        match self.dirty_count() {
+            0 => Ok(()),
+            dirty_sectors => Err(InvalidateError { dirty_sectors }),
+        }
+    }
+
+    /// Loads `sector` (if it isn't already cached) and keeps it in the cache
+    /// until it's unpinned; the eviction policy never picks pinned sectors.
+    /// Pinned sectors can still be modified and are flushed like any other
+    /// sector.
+    ///
+    /// At least one slot in the cache is always left unpinned so that other
+    /// sectors can still be loaded; pinning a sector past that point errors
+    /// with `FatError::OutOfSpace`. Pinning a sector that's already pinned
+    /// does nothing.
+    pub fn pin(&mut self, storage: &mut S, sector: SectorIdx) -> Result<(), FatError> {
+        if self.cache_table.get(sector).map_or(false, CacheEntry::is_pinned) {
+            return Ok(());
+        }
+
+        if self.pinned_count() + 1 >= CACHE_SIZE::to_usize() {
+            return Err(FatError::OutOfSpace);
+        }
+
+        let (entry, _) = self.try_get_sector_entry(storage, sector)?;
+        entry.set_pinned(true).expect("entry isn't `Free`");
+
+        Ok(())
+    }
+
+    /// Lets `sector` be evicted again. Returns `false` if it wasn't pinned.
+    pub fn unpin(&mut self, sector: SectorIdx) -> bool {
+        match self.cache_table.get_mut(sector) {
+            Some(entry) if entry.is_pinned() => {
+                entry.set_pinned(false).expect("entry isn't `Free`");
+                true
+            },
+            _ => false,
+        }
+    }
+
+    pub fn pinned_count(&self) -> usize {
+        self.cache_table.cache_entry_table.iter().filter(|e| e.is_pinned()).count()
+    }
+
+    /// Loads the sectors in `range` that aren't cached yet, ahead of them
+    /// being asked for.
+    ///
+    /// This is best-effort: free slots are used first, after which we'll
+    /// evict entries chosen by the eviction policy as long as they're clean
+    /// and not borrowed. Once the policy picks an entry that is, we stop
+    /// instead of writing back dirty data. At most a cache's worth of sectors
+    /// are loaded.
+    ///
+    /// Prefetched sectors count as hits when they're accessed; loading them
+    /// doesn't count as a miss.
+    pub fn prefetch(&mut self, storage: &mut S, range: Range<SectorIdx>) {
+        let end = core::cmp::min(range.end, self.num_sectors);
+        let end = core::cmp::min(end, range.start.checked_add(CACHE_SIZE::to_u64()).unwrap_or(end));
+
+        for sector in SectorRange::new(range.start..end) {
+            if self.cache_table.get(sector).is_some() {
+                continue;
+            }
+
+            let idx = match self.cache_bitmap.next_empty_bit() {
+                Ok(idx) => idx,
+                Err(()) => {
+                    if self.evict_clean_entry().is_err() {
+                        break;
+                    }
+
+                    self.cache_bitmap.next_empty_bit().expect("an empty sector after eviction")
+                },
+            };
+
+            if self.load_sector(storage, sector, idx).is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Like `evict_entry` but errors instead of evicting an entry that's dirty
+    /// or borrowed.
+    fn evict_clean_entry(&mut self) -> Result<(), ()> {
         if self.cache_table.len() == 0 { return Err(()); }
 
+        // `None` here means everything in the cache is pinned.
         let entry = self.eviction_policy.pick_entry_to_evict(
                 &mut self.cache_table.cache_entry_table)
-            .expect("must give an entry to evict when the cache table is not \
-                empty");
+            .ok_or(())?;
+
+        let sector_idx = entry.get_sector_idx().expect("resident entries have a sector index");
+        let arr_idx = entry.get_arr_idx().expect("resident entries have an arr index");
+
+        if entry.is_dirty() || self.cached_sectors[arr_idx].try_borrow_mut().is_err() {
+            return Err(());
+        }
+
+        self.cache_table.remove(sector_idx).expect("to be able to remove clean entries");
+        self.cache_bitmap.set(arr_idx, false).unwrap();
+
+        self.evictions += 1;
+
+        Ok(())
+    }
+
+    /// Returns `Err` if there are no entries there to evict (i.e. they're all
+    /// pinned) or if the entry
+    /// picked is dirty and writing it out fails (in which case it's left in the
+    /// cache).
+    /*pub */fn evict_entry(&mut self, storage: &mut S) -> Result<(), FatError> {
+        if self.cache_table.len() == 0 { return Err(FatError::OutOfSpace); }
+
+        // `None` here means everything in the cache is pinned.
+        let entry = self.eviction_policy.pick_entry_to_evict(
+                &mut self.cache_table.cache_entry_table)
+            .ok_or(FatError::OutOfSpace)?;
 
         let sector_idx = entry.get_sector_idx().expect("dirty entries have a sector index");
         let arr_idx = entry.get_arr_idx().expect("dirty entries have an arr index");
 
+        debug!("cache: evicting sector {} (dirty: {}); policy picked {:?}",
+            sector_idx.inner(), entry.is_dirty(), entry);
+
         // Check if the entry we're to remove is dirty:
         if entry.is_dirty() {
             // If it is, write it out:
-            storage.write_sector(
-                sector_idx.idx(),
+            write_out(
+                storage,
+                sector_idx,
                 // We do a mutable borrow here even though we don't _need_ to
                 // because we want to make sure that no one else has a reference
                 // to this sector that's being evicted. While we don't remove
@@ -645,7 +1128,8 @@ where
                 &self.cached_sectors[arr_idx]
                     .try_borrow_mut()
                     .expect("no references to a sector we're about to evict"),
-            ).unwrap();
+                self.verify_writes,
+            )?;
 
             // And mark it as clean:
             entry.mark_as_clean().unwrap();
@@ -655,27 +1139,175 @@ where
         self.cache_table.remove(sector_idx).expect("to be able to remove clean entries");
         self.cache_bitmap.set(arr_idx, false).unwrap();
 
+        self.evictions += 1;
+
         Ok(())
     }
 
     // Since storage has to be passed into us, unfortunately we can't do this
     // on Drop...
-    pub fn flush(&mut self, storage: &mut S) -> Result<(), ()> {
+    //
+    // Attempts to write out every dirty sector, even if some of the writes
+    // fail. Sectors that couldn't be written stay dirty (so a later flush can
+    // retry them) and are listed in `FlushError::failed_sectors`.
+    pub fn flush(&mut self, storage: &mut S) -> Result<(), FlushError<CACHE_SIZE_IN_SECTORS>> {
+        self.flush_matching(storage, |_| true)
+    }
+
+    /// Like `flush` but only writes out the dirty sectors in `range`; the
+    /// others are left dirty.
+    pub fn flush_range(&mut self, storage: &mut S, range: Range<SectorIdx>) -> Result<(), FlushError<CACHE_SIZE_IN_SECTORS>> {
+        self.flush_matching(storage, |s| range.contains(&s))
+    }
+
+    fn flush_matching(
+        &mut self,
+        storage: &mut S,
+        mut pred: impl FnMut(SectorIdx) -> bool,
+    ) -> Result<(), FlushError<CACHE_SIZE_IN_SECTORS>> {
         let ref cached_sectors = self.cached_sectors;
+        let verify = self.verify_writes;
+        let mut err: Option<FlushError<CACHE_SIZE_IN_SECTORS>> = None;
+        #[cfg(feature = "cache-trace")]
+        let mut written = 0usize;
+
+        self.cache_table.for_each_dirty_entry::<(), _>(|(_, e)| {
+            let sector_idx = e.get_sector_idx().expect("dirty entries have a sector index");
+            let arr_idx = e.get_arr_idx().expect("dirty entries have an arr index");
+
+            if !pred(sector_idx) { return Ok(()); }
 
-        self.cache_table.for_each_dirty_entry(|(idx, e)| {
-            storage.write_sector(
-                e.get_sector_idx().expect("dirty entries have a sector index").idx(),
+            let res = write_out(
+                storage,
+                sector_idx,
                 // We don't actually need a mutable borrow here but, as the
                 // message below explains, we should always get it and it's a
                 // good sanity test.
-                &cached_sectors[idx]
+                &cached_sectors[arr_idx]
                     .try_borrow_mut()
                     .expect("no references to any sectors when we have a mutable reference to the sector cache"),
-            ).unwrap();
+                verify,
+            );
+
+            match res {
+                Ok(()) => {
+                    e.mark_as_clean().unwrap();
+                    #[cfg(feature = "cache-trace")]
+                    { written += 1; }
+                },
+                Err(w) => {
+                    let verify_error = match w {
+                        WriteOutError::Verify(v) => Some(v),
+                        WriteOutError::Storage(_) => None,
+                    };
 
-            e.mark_as_clean()
-        })
+                    err.get_or_insert_with(FlushError::new).push(*e, verify_error);
+                },
+            }
+
+            Ok(())
+        }).unwrap();
+
+        debug!("cache: flush wrote {} sectors ({} failed)",
+            written, err.as_ref().map_or(0, |e| e.num_failed));
+
+        match err {
+            Some(mut err) => {
+                err.failed.sort_unstable();
+                Err(err)
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Sectors in the cache that have modifications that haven't been written
+    /// out.
+    pub fn dirty_sectors<'a>(&'a self) -> impl Iterator<Item = SectorIdx> + 'a {
+        self.cache_table.cache_entry_table
+            .iter()
+            .filter(|e| e.is_dirty())
+            .map(|e| e.get_sector_idx().expect("dirty entries have a sector index"))
+    }
+
+    /// Number of sectors in the cache with modifications that haven't been
+    /// written out.
+    pub fn dirty_count(&self) -> usize {
+        self.cache_table.cache_entry_table.iter().filter(|e| e.is_dirty()).count()
+    }
+
+    /// Writes out up to `max_sectors` dirty sectors, oldest entries first, and
+    /// returns how many were written. Meant to be called periodically (i.e.
+    /// from a timer tick) to bound how long each flush takes; repeated calls
+    /// eventually flush everything.
+    ///
+    /// Sectors that fail to write are skipped (and left dirty) and don't count
+    /// towards `max_sectors`.
+    pub fn flush_some(&mut self, storage: &mut S, max_sectors: usize) -> usize {
+        let mut written = 0;
+        // Entry ages are unique so going through them in increasing order
+        // visits each dirty entry at most once.
+        let mut older_than: Option<u64> = None;
+
+        while written < max_sectors {
+            let oldest = self.cache_table.cache_entry_table
+                .iter_mut()
+                .filter_map(|e| match *e {
+                    CacheEntry::Dirty { age, .. } if older_than.map_or(true, |o| age > o) => Some((age, e)),
+                    _ => None,
+                })
+                .min_by_key(|(age, _)| *age);
+
+            let (age, entry) = match oldest {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            older_than = Some(age);
+
+            let sector_idx = entry.get_sector_idx().expect("dirty entries have a sector index");
+            let arr_idx = entry.get_arr_idx().expect("dirty entries have an arr index");
+
+            let res = write_out(
+                storage,
+                sector_idx,
+                &self.cached_sectors[arr_idx]
+                    .try_borrow_mut()
+                    .expect("no references to any sectors when we have a mutable reference to the sector cache"),
+                self.verify_writes,
+            );
+
+            if res.is_ok() {
+                entry.mark_as_clean().unwrap();
+                written += 1;
+            }
+        }
+
+        written
+    }
+
+    /// Writes out a single dirty sector and marks it as clean.
+    ///
+    /// Does nothing if the sector isn't in the cache or isn't dirty.
+    /*pub */fn write_through(&mut self, storage: &mut S, index: SectorIdx) {
+        if let Some(entry) = self.cache_table.get_mut(index) {
+            if entry.is_dirty() {
+                let arr_idx = entry.get_arr_idx().expect("dirty entries have an arr index");
+
+                let res = write_out(
+                    storage,
+                    index,
+                    &self.cached_sectors[arr_idx]
+                        .try_borrow_mut()
+                        .expect("no references to a sector that's being written through"),
+                    self.verify_writes,
+                );
+
+                // If the write fails the sector stays dirty; the next flush
+                // will try it again (and report the error).
+                if res.is_ok() {
+                    entry.mark_as_clean().unwrap();
+                }
+            }
+        }
     }
 
     pub fn upgrade<'s>(
@@ -687,25 +1319,41 @@ where
         SectorCacheWithStorage::new(self, storage)
     }
 
+    /// Panics if the sector has to be read in and that fails; see
+    /// `try_get_sector_entry`.
     pub fn get_sector_entry(
         &mut self,
         storage: &mut S,
         index: SectorIdx,
     ) -> (&mut CacheEntry, RefMut<'_, u64>) {
+        self.try_get_sector_entry(storage, index).expect("loading the sector to succeed")
+    }
+
+    /// Errors if the sector isn't cached and reading it in (or evicting a
+    /// sector to make room for it) fails. The cache is left as it was.
+    pub fn try_get_sector_entry(
+        &mut self,
+        storage: &mut S,
+        index: SectorIdx,
+    ) -> Result<(&mut CacheEntry, RefMut<'_, u64>), FatError> {
         // See if we've already got this sector in the cache:
         if let Some(_) = self.cache_table.get(index) {
             // return c; // Unfortunately the borrow checker is not smart enough
                          // to see that this arm is mutually exclusive from the
                          // other arm because of the return.
+            self.hits += 1;
+            trace!("cache: hit for sector {}", index.inner());
         } else {
             // If we don't, try to load it into the cache.
+            self.misses += 1;
+            trace!("cache: miss for sector {}", index.inner());
 
             // First, let's get the index where we can place the sector:
             let idx = match self.cache_bitmap.next_empty_bit() {
                 Ok(idx) => idx,
                 Err(()) => {
                     // If the cache is full, we need to evict a sector.
-                    self.evict_entry(storage).expect("eviction to succeed");
+                    self.evict_entry(storage)?;
 
                     // Now, we can try to get an index again; this time it
                     // _must_ succeed:
@@ -713,31 +1361,100 @@ where
                 },
             };
 
-            // Load the sector in:
-            // (it's a little silly that we go lookup the index to this sector
-            // again but it's worth it for maintaining the symmetry)
-            storage.read_sector(
-                index.idx(),
-                &mut self.cached_sectors[idx].try_borrow_mut().expect("clean entries to have no references")
-            ).unwrap();
-
-            // Add to the cache table and the bitmap:
-            self.cache_bitmap.set(idx, true).unwrap();
-            match self.cache_table
-                    .insert(index, idx, &mut self.counter.borrow_mut()) {
-                Ok(_entry) => /*entry*/ {},
-
-                // It's not possible that we're out of space; the cache bitmap
-                // gave us an index.
-                Err(None) => unreachable!(),
-
-                // It's not possible that this sector is already cached; we
-                // started by looking it up.
-                Err(Some(_)) => unreachable!(),
+            self.load_sector(storage, index, idx)?;
+        }
+
+        Ok((self.cache_table.get_mut(index).unwrap(), self.counter.borrow_mut()))
+    }
+
+    /// Reads a sector that isn't in the cache into the free slot at `idx`.
+    ///
+    /// If the read fails the slot is left free.
+    fn load_sector(&mut self, storage: &mut S, index: SectorIdx, idx: usize) -> Result<(), FatError> {
+        // Load the sector in:
+        // (it's a little silly that we go lookup the index to this sector
+        // again but it's worth it for maintaining the symmetry)
+        storage.read_sector(
+            index.idx(),
+            &mut self.cached_sectors[idx].try_borrow_mut().expect("clean entries to have no references")
+        )?;
+
+        // Add to the cache table and the bitmap:
+        self.cache_bitmap.set(idx, true).unwrap();
+        match self.cache_table
+                .insert(index, idx, &mut self.counter.borrow_mut()) {
+            Ok(_entry) => /*entry*/ {},
+
+            // It's not possible that we're out of space; the cache bitmap
+            // gave us an index.
+            Err(None) => unreachable!(),
+
+            // It's not possible that this sector is already cached; callers
+            // check first.
+            Err(Some(_)) => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    /// Loads the sectors at the start of `range` that aren't cached into free
+    /// slots. Stops at the first sector that's already cached or once there
+    /// are no free slots left; never evicts anything.
+    fn read_ahead(&mut self, storage: &mut S, range: Range<SectorIdx>) {
+        for sector in SectorRange::new(range) {
+            if sector >= self.num_sectors || self.cache_table.get(sector).is_some() {
+                break;
             }
+
+            let loaded = match self.cache_bitmap.next_empty_bit() {
+                Ok(idx) => self.load_sector(storage, sector, idx),
+                Err(()) => break,
+            };
+
+            // Read ahead is best effort; if it fails the sector will be tried
+            // again (and the error reported) when it's actually asked for.
+            if loaded.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// See `SectorCacheWithStorage::get_range`.
+    fn get_range(&mut self, storage: &mut S, range: Range<SectorIdx>, mut func: impl FnMut(SectorIdx, &[u8])) -> Result<(), FatError> {
+        if range.start < range.end && range.end > self.num_sectors {
+            return Err(FatError::OutOfRange);
         }
 
-        (self.cache_table.get_mut(index).unwrap(), self.counter.borrow_mut())
+        for sector in SectorRange::new(range.clone()) {
+            let pos = match self.cache_table.position(sector) {
+                Some(pos) => {
+                    self.hits += 1;
+                    pos
+                },
+
+                None => {
+                    // Load this sector (evicting if we have to) and then as
+                    // many of the ones after it as we can fit for free.
+                    let _ = self.try_get_sector_entry(storage, sector)?;
+                    if let Some(start) = sector.checked_add(1) {
+                        let end = start.checked_add(self.readahead_window as u64).unwrap_or(range.end);
+                        self.read_ahead(storage, start..core::cmp::min(end, range.end));
+                    }
+
+                    self.cache_table.position(sector).expect("sector was just loaded")
+                },
+            };
+
+            let entry = &self.cache_table.cache_entry_table[pos];
+            entry.accessed(&mut self.counter.borrow_mut()).expect("entry isn't `Free`");
+
+            let arr_idx = entry.get_arr_idx().expect("entry has an arr index");
+            func(sector, &self.cached_sectors[arr_idx]
+                .try_borrow()
+                .expect("immutable sector borrows always succeed"));
+        }
+
+        Ok(())
     }
 }
 
@@ -748,6 +1465,7 @@ where
     SECT_SIZE: ArrayLength<u8>,
     CACHE_SIZE: ArrayLength<RefCell<GenericArray<u8, SECT_SIZE>>>,
     CACHE_SIZE: ArrayLength<CacheEntry>,
+    CACHE_SIZE: ArrayLength<IndexLink>,
     CACHE_SIZE: BitMapLen,
 {
     pub fn change_eviction_policy(&mut self, ev: DynEvictionPolicy) {
@@ -762,6 +1480,7 @@ where
     SECT_SIZE: ArrayLength<u8>,
     CACHE_SIZE: ArrayLength<RefCell<GenericArray<u8, SECT_SIZE>>>,
     CACHE_SIZE: ArrayLength<CacheEntry>,
+    CACHE_SIZE: ArrayLength<IndexLink>,
     CACHE_SIZE: BitMapLen,
     Ev: EvictionPolicy,
 {
@@ -791,6 +1510,7 @@ where
     SECTOR_SIZE: ArrayLength<u8>,
     CACHE_SIZE_IN_SECTORS: ArrayLength<RefCell<GenericArray<u8, SECTOR_SIZE>>>,
     CACHE_SIZE_IN_SECTORS: ArrayLength<CacheEntry>,
+    CACHE_SIZE_IN_SECTORS: ArrayLength<IndexLink>,
     CACHE_SIZE_IN_SECTORS: BitMapLen,
     Eviction: EvictionPolicy,
 {
@@ -799,6 +1519,12 @@ where
 
     flush_on_drop: bool,
 
+    // In `WriteThrough` mode this is the sector handed out by the last call to
+    // `get_mut`. We can't write it out in `get_mut` itself (the caller hasn't
+    // made their modifications yet!) so we do it once we know the mutable
+    // borrow has ended: on the next access or when we're dropped.
+    pending_write_through: Cell<Option<SectorIdx>>,
+
     _ty: PhantomData<Ty>,
 }
 
@@ -809,6 +1535,7 @@ where
     SS: ArrayLength<u8>,
     CS: ArrayLength<RefCell<GenericArray<u8, SS>>>,
     CS: ArrayLength<CacheEntry>,
+    CS: ArrayLength<IndexLink>,
     CS: BitMapLen,
     Ev: EvictionPolicy,
 {
@@ -820,10 +1547,42 @@ where
         (self.sector_cache.borrow_mut(), self.storage.borrow_mut())
     }
 
+    fn commit_write_through(&self) {
+        if let Some(sector) = self.pending_write_through.take() {
+            let (mut sector_cache, mut storage) = self.refs();
+            sector_cache.write_through(&mut storage, sector);
+        }
+    }
+
+    /// Whether `get` (or `get_mut`) for `index` can be served without evicting
+    /// anything: either the sector is already in the cache or there's a free
+    /// slot for it.
+    ///
+    /// When this is `true` the call can't panic because of outstanding borrows
+    /// (it can still fail to read the sector in). `false` for sectors past the
+    /// end of the storage.
+    pub fn can_get_without_eviction(&self, index: SectorIdx) -> bool {
+        let sector_cache = self.sector_cache.borrow();
+
+        index < sector_cache.num_sectors && (
+            sector_cache.cache_table.position(index).is_some() ||
+            sector_cache.cache_table.free_entries() > 0
+        )
+    }
+
     /// Note: this will panic if, in order to load the requested sector, we end
     /// up needing to evict a sector that has a borrow currently out.
+    ///
+    /// Also panics if reading the sector in fails; see `try_get`.
     pub fn get<'r>(&'r self, index: SectorIdx) -> Ref<'r, GenericArray<u8, SS>> {
-        let arr_idx = self.get_inner(index, false);
+        self.try_get(index).expect("loading the sector to succeed")
+    }
+
+    /// Like `get` but errors (leaving the cache as it was) if the sector is
+    /// past the end of the storage or if reading it in (or writing out the
+    /// sector evicted to make room for it) fails.
+    pub fn try_get<'r>(&'r self, index: SectorIdx) -> Result<Ref<'r, GenericArray<u8, SS>>, FatError> {
+        let arr_idx = self.try_get_inner(index, false)?;
 
         #[allow(unsafe_code)]
         // I think this is safe; we're getting an untracked reference to the
@@ -838,20 +1597,31 @@ where
             self.sector_cache.try_borrow_unguarded().unwrap()
         };
 
-        sector_cache_ref.cached_sectors[arr_idx]
+        Ok(sector_cache_ref.cached_sectors[arr_idx]
             .try_borrow()
-            .expect("immutable sector borrows always succeed")
+            .expect("immutable sector borrows always succeed"))
     }
 
     // Note: this will panic if, in order to load the requested sector, we end
     // up needing to evict a sector that has a borrow currently out.
     fn get_inner(&self, index: SectorIdx, mark_as_dirty: bool) -> usize {
+        assert!(index < self.sector_cache.borrow().num_sectors);
+
+        self.try_get_inner(index, mark_as_dirty).expect("loading the sector to succeed")
+    }
+
+    fn try_get_inner(&self, index: SectorIdx, mark_as_dirty: bool) -> Result<usize, FatError> {
+        // Any borrow handed out by `get_mut` must have ended by now.
+        self.commit_write_through();
+
         let (mut sector_cache, mut storage) = self.refs();
 
-        assert!(index < sector_cache.num_sectors);
+        if index >= sector_cache.num_sectors {
+            return Err(FatError::OutOfRange);
+        }
 
         let (cache_entry, mut counter) =
-            sector_cache.get_sector_entry(&mut storage, index);
+            sector_cache.try_get_sector_entry(&mut storage, index)?;
 
         // Mark the entry as accessed.
         cache_entry
@@ -872,16 +1642,88 @@ where
         }
 
         // Finally, get the entry's corresponding sector cache array:
-        cache_entry
+        Ok(cache_entry
             .get_arr_idx()
-            .expect("entry has an arr index")
+            .expect("entry has an arr index"))
     }
 
+    /// Hands each sector in `range` to `func`, in order.
+    ///
+    /// This is cheaper than calling `get` for each sector: on a miss, we read
+    /// ahead (up to the cache's `readahead_window`) into any free slots so that
+    /// the rest of the range is (hopefully) cached by the time we get to it.
+    ///
+    /// The sectors are lent to a closure rather than returned so that we're
+    /// free to evict sectors we're done with while serving the range (see
+    /// `make_indexable`).
+    ///
+    /// Unlike `get`, this errors (rather than panicking) if reading a sector
+    /// in fails, in which case `func` will have been called for the sectors
+    /// before it. Ranges that go past the end of the storage error with
+    /// `OutOfRange` without `func` being called at all.
+    pub fn get_range(&mut self, range: Range<SectorIdx>, func: impl FnMut(SectorIdx, &[u8])) -> Result<(), FatError> {
+        self.commit_write_through();
+
+        let (mut sector_cache, mut storage) = self.refs();
+        sector_cache.get_range(&mut storage, range, func)
+    }
+
+    /// Hands the sectors in `range` to `func` as one slice.
+    ///
+    /// The sectors in the cache aren't laid out back to back in memory (see
+    /// the note at the end of this file) so we can only lend a view of the
+    /// cache itself when `range` is a single sector. For anything longer, the
+    /// sectors are copied into `scratch` (with `get_range`) and `func` gets
+    /// the part of `scratch` that was filled in.
+    ///
+    /// Returns `None` without calling `func` if the sectors need to be copied
+    /// and `scratch` isn't big enough to hold them or if reading them fails.
+    pub fn with_contiguous<R>(
+        &mut self,
+        range: Range<SectorIdx>,
+        scratch: &mut [u8],
+        func: impl FnOnce(&[u8]) -> R,
+    ) -> Option<R> {
+        let sector_size = SS::to_usize();
+        let num_sectors = range.end.inner().saturating_sub(*range.start.inner()) as usize;
+
+        if num_sectors == 1 {
+            // No copy; this is just `try_get`.
+            return Some(func(&self.try_get(range.start).ok()?[..]));
+        }
+
+        let len = num_sectors * sector_size;
+        if scratch.len() < len {
+            return None;
+        }
+
+        let start = range.start;
+        self.get_range(range, |idx, data| {
+            let offset = ((idx.inner() - start.inner()) as usize) * sector_size;
+            scratch[offset..][..sector_size].copy_from_slice(data);
+        }).ok()?;
+
+        Some(func(&scratch[..len]))
+    }
+
+    /// Panics if reading the sector in fails; see `try_get_mut`.
     pub fn get_mut(&mut self, index: SectorIdx) -> &mut GenericArray<u8, SS> {
-        let arr_idx = self.get_inner(index, true);
+        assert!(index < self.sector_cache.get_mut().num_sectors);
+
+        self.try_get_mut(index).expect("loading the sector to succeed")
+    }
+
+    /// Like `get_mut` but errors (leaving the cache as it was) if the sector
+    /// is past the end of the storage or if reading it in fails.
+    pub fn try_get_mut(&mut self, index: SectorIdx) -> Result<&mut GenericArray<u8, SS>, FatError> {
+        let arr_idx = self.try_get_inner(index, true)?;
+
+        if let CacheMode::WriteThrough = self.sector_cache.get_mut().mode {
+            self.pending_write_through.set(Some(index));
+        }
 
         // hey look! no unsafe
-        self.sector_cache.get_mut().cached_sectors[arr_idx].get_mut()
+        Ok(self.sector_cache.get_mut().cached_sectors[arr_idx].get_mut())
     }
 }
 
@@ -892,6 +1734,7 @@ where
     SS: ArrayLength<u8>,
     CS: ArrayLength<RefCell<GenericArray<u8, SS>>>,
     CS: ArrayLength<CacheEntry>,
+    CS: ArrayLength<IndexLink>,
     CS: BitMapLen,
     Ev: EvictionPolicy,
 {
@@ -902,6 +1745,8 @@ where
 
             flush_on_drop: false,
 
+            pending_write_through: Cell::new(None),
+
             _ty: PhantomData,
         }
     }
@@ -962,10 +1807,13 @@ where
     SS: ArrayLength<u8>,
     CS: ArrayLength<RefCell<GenericArray<u8, SS>>>,
     CS: ArrayLength<CacheEntry>,
+    CS: ArrayLength<IndexLink>,
     CS: BitMapLen,
     Ev: EvictionPolicy,
 {
     fn drop(&mut self) {
+        self.commit_write_through();
+
         if self.flush_on_drop {
             self.sector_cache.borrow_mut().flush(&mut self.storage.borrow_mut()).unwrap()
         }
@@ -979,6 +1827,7 @@ where
     SECT_SIZE: ArrayLength<u8>,
     CACHE_SIZE: ArrayLength<RefCell<GenericArray<u8, SECT_SIZE>>>,
     CACHE_SIZE: ArrayLength<CacheEntry>,
+    CACHE_SIZE: ArrayLength<IndexLink>,
     CACHE_SIZE: BitMapLen,
     Ev: EvictionPolicy,
 {
@@ -1010,6 +1859,7 @@ where
     SECT_SIZE: ArrayLength<u8>,
     CACHE_SIZE: ArrayLength<RefCell<GenericArray<u8, SECT_SIZE>>>,
     CACHE_SIZE: ArrayLength<CacheEntry>,
+    CACHE_SIZE: ArrayLength<IndexLink>,
     CACHE_SIZE: BitMapLen,
     Ev: EvictionPolicy,
 {
@@ -1032,3 +1882,7 @@ where
 //
 // In any case, the use case for having an actually contiguous array of memory
 // that represents a file seems extremely small/niche.
+//
+// For the cases where it does come up, `SectorCacheWithStorage::with_contiguous`
+// copies the range into a buffer the caller provides (unless the range is a
+// single sector, in which case there's nothing to stitch together).
@@ -1,21 +1,21 @@
 //! Directory entries. Files or Folders.
 
 use crate::Storage;
-use super::FatFs;
-use super::types::{ClusterIdx, SectorIdx};
+use super::{FatError, FatFs};
+use super::types::{ClusterIdx, ClusterOffset, SectorIdx};
 use super::cache::EvictionPolicy;
-use super::table::FatEntry;
+use super::table::{FatEntry, TraceError};
 use super::file::File;
+use crate::util::Bits;
 
 use generic_array::{ArrayLength, GenericArray};
-use typenum::consts::U512;
 
 use core::cell::RefCell;
 use core::convert::TryInto;
 use core::fmt::{self, Debug};
 use core::iter::Iterator;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Attribute {
     ReadOnly = 0x01,
     Hidden = 0x02,
@@ -91,6 +91,8 @@ impl Attribute {
 
 #[repr(transparent)]
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
 pub struct AttributeSet {
     inner: u8
 }
@@ -120,18 +122,42 @@ impl AttributeSet {
         self
     }
 
+    /// Same as `apply`; reads better alongside `without`.
+    pub const fn with(self, a: Attribute) -> Self {
+        self.apply(a)
+    }
+
+    pub const fn without(mut self, a: Attribute) -> Self {
+        self.inner &= !(a as u8);
+        self
+    }
+
+    pub const fn contains(&self, a: Attribute) -> bool {
+        (self.inner & (a as u8)) != 0
+    }
+
     pub fn is_dir(&self) -> bool {
-        (self.inner & (Attribute::Directory as u8)) != 0
+        self.contains(Attribute::Directory)
     }
 
+    /// Anything that isn't a directory or a volume label. Long file name
+    /// entries have the volume label bit set so they aren't files either.
+    ///
+    /// Files don't necessarily have the `Archive` bit set.
     pub fn is_file(&self) -> bool {
-        (self.inner & (Attribute::Archive as u8)) != 0
+        !self.is_dir() && !self.is_volume_id()
+    }
+
+    pub fn is_volume_id(&self) -> bool {
+        self.contains(Attribute::VolumeId)
     }
 }
 
+/// Compares equal to names that only differ in padding (see `trim_padding`).
 #[repr(transparent)]
-#[derive(Clone, PartialEq, Eq, Default)]
-pub struct FileName(pub [u8; 8]);
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FileName(#[cfg_attr(feature = "serde", serde(with = "crate::util::serde_text"))] pub [u8; 8]);
 
 impl Debug for FileName {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -147,20 +173,42 @@ impl Debug for FileName {
     }
 }
 
+impl PartialEq for FileName {
+    fn eq(&self, other: &Self) -> bool {
+        trim_padding(&self.0) == trim_padding(&other.0)
+    }
+}
+
+impl Eq for FileName { }
+
 impl FileName {
-    // Just discards extra/non-ascii characters.
+    /// Whether this is `name` (i.e. `b"readme"` for `README  `), ignoring case
+    /// and padding.
+    pub fn matches(&self, name: &[u8]) -> bool {
+        trim_padding(&self.0).eq_ignore_ascii_case(trim_padding(name))
+    }
+}
+
+impl FileName {
+    // Just discards extra/non-ascii characters. Space padded.
     pub fn new(s: &str) -> Self {
-        Self(if s.chars().any(|c| !c.is_ascii()) {
-            [0; 8]
+        let mut arr = [b' '; 8];
+        if s.chars().any(|c| !c.is_ascii()) {
+            arr = [0; 8];
         } else {
-            s.as_bytes()[0..8].try_into().unwrap()
-        })
+            arr.iter_mut().zip(s.bytes()).for_each(|(d, c)| *d = c);
+        }
+
+        Self(arr)
     }
 }
 
+/// Compares equal to extensions that only differ in padding (see
+/// `trim_padding`).
 #[repr(transparent)]
-#[derive(Clone, PartialEq, Eq, Default)]
-pub struct FileExt(pub [u8; 3]);
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FileExt(#[cfg_attr(feature = "serde", serde(with = "crate::util::serde_text"))] pub [u8; 3]);
 
 impl Debug for FileExt {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -176,19 +224,157 @@ impl Debug for FileExt {
     }
 }
 
+impl PartialEq for FileExt {
+    fn eq(&self, other: &Self) -> bool {
+        trim_padding(&self.0) == trim_padding(&other.0)
+    }
+}
+
+impl Eq for FileExt { }
+
 impl FileExt {
-    // Just discards extra/non-ascii characters.
+    /// Whether this is `ext` (i.e. `b"txt"` for `TXT`), ignoring case and
+    /// padding.
+    pub fn matches(&self, ext: &[u8]) -> bool {
+        trim_padding(&self.0).eq_ignore_ascii_case(trim_padding(ext))
+    }
+}
+
+impl FileExt {
+    // Just discards extra/non-ascii characters. Space padded.
     pub fn new(s: &str) -> Self {
-        Self(if s.chars().any(|c| !c.is_ascii()) {
-            [0; 3]
+        let mut arr = [b' '; 3];
+        if s.chars().any(|c| !c.is_ascii()) {
+            arr = [0; 3];
         } else {
-            s.as_bytes()[0..3].try_into().unwrap()
-        })
+            arr.iter_mut().zip(s.bytes()).for_each(|(d, c)| *d = c);
+        }
+
+        Self(arr)
     }
 }
 
+/// Names and extensions are padded with spaces on disk but some tools pad them
+/// with NULs instead; this strips either.
+fn trim_padding(b: &[u8]) -> &[u8] {
+    let len = b.iter().rposition(|c| *c != 0x20 && *c != 0x00).map_or(0, |l| l + 1);
+    &b[..len]
+}
+
+/// Turns a path component (i.e. `readme.txt`) into the upper-cased, space
+/// padded 8.3 name that goes in a directory entry.
+///
+/// Errors if the component doesn't fit in 8.3 or has characters that aren't
+/// allowed in short names.
+pub fn short_name(component: &[u8]) -> Result<(FileName, FileExt), FatError> {
+    let (name, ext) = match component.iter().rposition(|c| *c == '.' as u8) {
+        Some(dot) => (&component[..dot], &component[(dot + 1)..]),
+        None => (component, &[][..]),
+    };
+
+    if name.is_empty() || name.len() > 8 || ext.len() > 3 {
+        return Err(FatError::InvalidArgument);
+    }
+
+    let valid = |c: &u8| c.is_ascii_graphic() && !b"\"*+,./:;<=>?[\\]|".contains(c);
+    if !name.iter().chain(ext.iter()).all(valid) {
+        return Err(FatError::InvalidArgument);
+    }
+
+    let mut file_name = FileName(*b"        ");
+    let mut file_ext = FileExt(*b"   ");
+
+    for (d, c) in file_name.0.iter_mut().zip(name) { *d = c.to_ascii_uppercase(); }
+    for (d, c) in file_ext.0.iter_mut().zip(ext) { *d = c.to_ascii_uppercase(); }
+
+    Ok((file_name, file_ext))
+}
+
+/// Turns `label` into the upper-cased, space padded 11 bytes that a volume
+/// label is stored as.
+///
+/// Errors if `label` is empty, is longer than 11 bytes, or has characters that
+/// aren't allowed in short names (spaces are fine).
+pub fn volume_label(label: &[u8]) -> Result<[u8; 11], FatError> {
+    if label.is_empty() || label.len() > 11 {
+        return Err(FatError::InvalidArgument);
+    }
+
+    let valid = |c: &u8| (c.is_ascii_graphic() || *c == b' ') && !b"\"*+,./:;<=>?[\\]|".contains(c);
+    if !label.iter().all(valid) {
+        return Err(FatError::InvalidArgument);
+    }
+
+    let mut out = *b"           ";
+    for (d, c) in out.iter_mut().zip(label) { *d = c.to_ascii_uppercase(); }
+
+    Ok(out)
+}
+
+/// A date as stored in a directory entry, decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Date {
+    pub year: u16,
+    /// 1 to 12.
+    pub month: u8,
+    /// 1 to 31.
+    pub day: u8,
+}
+
+impl Date {
+    /// Bits 15-9 are years since 1980, bits 8-5 the month, and bits 4-0 the
+    /// day.
+    pub fn from_fat(date: u16) -> Self {
+        Self {
+            year: 1980 + date.bits(9..16),
+            month: date.bits(5..9) as u8,
+            day: date.bits(0..5) as u8,
+        }
+    }
+
+    /// The inverse of `from_fat`. Years outside of 1980 to 2107 don't fit and
+    /// are clamped.
+    pub fn to_fat(&self) -> u16 {
+        let years = self.year.max(1980).min(1980 + 127) - 1980;
+
+        (years << 9) | ((self.month as u16 & 0xF) << 5) | (self.day as u16 & 0x1F)
+    }
+}
+
+/// A date and time as stored in a directory entry, decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct DateTime {
+    pub date: Date,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Bits 15-11 of `time` are the hour, bits 10-5 the minute, and bits 4-0
+    /// the second divided by 2.
+    pub fn from_fat(date: u16, time: u16) -> Self {
+        Self {
+            date: Date::from_fat(date),
+            hour: time.bits(11..16) as u8,
+            minute: time.bits(5..11) as u8,
+            second: (time.bits(0..5) * 2) as u8,
+        }
+    }
+
+    /// The inverse of `from_fat`; returns `(date, time)`. Odd seconds are
+    /// rounded down.
+    pub fn to_fat(&self) -> (u16, u16) {
+        let time = ((self.hour as u16 & 0x1F) << 11) |
+            ((self.minute as u16 & 0x3F) << 5) |
+            ((self.second as u16 / 2) & 0x1F);
+
+        (self.date.to_fat(), time)
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DirEntry {
     // Offset: 00
     pub file_name: FileName,
@@ -218,6 +404,109 @@ pub struct DirEntry {
     pub file_size: u32,
 }
 
+/// The entry's 8.3 name as it'd appear in a path: `README.TXT`, or `SUBDIR`
+/// for names without an extension (see `DirEntry::write_short_name`).
+// TODO: once long file names are supported, show the long name when there is
+// one.
+impl fmt::Display for DirEntry {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut buf = [0u8; 12];
+        let len = self.write_short_name(&mut buf).map_err(|_| fmt::Error)?;
+
+        buf[..len].iter().try_for_each(|b| write!(fmt, "{}", *b as char))
+    }
+}
+
+/// Builds a `DirEntry` without having to pack the timestamp and cluster
+/// fields by hand.
+///
+/// Timestamps that aren't given are left as zero, like `DirEntry::new_file`
+/// leaves them.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DirEntryBuilder {
+    name: FileName,
+    ext: FileExt,
+    attributes: AttributeSet,
+    cluster: ClusterIdx,
+    size: u32,
+    created: Option<DateTime>,
+    modified: Option<DateTime>,
+    accessed: Option<DateTime>,
+}
+
+impl DirEntryBuilder {
+    pub fn new(name: FileName, ext: FileExt) -> Self {
+        Self { name, ext, ..Self::default() }
+    }
+
+    pub fn attribute(mut self, a: Attribute) -> Self {
+        self.attributes = self.attributes.with(a);
+        self
+    }
+
+    /// Replaces any attributes set so far.
+    pub fn attributes(mut self, a: AttributeSet) -> Self {
+        self.attributes = a;
+        self
+    }
+
+    pub fn cluster(mut self, c: ClusterIdx) -> Self {
+        self.cluster = c;
+        self
+    }
+
+    pub fn size(mut self, size: u32) -> Self {
+        self.size = size;
+        self
+    }
+
+    /// Unlike the other two timestamps, this one keeps odd seconds.
+    pub fn created(mut self, dt: DateTime) -> Self {
+        self.created = Some(dt);
+        self
+    }
+
+    pub fn modified(mut self, dt: DateTime) -> Self {
+        self.modified = Some(dt);
+        self
+    }
+
+    /// Only the date is stored.
+    pub fn accessed(mut self, dt: DateTime) -> Self {
+        self.accessed = Some(dt);
+        self
+    }
+
+    pub fn build(&self) -> DirEntry {
+        let mut d = DirEntry::default();
+
+        d.file_name = self.name.clone();
+        d.file_ext = self.ext.clone();
+        d.attributes = self.attributes;
+        d.set_cluster_idx(self.cluster);
+        d.file_size = self.size;
+
+        if let Some(dt) = self.created {
+            let (date, time) = dt.to_fat();
+            d.creation_date = date;
+            d.creation_time_double_secs = time;
+            d.creation_time_tenth_secs = (dt.second % 2) * 100;
+        }
+
+        if let Some(dt) = self.modified {
+            let (date, time) = dt.to_fat();
+            d.last_modif_date = date;
+            d.last_modif_time = time;
+        }
+
+        if let Some(dt) = self.accessed {
+            d.last_access_date = dt.date.to_fat();
+        }
+
+        d
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum State {
     Exists,
@@ -259,6 +548,28 @@ impl DirEntry {
         d
     }
 
+    /// A volume label entry; these go in the root directory and use the name
+    /// and extension fields for an 11 byte label.
+    pub fn new_volume_label(label: [u8; 11]) -> Self {
+        let mut d = Self::default();
+
+        d.file_name = FileName(label[..8].try_into().unwrap());
+        d.file_ext = FileExt(label[8..].try_into().unwrap());
+        d.attributes.inner |= Attribute::VolumeId as u8;
+
+        d
+    }
+
+    /// The name and extension fields together, as a volume label entry uses
+    /// them.
+    pub fn label(&self) -> [u8; 11] {
+        let mut label = [0; 11];
+        label[..8].copy_from_slice(&self.file_name.0);
+        label[8..].copy_from_slice(&self.file_ext.0);
+
+        label
+    }
+
     pub fn from_arr(arr: [u8; 32]) -> Self {
         macro_rules! e {
             ($ty:tt, $offset:literal :+ $num:literal) => {
@@ -287,8 +598,8 @@ impl DirEntry {
         }
     }
 
-    pub fn from_slice(slice: &[u8]) -> Result<Self, ()> {
-        Ok(Self::from_arr(slice.try_into().map_err(|_| ())?))
+    pub fn from_slice(slice: &[u8]) -> Result<Self, FatError> {
+        Ok(Self::from_arr(slice.try_into().map_err(|_| FatError::InvalidArgument)?))
     }
 
     pub fn into_arr(&self, arr: &mut [u8; 32]) {
@@ -307,6 +618,56 @@ impl DirEntry {
         arr[28..32].copy_from_slice(&self.file_size.to_le_bytes());
     }
 
+    /// Writes the entry's 8.3 name as it'd appear in a path (i.e. `README.TXT`
+    /// or `SUBDIR`) into `buf` and returns its length.
+    ///
+    /// Errors if `buf` is too small.
+    pub fn write_short_name(&self, buf: &mut [u8]) -> Result<usize, FatError> {
+        let name = trim_padding(&self.file_name.0);
+        let ext = trim_padding(&self.file_ext.0);
+
+        let len = name.len() + if ext.is_empty() { 0 } else { 1 + ext.len() };
+        if len > buf.len() {
+            return Err(FatError::OutOfSpace);
+        }
+
+        buf[..name.len()].copy_from_slice(name);
+        if !ext.is_empty() {
+            buf[name.len()] = '.' as u8;
+            buf[(name.len() + 1)..len].copy_from_slice(ext);
+        }
+
+        Ok(len)
+    }
+
+    /// Whether this is the `.` entry at the start of a subdirectory.
+    pub fn is_dot(&self) -> bool {
+        self.file_name.0 == *b".       " && self.file_ext.0 == *b"   "
+    }
+
+    /// Whether this is the `..` entry at the start of a subdirectory.
+    pub fn is_dotdot(&self) -> bool {
+        self.file_name.0 == *b"..      " && self.file_ext.0 == *b"   "
+    }
+
+    pub fn created(&self) -> DateTime {
+        let mut dt = DateTime::from_fat(self.creation_date, self.creation_time_double_secs);
+
+        // The creation time has an extra field with 10ms resolution (0 to 199)
+        // that can push the seconds up by one.
+        dt.second += self.creation_time_tenth_secs / 100;
+        dt
+    }
+
+    pub fn modified(&self) -> DateTime {
+        DateTime::from_fat(self.last_modif_date, self.last_modif_time)
+    }
+
+    /// Only the date of the last access is recorded.
+    pub fn accessed(&self) -> Date {
+        Date::from_fat(self.last_access_date)
+    }
+
     pub fn cluster_idx(&self) -> ClusterIdx {
         ClusterIdx::new((self.cluster_num_upper as u32) << 16 | (self.cluster_num_lower as u32))
     }
@@ -328,9 +689,10 @@ impl DirEntry {
         s: &'s mut S,
     ) -> Option<DirIter<'f, 's, S, CS, Ev>>
     where
-        S: Storage<Word = u8, SECTOR_SIZE = U512>,
-        CS: ArrayLength<RefCell<GenericArray<u8, U512>>>,
+        S: Storage<Word = u8>,
+        CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
         CS: ArrayLength<super::cache::CacheEntry>,
+        CS: ArrayLength<super::cache::IndexLink>,
         CS: crate::util::BitMapLen,
         Ev: EvictionPolicy,
     {
@@ -356,9 +718,10 @@ impl DirEntry {
     //     s: &'s mut S,
     // ) -> Result<(), ()>
     // where
-    //     S: Storage<Word = u8, SECTOR_SIZE = U512>,
-    //     CS: ArrayLength<RefCell<GenericArray<u8, U512>>>,
+    //     S: Storage<Word = u8>,
+    //     CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
     //     CS: ArrayLength<super::cache::CacheEntry>,
+    //     CS: ArrayLength<super::cache::IndexLink>,
     //     CS: crate::util::BitMapLen,
     //     Ev: EvictionPolicy,
     // {
@@ -368,11 +731,81 @@ impl DirEntry {
     // }
 }
 
+/// A directory entry borrowed straight out of a cached sector.
+///
+/// Fields are only decoded when asked for, so looking at (say) just the
+/// attributes of an entry is cheap. Use `to_entry` to get an owned `DirEntry`
+/// (i.e. to modify it and write it back).
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct DirEntryRef<'a>(&'a [u8; 32]);
+
+impl Debug for DirEntryRef<'_> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.to_entry().fmt(fmt)
+    }
+}
+
+impl<'a> DirEntryRef<'a> {
+    pub fn new(bytes: &'a [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &'a [u8; 32] {
+        self.0
+    }
+
+    pub fn file_name(&self) -> FileName {
+        FileName(self.0[0..8].try_into().unwrap())
+    }
+
+    pub fn file_ext(&self) -> FileExt {
+        FileExt(self.0[8..11].try_into().unwrap())
+    }
+
+    pub fn attributes(&self) -> AttributeSet {
+        AttributeSet { inner: self.0[11] }
+    }
+
+    pub fn state(&self) -> State {
+        match self.0[0] {
+            0x00 => State::End,
+            0xE5 => State::Deleted,
+            _ => State::Exists,
+        }
+    }
+
+    /// See `DirEntry::is_dot`.
+    pub fn is_dot(&self) -> bool {
+        self.0[0..11] == *b".          "
+    }
+
+    /// See `DirEntry::is_dotdot`.
+    pub fn is_dotdot(&self) -> bool {
+        self.0[0..11] == *b"..         "
+    }
+
+    pub fn cluster_idx(&self) -> ClusterIdx {
+        let upper = u16::from_le_bytes([self.0[20], self.0[21]]) as u32;
+        let lower = u16::from_le_bytes([self.0[26], self.0[27]]) as u32;
+
+        ClusterIdx::new(upper << 16 | lower)
+    }
+
+    pub fn file_size(&self) -> u32 {
+        u32::from_le_bytes(self.0[28..32].try_into().unwrap())
+    }
+
+    pub fn to_entry(&self) -> DirEntry {
+        DirEntry::from_arr(*self.0)
+    }
+}
+
 pub struct DirIter<'f, 's, S, CS, Ev>
 where
-    S: Storage<Word = u8, SECTOR_SIZE = U512>,
-    CS: ArrayLength<RefCell<GenericArray<u8, U512>>>,
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
     CS: ArrayLength<super::cache::CacheEntry>,
+    CS: ArrayLength<super::cache::IndexLink>,
     CS: crate::util::BitMapLen,
     Ev: EvictionPolicy,
 {
@@ -383,21 +816,32 @@ where
     pub current_offset: Option<u32>,
 
     hit_end_offset: Option<u32>,
+    /// The first deleted slot the iterator has gone past, if any.
+    first_deleted: Option<(ClusterIdx, u32)>,
+    include_dot_entries: bool,
+    error: Option<TraceError>,
 }
 
 impl<'f, 's, S, CS, Ev> DirIter<'f, 's, S, CS, Ev>
 where
-    S: Storage<Word = u8, SECTOR_SIZE = U512>,
-    CS: ArrayLength<RefCell<GenericArray<u8, U512>>>,
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
     CS: ArrayLength<super::cache::CacheEntry>,
+    CS: ArrayLength<super::cache::IndexLink>,
     CS: crate::util::BitMapLen,
     Ev: EvictionPolicy,
 {
+    /// Cluster 0 (what `..` entries use to refer to the root directory) is
+    /// taken to mean the root directory; on FAT32 this is the start of the
+    /// root directory's cluster chain, which is followed like any other
+    /// directory's.
     pub fn from_cluster(
         cluster: ClusterIdx,
         fs: &'f mut FatFs<S, CS, Ev>,
         storage: &'s mut S
     ) -> Self {
+        let cluster = if cluster == ClusterIdx::new(0) { fs.root_dir_cluster_num } else { cluster };
+
         Self {
             file_sys: fs,
             storage,
@@ -406,54 +850,221 @@ where
             current_offset: Some(0),
 
             hit_end_offset: None,
+            first_deleted: None,
+            include_dot_entries: false,
+            error: None,
         }
     }
 
-    // TODO: support growing directories to more clusters!
-    //
-    // This only works if the iterator hit the end of a directory structure.
-    pub fn add_entry(&mut self, entry: DirEntry) -> Result<(), ()> {
-        let bytes_in_a_cluster = self.file_sys.bytes_in_a_cluster();
+    /// Like `FatEntryTracer::error`: once the iterator stops, this says
+    /// whether it's because reading the directory (or following its cluster
+    /// chain) failed rather than because the directory ended.
+    pub fn error(&self) -> Option<TraceError> {
+        self.error
+    }
 
-        if let Some(end) = self.hit_end_offset.take() {
-            if end + 64 >= bytes_in_a_cluster {
-                unimplemented!()
-                // We'd need to go call grow_file...
+    /// The `.` and `..` entries in subdirectories are skipped unless this is
+    /// set.
+    pub fn with_dot_entries(mut self, include: bool) -> Self {
+        self.include_dot_entries = include;
+        self
+    }
+
+    /// Like `next` but instead of copying the next entry out, lends it to
+    /// `func` right out of the cache and returns what `func` does.
+    ///
+    /// Entries that the iterator skips (long file name entries and, unless
+    /// asked for, dot entries) are never decoded past the bytes needed to
+    /// tell that they're to be skipped.
+    pub fn next_with<R>(
+        &mut self,
+        mut func: impl FnMut((ClusterIdx, u32), DirEntryRef<'_>) -> R,
+    ) -> Option<R> {
+        loop {
+            let offset = self.current_offset?;
+            let current_cluster = self.current_cluster;
+
+            // Entries are 32 bytes and sectors are a multiple of 32 bytes so
+            // an entry never spans two sectors.
+            let (sector, sector_offset) = self.file_sys.cluster_to_sector(current_cluster, ClusterOffset::new(offset));
+            let end = self.file_sys.range_chk(sector, *sector_offset, 32).ok()
+                .and_then(|()| sector.checked_add(1));
+
+            let include_dot_entries = self.include_dot_entries;
+            let mut state = None;
+            let mut ret = None;
+
+            let res = end.ok_or(FatError::OutOfRange).and_then(|end| {
+                self.file_sys.cache.upgrade(self.storage).get_range(sector..end, |_, data| {
+                    let start = *sector_offset as usize;
+                    let entry = DirEntryRef::new(data[start..(start + 32)].try_into().unwrap());
+                    state = Some(entry.state());
+
+                    let skip = entry.attributes() == AttributeSet::LFN ||
+                        (!include_dot_entries && (entry.is_dot() || entry.is_dotdot()));
+                    if !skip {
+                        ret = Some(func((current_cluster, offset), entry));
+                    }
+                })
+            });
+
+            let state = match (res, state) {
+                (Ok(()), Some(state)) => state,
+                _ => {
+                    self.current_offset = None;
+                    self.error = Some(TraceError::Io);
+                    return None;
+                }
+            };
+
+            // Deleted long file name entries count too.
+            if state == State::Deleted && self.first_deleted.is_none() {
+                self.first_deleted = Some((current_cluster, offset));
+            }
+
+            let bytes_in_a_cluster = self.file_sys.bytes_in_cluster(current_cluster);
+
+            if let State::End = state {
+                self.hit_end_offset = Some(offset);
+                self.current_offset = None;
+            } else if offset + 32 >= bytes_in_a_cluster &&
+                    self.file_sys.is_fixed_root_dir(current_cluster) {
+                // The FAT12/16 root directory has nothing after it.
+                self.current_offset = None;
+            } else if offset + 32 >= bytes_in_a_cluster {
+                // The tracer hands back the cluster it starts at first; the
+                // one after that is the next cluster of the directory.
+                let mut tracer = FatEntry::from(current_cluster).trace(self.file_sys, self.storage);
+                tracer.next();
+
+                match tracer.next() {
+                    Some(next) => {
+                        self.current_cluster = next.next;
+                        self.current_offset = Some((offset + 32) % bytes_in_a_cluster);
+                    }
+                    None => {
+                        // A directory that fills its last cluster has no
+                        // terminator entry.
+                        self.error = tracer.error();
+                        self.current_offset = None;
+                    }
+                }
             } else {
-                let f = FatEntry::from(self.current_cluster);
-                let mut t = f.upgrade(self.file_sys, self.storage);
+                self.current_offset = Some(offset + 32);
+            }
 
-                // Write the new entry in the current end location:
-                let mut buf = [0u8; 32];
-                entry.into_arr(&mut buf);
+            if ret.is_some() {
+                return ret;
+            }
+        }
+    }
+
+    /// Finds the first existing entry named `name`.`ext` (compared like
+    /// `FileName::matches`: padding and case don't matter), starting from
+    /// wherever the iterator is.
+    ///
+    /// Stops as soon as there's a match or at the end of the directory; only
+    /// the entries' name bytes are looked at until then (see `next_with`).
+    /// This isn't `Iterator::find` so that that's still usable with a closure.
+    // TODO: match long file names too, once we read them.
+    pub fn find_entry(&mut self, name: &FileName, ext: &FileExt) -> Option<((ClusterIdx, u32), DirEntry)> {
+        while let Some(found) = self.next_with(|pos, entry| {
+            let matches = entry.state() == State::Exists &&
+                entry.file_name().matches(&name.0) &&
+                entry.file_ext().matches(&ext.0);
+
+            if matches { Some((pos, entry.to_entry())) } else { None }
+        }) {
+            if found.is_some() {
+                return found;
+            }
+        }
+
+        None
+    }
 
-                t.write(end, buf.iter().cloned()).unwrap();
+    /// Puts `entry` in the first deleted slot the iterator went past or, if
+    /// there wasn't one, at the end of the directory (in which case the
+    /// iterator must have hit the end of the directory).
+    ///
+    /// Directories that are full get another cluster; the FAT12/16 root
+    /// directory can't grow so this errors with `OutOfSpace` when it's full.
+    ///
+    /// We don't write long file name entries so a single free slot is always
+    /// enough.
+    pub fn add_entry(&mut self, entry: DirEntry) -> Result<(), FatError> {
+        let mut buf = [0u8; 32];
+        entry.into_arr(&mut buf);
 
-                // TODO: in the past we actually just called `into_arr` straight
-                // on the cached array; I wonder if there's performance gains to
-                // be had from exposing that as the API. This is still very
-                // doable right here by calling `self.fs.cache.upgrade` but it
-                // opens up some edge cases (i.e. access across sectors).
+        if let Some((cluster, offset)) = self.first_deleted.take() {
+            return FatEntry::from(cluster)
+                .upgrade(self.file_sys, self.storage)
+                .write(ClusterOffset::new(offset), buf.iter().cloned());
+        }
 
-                // Next, write a new terminator entry after the added entry:
-                let terminator = DirEntry::empty();
-                terminator.into_arr(&mut buf);
+        let bytes_in_a_cluster = self.file_sys.bytes_in_cluster(self.current_cluster);
 
-                t.write(end + 32, buf.iter().cloned()).unwrap();
+        if let Some(end) = self.hit_end_offset {
+            let f = FatEntry::from(self.current_cluster);
+            let mut t = f.upgrade(self.file_sys, self.storage);
 
-                // Finally, restore `current_offset` so the iterator can resume.
-                self.current_offset = Some(end);
-                Ok(())
+            // Write the new entry in the current end location:
+            t.write(ClusterOffset::new(end), buf.iter().cloned())?;
+
+            // TODO: in the past we actually just called `into_arr` straight
+            // on the cached array; I wonder if there's performance gains to
+            // be had from exposing that as the API. This is still very
+            // doable right here by calling `self.fs.cache.upgrade` but it
+            // opens up some edge cases (i.e. access across sectors).
+
+            // Next, write a new terminator entry after the added entry. If the
+            // entry took the cluster's last slot there's no room for one; the
+            // end of the chain marks the end of the directory instead.
+            if end + 32 < bytes_in_a_cluster {
+                DirEntry::empty().into_arr(&mut buf);
+                t.write(ClusterOffset::new(end + 32), buf.iter().cloned())?;
+                self.hit_end_offset = Some(end + 32);
+            } else {
+                self.hit_end_offset = None;
             }
-        } else {
-            Err(())
+
+            // Finally, restore `current_offset` so the iterator can resume.
+            self.current_offset = Some(end);
+            return Ok(());
         }
+
+        // Otherwise we must have run off the end of a directory whose last
+        // cluster is full.
+        if self.current_offset.is_some() {
+            return Err(FatError::InvalidArgument);
+        }
+        if let Some(err) = self.error {
+            return Err(err.into());
+        }
+        if self.file_sys.is_fixed_root_dir(self.current_cluster) {
+            return Err(FatError::OutOfSpace);
+        }
+
+        let new = super::file::next_cluster(self.file_sys, self.storage, self.current_cluster, true)?;
+
+        // The rest of the new cluster has to read as the end of the directory.
+        self.file_sys.clear_cluster(self.storage, new)?;
+        FatEntry::from(new)
+            .upgrade(self.file_sys, self.storage)
+            .write(ClusterOffset::new(0), buf.iter().cloned())?;
+
+        // Resume from the new entry (like above):
+        self.current_cluster = new;
+        self.current_offset = Some(0);
+        self.hit_end_offset = None;
+
+        Ok(())
     }
 
     pub fn delete(
         &mut self,
         ((c, offset), mut de): ((ClusterIdx, u32), DirEntry),
-    ) -> Result<(), ()> {
+    ) -> Result<(), FatError> {
         // TODO: actually clear the clusters this file has!
         // i.e. make a FatEntryTracer and mark each cluster as unused.
 
@@ -477,61 +1088,22 @@ where
         de.file_name.0[0] = 0xE5;
         de.into_arr(&mut buf);
 
-        t.write(offset, buf.iter().cloned()).unwrap();
-
-        Ok(())
+        t.write(ClusterOffset::new(offset), buf.iter().cloned())
     }
 }
 
 impl<'f, 's, S, CS, Ev> Iterator for DirIter<'f, 's, S, CS, Ev>
 where
-    S: Storage<Word = u8, SECTOR_SIZE = U512>,
-    CS: ArrayLength<RefCell<GenericArray<u8, U512>>>,
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
     CS: ArrayLength<super::cache::CacheEntry>,
+    CS: ArrayLength<super::cache::IndexLink>,
     CS: crate::util::BitMapLen,
     Ev: EvictionPolicy,
 {
     type Item = ((ClusterIdx, u32), DirEntry);
 
     fn next(&mut self) -> Option<((ClusterIdx, u32), DirEntry)> {
-        let entry = if let Some(offset) = self.current_offset {
-            let current_cluster = self.current_cluster;
-            let f = FatEntry::from(current_cluster);
-            let mut t = f.upgrade(self.file_sys, self.storage);
-
-            let mut buf = [0u8; 32];
-            t.read(offset, &mut buf).unwrap();
-            let entry = DirEntry::from_arr(buf);
-
-            if let State::End = entry.state() {
-                self.hit_end_offset = Some(offset);
-                self.current_offset = None;
-            } else {
-                let bytes_in_a_cluster = self.file_sys.bytes_in_a_cluster();
-                self.current_offset = Some(if offset + 32 >= bytes_in_a_cluster {
-                    let mut tracer = f.trace(self.file_sys, self.storage);
-                    self.current_cluster = tracer.next().unwrap().next;
-
-                    (offset + 32) % bytes_in_a_cluster
-                } else {
-                    offset + 32
-                });
-            }
-
-            Some(((current_cluster, offset), entry))
-        } else {
-            None
-        };
-
-        if let Some((i, entry)) = entry {
-            if entry.attributes == AttributeSet::LFN {
-                // if so, skip this!
-                self.next()
-            } else {
-                Some((i, entry))
-            }
-        } else {
-            None
-        }
+        self.next_with(|pos, entry| (pos, entry.to_entry()))
     }
 }
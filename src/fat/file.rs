@@ -1,15 +1,17 @@
 //! Files. Just files.
 
-use super::FatFs;
+use super::{FatError, FatFs};
 use super::dir::DirEntry;
+use super::table::{FatEntryTracer, TraceError};
+use super::types::{ClusterIdx, ClusterOffset, SectorIdx};
 use super::cache::EvictionPolicy;
 use crate::util::BitMapLen;
 
 use storage_traits::Storage;
 use generic_array::{ArrayLength, GenericArray};
-use typenum::consts::U512;
 
 use core::cell::RefCell;
+use core::ops::Range;
 
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -28,9 +30,10 @@ impl File {
         storage: &'s mut S,
     ) -> FileWrapper<'file, 'f, 's, S, CS, Ev>
     where
-        S: Storage<Word = u8, SECTOR_SIZE = U512>,
-        CS: ArrayLength<RefCell<GenericArray<u8, U512>>>,
+        S: Storage<Word = u8>,
+        CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
         CS: ArrayLength<super::cache::CacheEntry>,
+        CS: ArrayLength<super::cache::IndexLink>,
         CS: BitMapLen,
         Ev: EvictionPolicy,
     {
@@ -40,9 +43,10 @@ impl File {
 
 pub struct FileWrapper<'file, 'f, 's, S, CS, Ev>
 where
-    S: Storage<Word = u8, SECTOR_SIZE = U512>,
-    CS: ArrayLength<RefCell<GenericArray<u8, U512>>>,
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
     CS: ArrayLength<super::cache::CacheEntry>,
+    CS: ArrayLength<super::cache::IndexLink>,
     CS: BitMapLen,
     Ev: EvictionPolicy,
 {
@@ -54,9 +58,10 @@ where
 
 impl<'file, 'f, 's, S, CS, Ev> FileWrapper<'file, 'f, 's, S, CS, Ev>
 where
-    S: Storage<Word = u8, SECTOR_SIZE = U512>,
-    CS: ArrayLength<RefCell<GenericArray<u8, U512>>>,
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
     CS: ArrayLength<super::cache::CacheEntry>,
+    CS: ArrayLength<super::cache::IndexLink>,
     CS: BitMapLen,
     Ev: EvictionPolicy,
 {
@@ -68,5 +73,434 @@ where
         Self { inner, fs, storage }
     }
 
+    /// Reads up to `buf.len()` bytes starting `offset` bytes into the file and
+    /// returns how many bytes were read.
+    ///
+    /// Unlike `read_at` this stops at the end of the file (as its directory
+    /// entry has it) so the unused tail of the file's last cluster is never
+    /// read. Reading at the end of the file succeeds and reads nothing;
+    /// reading past it fails.
+    pub fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<usize, FatError> {
+        let remaining = self.inner.inner.file_size.checked_sub(offset).ok_or(FatError::OutOfRange)?;
+        let len = core::cmp::min(buf.len() as u64, remaining as u64) as usize;
 
+        if len > 0 {
+            self.read_at(offset, &mut buf[..len])?;
+        }
+
+        Ok(len)
+    }
+
+    /// Fills `buf` with the bytes starting `offset` bytes into the file.
+    ///
+    /// This is bounded by the file's cluster chain, not its size; use `read`
+    /// to stop at the file's end.
+    ///
+    /// The chain is only followed as far as the end of the read, and runs of
+    /// contiguous clusters are read as one run of sectors. Prefer fewer,
+    /// larger reads.
+    pub fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), FatError> {
+        self.for_each_chunk(offset, buf.len(), false, |fs, s, sector, sector_offset, range| {
+            fs.read(s, sector, sector_offset, &mut buf[range])
+        })
+    }
+
+    /// Reads the whole file (as far as its size goes) into the start of `buf`
+    /// and returns the file's size.
+    ///
+    /// This is one `read_at` so contiguous files are read as one run of
+    /// sectors. Errors with `OutOfSpace` (without reading anything) if `buf`
+    /// is smaller than the file.
+    pub fn read_to_slice(&mut self, buf: &mut [u8]) -> Result<usize, FatError> {
+        let size = self.inner.inner.file_size as usize;
+        if buf.len() < size {
+            return Err(FatError::OutOfSpace);
+        }
+
+        if size > 0 {
+            self.read_at(0, &mut buf[..size])?;
+        }
+
+        Ok(size)
+    }
+
+    /// Writes `data` starting `offset` bytes into the file, growing the file's
+    /// cluster chain if needed.
+    ///
+    /// Note that this doesn't update the size in the file's directory entry.
+    /// For the same reason this errors with `InvalidArgument` for files that
+    /// don't have any clusters yet (empty files made elsewhere can have none);
+    /// `FatFs::write_file` gives them one.
+    pub fn write_at(&mut self, offset: u32, data: &[u8]) -> Result<(), FatError> {
+        self.for_each_chunk(offset, data.len(), true, |fs, s, sector, sector_offset, range| {
+            fs.write(s, sector, sector_offset, &data[range])
+        })
+    }
+
+    /// Like `write_at` with `len` zeros, but without needing a buffer of them;
+    /// the cluster chain is only walked once.
+    pub(super) fn write_zeros_at(&mut self, offset: u32, len: u32) -> Result<(), FatError> {
+        self.for_each_chunk(offset, len as usize, true, |fs, s, sector, sector_offset, range| {
+            fs.write_iter(s, sector, sector_offset, core::iter::repeat(0).take(range.len()))
+        })
+    }
+
+    /// Splits `len` bytes starting at `offset` into runs of contiguous
+    /// clusters and calls `func` with the starting sector of each run and the
+    /// run's range within the `len` bytes.
+    fn for_each_chunk(
+        &mut self,
+        offset: u32,
+        len: usize,
+        grow: bool,
+        mut func: impl FnMut(&mut FatFs<S, CS, Ev>, &mut S, SectorIdx, u16, Range<usize>) -> Result<(), FatError>,
+    ) -> Result<(), FatError> {
+        let cluster_size = self.fs.bytes_in_a_cluster() as u64;
+
+        let mut cluster = self.inner.inner.cluster_idx();
+        let mut cluster_start = 0u64;
+
+        // Cluster 0 would map to the root directory (or to cluster 2, on
+        // FAT32), not to the file.
+        if *cluster.inner() < 2 && len > 0 {
+            return Err(if grow { FatError::InvalidArgument } else { FatError::OutOfRange });
+        }
+
+        let mut done = 0usize;
+
+        // The cluster after `cluster`, if we've already looked it up.
+        let mut upcoming = None;
+
+        // The run we haven't handed to `func` yet: where it starts, its range
+        // within the `len` bytes, and its last cluster.
+        let mut pending: Option<(SectorIdx, u16, Range<usize>, ClusterIdx)> = None;
+
+        while done < len {
+            let pos = (offset as u64) + (done as u64);
+
+            while pos >= cluster_start + cluster_size {
+                cluster = match upcoming.take() {
+                    Some(next) => next,
+                    None => self.next_cluster(cluster, grow)?,
+                };
+                cluster_start += cluster_size;
+            }
+
+            let in_cluster = pos - cluster_start;
+            let chunk = core::cmp::min(cluster_size - in_cluster, (len - done) as u64) as usize;
+
+            // For reads that continue into the next cluster, start pulling it
+            // into the cache before we serve this one (unless it's contiguous
+            // with this one, in which case it's read along with it).
+            if !grow && done + chunk < len {
+                if let Ok(next) = self.next_cluster(cluster, false) {
+                    if *next.inner() != *cluster.inner() + 1 {
+                        let sectors = self.fs.cluster_to_sector_range(next);
+                        let window = self.fs.cache.readahead_window() as u64;
+                        let end = core::cmp::min(sectors.end, SectorIdx::new(sectors.start.inner() + window));
+
+                        self.fs.cache.prefetch(self.storage, sectors.start..end);
+                    }
+
+                    upcoming = Some(next);
+                }
+            }
+
+            // Clusters that follow on from the previous one are added to its
+            // run instead of being handled on their own.
+            match &mut pending {
+                Some((_, _, range, last)) if *last.inner() + 1 == *cluster.inner() => {
+                    range.end = done + chunk;
+                    *last = cluster;
+                },
+                _ => {
+                    let (sector, sector_offset) = self.fs.cluster_to_sector(cluster, ClusterOffset::new(in_cluster as u32));
+                    let run = (sector, *sector_offset, done..(done + chunk), cluster);
+
+                    if let Some((sector, sector_offset, range, _)) = pending.replace(run) {
+                        func(self.fs, self.storage, sector, sector_offset, range)?;
+                    }
+                },
+            }
+
+            done += chunk;
+        }
+
+        if let Some((sector, sector_offset, range, _)) = pending {
+            func(self.fs, self.storage, sector, sector_offset, range)?;
+        }
+
+        Ok(())
+    }
+
+    fn next_cluster(&mut self, cluster: ClusterIdx, grow: bool) -> Result<ClusterIdx, FatError> {
+        next_cluster(self.fs, self.storage, cluster, grow)
+    }
+
+    /// Finds the end of the file (going through its cluster chain once) and
+    /// returns a handle that appends to it.
+    ///
+    /// Errors if the file's cluster chain is shorter than its size says it
+    /// should be.
+    ///
+    /// Empty files that don't have any clusters yet (other implementations
+    /// make these) get their first cluster on the first append; it shows up in
+    /// [`AppendHandle::entry`].
+    pub fn open_append(self) -> Result<AppendHandle<'f, 's, S, CS, Ev>, FatError> {
+        let entry = self.inner.inner.clone();
+        let cluster_size = self.fs.bytes_in_a_cluster();
+
+        if *entry.cluster_idx().inner() < 2 {
+            if entry.file_size != 0 {
+                return Err(FatError::Corrupt);
+            }
+
+            // As if the file ended at the end of a full cluster so that the
+            // first append allocates one.
+            return Ok(AppendHandle {
+                fs: self.fs,
+                storage: self.storage,
+                last_cluster: entry.cluster_idx(),
+                entry,
+                offset_in_cluster: cluster_size,
+            });
+        }
+
+        // We stop at the end of a full cluster rather than at the start of the
+        // next one since the next one might not exist yet.
+        let size = entry.file_size;
+        let clusters_used = if size == 0 { 1 } else { (size - 1) / cluster_size + 1 };
+        let offset_in_cluster = size - (clusters_used - 1) * cluster_size;
+
+        let last_cluster = FatEntryTracer::starting_at(self.fs, self.storage, entry.cluster_idx())
+            .nth((clusters_used - 1) as usize)
+            .ok_or(FatError::Corrupt)?
+            .next;
+
+        Ok(AppendHandle {
+            fs: self.fs,
+            storage: self.storage,
+            entry,
+            last_cluster,
+            offset_in_cluster,
+        })
+    }
+
+    /// The sectors the file's clusters occupy on the storage, in file order,
+    /// with runs of contiguous clusters merged into one range. Meant for
+    /// handing the file's data to a DMA engine without going through the
+    /// cache.
+    ///
+    /// This covers the whole cluster chain (not just as much of it as the
+    /// file's size says is used) so empty files made by `FatFs::create_file`
+    /// have one extent: their first cluster. Only files without any clusters
+    /// (empty files made elsewhere can have cluster 0) have no extents.
+    /// Changes to the file that are still in the cache aren't on the storage
+    /// until the cache is flushed.
+    pub fn data_extents(&mut self) -> DataExtents<'_, '_, S, CS, Ev> {
+        let first = self.inner.inner.cluster_idx();
+
+        let mut tracer = FatEntryTracer::starting_at(&mut *self.fs, &mut *self.storage, first);
+        if *first.inner() < 2 {
+            tracer.current_cluster_idx = None;
+        }
+
+        DataExtents { tracer, pending: None }
+    }
+}
+
+/// See [`FileWrapper::data_extents`].
+///
+/// Stops early if the file's cluster chain is broken; `error` says whether
+/// that happened.
+pub struct DataExtents<'f, 's, S, CS, Ev>
+where
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
+    CS: ArrayLength<super::cache::CacheEntry>,
+    CS: ArrayLength<super::cache::IndexLink>,
+    CS: BitMapLen,
+    Ev: EvictionPolicy,
+{
+    tracer: FatEntryTracer<'f, 's, S, CS, Ev>,
+    /// The extent we're still growing.
+    pending: Option<Range<SectorIdx>>,
+}
+
+impl<'f, 's, S, CS, Ev> DataExtents<'f, 's, S, CS, Ev>
+where
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
+    CS: ArrayLength<super::cache::CacheEntry>,
+    CS: ArrayLength<super::cache::IndexLink>,
+    CS: BitMapLen,
+    Ev: EvictionPolicy,
+{
+    pub fn error(&self) -> Option<TraceError> {
+        self.tracer.error()
+    }
+}
+
+impl<'f, 's, S, CS, Ev> Iterator for DataExtents<'f, 's, S, CS, Ev>
+where
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
+    CS: ArrayLength<super::cache::CacheEntry>,
+    CS: ArrayLength<super::cache::IndexLink>,
+    CS: BitMapLen,
+    Ev: EvictionPolicy,
+{
+    type Item = Range<SectorIdx>;
+
+    fn next(&mut self) -> Option<Range<SectorIdx>> {
+        while let Some(entry) = self.tracer.next() {
+            let sectors = self.tracer.file_sys.cluster_to_sector_range(entry.next);
+
+            if let Some(pending) = &mut self.pending {
+                if pending.end == sectors.start {
+                    pending.end = sectors.end;
+                    continue;
+                }
+            }
+
+            if let Some(done) = self.pending.replace(sectors) {
+                return Some(done);
+            }
+        }
+
+        self.pending.take()
+    }
+}
+
+using_std! {
+    impl<'file, 'f, 's, S, CS, Ev> FileWrapper<'file, 'f, 's, S, CS, Ev>
+    where
+        S: Storage<Word = u8>,
+        CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
+        CS: ArrayLength<super::cache::CacheEntry>,
+        CS: ArrayLength<super::cache::IndexLink>,
+        CS: BitMapLen,
+        Ev: EvictionPolicy,
+    {
+        /// Reads the whole file into a `Vec`; see `read_to_slice`.
+        pub fn read_to_end(&mut self) -> Result<Vec<u8>, FatError> {
+            let mut buf = vec![0; self.inner.inner.file_size as usize];
+            self.read_to_slice(&mut buf)?;
+
+            Ok(buf)
+        }
+    }
+}
+
+/// Appends to a file without going through the file's whole cluster chain for
+/// each append; made with [`FileWrapper::open_append`].
+///
+/// The file size in the handle's copy of the directory entry is kept up to
+/// date; use [`FatFs::write_dir_entry`] with [`AppendHandle::entry`] to
+/// persist it.
+pub struct AppendHandle<'f, 's, S, CS, Ev>
+where
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
+    CS: ArrayLength<super::cache::CacheEntry>,
+    CS: ArrayLength<super::cache::IndexLink>,
+    CS: BitMapLen,
+    Ev: EvictionPolicy,
+{
+    pub fs: &'f mut FatFs<S, CS, Ev>,
+    pub storage: &'s mut S,
+
+    entry: DirEntry,
+    /// The cluster that the end of the file is in; below 2 if the file
+    /// doesn't have any clusters yet.
+    last_cluster: ClusterIdx,
+    /// Bytes of `last_cluster` that are used; this is the cluster size (not 0)
+    /// when the file ends exactly at the end of a cluster.
+    offset_in_cluster: u32,
+}
+
+impl<'f, 's, S, CS, Ev> AppendHandle<'f, 's, S, CS, Ev>
+where
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
+    CS: ArrayLength<super::cache::CacheEntry>,
+    CS: ArrayLength<super::cache::IndexLink>,
+    CS: BitMapLen,
+    Ev: EvictionPolicy,
+{
+    /// The file's directory entry, with its current size.
+    pub fn entry(&self) -> &DirEntry {
+        &self.entry
+    }
+
+    /// Writes `data` at the end of the file, growing its cluster chain as
+    /// clusters fill up.
+    ///
+    /// Errors if this would make the file bigger than 4 GiB. If a write fails
+    /// partway through the size reflects what was written.
+    pub fn append(&mut self, data: &[u8]) -> Result<(), FatError> {
+        let cluster_size = self.fs.bytes_in_a_cluster();
+
+        if (self.entry.file_size as u64) + (data.len() as u64) > u32::max_value() as u64 {
+            return Err(FatError::OutOfRange);
+        }
+
+        let mut done = 0;
+        while done < data.len() {
+            if self.offset_in_cluster == cluster_size {
+                self.last_cluster = if *self.last_cluster.inner() < 2 {
+                    let first = self.fs.allocate_first_cluster(self.storage)?;
+                    self.entry.set_cluster_idx(first);
+                    first
+                } else {
+                    next_cluster(self.fs, self.storage, self.last_cluster, true)?
+                };
+                self.offset_in_cluster = 0;
+            }
+
+            let chunk = core::cmp::min(cluster_size - self.offset_in_cluster, (data.len() - done) as u32);
+            let (sector, sector_offset) = self.fs.cluster_to_sector(self.last_cluster, ClusterOffset::new(self.offset_in_cluster));
+            self.fs.write(self.storage, sector, *sector_offset, &data[done..(done + chunk as usize)])?;
+
+            self.offset_in_cluster += chunk;
+            self.entry.file_size += chunk;
+            done += chunk as usize;
+        }
+
+        Ok(())
+    }
+}
+
+/// The cluster after `cluster` in the chain. If `cluster` is the end of the
+/// chain this either errors or (if `grow` is set) adds a cluster.
+pub(super) fn next_cluster<S, CS, Ev>(
+    fs: &mut FatFs<S, CS, Ev>,
+    storage: &mut S,
+    cluster: ClusterIdx,
+    grow: bool,
+) -> Result<ClusterIdx, FatError>
+where
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
+    CS: ArrayLength<super::cache::CacheEntry>,
+    CS: ArrayLength<super::cache::IndexLink>,
+    CS: BitMapLen,
+    Ev: EvictionPolicy,
+{
+    let mut tracer = FatEntryTracer::starting_at(fs, storage, cluster);
+    tracer.next();
+
+    match tracer.next() {
+        Some(next) => Ok(next.next),
+        None => match tracer.error() {
+            // A broken chain can't be grown.
+            Some(err) => Err(err.into()),
+            None if grow => {
+                tracer.grow_file()?;
+                tracer.next().map(|e| e.next).ok_or(FatError::Corrupt)
+            },
+            // Reading past the end of the chain.
+            None => Err(FatError::OutOfRange),
+        },
+    }
 }
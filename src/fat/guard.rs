@@ -0,0 +1,132 @@
+//! A RAII wrapper for mounted filesystems.
+
+use super::{FatFs, MountError};
+use super::cache::{self, EvictionPolicy};
+use crate::util::BitMapLen;
+use crate::gpt::PartitionEntry;
+
+use storage_traits::Storage;
+use generic_array::{ArrayLength, GenericArray};
+
+use core::cell::RefCell;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+
+/// Holds a mounted `FatFs` and the `Storage` it's backed by and flushes the
+/// filesystem's cache when dropped.
+///
+/// Made with [`FatFs::mount_guarded`].
+pub struct MountGuard<'s, S, CS, Ev>
+where
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
+    CS: ArrayLength<cache::CacheEntry>,
+    CS: ArrayLength<cache::IndexLink>,
+    CS: BitMapLen,
+    Ev: EvictionPolicy,
+{
+    // This is only ever not dropped if flushing fails in our Drop impl; see
+    // below.
+    fs: ManuallyDrop<FatFs<S, CS, Ev>>,
+    storage: &'s mut S,
+}
+
+impl<S, CS, Ev> FatFs<S, CS, Ev>
+where
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
+    CS: ArrayLength<cache::CacheEntry>,
+    CS: ArrayLength<cache::IndexLink>,
+    CS: BitMapLen,
+    Ev: EvictionPolicy,
+{
+    /// Like [`FatFs::mount`] but the returned filesystem holds onto the
+    /// storage and is flushed when it's dropped.
+    pub fn mount_guarded<'s>(
+        s: &'s mut S,
+        partition: &PartitionEntry,
+        ev: Ev,
+    ) -> Result<MountGuard<'s, S, CS, Ev>, MountError> {
+        let fs = Self::mount(s, partition, ev)?;
+
+        Ok(MountGuard {
+            fs: ManuallyDrop::new(fs),
+            storage: s,
+        })
+    }
+}
+
+impl<'s, S, CS, Ev> MountGuard<'s, S, CS, Ev>
+where
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
+    CS: ArrayLength<cache::CacheEntry>,
+    CS: ArrayLength<cache::IndexLink>,
+    CS: BitMapLen,
+    Ev: EvictionPolicy,
+{
+    pub fn storage(&mut self) -> &mut S {
+        self.storage
+    }
+
+    /// Most `FatFs` methods want the storage passed in alongside the
+    /// filesystem; this gets you both at once.
+    pub fn split(&mut self) -> (&mut FatFs<S, CS, Ev>, &mut S) {
+        (&mut self.fs, self.storage)
+    }
+}
+
+impl<'s, S, CS, Ev> Deref for MountGuard<'s, S, CS, Ev>
+where
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
+    CS: ArrayLength<cache::CacheEntry>,
+    CS: ArrayLength<cache::IndexLink>,
+    CS: BitMapLen,
+    Ev: EvictionPolicy,
+{
+    type Target = FatFs<S, CS, Ev>;
+
+    fn deref(&self) -> &FatFs<S, CS, Ev> {
+        &self.fs
+    }
+}
+
+impl<'s, S, CS, Ev> DerefMut for MountGuard<'s, S, CS, Ev>
+where
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
+    CS: ArrayLength<cache::CacheEntry>,
+    CS: ArrayLength<cache::IndexLink>,
+    CS: BitMapLen,
+    Ev: EvictionPolicy,
+{
+    fn deref_mut(&mut self) -> &mut FatFs<S, CS, Ev> {
+        &mut self.fs
+    }
+}
+
+impl<'s, S, CS, Ev> Drop for MountGuard<'s, S, CS, Ev>
+where
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
+    CS: ArrayLength<cache::CacheEntry>,
+    CS: ArrayLength<cache::IndexLink>,
+    CS: BitMapLen,
+    Ev: EvictionPolicy,
+{
+    fn drop(&mut self) {
+        match self.fs.cache.flush(self.storage) {
+            // Safe because we never touch `self.fs` again.
+            Ok(()) => unsafe { ManuallyDrop::drop(&mut self.fs) },
+
+            // The `SectorCache` Drop impl panics if there are dirty entries
+            // left. Since we can't return the error (and don't want to panic
+            // while possibly already panicking) we leak the filesystem
+            // instead.
+            Err(e) => log::error!("Failed to flush the cache while \
+                unmounting ({:?}); leaking the filesystem and its dirty \
+                sectors!", e),
+        }
+    }
+}
@@ -1,16 +1,19 @@
 //! FAT filesystem things!
 
 use super::Storage;
-use super::gpt::{PartitionEntry, Guid};
+use super::storage::{EraseError, StorageExt};
+use super::gpt::{Gpt, PartitionEntry, Guid};
 use super::util::BitMapLen;
 
-use boot_sector::BootSector;
+use boot_sector::{BootSector, FsInfo, BACKUP_BOOT_SECTOR};
 
 use generic_array::{ArrayLength, GenericArray};
-use typenum::consts::U512;
+use storage_traits::errors::{ReadError, WriteError};
+use typenum::marker_traits::Unsigned;
 
 use core::cell::RefCell;
 use core::convert::TryInto;
+use core::fmt::{self, Debug};
 use core::marker::PhantomData;
 use core::ops::Range;
 
@@ -18,70 +21,353 @@ pub mod cache;
 use cache::{SectorCache, EvictionPolicy, DynEvictionPolicy};
 
 pub mod types;
-use types::{SectorIdx, ClusterIdx};
+use types::{ByteOffset, ClusterOffset, SectorIdx, SectorRange, ClusterIdx};
 
 pub mod boot_sector;
 pub mod table;
+use table::{FatEntry, FatEntryKind, FatEntryTracer, FatType, FreeRuns, TraceError};
+
 pub mod dir;
-use dir::{DirEntry, DirIter};
+use dir::{AttributeSet, Date, DateTime, DirEntry, DirIter, FileName, FileExt, State};
 
 pub mod file;
+use file::File;
+
+pub mod guard;
 
-const FAT_ENTRY_SIZE_IN_BYTES: u16 = 4;
+/// Longest path `FatFs::walk` can build.
+const WALK_MAX_PATH_LEN: usize = 256;
 
-// Another TODO: relax the 512B sector size restriction in this file.
+/// The offset in the location `FatFs::lookup_path` gives the root directory,
+/// which doesn't have an entry. Real entries are at multiples of 32 so this
+/// can't be confused with one.
+pub const ROOT_DIR_ENTRY_OFFSET: u32 = u32::MAX;
 
 // TODO: this should hold a mutable reference to the storage that it is backed
 // by; we currently don't do this to make the FFI a little easier.
 
+/// A mounted FAT volume.
+///
+/// Like the `SectorCache` underneath it, this works with byte oriented storage
+/// (`Word = u8`); storage with wider words can be wrapped in a
+/// `storage_adapters::ByteStorage`.
 #[allow(non_camel_case_types)]
-#[derive(Debug)]
 pub struct FatFs<S, CACHE_SIZE, Ev = DynEvictionPolicy>
 where
-    S: Storage<Word = u8, SECTOR_SIZE = U512>,
-    CACHE_SIZE: ArrayLength<RefCell<GenericArray<u8, U512>>>,
+    S: Storage<Word = u8>,
+    CACHE_SIZE: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
     CACHE_SIZE: ArrayLength<cache::CacheEntry>,
+    CACHE_SIZE: ArrayLength<cache::IndexLink>,
     CACHE_SIZE: BitMapLen,
     Ev: EvictionPolicy,
 {
     pub starting_lba: SectorIdx,
     pub ending_lba: SectorIdx,
     pub num_sectors: u64,
+    /// The boot sector we mounted with: `starting_lba` unless that one was bad
+    /// and we fell back to the backup (see `mount`).
+    pub boot_sector: SectorIdx,
 
-    pub sector_size_in_bytes: u16, // Currently we _assume_ this is 512 (todo!)..
+    pub fat_type: FatType,
+    pub sector_size_in_bytes: u16, // Always matches `S::SECTOR_SIZE`.
     pub fat_table_size_in_sectors: u32,
     pub num_fat_tables: u8, // TODO! we currently ignore all but the first (i.e. we don't update the other ones..)
     pub cluster_size_in_sectors: u8,
 
     pub fat_starting_sector: SectorIdx,
+    /// On FAT12/16 the root directory is a fixed region between the FATs and
+    /// the data clusters rather than a cluster chain; this is its size (0 on
+    /// FAT32).
+    pub root_dir_size_in_sectors: u32,
+    /// On FAT12/16 this is 0, which stands in for the fixed root directory
+    /// region (`..` entries use 0 to refer to the root too).
     pub root_dir_cluster_num: ClusterIdx,
     pub next_known_free_cluster: ClusterIdx,
+    /// Number of data clusters; the first one is cluster 2.
+    pub cluster_count: u32,
+    /// Used when new files are created; `FirstFit` unless changed.
+    pub alloc_policy: AllocPolicy,
 
-    pub cache: SectorCache<S, U512, CACHE_SIZE, Ev>,
+    pub cache: SectorCache<S, S::SECTOR_SIZE, CACHE_SIZE, Ev>,
 
     // storage: &'s mut S,
     _s: PhantomData</*&'s */S>,
 }
 
+/// Ways `FatFs::lookup_path` can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LookupError<'p> {
+    /// Nothing in the directory matched this component of the path.
+    NotFound(&'p [u8]),
+    /// A component that wasn't the last one (or that was followed by a `/`)
+    /// named something other than a directory.
+    NotADirectory,
+    /// The path was empty.
+    Empty,
+}
+
+/// Ways `FatFs::open` can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenError<'p> {
+    /// Nothing in the directory matched this component of the path.
+    NotFound(&'p [u8]),
+    /// A component before the last one named something other than a
+    /// directory.
+    NotADirectory,
+    /// The path names a directory.
+    IsADirectory,
+    /// The path was empty.
+    Empty,
+}
+
+/// How `FatFs::create_file` picks the first cluster of a new file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocPolicy {
+    /// Whichever free cluster `FatFs::next_free_cluster` turns up.
+    FirstFit,
+    /// The first free cluster that starts on a sector (counting from the start
+    /// of the storage, not the partition) that's a multiple of this many
+    /// sectors. Handy for DMA engines that like aligned transfers.
+    ///
+    /// If no free cluster is aligned this falls back to `FirstFit`. 0 and 1
+    /// mean the same thing as `FirstFit`.
+    AlignedTo(u8),
+}
+
+impl Default for AllocPolicy { fn default() -> Self { AllocPolicy::FirstFit } }
+
+/// A cluster index that's past the end of the FAT; see
+/// `FatFs::cluster_to_table_pos`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfRange(pub ClusterIdx);
+
+/// Why an operation on a `FatFs` (or on one of its files or directories)
+/// failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatError {
+    /// Reading from or writing to the storage failed.
+    Io,
+    /// There are no free clusters left (or a directory or buffer is full).
+    OutOfSpace,
+    /// A cluster, sector, or offset is past the end of whatever it's in (the
+    /// volume, the FAT, a cluster, a file).
+    OutOfRange,
+    /// Nothing is at the path given.
+    NotFound,
+    /// Something other than a directory was used as one.
+    NotADirectory,
+    /// A directory was used as a file.
+    IsADirectory,
+    /// The on-disk structures don't make sense: i.e. a cluster chain that
+    /// loops, runs into a free cluster, or is shorter than its file.
+    Corrupt,
+    /// There's already something at the path given.
+    AlreadyExists,
+    /// A name that isn't a valid 8.3 name or volume label, an empty path, or
+    /// some other argument that doesn't make sense for the operation.
+    InvalidArgument,
+    /// Mounting the volume failed; i.e. `FatFs::format` couldn't mount the
+    /// volume it just made.
+    Mount(MountError),
+}
+
+impl From<OutOfRange> for FatError {
+    fn from(_: OutOfRange) -> Self {
+        FatError::OutOfRange
+    }
+}
+
+impl From<LookupError<'_>> for FatError {
+    fn from(err: LookupError<'_>) -> Self {
+        match err {
+            LookupError::NotFound(_) => FatError::NotFound,
+            LookupError::NotADirectory => FatError::NotADirectory,
+            LookupError::Empty => FatError::InvalidArgument,
+        }
+    }
+}
+
+impl From<OpenError<'_>> for FatError {
+    fn from(err: OpenError<'_>) -> Self {
+        match err {
+            OpenError::NotFound(_) => FatError::NotFound,
+            OpenError::NotADirectory => FatError::NotADirectory,
+            OpenError::IsADirectory => FatError::IsADirectory,
+            OpenError::Empty => FatError::InvalidArgument,
+        }
+    }
+}
+
+impl From<TraceError> for FatError {
+    fn from(err: TraceError) -> Self {
+        match err {
+            TraceError::Io => FatError::Io,
+            TraceError::OutOfRange(_) | TraceError::Cycle | TraceError::BrokenChain => FatError::Corrupt,
+        }
+    }
+}
+
+/// Storage errors other than out of range accesses all become `Io`; the
+/// storage's own error type doesn't make it through.
+impl<E> From<ReadError<E>> for FatError {
+    fn from(err: ReadError<E>) -> Self {
+        match err {
+            ReadError::OutOfRange { .. } => FatError::OutOfRange,
+            _ => FatError::Io,
+        }
+    }
+}
+
+/// See the `ReadError` impl.
+impl<E> From<WriteError<E>> for FatError {
+    fn from(err: WriteError<E>) -> Self {
+        match err {
+            WriteError::OutOfRange { .. } => FatError::OutOfRange,
+            _ => FatError::Io,
+        }
+    }
+}
+
+/// See the `ReadError` impl.
+impl<W, T> From<EraseError<W, T>> for FatError {
+    fn from(err: EraseError<W, T>) -> Self {
+        match err {
+            EraseError::ErrorInIndividualErase(err) => err.into(),
+            EraseError::Other(_) => FatError::Io,
+        }
+    }
+}
+
+impl<CS: ArrayLength<cache::CacheEntry>> From<cache::FlushError<CS>> for FatError {
+    fn from(_: cache::FlushError<CS>) -> Self {
+        FatError::Io
+    }
+}
+
+/// What `FatFs::metadata` returns: the parts of a directory entry that callers
+/// usually care about, decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub is_dir: bool,
+    pub is_file: bool,
+    /// The file's size in bytes; 0 for directories.
+    pub len: u32,
+    pub created: DateTime,
+    pub modified: DateTime,
+    pub accessed: Date,
+}
+
+impl Metadata {
+    fn from_entry(entry: &DirEntry) -> Self {
+        Self {
+            is_dir: entry.attributes.is_dir(),
+            is_file: entry.attributes.is_file(),
+            len: entry.file_size,
+            created: entry.created(),
+            modified: entry.modified(),
+            accessed: entry.accessed(),
+        }
+    }
+}
+
+/// Ways `FatFs::mount` can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MountError {
+    /// The partition's type isn't `Guid::microsoft_basic_data()`.
+    WrongPartitionType,
+    /// The BPB's sector size doesn't match the storage's.
+    UnsupportedSectorSize,
+    /// The boot sector isn't a FAT boot sector (i.e. it's exFAT or NTFS) or
+    /// its geometry doesn't make sense (no FATs, clusters that aren't a power
+    /// of two sectors big).
+    NotFat,
+    /// A FAT32 volume with a version other than 0.0.
+    UnsupportedVersion,
+    /// Reading the volume's metadata failed.
+    Io,
+}
+
+/// Ways `FatFs::mount_nth` and `FatFs::mount_first_of_type` can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionMountError {
+    /// There's no (used) partition at the index or with the type given.
+    NoSuchPartition,
+    /// The partition was found but couldn't be mounted.
+    MountFailed(MountError),
+}
+
+// Written out by hand so that we don't need `S::SECTOR_SIZE: Debug`.
+impl<S, CS, Ev> Debug for FatFs<S, CS, Ev>
+where
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
+    CS: ArrayLength<cache::CacheEntry>,
+    CS: ArrayLength<cache::IndexLink>,
+    CS: BitMapLen,
+    Ev: EvictionPolicy,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("FatFs")
+            .field("starting_lba", &self.starting_lba)
+            .field("ending_lba", &self.ending_lba)
+            .field("num_sectors", &self.num_sectors)
+            .field("boot_sector", &self.boot_sector)
+            .field("fat_type", &self.fat_type)
+            .field("sector_size_in_bytes", &self.sector_size_in_bytes)
+            .field("fat_table_size_in_sectors", &self.fat_table_size_in_sectors)
+            .field("num_fat_tables", &self.num_fat_tables)
+            .field("cluster_size_in_sectors", &self.cluster_size_in_sectors)
+            .field("fat_starting_sector", &self.fat_starting_sector)
+            .field("root_dir_size_in_sectors", &self.root_dir_size_in_sectors)
+            .field("root_dir_cluster_num", &self.root_dir_cluster_num)
+            .field("next_known_free_cluster", &self.next_known_free_cluster)
+            .field("cluster_count", &self.cluster_count)
+            .field("alloc_policy", &self.alloc_policy)
+            .field("cache", &self.cache)
+            .finish()
+    }
+}
+
 impl<S, CS, Ev> FatFs<S, CS, Ev>
 where
-    S: Storage<Word = u8, SECTOR_SIZE = U512>,
-    CS: ArrayLength<RefCell<GenericArray<u8, U512>>>,
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
     CS: ArrayLength<cache::CacheEntry>,
+    CS: ArrayLength<cache::IndexLink>,
     CS: BitMapLen,
     Ev: EvictionPolicy,
 {
-    pub fn mount(s: &/*'s*/ mut S, partition: &PartitionEntry, ev: Ev) -> Result<Self, ()> {
+    /// If the boot sector at the start of the partition doesn't check out (the
+    /// sector size is wrong or it doesn't look like FAT) but there's a valid
+    /// FAT32 backup boot sector in the usual spot (`BACKUP_BOOT_SECTOR`), the
+    /// backup is used instead. The bad boot sector is left as it is.
+    pub fn mount(s: &/*'s*/ mut S, partition: &PartitionEntry, ev: Ev) -> Result<Self, MountError> {
         if partition.partition_type != Guid::microsoft_basic_data() {
-            return Err(());
+            return Err(MountError::WrongPartitionType);
         }
 
         let mut cache = SectorCache::new(s, SectorIdx::new(partition.last_lba), ev);
 
-        let boot_sect = BootSector::read(
-            &cache.upgrade(s).get(SectorIdx::new(partition.first_lba))
-        );
-        assert_eq!(512, boot_sect.bpb.bytes_per_logical_sector);
+        let primary = SectorIdx::new(partition.first_lba);
+        let (boot_sector, boot_sect) = match Self::check_boot_sector(&mut cache, s, primary, false) {
+            Ok(boot_sect) => (primary, boot_sect),
+            Err(err) => {
+                let backup = primary.checked_add(BACKUP_BOOT_SECTOR as u64)
+                    .filter(|sector| *sector.inner() <= partition.last_lba)
+                    .ok_or(err)?;
+
+                let boot_sect = Self::check_boot_sector(&mut cache, s, backup, true)
+                    .ok()
+                    .filter(|b| b.fat_type() == FatType::Fat32)
+                    .filter(|b| b.bpb.boot_sector_backup_logical_sector_start_num == BACKUP_BOOT_SECTOR)
+                    .ok_or(err)?;
+
+                log::warn!("The boot sector at {} is bad ({:?}); using the backup at {}.",
+                    primary.inner(), err, backup.inner());
+
+                (backup, boot_sect)
+            }
+        };
 
         let starting_lba = SectorIdx::new(partition.first_lba);
         let ending_lba = SectorIdx::new(partition.last_lba);
@@ -90,215 +376,1503 @@ where
 
         let num_sectors = partition.last_lba - partition.first_lba;
 
-        Ok(Self {
+        let fat_type = boot_sect.fat_type();
+        if fat_type == FatType::Fat32 && boot_sect.bpb.version != 0 {
+            return Err(MountError::UnsupportedVersion);
+        }
+
+        let root_dir_cluster_num = match fat_type {
+            FatType::Fat32 => ClusterIdx::new(boot_sect.bpb.root_dir_cluster_num),
+            FatType::Fat12 | FatType::Fat16 => ClusterIdx::new(0),
+        };
+        let mut fs = Self {
             starting_lba,
             ending_lba,
             num_sectors,
+            boot_sector,
 
+            fat_type,
             sector_size_in_bytes: boot_sect.bpb.bytes_per_logical_sector,
-            fat_table_size_in_sectors: boot_sect.bpb.logical_sectors_per_fat_extended,
+            fat_table_size_in_sectors: boot_sect.fat_size_in_sectors(),
             num_fat_tables: boot_sect.bpb.num_file_alloc_tables,
             cluster_size_in_sectors,
 
-            fat_starting_sector: boot_sect.starting_fat_sector(),
-            root_dir_cluster_num: ClusterIdx::new(boot_sect.bpb.root_dir_cluster_num),
-            next_known_free_cluster: ClusterIdx::new(boot_sect.bpb.root_dir_cluster_num),
+            fat_starting_sector: boot_sect.starting_fat_sector(starting_lba),
+            root_dir_size_in_sectors: boot_sect.root_dir_size_in_sectors(),
+            root_dir_cluster_num,
+            // Fixed up below.
+            next_known_free_cluster: ClusterIdx::new(2),
+            cluster_count: boot_sect.cluster_count(),
+            alloc_policy: AllocPolicy::FirstFit,
 
             cache,
 
             _s: PhantomData,
-        })
+        };
+
+        fs.seed_next_free_cluster(s, &boot_sect).map_err(|_| MountError::Io)?;
+        fs.pin_metadata(s).map_err(|_| MountError::Io)?;
+
+        Ok(fs)
+    }
+
+    /// Reads the boot sector at `sector` and makes sure it's one we can mount.
+    ///
+    /// Backups are held to a higher standard: they also need the `0x55AA`
+    /// signature at the end of the sector (plenty of the primaries we mount
+    /// don't have it).
+    fn check_boot_sector(
+        cache: &mut SectorCache<S, S::SECTOR_SIZE, CS, Ev>,
+        s: &mut S,
+        sector: SectorIdx,
+        backup: bool,
+    ) -> Result<BootSector, MountError> {
+        let (boot_sect, fs_type_label) = {
+            let c = cache.upgrade(s);
+            let sector = c.try_get(sector).map_err(|_| MountError::Io)?;
+
+            if backup && sector[0x1FE..0x200] != [0x55, 0xAA] {
+                return Err(MountError::NotFat);
+            }
+
+            let boot_sect = BootSector::read(&sector);
+            let label = BootSector::read_file_system_type(&sector, boot_sect.fat_type());
+
+            (boot_sect, label)
+        };
+
+        // The BPB's sector size has to match the storage's.
+        if boot_sect.bpb.bytes_per_logical_sector as usize != S::SECTOR_SIZE::to_usize() {
+            return Err(MountError::UnsupportedSectorSize);
+        }
+
+        // The FAT type comes from the cluster count, not the file system type
+        // string, but a string that doesn't say FAT means this is something
+        // else. exFAT and NTFS boot sectors have their names where the OEM
+        // name goes and zeros where most of the BPB goes.
+        let not_fat = fs_type_label.map_or(false, |l| !l.starts_with(b"FAT")) ||
+            boot_sect.oem_name == *b"EXFAT   " ||
+            boot_sect.oem_name == *b"NTFS    " ||
+            !boot_sect.bpb.logical_sectors_per_cluster.is_power_of_two() ||
+            boot_sect.bpb.num_file_alloc_tables == 0;
+        if not_fat {
+            return Err(MountError::NotFat);
+        }
+
+        Ok(boot_sect)
+    }
+
+    /// Pins the first sector of the FAT and then as much of the root directory
+    /// as fits in the cache since just about every lookup goes through them.
+    ///
+    /// At most `(cache size - 1) / 4` sectors are pinned so there's plenty of
+    /// room left for everything else; caches of 4 sectors or fewer don't pin
+    /// anything.
+    fn pin_metadata(&mut self, s: &mut S) -> Result<(), FatError> {
+        let budget = <CS as Unsigned>::to_usize().saturating_sub(1) / 4;
+        let root_dir = SectorRange::new(self.cluster_to_sector_range(self.root_dir_cluster_num));
+        let ending_lba = self.ending_lba;
+
+        let sectors = core::iter::once(self.fat_starting_sector)
+            .chain(root_dir)
+            .filter(|sector| *sector <= ending_lba)
+            .take(budget);
+
+        for sector in sectors {
+            self.cache.pin(s, sector)?;
+        }
+
+        Ok(())
+    }
+
+    /// Points `next_known_free_cluster` at the cluster in the FSInfo sector's
+    /// hint if there is one (FAT32 only) and it's in range. Otherwise we find
+    /// the first free cluster ourselves.
+    fn seed_next_free_cluster(&mut self, s: &mut S, boot_sect: &BootSector) -> Result<(), FatError> {
+        let info = match self.fs_info_sector(boot_sect) {
+            Some(sector) => FsInfo::read(&*self.cache.upgrade(s).try_get(sector)?),
+            None => None,
+        };
+
+        let valid = 2..self.num_clusters();
+        let hint = info.map(|info| info.next_free_cluster).filter(|c| valid.contains(c));
+
+        match hint {
+            Some(c) => self.next_known_free_cluster = ClusterIdx::new(c),
+            None => {
+                self.next_known_free_cluster = ClusterIdx::new(2);
+
+                // A full volume is still mountable.
+                let _ = self.find_free_cluster(s)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Where the FSInfo sector is, if the volume has one (only FAT32 volumes
+    /// do).
+    fn fs_info_sector(&self, boot_sect: &BootSector) -> Option<SectorIdx> {
+        let fs_info_sector = boot_sect.bpb.fs_info_logical_sector_num;
+
+        match self.fat_type {
+            FatType::Fat32 if fs_info_sector != 0 && fs_info_sector != 0xFFFF => {
+                self.starting_lba.checked_add(fs_info_sector as u64)
+                    .filter(|sector| *sector <= self.ending_lba)
+            },
+            _ => None,
+        }
+    }
+
+    /// Flushes the cache, updates the FSInfo sector (FAT32 only) and the
+    /// backup boot sector (if there is one), and then gets rid of the
+    /// filesystem.
+    ///
+    /// If flushing fails the filesystem is leaked (along with its dirty
+    /// sectors) rather than dropped, since `SectorCache`'s Drop impl would
+    /// panic.
+    pub fn unmount(mut self, s: &mut S) -> Result<(), FatError> {
+        let res = self.write_back_metadata(s);
+
+        match self.cache.flush(s) {
+            Ok(()) => res,
+            Err(e) => {
+                log::error!("Failed to flush the cache while unmounting \
+                    ({:?}); leaking the filesystem and its dirty sectors!", e);
+                core::mem::forget(self);
+
+                Err(FatError::Io)
+            }
+        }
+    }
+
+    /// The parts of `unmount` that happen before the flush.
+    fn write_back_metadata(&mut self, s: &mut S) -> Result<(), FatError> {
+        let boot_sect = self.get_boot_sect(s)?;
+
+        if let Some(sector) = self.fs_info_sector(&boot_sect) {
+            let info = FsInfo {
+                free_cluster_count: self.free_cluster_count(s)?,
+                next_free_cluster: *self.next_known_free_cluster.inner(),
+            };
+
+            info.write(self.cache.upgrade(s).try_get_mut(sector)?);
+        }
+
+        // Only FAT32 has a backup boot sector; on FAT12/16 these bytes are part
+        // of the volume label.
+        let backup = boot_sect.bpb.boot_sector_backup_logical_sector_start_num;
+        if self.fat_type == FatType::Fat32 && backup != 0 && backup != 0xFFFF {
+            let backup = self.starting_lba.checked_add(backup as u64)
+                .filter(|sector| *sector <= self.ending_lba)
+                .ok_or(FatError::Corrupt)?;
+
+            let mut cache = self.cache.upgrade(s);
+            if backup != self.boot_sector {
+                let sector = cache.try_get(self.boot_sector)?.clone();
+                *cache.try_get_mut(backup)? = sector;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Mounts the `n`th partition in `gpt`.
+    pub fn mount_nth(s: &mut S, gpt: &Gpt, n: u32, ev: Ev) -> Result<Self, PartitionMountError> {
+        let partition = gpt.get_partition_entry(s, n)
+            .map_err(|_| PartitionMountError::NoSuchPartition)?;
+
+        if partition.partition_type == Guid::from_bytes([0; 16]) {
+            return Err(PartitionMountError::NoSuchPartition);
+        }
+
+        Self::mount(s, &partition, ev).map_err(PartitionMountError::MountFailed)
+    }
+
+    /// Mounts the first partition in `gpt` whose type is `partition_type`
+    /// (i.e. `Guid::microsoft_basic_data()`).
+    pub fn mount_first_of_type(s: &mut S, gpt: &Gpt, partition_type: Guid, ev: Ev) -> Result<Self, PartitionMountError> {
+        for n in 0..gpt.num_partition_entries() {
+            let partition = gpt.get_partition_entry(s, n)
+                .map_err(|_| PartitionMountError::NoSuchPartition)?;
+
+            if partition.partition_type == partition_type {
+                return Self::mount(s, &partition, ev).map_err(PartitionMountError::MountFailed);
+            }
+        }
+
+        Err(PartitionMountError::NoSuchPartition)
+    }
+
+    /// Every sector in the partition (`starting_lba` through `ending_lba`).
+    ///
+    /// `SectorRange::clamp` a range to this to skip the sectors `range_chk`
+    /// would reject.
+    pub fn valid_sector_range(&self) -> SectorRange {
+        let end = self.ending_lba.checked_add(1).unwrap_or(self.ending_lba);
+
+        SectorRange::new(self.starting_lba..end)
     }
 
     pub fn bytes_in_a_cluster(&self) -> u32 {
         (self.cluster_size_in_sectors as u32) * (self.sector_size_in_bytes as u32)
     }
 
+    /// Like `bytes_in_a_cluster` but knows that the FAT12/16 root directory
+    /// region isn't cluster sized.
+    pub fn bytes_in_cluster(&self, idx: ClusterIdx) -> u32 {
+        if self.is_fixed_root_dir(idx) {
+            self.root_dir_size_in_sectors * (self.sector_size_in_bytes as u32)
+        } else {
+            self.bytes_in_a_cluster()
+        }
+    }
+
+    /// Whether `idx` refers to the fixed FAT12/16 root directory region.
+    pub fn is_fixed_root_dir(&self, idx: ClusterIdx) -> bool {
+        self.fat_type != FatType::Fat32 && idx == self.root_dir_cluster_num
+    }
+
     /// Cluster Index to the corresponding FAT Table entry's sector and byte
     /// offset.
-    pub fn cluster_to_table_pos(&self, idx: ClusterIdx) -> (SectorIdx, u16) {
-        Self::cluster_to_table_pos_inner(
+    ///
+    /// Errors for clusters past the end of the volume (see `num_clusters`);
+    /// their entries either aren't in the FAT or don't mean anything.
+    pub fn cluster_to_table_pos(&self, idx: ClusterIdx) -> Result<(SectorIdx, ByteOffset), OutOfRange> {
+        if *idx.inner() >= self.num_clusters() {
+            return Err(OutOfRange(idx));
+        }
+
+        Ok(Self::cluster_to_table_pos_inner(
+            self.fat_type,
             self.sector_size_in_bytes,
             self.fat_starting_sector,
             idx,
-        )
+        ))
     }
 
+    /// For FAT12 the entry can straddle two sectors; the position returned is
+    /// that of the entry's first byte.
     pub fn cluster_to_table_pos_inner(
+        fat_type: FatType,
         sector_size_in_bytes: u16,
         fat_starting_sector: SectorIdx,
         idx: ClusterIdx,
-    ) -> (SectorIdx, u16) {
-        let cluster_entries_per_fat_sector: u64 =
-            (sector_size_in_bytes as u64) / 4;
+    ) -> (SectorIdx, ByteOffset) {
+        let byte_offset = fat_type.entry_offset(idx);
 
         let sector_idx =
-            fat_starting_sector.inner() + ((*idx as u64) / cluster_entries_per_fat_sector);
+            fat_starting_sector.inner() + (byte_offset / (sector_size_in_bytes as u64));
+        let byte_offset = byte_offset % (sector_size_in_bytes as u64);
+
+        (SectorIdx::new(sector_idx), ByteOffset::new(byte_offset as u16))
+    }
+
+    pub fn read_fat_entry(&mut self, s: &mut S, idx: ClusterIdx) -> Result<FatEntry, FatError> {
+        let (sector, offset) = self.cluster_to_table_pos(idx)?;
 
-        let byte_offset = idx.inner() % (cluster_entries_per_fat_sector as u32);
-        let byte_offset = byte_offset * (FAT_ENTRY_SIZE_IN_BYTES as u32);
+        let mut buf = [0u8; 4];
+        self.read(s, sector, *offset, &mut buf[..self.fat_type.entry_span_in_bytes()])?;
 
-        (SectorIdx::new(sector_idx), byte_offset as u16)
+        Ok(self.fat_type.decode(idx, buf))
     }
 
-    pub fn cluster_to_sector(&self, idx: ClusterIdx, offset: u32) -> (SectorIdx, u16) {
-        // Convert the cluster idx + offset to sector idx.
-        let sector_idx = (*idx.inner() as u64) * (self.cluster_size_in_sectors as u64);
-        let sector_idx = sector_idx + ((offset as u64) / (self.sector_size_in_bytes as u64));
-        // let sector_idx = sector_idx - 2;
+    pub fn write_fat_entry(&mut self, s: &mut S, idx: ClusterIdx, entry: &FatEntry) -> Result<(), FatError> {
+        let (sector, offset) = self.cluster_to_table_pos(idx)?;
+        let span = self.fat_type.entry_span_in_bytes();
+
+        // FAT12 entries share bytes with their neighbours and FAT32 entries
+        // have reserved bits that we have to keep so we read-modify-write.
+        let mut buf = [0u8; 4];
+        self.read(s, sector, *offset, &mut buf[..span])?;
+        self.fat_type.encode(idx, entry, &mut buf);
+
+        self.write(s, sector, *offset, &buf[..span])
+    }
 
-        // Add in the number of sectors used for the FAT/boot sector/whatever.
-        let sector_idx = sector_idx + *self.fat_starting_sector.inner() +
+    pub fn cluster_to_sector(&self, idx: ClusterIdx, offset: ClusterOffset) -> (SectorIdx, ByteOffset) {
+        // The sectors used for the FAT/boot sector/whatever.
+        let fat_region_end = *self.fat_starting_sector.inner() +
             (self.fat_table_size_in_sectors as u64) * (self.num_fat_tables as u64);
-        let sector_idx = SectorIdx::new(sector_idx);
 
-        let offset = offset % (self.sector_size_in_bytes as u32);
+        let sector_idx = if self.is_fixed_root_dir(idx) {
+            // The FAT12/16 root directory comes right after the FATs.
+            fat_region_end
+        } else {
+            // Convert the cluster idx to sector idx. The first data cluster is
+            // cluster 2 (0 and 1 don't have sectors; we map them onto cluster
+            // 2's rather than underflowing).
+            let sector_idx = (idx.inner().saturating_sub(2) as u64) * (self.cluster_size_in_sectors as u64);
+
+            // Data clusters come after the FATs and the root directory region.
+            sector_idx + fat_region_end + (self.root_dir_size_in_sectors as u64)
+        };
+
+        let (sectors, offset) = offset.split(self.sector_size_in_bytes);
 
-        (sector_idx, offset as u16)
+        (SectorIdx::new(sector_idx + (sectors as u64)), offset)
     }
 
     pub fn cluster_to_sector_range(&self, idx: ClusterIdx) -> Range<SectorIdx> {
-        let (start, _) = self.cluster_to_sector(idx, 0);
+        let (start, _) = self.cluster_to_sector(idx, ClusterOffset::new(0));
 
-        start..SectorIdx::new(*start.inner() + (self.cluster_size_in_sectors as u64))
+        let len = if self.is_fixed_root_dir(idx) {
+            self.root_dir_size_in_sectors as u64
+        } else {
+            self.cluster_size_in_sectors as u64
+        };
+
+        start..SectorIdx::new(*start.inner() + len)
     }
 
-    pub fn get_boot_sect(&mut self, s: & mut S) -> Result<BootSector, ()> {
-        Ok(BootSector::read(&*self.cache.upgrade(s).get(self.starting_lba)))
+    /// Reads all of cluster `idx` into `buf`, which has to be exactly
+    /// `bytes_in_cluster(idx)` long.
+    ///
+    /// Errors with `OutOfRange` for clusters that aren't data clusters on this
+    /// volume (the FAT12/16 root directory counts as one).
+    pub fn read_cluster(&mut self, s: &mut S, idx: ClusterIdx, buf: &mut [u8]) -> Result<(), FatError> {
+        let range = self.whole_cluster_range(idx, buf.len())?;
+        let start = range.start;
+        let sector_size = self.sector_size_in_bytes as usize;
+
+        self.cache.upgrade(s).get_range(range, |sector, data| {
+            let pos = ((*sector.inner() - *start.inner()) as usize) * sector_size;
+            buf[pos..(pos + sector_size)].copy_from_slice(data);
+        })
     }
 
-    pub fn next_free_cluster(&mut self, s: &mut S) -> Result<ClusterIdx, ()> {
-        let num_clusters = self.fat_table_size_in_sectors *
-            ((self.sector_size_in_bytes as u32) / (FAT_ENTRY_SIZE_IN_BYTES as u32));
+    /// Overwrites all of cluster `idx` with `data`, which has to be exactly
+    /// `bytes_in_cluster(idx)` long. See `read_cluster`.
+    pub fn write_cluster(&mut self, s: &mut S, idx: ClusterIdx, data: &[u8]) -> Result<(), FatError> {
+        let range = self.whole_cluster_range(idx, data.len())?;
+        let sector_size = self.sector_size_in_bytes as usize;
+
+        let mut cache = self.cache.upgrade(s);
+        for (sector, chunk) in SectorRange::new(range).zip(data.chunks(sector_size)) {
+            cache.try_get_mut(sector)?.copy_from_slice(chunk);
+        }
 
-        let ssib = self.sector_size_in_bytes;
-        let fss = self.fat_starting_sector;
-        let to_table_pos = move |idx| Self::cluster_to_table_pos_inner(ssib, fss, idx);
+        Ok(())
+    }
+
+    // The sectors in cluster `idx`, provided it's a data cluster and `len` is
+    // its size in bytes.
+    fn whole_cluster_range(&self, idx: ClusterIdx, len: usize) -> Result<Range<SectorIdx>, FatError> {
+        if !self.is_fixed_root_dir(idx) && (*idx.inner() < 2 || *idx.inner() >= self.num_clusters()) {
+            return Err(FatError::OutOfRange);
+        }
+
+        if len != self.bytes_in_cluster(idx) as usize {
+            return Err(FatError::InvalidArgument);
+        }
+
+        Ok(self.cluster_to_sector_range(idx))
+    }
+
+    pub fn get_boot_sect(&mut self, s: & mut S) -> Result<BootSector, FatError> {
+        Ok(BootSector::read(&*self.cache.upgrade(s).try_get(self.boot_sector)?))
+    }
+
+    /// The name of whatever formatted the volume (i.e. `mkfs.fat` or
+    /// `MSDOS5.0`), as it appears in the boot sector.
+    pub fn oem_name(&mut self, s: &mut S) -> Result<[u8; 8], FatError> {
+        Ok(self.get_boot_sect(s)?.oem_name)
+    }
+
+    /// `0xF8` for fixed disks, `0xF0` for most removable media.
+    pub fn media_descriptor(&mut self, s: &mut S) -> Result<u8, FatError> {
+        Ok(self.get_boot_sect(s)?.bpb.media_descriptor)
+    }
+
+    /// The volume id (serial number) from the boot sector; `None` if the boot
+    /// sector doesn't have the extended boot signature.
+    pub fn volume_id(&mut self, s: &mut S) -> Result<Option<u32>, FatError> {
+        Ok(BootSector::read_volume_id(
+            &*self.cache.upgrade(s).try_get(self.boot_sector)?,
+            self.fat_type,
+        ))
+    }
+
+    /// Number of entries that fit in the FAT; no cluster chain can be longer
+    /// than this.
+    pub fn num_fat_entries(&self) -> u32 {
+        self.fat_type.entries_in(
+            (self.fat_table_size_in_sectors as u64) * (self.sector_size_in_bytes as u64)
+        ) as u32
+    }
+
+    /// Number of FAT entries that refer to actual clusters (including the two
+    /// reserved entries at the start). FATs are often a little bigger than
+    /// they need to be; the entries past this aren't for anything.
+    pub fn num_clusters(&self) -> u32 {
+        core::cmp::min(self.num_fat_entries(), self.cluster_count.saturating_add(2))
+    }
+
+    /// Finds a free cluster and marks it as the end of a chain.
+    ///
+    /// Errors with `OutOfSpace` if the volume is full (after having looked at
+    /// every cluster once). Never hands out the two reserved clusters (0 and 1).
+    pub fn next_free_cluster(&mut self, s: &mut S) -> Result<ClusterIdx, FatError> {
+        let cluster = self.find_free_cluster(s)?.ok_or(FatError::OutOfSpace)?;
+
+        // Mark this cluster as the end of a chain:
+        let end_of_chain = self.fat_type.end_of_chain();
+        self.write_fat_entry(s, cluster, &end_of_chain)?;
+
+        self.next_known_free_cluster = self.cluster_after(cluster);
+
+        Ok(cluster)
+    }
+
+    /// Like `next_free_cluster` but follows `alloc_policy`; this is what new
+    /// files get their first cluster from.
+    pub fn allocate_first_cluster(&mut self, s: &mut S) -> Result<ClusterIdx, FatError> {
+        let alignment = match self.alloc_policy {
+            AllocPolicy::AlignedTo(a) if a > 1 => a as u64,
+            _ => return self.next_free_cluster(s),
+        };
+
+        for idx in 2..self.num_clusters() {
+            let cluster = ClusterIdx::new(idx);
+
+            // Checking the alignment is cheap; reading the FAT entry isn't.
+            let (sector, _) = self.cluster_to_sector(cluster, ClusterOffset::new(0));
+            if sector.inner() % alignment != 0 { continue; }
+
+            if self.read_fat_entry(s, cluster)?.classify(self.fat_type) == FatEntryKind::Free {
+                self.write_fat_entry(s, cluster, &self.fat_type.end_of_chain())?;
+                return Ok(cluster);
+            }
+        }
+
+        self.next_free_cluster(s)
+    }
+
+    /// Allocates `count` clusters as a single chain and returns the first one.
+    ///
+    /// The first run of `count` free clusters is used if there is one (going
+    /// through the FAT from the start); otherwise the chain is made from
+    /// whichever free clusters `next_free_cluster` turns up.
+    ///
+    /// Errors if `count` is 0 or there aren't `count` free clusters, in which
+    /// case nothing is allocated.
+    pub fn allocate_chain(&mut self, s: &mut S, count: u32) -> Result<ClusterIdx, FatError> {
+        if count == 0 {
+            return Err(FatError::InvalidArgument);
+        }
+
+        let mut run_start = 2;
+        let mut run_len = 0;
+        let mut run = None;
+
+        for idx in 2..self.num_clusters() {
+            if self.read_fat_entry(s, ClusterIdx::new(idx))?.classify(self.fat_type) == FatEntryKind::Free {
+                if run_len == 0 { run_start = idx; }
+                run_len += 1;
+
+                if run_len == count {
+                    run = Some(run_start);
+                    break;
+                }
+            } else {
+                run_len = 0;
+            }
+        }
+
+        if let Some(start) = run {
+            let end = start + count - 1;
+
+            for idx in start..end {
+                self.write_fat_entry(s, ClusterIdx::new(idx), &FatEntry::from(ClusterIdx::new(idx + 1)))?;
+            }
+            self.write_fat_entry(s, ClusterIdx::new(end), &self.fat_type.end_of_chain())?;
+
+            self.next_known_free_cluster = self.cluster_after(ClusterIdx::new(end));
+
+            return Ok(ClusterIdx::new(start));
+        }
+
+        // Check that there's enough room first so that we don't leave half a
+        // chain behind:
+        if self.free_cluster_count(s)? < count {
+            return Err(FatError::OutOfSpace);
+        }
+
+        let head = self.next_free_cluster(s)?;
+        let mut prev = head;
+        for _ in 1..count {
+            let next = self.next_free_cluster(s)?;
+            self.write_fat_entry(s, prev, &FatEntry::from(next))?;
+            prev = next;
+        }
+
+        Ok(head)
+    }
+
+    /// Marks every cluster in the chain starting at `start` as free.
+    fn free_chain(&mut self, s: &mut S, start: ClusterIdx) -> Result<(), FatError> {
+        let mut cluster = start;
+
+        // Bounded, in case the chain loops back on itself.
+        for _ in 0..self.num_fat_entries() {
+            let entry = self.read_fat_entry(s, cluster)?;
+            self.write_fat_entry(s, cluster, &FatEntry::FREE)?;
+
+            match entry.classify(self.fat_type) {
+                FatEntryKind::Next(next) => cluster = next,
+                _ => return Ok(()),
+            }
+        }
+
+        Err(FatError::Corrupt)
+    }
+
+    /// Moves the file at `path` to a contiguous run of clusters (see
+    /// `allocate_chain`) so that it can be read in one go. Files that are
+    /// already contiguous are left alone.
+    ///
+    /// The file's data is copied over and its directory entry updated before
+    /// its old clusters are freed. Errors (without changing anything) if
+    /// there isn't a long enough run of free clusters.
+    ///
+    /// Nothing else should be using the file while this runs.
+    pub fn defragment_file(&mut self, s: &mut S, path: &[u8]) -> Result<(), FatError> {
+        let (loc, mut entry) = self.lookup_path(s, path)?;
+        if !entry.attributes.is_file() {
+            return Err(FatError::IsADirectory);
+        }
+
+        let old = entry.cluster_idx();
+        let info = FatEntryTracer::starting_at(self, s, old).analyze()?;
+        if info.is_contiguous {
+            return Ok(());
+        }
+
+        let new = self.allocate_chain(s, info.cluster_count)?;
+        let new_info = FatEntryTracer::starting_at(self, s, new).analyze()?;
+        if !new_info.is_contiguous {
+            self.free_chain(s, new)?;
+            return Err(FatError::OutOfSpace);
+        }
+
+        let mut from = old;
+        for i in 0..info.cluster_count {
+            self.copy_cluster(s, from, ClusterIdx::new(new.inner() + i))?;
+            from = self.read_fat_entry(s, from)?.next;
+        }
+
+        entry.set_cluster_idx(new);
+        self.write_dir_entry(s, loc, &entry)?;
+
+        self.free_chain(s, old)
+    }
+
+    /// Copies the contents of cluster `from` to cluster `to`, a sector at a
+    /// time through the cache.
+    ///
+    /// Like `read_cluster` and `write_cluster` this errors with `OutOfRange`
+    /// if either cluster isn't a data cluster.
+    fn copy_cluster(&mut self, s: &mut S, from: ClusterIdx, to: ClusterIdx) -> Result<(), FatError> {
+        let from = self.whole_cluster_range(from, self.bytes_in_cluster(from) as usize)?;
+        let to = self.whole_cluster_range(to, self.bytes_in_cluster(to) as usize)?;
+
+        // Only the FAT12/16 root directory is a different size.
+        if from.end.inner() - from.start.inner() != to.end.inner() - to.start.inner() {
+            return Err(FatError::InvalidArgument);
+        }
 
         let mut cache = self.cache.upgrade(s);
+        for (src, dest) in SectorRange::new(from).zip(SectorRange::new(to)) {
+            let sector = cache.try_get(src)?.clone();
+            *cache.try_get_mut(dest)? = sector;
+        }
 
-        // Rather than attempt to free up space or detect when we're at full
-        // capacity or do _anything_ intelligent, this will simply spin if we're
-        // full.
-        loop {
-            let (sector, offset) = to_table_pos(self.next_known_free_cluster);
+        Ok(())
+    }
+
+    /// Frees every cluster in the chain starting at `first` except for `first`
+    /// itself, which becomes the end of the chain.
+    fn truncate_chain(&mut self, s: &mut S, first: ClusterIdx) -> Result<(), FatError> {
+        let entry = self.read_fat_entry(s, first)?;
+        self.write_fat_entry(s, first, &self.fat_type.end_of_chain())?;
+
+        match entry.classify(self.fat_type) {
+            FatEntryKind::Next(next) => self.free_chain(s, next),
+            _ => Ok(()),
+        }
+    }
+
+    /// Marks a free cluster as bad so that it's never allocated (i.e. after a
+    /// write to it failed).
+    ///
+    /// Errors if the cluster is in use; its file has to be moved off of it
+    /// first.
+    pub fn mark_bad_cluster(&mut self, s: &mut S, idx: ClusterIdx) -> Result<(), FatError> {
+        if *idx.inner() < 2 {
+            return Err(FatError::OutOfRange);
+        }
 
-            let next = ClusterIdx::new(u32::from_le_bytes(
-                cache.get(sector)[offset as usize .. (offset + 4) as usize].try_into().unwrap(),
-            ));
+        match self.read_fat_entry(s, idx)?.classify(self.fat_type) {
+            FatEntryKind::Free | FatEntryKind::Bad => {
+                self.write_fat_entry(s, idx, &self.fat_type.bad_cluster())
+            }
+            _ => Err(FatError::InvalidArgument),
+        }
+    }
+
+    /// Counts the free clusters by going through the whole FAT.
+    pub fn free_cluster_count(&mut self, s: &mut S) -> Result<u32, FatError> {
+        let mut free = 0;
 
-            if table::FatEntry::from(next) == table::FatEntry::FREE {
-                // Mark this cluster as the end of a chain:
-                let bytes = table::FatEntry::END_OF_CHAIN.next.to_le_bytes();
+        for idx in 2..self.num_clusters() {
+            if self.read_fat_entry(s, ClusterIdx::new(idx))?.classify(self.fat_type) == FatEntryKind::Free {
+                free += 1;
+            }
+        }
+
+        Ok(free)
+    }
 
-                cache.get_mut(sector)[(offset as usize)..(offset as usize + (FAT_ENTRY_SIZE_IN_BYTES as usize))]
-                    .copy_from_slice(&bytes);
+    /// Every run of consecutive free clusters as `(first cluster, length)`,
+    /// lowest clusters first; allocators can use this to pick the run that
+    /// fits best instead of scanning the FAT themselves.
+    pub fn free_runs<'f, 's>(&'f mut self, s: &'s mut S) -> FreeRuns<'f, 's, S, CS, Ev> {
+        FreeRuns::new(self, s)
+    }
+
+    /// The cluster after `idx`, wrapping around to cluster 2.
+    fn cluster_after(&self, idx: ClusterIdx) -> ClusterIdx {
+        let next = idx.inner() + 1;
+
+        ClusterIdx::new(if next >= self.num_clusters() { 2 } else { next })
+    }
+
+    /// Looks for a free cluster starting at `next_known_free_cluster` and
+    /// moves `next_known_free_cluster` up to it. `None` if there aren't any
+    /// free clusters.
+    fn find_free_cluster(&mut self, s: &mut S) -> Result<Option<ClusterIdx>, FatError> {
+        let num_clusters = self.num_clusters();
+        if num_clusters <= 2 { return Ok(None) }
+
+        if !(2..num_clusters).contains(self.next_known_free_cluster.inner()) {
+            self.next_known_free_cluster = ClusterIdx::new(2);
+        }
 
-                let current_cluster = self.next_known_free_cluster;
-                self.next_known_free_cluster =
-                    ClusterIdx::new((self.next_known_free_cluster.inner() + 1) % num_clusters);
+        for _ in 2..num_clusters {
+            let entry = self.read_fat_entry(s, self.next_known_free_cluster)?;
 
-                break Ok(current_cluster);
+            // Bad (and reserved) clusters are never handed out.
+            if entry.classify(self.fat_type) == FatEntryKind::Free {
+                return Ok(Some(self.next_known_free_cluster));
             }
 
             // If that didn't work, onto the next!
-            self.next_known_free_cluster = ClusterIdx::new((self.next_known_free_cluster.inner() + 1) % num_clusters);
+            self.next_known_free_cluster = self.cluster_after(self.next_known_free_cluster);
         }
+
+        // No space left.
+        Ok(None)
     }
 
-    pub fn lookup_path(&mut self, s: &mut S, path: &[u8]) -> Result<((ClusterIdx, u32), DirEntry), ()> {
-        let mut dir_cluster = self.root_dir_cluster_num;
-        let mut dir_entry = Some(((dir_cluster, 0), DirEntry::empty()));
-        dir_entry.as_mut().unwrap().1.set_cluster_idx(dir_cluster);
+    /// Finds the entry at `path`, which is made up of 8.3 names separated by
+    /// `/`s. Leading, trailing, and repeated `/`s are ignored except that a
+    /// trailing `/` requires the entry to be a directory. `.` and `..`
+    /// components are followed (`..` in the root directory is the root
+    /// directory).
+    ///
+    /// Returns the location of the entry (as `DirIter` does) along with the
+    /// entry itself. The root directory doesn't have an entry; for `/` a blank
+    /// directory entry pointing at the root directory is returned, at a
+    /// location whose offset is `ROOT_DIR_ENTRY_OFFSET`.
+    pub fn lookup_path<'p>(
+        &mut self,
+        s: &mut S,
+        path: &'p [u8],
+    ) -> Result<((ClusterIdx, u32), DirEntry), LookupError<'p>> {
+        if path.is_empty() {
+            return Err(LookupError::Empty);
+        }
+
+        let root = self.root_dir_cluster_num;
+        let mut dir_entry = ((root, ROOT_DIR_ENTRY_OFFSET), DirEntry::new_dir(FileName::default(), root));
 
         for path_segment in path.split(|c| *c == '/' as u8) {
-            if path_segment.len() == 0 { continue; }
-
-            let mut p = path_segment.split(|c| *c == '.' as u8);
-            let name = p.next().unwrap();
-            let ext = p.next();
-
-            dir_entry = None;
-
-            for (m, dir) in DirIter::from_cluster(dir_cluster, self, s) {
-                if name.iter().take(8).enumerate().all(|(idx, c)| {
-                    dir.file_name.0[idx] == c.to_ascii_uppercase()
-                }) && if let Some(ext) = ext {
-                    ext.iter().take(3).enumerate().all(|(idx, c)| {
-                        dir.file_ext.0[idx] == c.to_ascii_uppercase()
-                    })
-                } else { true } {
-                    // if the file name matches, this is now dir_cluster:
-                    dir_cluster = dir.cluster_idx();
-                    dir_entry = Some((m, dir));
+            if path_segment.len() == 0 || path_segment == b"." { continue; }
 
-                    break;
+            if path_segment == b".." {
+                if !dir_entry.1.attributes.is_dir() {
+                    return Err(LookupError::NotADirectory);
                 }
+
+                // The root directory is its own parent.
+                if dir_entry.1.cluster_idx() == root {
+                    continue;
+                }
+
+                let dot_dot = DirIter::from_cluster(dir_entry.1.cluster_idx(), self, s)
+                    .with_dot_entries(true)
+                    .find(|(_, dir)| dir.is_dotdot())
+                    .ok_or(LookupError::NotFound(path_segment))?;
+
+                // `..` is 0 when the parent is the root directory.
+                dir_entry = if dot_dot.1.cluster_idx() == ClusterIdx::new(0) {
+                    ((root, ROOT_DIR_ENTRY_OFFSET), DirEntry::new_dir(FileName::default(), root))
+                } else {
+                    dot_dot
+                };
+
+                continue;
             }
 
-            if dir_entry.is_none() {
-                return Err(());
+            // The extension is whatever follows the last `.`.
+            let (name, ext) = match path_segment.iter().rposition(|c| *c == '.' as u8) {
+                Some(dot) => (&path_segment[..dot], Some(&path_segment[(dot + 1)..])),
+                None => (path_segment, None),
+            };
+
+            let mut iter = dir_entry.1
+                .into_dir_iter(self, s)
+                .ok_or(LookupError::NotADirectory)?;
+
+            // Anything that doesn't fit in 8.3 can't be in the directory.
+            let ext = ext.unwrap_or(b"");
+            if name.len() > 8 || ext.len() > 3 {
+                return Err(LookupError::NotFound(path_segment));
             }
+
+            let mut file_name = FileName(*b"        ");
+            let mut file_ext = FileExt(*b"   ");
+            file_name.0[..name.len()].copy_from_slice(name);
+            file_ext.0[..ext.len()].copy_from_slice(ext);
+
+            // Names are supposed to be stored upper-cased but we don't count
+            // on it (`find_entry` ignores case).
+            dir_entry = iter
+                .find_entry(&file_name, &file_ext)
+                .ok_or(LookupError::NotFound(path_segment))?;
+        }
+
+        if path.ends_with(b"/") && !dir_entry.1.attributes.is_dir() {
+            return Err(LookupError::NotADirectory);
         }
 
-        if let Some(dir_entry) = dir_entry {
-            Ok(dir_entry)
+        Ok(dir_entry)
+    }
+
+    /// Whether there's anything at `path`. Lookups that fail for any reason
+    /// count as nothing being there.
+    pub fn exists(&mut self, s: &mut S, path: &[u8]) -> bool {
+        self.lookup_path(s, path).is_ok()
+    }
+
+    /// Looks up `path` (see `lookup_path`) and decodes its entry.
+    pub fn metadata<'p>(&mut self, s: &mut S, path: &'p [u8]) -> Result<Metadata, LookupError<'p>> {
+        self.lookup_path(s, path).map(|(_, entry)| Metadata::from_entry(&entry))
+    }
+
+    /// Finds the volume label entry in the root directory.
+    fn find_volume_label_entry(&mut self, s: &mut S) -> Result<Option<((ClusterIdx, u32), DirEntry)>, FatError> {
+        let mut iter = DirIter::from_cluster(self.root_dir_cluster_num, self, s);
+        let found = iter.find(|(_, entry)| entry.state() == State::Exists && entry.attributes.is_volume_id());
+
+        match (found, iter.error()) {
+            (None, Some(err)) => Err(err.into()),
+            (found, _) => Ok(found),
+        }
+    }
+
+    /// The volume's label, space padded to 11 bytes.
+    ///
+    /// The volume label entry in the root directory wins; if there isn't one
+    /// the label in the boot sector is used. `None` if neither has a label
+    /// (`NO NAME` in the boot sector counts as no label).
+    pub fn volume_label_bytes(&mut self, s: &mut S) -> Result<Option<[u8; 11]>, FatError> {
+        if let Some((_, entry)) = self.find_volume_label_entry(s)? {
+            return Ok(Some(entry.label()));
+        }
+
+        let label = BootSector::read_volume_label(
+            &*self.cache.upgrade(s).try_get(self.boot_sector)?,
+            self.fat_type,
+        );
+
+        Ok(label.filter(|label| {
+            *label != *b"NO NAME    " && !label.iter().all(|c| *c == b' ' || *c == 0)
+        }))
+    }
+
+    /// Sets the volume's label in both the root directory (adding a volume
+    /// label entry if there isn't one) and the boot sector.
+    ///
+    /// `label` is upper-cased; errors if it's empty, longer than 11 bytes, or
+    /// has characters that aren't allowed in short names.
+    pub fn set_volume_label(&mut self, s: &mut S, label: &str) -> Result<(), FatError> {
+        let label = dir::volume_label(label.as_bytes())?;
+
+        match self.find_volume_label_entry(s)? {
+            Some(((cluster, offset), _)) => {
+                let f = FatEntry::from(cluster);
+                f.upgrade(self, s).write(ClusterOffset::new(offset), label.iter().cloned())?;
+            }
+            None => {
+                let root = self.root_dir_cluster_num;
+                self.add_dir_entry(s, root, DirEntry::new_volume_label(label))?;
+            }
+        }
+
+        BootSector::write_volume_label(
+            self.cache.upgrade(s).try_get_mut(self.boot_sector)?,
+            self.fat_type,
+            &label,
+        );
+
+        Ok(())
+    }
+
+    /// Visits every entry under the directory starting at `start`, depth first.
+    /// `visit` gets each entry's path (relative to `start` but with a leading
+    /// `/`) and the entry itself; directories are visited before their
+    /// contents.
+    ///
+    /// `.` and `..` entries, volume labels, and deleted entries are skipped.
+    /// Errors if a path gets longer than 256 bytes.
+    pub fn walk(&mut self, s: &mut S, start: ClusterIdx, mut visit: impl FnMut(&[u8], &DirEntry)) -> Result<(), FatError> {
+        let mut path = [0u8; WALK_MAX_PATH_LEN];
+        self.walk_inner(s, start, &mut path, 0, &mut visit)
+    }
+
+    // Rather than nesting `DirIter`s (which all want `self` and `s`) we make a
+    // new `DirIter` for each entry and only hold on to its position.
+    fn walk_inner(
+        &mut self,
+        s: &mut S,
+        dir: ClusterIdx,
+        path: &mut [u8; WALK_MAX_PATH_LEN],
+        len: usize,
+        visit: &mut impl FnMut(&[u8], &DirEntry),
+    ) -> Result<(), FatError> {
+        let mut pos = Some((dir, 0));
+
+        while let Some((cluster, offset)) = pos {
+            let mut iter = DirIter::from_cluster(cluster, self, s);
+            iter.current_offset = Some(offset);
+
+            let next = iter.next();
+            pos = iter.current_offset.map(|o| (iter.current_cluster, o));
+
+            let entry = match next {
+                Some((_, entry)) => entry,
+                None => match iter.error() {
+                    Some(err) => return Err(err.into()),
+                    None => break,
+                },
+            };
+
+            if entry.state() != State::Exists || entry.attributes.is_volume_id() {
+                continue;
+            }
+
+            if len >= path.len() { return Err(FatError::OutOfSpace) }
+            path[len] = '/' as u8;
+            let new_len = len + 1 + entry.write_short_name(&mut path[(len + 1)..])?;
+
+            visit(&path[..new_len], &entry);
+
+            if entry.attributes.is_dir() {
+                self.walk_inner(s, entry.cluster_idx(), path, new_len, visit)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copies the entries in the directory at `path` into `out` and returns
+    /// how many were copied.
+    ///
+    /// As with `walk`, `.` and `..` entries, volume labels, and deleted entries
+    /// are skipped (long file name entries never show up). Errors with
+    /// `OutOfSpace` if `out` can't hold all the entries; the ones that fit are
+    /// still copied.
+    pub fn list_dir(&mut self, s: &mut S, path: &[u8], out: &mut [DirEntry]) -> Result<usize, FatError> {
+        let mut count = 0;
+
+        self.visit_dir(s, path, |entry| {
+            let slot = out.get_mut(count).ok_or(FatError::OutOfSpace)?;
+            *slot = entry;
+            count += 1;
+
+            Ok(())
+        })?;
+
+        Ok(count)
+    }
+
+    // Calls `visit` with each live entry in the directory at `path`, stopping
+    // at the first error.
+    fn visit_dir(
+        &mut self,
+        s: &mut S,
+        path: &[u8],
+        mut visit: impl FnMut(DirEntry) -> Result<(), FatError>,
+    ) -> Result<(), FatError> {
+        let (_, dir) = self.lookup_path(s, path)?;
+        let mut iter = dir.into_dir_iter(self, s).ok_or(FatError::NotADirectory)?;
+
+        for (_, entry) in iter.by_ref() {
+            if entry.state() != State::Exists || entry.attributes.is_volume_id() {
+                continue;
+            }
+
+            visit(entry)?;
+        }
+
+        match iter.error() {
+            Some(err) => Err(err.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Creates the directory at `path`. Its parent has to exist already.
+    pub fn create_dir(&mut self, s: &mut S, path: &[u8]) -> Result<(), FatError> {
+        let (parent, name, ext) = self.prepare_new_entry(s, path)?;
+
+        let cluster = self.next_free_cluster(s)?;
+        self.clear_cluster(s, cluster)?;
+
+        // Every directory but the root starts with `.` and `..`; `..` is 0 when
+        // the parent is the root directory.
+        let parent_ref = if parent == self.root_dir_cluster_num {
+            ClusterIdx::new(0)
+        } else {
+            parent
+        };
+
+        let mut dot = DirEntry::new_dir(FileName(*b".       "), cluster);
+        dot.file_ext = FileExt(*b"   ");
+        let mut dot_dot = DirEntry::new_dir(FileName(*b"..      "), parent_ref);
+        dot_dot.file_ext = FileExt(*b"   ");
+
+        let mut buf = [0u8; 32];
+        for (offset, entry) in [(0, dot), (32, dot_dot)].iter() {
+            entry.into_arr(&mut buf);
+            FatEntry::from(cluster).upgrade(self, s).write(ClusterOffset::new(*offset), buf.iter().cloned())?;
+        }
+
+        let mut entry = DirEntry::new_dir(name, cluster);
+        entry.file_ext = ext;
+
+        // Don't leak the cluster if there's no room in the parent:
+        if let Err(err) = self.add_dir_entry(s, parent, entry) {
+            self.free_chain(s, cluster)?;
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    /// Looks up the file at `path`; read and write it with `File::upgrade`.
+    ///
+    /// Volume label entries aren't files (or directories) and can't be
+    /// opened; they're reported as `NotFound`.
+    pub fn open<'p>(&mut self, s: &mut S, path: &'p [u8]) -> Result<File, OpenError<'p>> {
+        let (_, entry) = self.lookup_path(s, path).map_err(|e| match e {
+            LookupError::NotFound(component) => OpenError::NotFound(component),
+            LookupError::NotADirectory => OpenError::NotADirectory,
+            LookupError::Empty => OpenError::Empty,
+        })?;
+
+        if entry.attributes.is_dir() {
+            return Err(OpenError::IsADirectory);
+        }
+
+        entry.into_file().map_err(|_| {
+            let last = path.rsplit(|c| *c == b'/').next().unwrap_or(path);
+            OpenError::NotFound(last)
+        })
+    }
+
+    /// Creates an empty file at `path` with one cluster allocated to it (see
+    /// `alloc_policy`). Its parent directory has to exist already.
+    pub fn create_file(&mut self, s: &mut S, path: &[u8]) -> Result<File, FatError> {
+        let (parent, name, ext) = self.prepare_new_entry(s, path)?;
+
+        let cluster = self.allocate_first_cluster(s)?;
+        let entry = DirEntry::new_file(name, ext, cluster);
+
+        // Like `create_dir`:
+        if let Err(err) = self.add_dir_entry(s, parent, entry.clone()) {
+            self.free_chain(s, cluster)?;
+            return Err(err);
+        }
+
+        Ok(File::new(entry))
+    }
+
+    /// Like `create_file` but if there's already a file at `path` it's
+    /// truncated instead (i.e. `O_CREAT | O_TRUNC`).
+    ///
+    /// A truncated file keeps its directory entry and first cluster (so
+    /// existing `File`s for it still point at the right place, unless it had
+    /// no clusters to begin with in which case it gets one); the rest of
+    /// its clusters are freed and its size and timestamps are zeroed, as for a
+    /// newly created file. Errors if `path` is a directory.
+    pub fn create_file_truncate(&mut self, s: &mut S, path: &[u8]) -> Result<File, FatError> {
+        let (loc, existing) = match self.lookup_path(s, path) {
+            Ok(found) => found,
+            Err(_) => return self.create_file(s, path),
+        };
+
+        if !existing.attributes.is_file() {
+            return Err(FatError::IsADirectory);
+        }
+
+        // Empty files made elsewhere can be without clusters; they get one,
+        // as if they'd just been created.
+        let had_cluster = *existing.cluster_idx().inner() >= 2;
+        let cluster = if had_cluster {
+            self.truncate_chain(s, existing.cluster_idx())?;
+            existing.cluster_idx()
         } else {
-            Err(())
+            self.allocate_first_cluster(s)?
+        };
+
+        let mut entry = DirEntry::new_file(existing.file_name, existing.file_ext, cluster);
+        entry.attributes = existing.attributes;
+        if let Err(err) = self.write_dir_entry(s, loc, &entry) {
+            if !had_cluster { self.free_chain(s, cluster)?; }
+            return Err(err);
+        }
+
+        Ok(File::new(entry))
+    }
+
+    /// Copies the file at `from` to a new file at `to` and returns the number
+    /// of bytes copied.
+    ///
+    /// The copy is done a cluster at a time; the destination gets exactly as
+    /// many clusters as the source's size calls for. Errors with
+    /// `AlreadyExists` if there's already something at `to` (see
+    /// `copy_file_overwrite`).
+    pub fn copy_file(&mut self, s: &mut S, from: &[u8], to: &[u8]) -> Result<u32, FatError> {
+        self.copy_file_inner(s, from, to, false)
+    }
+
+    /// Like `copy_file` but if `to` is an existing file, its contents are
+    /// replaced. Its directory entry and first cluster are reused.
+    pub fn copy_file_overwrite(&mut self, s: &mut S, from: &[u8], to: &[u8]) -> Result<u32, FatError> {
+        self.copy_file_inner(s, from, to, true)
+    }
+
+    fn copy_file_inner(&mut self, s: &mut S, from: &[u8], to: &[u8], overwrite: bool) -> Result<u32, FatError> {
+        let (_, src) = self.lookup_path(s, from)?;
+        if !src.attributes.is_file() {
+            return Err(FatError::IsADirectory);
+        }
+
+        let (loc, mut dest) = match self.lookup_path(s, to) {
+            Ok((loc, dest)) if overwrite && dest.attributes.is_file() => {
+                // Copying a file over itself would free it out from under us.
+                // (Empty files without clusters all have cluster 0.)
+                if dest.cluster_idx() == src.cluster_idx() && *dest.cluster_idx().inner() >= 2 {
+                    return Err(FatError::InvalidArgument);
+                }
+
+                let mut dest = dest;
+                if *dest.cluster_idx().inner() >= 2 {
+                    self.truncate_chain(s, dest.cluster_idx())?;
+                } else {
+                    self.ensure_first_cluster(s, loc, &mut dest)?;
+                }
+                (loc, dest)
+            }
+            Ok(_) if overwrite => return Err(FatError::IsADirectory),
+            Ok(_) => return Err(FatError::AlreadyExists),
+            Err(_) => {
+                self.create_file(s, to)?;
+                self.lookup_path(s, to)?
+            }
+        };
+
+        let cluster_size = self.bytes_in_a_cluster();
+        // Files can be up to 4 GiB - 1 so rounding up mustn't overflow.
+        let clusters = src.file_size / cluster_size + (src.file_size % cluster_size != 0) as u32;
+
+        let mut from = src.cluster_idx();
+        let mut to = dest.cluster_idx();
+        for i in 0..clusters {
+            if i != 0 {
+                from = match self.read_fat_entry(s, from)?.classify(self.fat_type) {
+                    FatEntryKind::Next(next) => next,
+                    // The source's chain is shorter than its size says.
+                    _ => return Err(FatError::Corrupt),
+                };
+
+                let next = self.next_free_cluster(s)?;
+                self.write_fat_entry(s, to, &FatEntry::from(next))?;
+                to = next;
+            }
+
+            self.copy_cluster(s, from, to)?;
+        }
+
+        dest.file_size = src.file_size;
+        self.write_dir_entry(s, loc, &dest)?;
+
+        Ok(src.file_size)
+    }
+
+    /// Like `create_dir` but also creates any missing parents. Directories
+    /// that already exist are left alone.
+    pub fn create_dir_all(&mut self, s: &mut S, path: &[u8]) -> Result<(), FatError> {
+        let mut end = 0;
+
+        for component in path.split(|c| *c == '/' as u8) {
+            end += component.len() + 1;
+            if component.len() == 0 { continue; }
+
+            let prefix = &path[..(end - 1)];
+
+            match self.lookup_path(s, prefix) {
+                Ok((_, entry)) if entry.attributes.is_dir() => continue,
+                Ok(_) => return Err(FatError::NotADirectory),
+                Err(LookupError::NotFound(_)) => self.create_dir(s, prefix)?,
+                Err(e) => return Err(e.into()),
+            }
         }
+
+        Ok(())
     }
 
-    fn range_chk(&self, sector: SectorIdx, offset: u16, len: usize) -> Result<(), ()> {
+    /// Reads up to `buf.len()` bytes starting `offset` bytes into the file at
+    /// `path` and returns how many bytes were read.
+    ///
+    /// Reads that go past the end of the file are cut short. Reading at the
+    /// end of the file succeeds and reads nothing; reading past it fails.
+    pub fn read_file(&mut self, s: &mut S, path: &[u8], offset: u32, buf: &mut [u8]) -> Result<usize, FatError> {
+        let file = self.open(s, path)?;
+
+        file.upgrade(self, s).read(offset, buf)
+    }
+
+    /// Writes `data` starting `offset` bytes into the file at `path`, growing
+    /// the file (and updating the size in its directory entry) if the write
+    /// goes past its end.
+    ///
+    /// Writing past the end of the file fills the gap between the old end of
+    /// the file and `offset` with zeros.
+    pub fn write_file(&mut self, s: &mut S, path: &[u8], offset: u32, data: &[u8]) -> Result<(), FatError> {
+        let (loc, mut entry) = self.lookup_path(s, path)?;
+
+        let end = data.len().try_into().ok()
+            .and_then(|len| offset.checked_add(len))
+            .ok_or(FatError::OutOfRange)?;
+        if !entry.attributes.is_file() {
+            return Err(if entry.attributes.is_dir() { FatError::IsADirectory } else { FatError::NotFound });
+        }
+
+        if end > 0 {
+            self.ensure_first_cluster(s, loc, &mut entry)?;
+        }
+        let file = File::new(entry.clone());
+
+        // Clusters we grow the file into aren't cleared so we zero the gap.
+        if offset > entry.file_size {
+            file.upgrade(self, s).write_zeros_at(entry.file_size, offset - entry.file_size)?;
+        }
+
+        file.upgrade(self, s).write_at(offset, data)?;
+
+        if end > entry.file_size {
+            entry.file_size = end;
+            self.write_dir_entry(s, loc, &entry)?;
+        }
+
+        Ok(())
+    }
+
+    /// Gives the file with directory entry `entry` (at `loc`) a first cluster
+    /// if it doesn't have one yet; empty files made elsewhere can have cluster
+    /// 0, which doesn't map to anywhere the file can be written.
+    fn ensure_first_cluster(&mut self, s: &mut S, loc: (ClusterIdx, u32), entry: &mut DirEntry) -> Result<(), FatError> {
+        if *entry.cluster_idx().inner() >= 2 {
+            return Ok(());
+        }
+
+        // Only empty files can get away without any clusters.
+        if entry.file_size != 0 {
+            return Err(FatError::Corrupt);
+        }
+
+        let cluster = self.allocate_first_cluster(s)?;
+        entry.set_cluster_idx(cluster);
+
+        if let Err(err) = self.write_dir_entry(s, loc, entry) {
+            self.free_chain(s, cluster)?;
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    /// Renames or moves the file or directory at `from` to `to`. The entry's
+    /// cluster chain, size, and timestamps are kept as is.
+    ///
+    /// Errors if `to`'s parent doesn't exist or if something is already at
+    /// `to`. `from` can't have `.` or `..` components (they'd name the root
+    /// directory or a `..` entry rather than something that can be moved) and
+    /// directories can't be moved into themselves.
+    pub fn rename(&mut self, s: &mut S, from: &[u8], to: &[u8]) -> Result<(), FatError> {
+        // Neither the root directory nor `.` and `..` entries can be moved.
+        if from.split(|c| *c == '/' as u8).any(|c| c == b"." || c == b"..") ||
+                from.iter().all(|c| *c == '/' as u8) {
+            return Err(FatError::InvalidArgument);
+        }
+
+        let (loc, mut entry) = self.lookup_path(s, from)?;
+        let (from_parent, _, _) = Self::split_parent(from).ok_or(FatError::InvalidArgument)?;
+        let (_, from_dir) = self.lookup_path(s, from_parent)?;
+        let (parent, name, ext) = self.prepare_new_entry(s, to)?;
+
+        // A directory moved somewhere under itself would be cut off from the
+        // rest of the tree.
+        if entry.attributes.is_dir() && self.is_within(s, parent, entry.cluster_idx())? {
+            return Err(FatError::InvalidArgument);
+        }
+
+        entry.file_name = name;
+        entry.file_ext = ext;
+
+        // Add the new entry before removing the old one so that a failure
+        // can't lose the file.
+        self.add_dir_entry(s, parent, entry.clone())?;
+        self.remove_dir_entry(s, from_dir.cluster_idx(), loc)?;
+
+        // Directories that move need their `..` updated.
+        if entry.attributes.is_dir() {
+            let parent_ref = if parent == self.root_dir_cluster_num {
+                ClusterIdx::new(0)
+            } else {
+                parent
+            };
+
+            let mut buf = [0u8; 32];
+            FatEntry::from(entry.cluster_idx()).upgrade(self, s).read(ClusterOffset::new(32), &mut buf)?;
+            let mut dot_dot = DirEntry::from_arr(buf);
+
+            if dot_dot.file_name == FileName(*b"..      ") {
+                dot_dot.set_cluster_idx(parent_ref);
+                self.write_dir_entry(s, (entry.cluster_idx(), 32), &dot_dot)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether the directory at `dir` is `ancestor` or somewhere under it,
+    /// going by the `..` entries.
+    fn is_within(&mut self, s: &mut S, mut dir: ClusterIdx, ancestor: ClusterIdx) -> Result<bool, FatError> {
+        let root = self.root_dir_cluster_num;
+
+        // Bounded, in case the `..` entries loop.
+        for _ in 0..self.num_clusters() {
+            if dir == ancestor {
+                return Ok(true);
+            }
+            if dir == root {
+                return Ok(false);
+            }
+
+            let (_, dot_dot) = DirIter::from_cluster(dir, self, s)
+                .with_dot_entries(true)
+                .find(|(_, entry)| entry.is_dotdot())
+                .ok_or(FatError::Corrupt)?;
+
+            // `..` is 0 when the parent is the root directory.
+            dir = match dot_dot.cluster_idx() {
+                c if c == ClusterIdx::new(0) => root,
+                c => c,
+            };
+        }
+
+        Err(FatError::Corrupt)
+    }
+
+    /// Writes `entry` over the directory entry at `loc` (a location as
+    /// returned by `lookup_path` or `DirIter`). This is how changes to an
+    /// entry (its size, timestamps, etc.) are persisted.
+    ///
+    /// The root directory doesn't have an entry so `lookup_path`'s location
+    /// for `/` can't be written to; this errors with `InvalidArgument`.
+    pub fn write_dir_entry(&mut self, s: &mut S, (cluster, offset): (ClusterIdx, u32), entry: &DirEntry) -> Result<(), FatError> {
+        if offset == ROOT_DIR_ENTRY_OFFSET {
+            return Err(FatError::InvalidArgument);
+        }
+
+        let mut buf = [0u8; 32];
+        entry.into_arr(&mut buf);
+
+        FatEntry::from(cluster).upgrade(self, s).write(ClusterOffset::new(offset), buf.iter().cloned())
+    }
+
+    /// Marks the entry at `loc` in the directory starting at `dir` as deleted,
+    /// along with any long file name entries right before it (which may be
+    /// in the directory's previous cluster).
+    fn remove_dir_entry(&mut self, s: &mut S, dir: ClusterIdx, (cluster, offset): (ClusterIdx, u32)) -> Result<(), FatError> {
+        if offset == ROOT_DIR_ENTRY_OFFSET {
+            return Err(FatError::InvalidArgument);
+        }
+
+        let mut cluster = cluster;
+        let mut offset = offset;
+
+        loop {
+            FatEntry::from(cluster).upgrade(self, s).write(ClusterOffset::new(offset), core::iter::once(0xE5))?;
+
+            if offset == 0 {
+                match self.previous_cluster(s, dir, cluster)? {
+                    Some(prev) => {
+                        cluster = prev;
+                        offset = self.bytes_in_cluster(prev);
+                    }
+                    None => break,
+                }
+            }
+            offset -= 32;
+
+            let mut buf = [0u8; 32];
+            FatEntry::from(cluster).upgrade(self, s).read(ClusterOffset::new(offset), &mut buf)?;
+
+            let prev = DirEntry::from_arr(buf);
+            if prev.attributes != AttributeSet::LFN || prev.state() != State::Exists {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The cluster before `cluster` in the chain starting at `first`, or
+    /// `None` if `cluster` is `first`.
+    fn previous_cluster(&mut self, s: &mut S, first: ClusterIdx, cluster: ClusterIdx) -> Result<Option<ClusterIdx>, FatError> {
+        if cluster == first || self.is_fixed_root_dir(first) {
+            return Ok(None);
+        }
+
+        // Bounded, in case the chain loops back on itself.
+        let mut prev = first;
+        for _ in 0..self.num_clusters() {
+            match self.read_fat_entry(s, prev)?.classify(self.fat_type) {
+                FatEntryKind::Next(next) if next == cluster => return Ok(Some(prev)),
+                FatEntryKind::Next(next) => prev = next,
+                _ => break,
+            }
+        }
+
+        Err(FatError::Corrupt)
+    }
+
+    /// Splits `path` into its parent's path, its last component, and the
+    /// path without any trailing `/`s. `None` if `path` is all `/`s.
+    fn split_parent(path: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+        let last = path.iter().rposition(|c| *c != '/' as u8)?;
+        let trimmed = &path[..=last];
+
+        Some(match trimmed.iter().rposition(|c| *c == '/' as u8) {
+            Some(slash) => (&trimmed[..=slash], &trimmed[(slash + 1)..], trimmed),
+            None => (&b"/"[..], trimmed, trimmed),
+        })
+    }
+
+    /// Splits `path` into the cluster of its parent directory and the 8.3 name
+    /// of its last component.
+    ///
+    /// Errors if the parent doesn't exist or if something is already at `path`.
+    fn prepare_new_entry(&mut self, s: &mut S, path: &[u8]) -> Result<(ClusterIdx, FileName, FileExt), FatError> {
+        let (parent, component, trimmed) = Self::split_parent(path).ok_or(FatError::InvalidArgument)?;
+
+        let (name, ext) = dir::short_name(component)?;
+
+        let (_, parent) = self.lookup_path(s, parent)?;
+        if !parent.attributes.is_dir() {
+            return Err(FatError::NotADirectory);
+        }
+
+        match self.lookup_path(s, trimmed) {
+            Err(LookupError::NotFound(_)) => Ok((parent.cluster_idx(), name, ext)),
+            Ok(_) => Err(FatError::AlreadyExists),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Appends `entry` to the directory starting at `dir`.
+    fn add_dir_entry(&mut self, s: &mut S, dir: ClusterIdx, entry: DirEntry) -> Result<(), FatError> {
+        let mut iter = DirIter::from_cluster(dir, self, s);
+        while iter.next().is_some() { }
+
+        iter.add_entry(entry)
+    }
+
+    /// Zeros every byte in a cluster.
+    fn clear_cluster(&mut self, s: &mut S, idx: ClusterIdx) -> Result<(), FatError> {
+        let (sector, offset) = self.cluster_to_sector(idx, ClusterOffset::new(0));
+        let len = self.bytes_in_cluster(idx) as usize;
+
+        self.write_iter(s, sector, *offset, core::iter::repeat(0).take(len))
+    }
+
+    fn range_chk(&self, sector: SectorIdx, offset: u16, len: usize) -> Result<(), FatError> {
         let valid_sector_range = self.starting_lba..=self.ending_lba;
 
         // Check for a valid offset.
         if !(0..self.sector_size_in_bytes).contains(&offset) {
-            return Err(())
+            return Err(FatError::OutOfRange)
         }
 
-        // Check that the entire range is in bounds.
-        let ending_offset = offset as u64 + len as u64;
-        let ending_sector = SectorIdx::new(sector.inner() +
-            (ending_offset / (self.sector_size_in_bytes as u64)) +
-            if ending_offset % (self.sector_size_in_bytes as u64) == 0 { 0 } else { 1 }
-        );
+        // Check that the entire range is in bounds. The sector with the last
+        // byte in it is the last one touched; a range that ends right on a
+        // sector boundary doesn't reach into the next sector.
+        let last_byte = (offset as u64 + len as u64).saturating_sub(1);
+        let (ending_sector, _) = sector.offset_in(last_byte, self.sector_size_in_bytes);
         if !(
             valid_sector_range.contains(&sector) &&
             valid_sector_range.contains(&ending_sector)
         ) {
-            return Err(())
+            return Err(FatError::OutOfRange)
         }
 
         Ok(())
     }
 
-    pub fn read(&mut self, s: &mut S, mut sector: SectorIdx, mut offset: u16, buffer: &mut [u8]) -> Result<(), ()> {
+    pub fn read(&mut self, s: &mut S, sector: SectorIdx, offset: u16, buffer: &mut [u8]) -> Result<(), FatError> {
         self.range_chk(sector, offset, buffer.len())?;
 
-        let cache = self.cache.upgrade(s);
+        if buffer.is_empty() {
+            return Ok(());
+        }
 
-        // TODO: write a less clunky version of this that auto-vectorizers can
-        // actually do something with.
-        //
-        // as in, use copy_from_slice and split into the appropriate chunks
-        //
-        // or maybe this is good enough
-        // who knows
-        for b in buffer.iter_mut() {
-            *b = cache.get(sector)[offset as usize];
+        let sector_size = self.sector_size_in_bytes as usize;
 
-            offset += 1;
+        let (end, end_offset) =
+            sector.offset_in(offset as u64 + buffer.len() as u64, self.sector_size_in_bytes);
+        let end = if end_offset == 0 { end } else { end.checked_add(1).ok_or(FatError::OutOfRange)? };
 
-            if offset == self.sector_size_in_bytes {
-                offset = 0;
-                sector = SectorIdx::new(sector.inner() + 1);
-            }
-        }
+        let mut offset = offset as usize;
+
+        // We copy out a sector at a time: first whatever's left of the sector
+        // we're starting in, then whole sectors, then part of the last sector.
+        let mut done = 0;
+        self.cache.upgrade(s).get_range(sector..end, |_, data| {
+            let len = core::cmp::min(sector_size - offset, buffer.len() - done);
+            buffer[done..(done + len)].copy_from_slice(&data[offset..(offset + len)]);
+
+            done += len;
+            offset = 0;
+        })?;
 
         Ok(())
     }
 
-    pub fn write_iter(&mut self, s: &mut S, mut sector: SectorIdx, mut offset: u16, data: impl Iterator<Item = u8>) -> Result<(), ()> {
+    pub fn write_iter(&mut self, s: &mut S, mut sector: SectorIdx, offset: u16, data: impl Iterator<Item = u8>) -> Result<(), FatError> {
         // Since we don't know how many elements this iterator will produce
         // up-front, we can't do a perfect job here.
         //
@@ -308,38 +1882,181 @@ where
         // elements that would have been produced.
         self.range_chk(sector, offset, data.size_hint().0)?;
 
+        let mut offset = offset as usize;
+        let mut data = data.peekable();
         let mut cache = self.cache.upgrade(s);
 
-        for b in data {
-            cache.get_mut(sector)[offset as usize] = b;
-
-            offset += 1;
-
-            if offset == self.sector_size_in_bytes{
-                offset = 0;
-                sector = SectorIdx::new(sector.inner() + 1);
+        // Rather than looking the sector up for every byte, we grab each
+        // sector once and fill it until either it or the iterator runs out.
+        while data.peek().is_some() {
+            // Unfortunately we can't do this check up-front since we're dealing
+            // with an iterator. It has to happen before the sector is touched
+            // so that a write ending on the partition's last byte is allowed.
+            if sector > self.ending_lba { return Err(FatError::OutOfRange) }
+
+            // `zip` stops on the sector running out _before_ pulling another
+            // byte out of the iterator so nothing gets dropped at the boundary.
+            let buf = cache.try_get_mut(sector)?;
+            for (dst, b) in buf[offset..].iter_mut().zip(&mut data) {
+                *dst = b;
             }
 
-            // Unfortunately we can't do this check up-front since we're dealing
-            // with an iterator.
-            if sector > self.ending_lba { return Err(()) }
+            offset = 0;
+            sector = sector.checked_add(1).ok_or(FatError::OutOfRange)?;
         }
 
         Ok(())
     }
 
-    pub fn write(&mut self, s: &mut S, sector: SectorIdx, offset: u16, buffer: &[u8]) -> Result<(), ()> {
-        // self.range_chk(sector, offset, buffer.len())?; // Unnecessary since we pass along a ExactSizeIterator.
-        self.write_iter(s, sector, offset, buffer.iter().cloned())
+    pub fn write(&mut self, s: &mut S, mut sector: SectorIdx, offset: u16, buffer: &[u8]) -> Result<(), FatError> {
+        self.range_chk(sector, offset, buffer.len())?;
+
+        let sector_size = self.sector_size_in_bytes as usize;
+        let mut offset = offset as usize;
+        let mut rest = buffer;
+        let mut cache = self.cache.upgrade(s);
+
+        // Like `read`: part of the first sector, then whole sectors, then part
+        // of the last sector; one cache lookup for each.
+        while !rest.is_empty() {
+            let len = core::cmp::min(sector_size - offset, rest.len());
+            let (chunk, tail) = rest.split_at(len);
+
+            cache.try_get_mut(sector)?[offset..(offset + len)].copy_from_slice(chunk);
+
+            rest = tail;
+            offset = 0;
+            sector = sector.checked_add(1).ok_or(FatError::OutOfRange)?;
+        }
+
+        Ok(())
     }
 
-    pub fn format(_storage: &/*'s*/ mut S, partition: &PartitionEntry) -> Result<Self, ()> {
+    /// Lays out a new, empty, volume on `partition` (see
+    /// `BootSector::for_volume`) and mounts it.
+    ///
+    /// Only the metadata (the reserved sectors, the FATs, and the root
+    /// directory) is touched: it's zeroed with `StorageExt::erase_range`,
+    /// which backends can do in bulk, and then the boot sector, the FSInfo
+    /// sector and the backup boot and FSInfo sectors (FAT32 only), and the
+    /// first few FAT entries are filled in. The data region is left as is.
+    pub fn format(s: &/*'s*/ mut S, partition: &PartitionEntry, ev: Ev) -> Result<Self, FatError>
+    where
+        S: StorageExt,
+    {
         if partition.partition_type != Guid::microsoft_basic_data() {
-            return Err(());
+            return Err(FatError::InvalidArgument);
+        }
+
+        let total_sectors = partition.last_lba.checked_sub(partition.first_lba)
+            .and_then(|n| n.checked_add(1))
+            .and_then(|n| n.try_into().ok())
+            .ok_or(FatError::InvalidArgument)?;
+        let sector_size = S::SECTOR_SIZE::to_u16();
+
+        // The hidden sectors field is informational (see
+        // `BootSector::starting_fat_sector`); volumes past 2 TiB get 0.
+        let boot_sect = BootSector::for_volume(
+            partition.first_lba.try_into().unwrap_or(0),
+            total_sectors,
+            sector_size,
+        ).ok_or(FatError::InvalidArgument)?;
+
+        let fat_type = boot_sect.fat_type();
+        let first_lba = partition.first_lba as usize;
+        let fat_start = boot_sect.starting_fat_sector(SectorIdx::new(partition.first_lba)).idx();
+        let fat_size = boot_sect.fat_size_in_sectors() as usize;
+        let num_fats = boot_sect.bpb.num_file_alloc_tables as usize;
+
+        // FAT32 root directories live in the first cluster.
+        let root_dir_end = fat_start + num_fats * fat_size + match fat_type {
+            FatType::Fat32 => boot_sect.bpb.logical_sectors_per_cluster as usize,
+            FatType::Fat12 | FatType::Fat16 => boot_sect.root_dir_size_in_sectors() as usize,
+        };
+
+        s.erase_range(first_lba..root_dir_end)?;
+
+        let mut sector = GenericArray::default();
+        boot_sect.write(&mut sector);
+        s.write_sector(first_lba, &sector)?;
+
+        let backup = boot_sect.bpb.boot_sector_backup_logical_sector_start_num;
+        if backup != 0 {
+            s.write_sector(first_lba + backup as usize, &sector)?;
+        }
+
+        if fat_type == FatType::Fat32 {
+            let mut sector = GenericArray::default();
+            FsInfo {
+                // Less the root directory's cluster.
+                free_cluster_count: boot_sect.cluster_count() - 1,
+                next_free_cluster: 3,
+            }.write(&mut sector);
+
+            s.write_sector(first_lba + boot_sect.bpb.fs_info_logical_sector_num as usize, &sector)?;
+
+            // The backup boot sector has a copy of the FSInfo sector right
+            // after it.
+            if backup != 0 {
+                s.write_sector(first_lba + backup as usize + 1, &sector)?;
+            }
+        }
+
+        // The first two entries hold the media descriptor and an end of chain
+        // marker. On FAT32 the root directory's cluster (2) is a chain of one.
+        let media = boot_sect.bpb.media_descriptor;
+        let mut sector = GenericArray::<u8, S::SECTOR_SIZE>::default();
+        match fat_type {
+            FatType::Fat12 => sector[..3].copy_from_slice(&[media, 0xFF, 0xFF]),
+            FatType::Fat16 => sector[..4].copy_from_slice(&[media, 0xFF, 0xFF, 0xFF]),
+            FatType::Fat32 => sector[..12].copy_from_slice(&[
+                media, 0xFF, 0xFF, 0x0F,
+                0xFF, 0xFF, 0xFF, 0x0F,
+                0xFF, 0xFF, 0xFF, 0x0F,
+            ]),
         }
 
-        todo!();
+        for n in 0..num_fats {
+            s.write_sector(fat_start + n * fat_size, &sector)?;
+        }
 
-        // Self::mount(storage, partition)
+        Self::mount(s, partition, ev).map_err(FatError::Mount)
+    }
+}
+
+using_std! {
+    impl<S, CS, Ev> FatFs<S, CS, Ev>
+    where
+        S: Storage<Word = u8>,
+        CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
+        CS: ArrayLength<cache::CacheEntry>,
+        CS: ArrayLength<cache::IndexLink>,
+        CS: BitMapLen,
+        Ev: EvictionPolicy,
+    {
+        /// `volume_label_bytes` without the padding.
+        pub fn volume_label(&mut self, s: &mut S) -> Result<Option<String>, FatError> {
+            Ok(self.volume_label_bytes(s)?.map(|label| {
+                let len = label.iter().rposition(|c| *c != b' ').map_or(0, |l| l + 1);
+
+                String::from_utf8_lossy(&label[..len]).into_owned()
+            }))
+        }
+
+        /// `list_dir` into a `Vec`, with each entry's name as it'd appear in a
+        /// path (see `DirEntry::write_short_name`).
+        pub fn list_dir_vec(&mut self, s: &mut S, path: &[u8]) -> Result<Vec<(String, DirEntry)>, FatError> {
+            let mut entries = Vec::new();
+
+            self.visit_dir(s, path, |entry| {
+                let mut name = [0u8; 12];
+                let len = entry.write_short_name(&mut name)?;
+
+                entries.push((String::from_utf8_lossy(&name[..len]).into_owned(), entry));
+                Ok(())
+            })?;
+
+            Ok(entries)
+        }
     }
 }
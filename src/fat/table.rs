@@ -1,19 +1,16 @@
 
 use crate::Storage;
-use super::FatFs;
-use super::types::{ClusterIdx, SectorIdx};
+use super::{FatError, FatFs, OutOfRange};
+use super::types::{ClusterIdx, ClusterOffset, SectorIdx};
 use super::cache::EvictionPolicy;
 
 use generic_array::{ArrayLength, GenericArray};
-use typenum::consts::U512;
 
 use core::cell::RefCell;
 use core::convert::TryInto;
 use core::iter::Iterator;
 use core::ops::Range;
 
-// Another TODO: relax the 512B sector size restriction in this file.
-
 #[repr(transparent)]
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FatEntry {
@@ -31,9 +28,10 @@ impl FatEntry {
         storage: &'s mut S,
     ) -> FatEntryTracer<'f, 's, S, CS, Ev>
     where
-        S: Storage<Word = u8, SECTOR_SIZE = U512>,
-        CS: ArrayLength<RefCell<GenericArray<u8, U512>>>,
+        S: Storage<Word = u8>,
+        CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
         CS: ArrayLength<super::cache::CacheEntry>,
+        CS: ArrayLength<super::cache::IndexLink>,
         CS: crate::util::BitMapLen,
         Ev: EvictionPolicy,
     {
@@ -45,9 +43,10 @@ impl FatEntry {
         fet: &'f mut FatEntryTracer<'f, 'f, S, CS, Ev>,
     ) -> FatEntryWrapper<'fet, 'f, 'f, S, CS, Ev>
     where
-        S: Storage<Word = u8, SECTOR_SIZE = U512>,
-        CS: ArrayLength<RefCell<GenericArray<u8, U512>>>,
+        S: Storage<Word = u8>,
+        CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
         CS: ArrayLength<super::cache::CacheEntry>,
+        CS: ArrayLength<super::cache::IndexLink>,
         CS: crate::util::BitMapLen,
         Ev: EvictionPolicy,
     {
@@ -60,9 +59,10 @@ impl FatEntry {
         storage: &'s mut S,
     ) -> FatEntryWrapper<'fet, 'f, 's, S, CS, Ev>
     where
-        S: Storage<Word = u8, SECTOR_SIZE = U512>,
-        CS: ArrayLength<RefCell<GenericArray<u8, U512>>>,
+        S: Storage<Word = u8>,
+        CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
         CS: ArrayLength<super::cache::CacheEntry>,
+        CS: ArrayLength<super::cache::IndexLink>,
         CS: crate::util::BitMapLen,
         Ev: EvictionPolicy,
     {
@@ -72,14 +72,160 @@ impl FatEntry {
 
 impl FatEntry {
     pub const FREE: FatEntry = FatEntry::from(ClusterIdx::new(0x0000_0000));
-    pub const END_OF_CHAIN: FatEntry = FatEntry::from(ClusterIdx::new(0xFFFF_FFF8));
+    /// The FAT32 value (FAT32 entries are 28 bits); see
+    /// `FatType::end_of_chain` for the others.
+    pub const END_OF_CHAIN: FatEntry = FatEntry::from(ClusterIdx::new(0x0FFF_FFF8));
+    /// The FAT32 value; see `FatType::bad_cluster` for the others.
+    pub const BAD_CLUSTER: FatEntry = FatEntry::from(ClusterIdx::new(0x0FFF_FFF7));
+
+    /// What this entry means, for a FAT of type `fat_type`. Bits above the
+    /// width of the entry (the top 4 bits, for FAT32) are ignored.
+    pub fn classify(&self, fat_type: FatType) -> FatEntryKind {
+        let mask = fat_type.entry_mask();
+        let value = *self.next.inner() & mask;
+
+        // The special values are the same at the top of each type's range:
+        // reserved from 0x..FF0 to 0x..FF6, then bad, then end of chain.
+        match value {
+            0 => FatEntryKind::Free,
+            1 => FatEntryKind::Reserved,
+            v if v >= mask - 7 => FatEntryKind::EndOfChain,
+            v if v == mask - 8 => FatEntryKind::Bad,
+            v if v >= mask - 15 => FatEntryKind::Reserved,
+            v => FatEntryKind::Next(ClusterIdx::new(v)),
+        }
+    }
+}
+
+/// See `FatEntry::classify`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatEntryKind {
+    Free,
+    /// Values that shouldn't show up in a chain (1, and the handful just
+    /// below the bad cluster marker).
+    Reserved,
+    /// The cluster has a defect and shouldn't be used.
+    Bad,
+    /// The next cluster in the chain.
+    Next(ClusterIdx),
+    EndOfChain,
+}
+
+/// The flavour of FAT a volume uses; this decides how wide the entries in the
+/// table are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// Picks the type for a volume with `clusters` data clusters. The type of
+    /// a FAT volume is determined by this count alone.
+    pub fn from_cluster_count(clusters: u32) -> Self {
+        if clusters < 4085 {
+            FatType::Fat12
+        } else if clusters < 65525 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        }
+    }
+
+    pub fn bits_per_entry(self) -> u32 {
+        match self {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        }
+    }
+
+    /// Number of bytes that need to be read to get at an entry. FAT12 entries
+    /// straddle two bytes.
+    pub fn entry_span_in_bytes(self) -> usize {
+        match self {
+            FatType::Fat12 | FatType::Fat16 => 2,
+            FatType::Fat32 => 4,
+        }
+    }
+
+    /// Offset of the first byte of `idx`'s entry from the start of the table.
+    pub fn entry_offset(self, idx: ClusterIdx) -> u64 {
+        ((*idx.inner() as u64) * (self.bits_per_entry() as u64)) / 8
+    }
+
+    /// Number of entries that fit in a table that's `bytes` long.
+    pub fn entries_in(self, bytes: u64) -> u64 {
+        (bytes * 8) / (self.bits_per_entry() as u64)
+    }
+
+    /// The bits of a (decoded) entry that hold its value.
+    pub fn entry_mask(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0x0FFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    pub fn bad_cluster(self) -> FatEntry {
+        match self {
+            FatType::Fat12 => FatEntry::from(ClusterIdx::new(0x0FF7)),
+            FatType::Fat16 => FatEntry::from(ClusterIdx::new(0xFFF7)),
+            FatType::Fat32 => FatEntry::BAD_CLUSTER,
+        }
+    }
+
+    pub fn end_of_chain(self) -> FatEntry {
+        match self {
+            FatType::Fat12 => FatEntry::from(ClusterIdx::new(0x0FF8)),
+            FatType::Fat16 => FatEntry::from(ClusterIdx::new(0xFFF8)),
+            FatType::Fat32 => FatEntry::END_OF_CHAIN,
+        }
+    }
+
+    /// Pulls `idx`'s entry out of the bytes starting at `entry_offset(idx)`.
+    ///
+    /// Only the first `entry_span_in_bytes` bytes of `bytes` are looked at.
+    pub fn decode(self, idx: ClusterIdx, bytes: [u8; 4]) -> FatEntry {
+        let raw = u32::from_le_bytes(bytes);
+
+        FatEntry::from(ClusterIdx::new(match self {
+            // Odd entries live in the upper 12 bits of their two bytes.
+            FatType::Fat12 if *idx.inner() % 2 == 1 => (raw >> 4) & 0x0FFF,
+            FatType::Fat12 => raw & 0x0FFF,
+            FatType::Fat16 => raw & 0xFFFF,
+            // The top 4 bits are reserved.
+            FatType::Fat32 => raw & 0x0FFF_FFFF,
+        }))
+    }
+
+    /// Puts `entry` into the bytes starting at `entry_offset(idx)`, leaving
+    /// the bits that belong to neighbouring FAT12 entries (and the reserved
+    /// top 4 bits of FAT32 entries) alone.
+    pub fn encode(self, idx: ClusterIdx, entry: &FatEntry, bytes: &mut [u8; 4]) {
+        let raw = u32::from_le_bytes(*bytes);
+        let next = *entry.next.inner();
+
+        let raw = match self {
+            FatType::Fat12 if *idx.inner() % 2 == 1 =>
+                (raw & !0xFFF0) | ((next & 0x0FFF) << 4),
+            FatType::Fat12 => (raw & !0x0FFF) | (next & 0x0FFF),
+            FatType::Fat16 => (raw & !0xFFFF) | (next & 0xFFFF),
+            FatType::Fat32 => (raw & !0x0FFF_FFFF) | (next & 0x0FFF_FFFF),
+        };
+
+        *bytes = raw.to_le_bytes();
+    }
 }
 
 pub struct FatEntryWrapper<'fet, 'f, 's, S, CS, Ev>
 where
-    S: Storage<Word = u8, SECTOR_SIZE = U512>,
-    CS: ArrayLength<RefCell<GenericArray<u8, U512>>>,
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
     CS: ArrayLength<super::cache::CacheEntry>,
+    CS: ArrayLength<super::cache::IndexLink>,
     CS: crate::util::BitMapLen,
     Ev: EvictionPolicy,
 {
@@ -90,9 +236,10 @@ where
 
 impl<'fet, 'f, 's, S, CS, Ev> FatEntryWrapper<'fet, 'f, 's, S, CS, Ev>
 where
-    S: Storage<Word = u8, SECTOR_SIZE = U512>,
-    CS: ArrayLength<RefCell<GenericArray<u8, U512>>>,
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
     CS: ArrayLength<super::cache::CacheEntry>,
+    CS: ArrayLength<super::cache::IndexLink>,
     CS: crate::util::BitMapLen,
     Ev: EvictionPolicy,
 {
@@ -109,15 +256,17 @@ where
     }
 
     fn cluster_size_in_bytes(&self) -> u32 {
-        (self.fs.cluster_size_in_sectors as u32) *
-        (self.fs.sector_size_in_bytes as u32)
+        self.fs.bytes_in_cluster(self.inner.next)
     }
 
-    fn range_chk(&self, offset: u32, len: usize) -> Result<(), ()> {
-        let max_offset = offset.checked_add(len.try_into().unwrap()).unwrap();
+    fn range_chk(&self, offset: ClusterOffset, len: usize) -> Result<(), FatError> {
+        let max_offset = len.try_into().ok()
+            .and_then(|len| offset.checked_add(len))
+            .ok_or(FatError::OutOfRange)?;
 
-        if max_offset >= self.cluster_size_in_bytes() {
-            Err(())
+        // Ending right at the end of the cluster is fine.
+        if *max_offset > self.cluster_size_in_bytes() {
+            Err(FatError::OutOfRange)
         } else {
             Ok(())
         }
@@ -125,37 +274,128 @@ where
 
     // offset into this cluster
     //
-    // users of this should constrain buf to the file's end?
-    pub fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), ()> {
-        self.range_chk(offset, buf.len())?;
+    // reads that run past the end of this cluster carry on into the next
+    // clusters in the chain (the FAT is only consulted when that happens)
+    //
+    // use `read_clamped` for the last cluster of a file so that this doesn't
+    // read past the file's end
+    pub fn read(&mut self, offset: ClusterOffset, buf: &mut [u8]) -> Result<(), FatError> {
+        let cluster_size = self.cluster_size_in_bytes();
+        if *offset > cluster_size {
+            return Err(FatError::OutOfRange);
+        }
 
-        let (sector_idx, offset) = self.fs.cluster_to_sector(self.inner.next, offset);
+        let len = core::cmp::min(buf.len() as u64, (cluster_size - *offset) as u64) as usize;
+        let (here, rest) = buf.split_at_mut(len);
 
-        // Since this is within a cluster, the sectors are back to back and
-        // we can just call fs.read once.
-        self.fs.read(self.storage, sector_idx, offset, buf)
+        if !here.is_empty() {
+            let (sector_idx, offset) = self.fs.cluster_to_sector(self.inner.next, offset);
+
+            // Since this is within a cluster, the sectors are back to back and
+            // we can just call fs.read once.
+            self.fs.read(self.storage, sector_idx, *offset, here)?;
+        }
+
+        if rest.is_empty() {
+            Ok(())
+        } else {
+            self.read_following(rest)
+        }
+    }
+
+    // Fills `buf` from the clusters after this one in the chain, starting at
+    // the beginning of the next cluster.
+    fn read_following(&mut self, mut buf: &mut [u8]) -> Result<(), FatError> {
+        let mut tracer = FatEntryTracer::starting_at(&mut *self.fs, &mut *self.storage, self.inner.next);
+
+        // This cluster; it's already been read.
+        let _ = tracer.next();
+
+        while !buf.is_empty() {
+            let cluster = match tracer.next() {
+                Some(FatEntry { next }) => next,
+                // Either the chain is broken or we ran off the end of it.
+                None => return Err(tracer.error().map_or(FatError::OutOfRange, FatError::from)),
+            };
+
+            let cluster_size = tracer.file_sys.bytes_in_cluster(cluster);
+            let len = core::cmp::min(buf.len() as u64, cluster_size as u64) as usize;
+            let (chunk, rest) = core::mem::take(&mut buf).split_at_mut(len);
+
+            let (sector_idx, offset) = tracer.file_sys.cluster_to_sector(cluster, ClusterOffset::new(0));
+            tracer.file_sys.read(tracer.storage, sector_idx, *offset, chunk)?;
+
+            buf = rest;
+        }
+
+        Ok(())
+    }
+
+    /// Like `read` but stops at `file_end`: the offset into this cluster that
+    /// the file ends at (i.e. `file_size % cluster_size` for the last cluster
+    /// of a file, unless that's 0).
+    ///
+    /// Returns how many bytes were read; 0 if `offset` is at or past
+    /// `file_end`.
+    pub fn read_clamped(&mut self, offset: ClusterOffset, buf: &mut [u8], file_end: ClusterOffset) -> Result<usize, FatError> {
+        let remaining = file_end.saturating_sub(*offset);
+        let len = core::cmp::min(buf.len() as u64, remaining as u64) as usize;
+
+        if len > 0 {
+            self.read(offset, &mut buf[..len])?;
+        }
+
+        Ok(len)
     }
 
     // offset into this cluster
     //
     // users of this should constrain buf to the file's end? or grow the file?
-    pub fn write(&mut self, offset: u32, data: impl Iterator<Item = u8>) -> Result<(), ()> {
+    pub fn write(&mut self, offset: ClusterOffset, data: impl Iterator<Item = u8>) -> Result<(), FatError> {
         self.range_chk(offset, data.size_hint().0)?;
 
         let (sector_idx, offset) = self.fs.cluster_to_sector(self.inner.next, offset);
 
         // Since this is within a cluster, the sectors are back to back and
         // we can just call fs.write once.
-        self.fs.write_iter(self.storage, sector_idx, offset, data)
+        self.fs.write_iter(self.storage, sector_idx, *offset, data)
     }
 }
 
+/// Summary of a cluster chain; see `FatEntryTracer::analyze`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainInfo {
+    /// Number of clusters in the chain.
+    pub cluster_count: u32,
+    /// Whether every cluster in the chain directly follows the one before it.
+    /// Contiguous chains can be read as one run of sectors.
+    pub is_contiguous: bool,
+    pub first_cluster: ClusterIdx,
+    pub last_cluster: ClusterIdx,
+}
+
+/// Why a `FatEntryTracer` (or a `DirIter`) stopped before the end of a chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceError {
+    /// Reading from the storage failed.
+    Io,
+    /// The chain is longer than the FAT has entries, so it has to loop back on
+    /// itself.
+    Cycle,
+    /// The chain points at a cluster past the end of the FAT.
+    OutOfRange(ClusterIdx),
+    /// The chain runs into a cluster whose entry says it's free, bad, or
+    /// reserved rather than pointing at the next cluster or ending the chain.
+    BrokenChain,
+}
+
 #[derive(Debug)]
 pub struct FatEntryTracer<'f, 's, S, CS, Ev>
 where
-    S: Storage<Word = u8, SECTOR_SIZE = U512>,
-    CS: ArrayLength<RefCell<GenericArray<u8, U512>>>,
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
     CS: ArrayLength<super::cache::CacheEntry>,
+    CS: ArrayLength<super::cache::IndexLink>,
     CS: crate::util::BitMapLen,
     Ev: EvictionPolicy,
 {
@@ -164,13 +404,19 @@ where
 
     pub current_cluster_idx: Option<ClusterIdx>,
     hit_end: Option<ClusterIdx>,
+
+    /// Number of clusters handed out so far; a chain that's longer than the
+    /// FAT has entries has to have a cycle in it.
+    visited: u32,
+    error: Option<TraceError>,
 }
 
 impl<'f, 's, S, CS, Ev> FatEntryTracer<'f, 's, S, CS, Ev>
 where
-    S: Storage<Word = u8, SECTOR_SIZE = U512>,
-    CS: ArrayLength<RefCell<GenericArray<u8, U512>>>,
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
     CS: ArrayLength<super::cache::CacheEntry>,
+    CS: ArrayLength<super::cache::IndexLink>,
     CS: crate::util::BitMapLen,
     Ev: EvictionPolicy,
 {
@@ -178,7 +424,8 @@ where
         fs: &'f mut FatFs<S, CS, Ev>,
         storage: &'s mut S
     ) -> Self {
-        Self::starting_at(fs, storage, fs.root_dir_cluster_num)
+        let root = fs.root_dir_cluster_num;
+        Self::starting_at(fs, storage, root)
     }
 
     pub fn starting_at(
@@ -186,54 +433,110 @@ where
         storage: &'s mut S,
         cluster_idx: ClusterIdx
     ) -> Self {
-        let (sector, _offset) = fs.cluster_to_table_pos(cluster_idx);
-
         Self {
             file_sys: fs,
             storage,
 
             current_cluster_idx: Some(cluster_idx),
             hit_end: None,
+
+            visited: 0,
+            error: None,
         }
     }
 
-    pub fn capacity(mut self) -> usize {
-        let cluster_size_in_bytes =
-            (self.file_sys.cluster_size_in_sectors as usize) *
-            (self.file_sys.sector_size_in_bytes as usize);
+    /// Once the tracer stops yielding entries, this says whether that's
+    /// because something went wrong rather than because it hit the end of the
+    /// chain. Errors are sticky: a tracer that's hit one stays stopped.
+    pub fn error(&self) -> Option<TraceError> {
+        self.error
+    }
 
-        self.count() * cluster_size_in_bytes
+    /// Whether the tracer stopped because the chain was longer than any valid
+    /// chain can be (i.e. it loops back on itself) rather than because it hit
+    /// the end of the chain.
+    pub fn took_too_long(&self) -> bool {
+        self.error == Some(TraceError::Cycle)
     }
 
-    /// Only works when the iterator has run out; returns `Err` otherwise.
-    pub fn grow_file(&mut self) -> Result<(), ()> {
-        if let Some(last_cluster) = self.hit_end.take() {
-            let given = self.file_sys.next_free_cluster(self.storage).unwrap();
+    pub fn capacity(mut self) -> Result<usize, TraceError> {
+        let cluster_size_in_bytes =
+            (self.file_sys.cluster_size_in_sectors as usize) *
+            (self.file_sys.sector_size_in_bytes as usize);
 
-            let (sector, offset) = self.file_sys.cluster_to_table_pos(
-                last_cluster,
-            );
+        let count = self.by_ref().count();
 
-            // Make the last cluster point to the new cluster:
-            let bytes = given.to_le_bytes();
+        match self.error {
+            Some(err) => Err(err),
+            None => Ok(count * cluster_size_in_bytes),
+        }
+    }
 
-            self.file_sys.write(self.storage, sector, offset, &bytes).unwrap();
+    /// Walks the rest of the chain, noting how long it is and whether it's
+    /// contiguous.
+    ///
+    /// If the tracer has already run out, this returns a `cluster_count` of 0
+    /// (and cluster 0 for the first and last clusters).
+    pub fn analyze(mut self) -> Result<ChainInfo, TraceError> {
+        let mut info = ChainInfo {
+            cluster_count: 0,
+            is_contiguous: true,
+            first_cluster: ClusterIdx::new(0),
+            last_cluster: ClusterIdx::new(0),
+        };
+
+        for FatEntry { next: cluster } in self.by_ref() {
+            if info.cluster_count == 0 {
+                info.first_cluster = cluster;
+            } else if info.last_cluster.checked_add(1) != Some(*cluster) {
+                info.is_contiguous = false;
+            }
 
-            // Make it so the iterator can be resumed:
-            self.current_cluster_idx = Some(given);
+            info.last_cluster = cluster;
+            info.cluster_count += 1;
+        }
 
-            Ok(())
-        } else {
-            Err(())
+        match self.error {
+            Some(err) => Err(err),
+            None => Ok(info),
         }
     }
+
+    /// Only works when the iterator has run out at the end of the chain;
+    /// returns `Err` otherwise (including when it stopped on a cycle).
+    ///
+    /// If allocating or linking in the new cluster fails the tracer is left as
+    /// it was, so this can be retried.
+    pub fn grow_file(&mut self) -> Result<(), FatError> {
+        let last_cluster = match self.hit_end {
+            Some(c) => c,
+            // Stopped early on a broken chain or hasn't reached the end yet.
+            None => return Err(self.error.map_or(FatError::InvalidArgument, FatError::from)),
+        };
+
+        let given = self.file_sys.next_free_cluster(self.storage)?;
+
+        // Make the last cluster point to the new cluster:
+        self.file_sys.write_fat_entry(
+            self.storage,
+            last_cluster,
+            &FatEntry::from(given),
+        )?;
+
+        // Make it so the iterator can be resumed:
+        self.hit_end = None;
+        self.current_cluster_idx = Some(given);
+
+        Ok(())
+    }
 }
 
 impl<'f, 's, S, CS, Ev> Iterator for /*&mut */FatEntryTracer<'f, 's, S, CS, Ev>
 where
-    S: Storage<Word = u8, SECTOR_SIZE = U512>,
-    CS: ArrayLength<RefCell<GenericArray<u8, U512>>>,
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
     CS: ArrayLength<super::cache::CacheEntry>,
+    CS: ArrayLength<super::cache::IndexLink>,
     CS: crate::util::BitMapLen,
     Ev: EvictionPolicy,
 {
@@ -241,22 +544,50 @@ where
 
     fn next(&mut self) -> Option<FatEntry> {
         if let Some(idx) = self.current_cluster_idx {
-            let (sector, offset) = self.file_sys.cluster_to_table_pos(
-                idx
-            );
-
-            // Get the next cluster index:
-            let mut buf = [0u8; 4];
-            self.file_sys.read(self.storage, sector, offset, &mut buf).unwrap();
+            if self.visited >= self.file_sys.num_fat_entries() {
+                self.current_cluster_idx = None;
+                self.error = Some(TraceError::Cycle);
+                return None;
+            }
+            self.visited += 1;
 
-            let next: ClusterIdx = ClusterIdx::new(u32::from_le_bytes(buf));
-            let fat_entry = FatEntry::from(next);
+            // The FAT12/16 root directory isn't in the table; it's the only
+            // "cluster" in its chain and it can't be grown.
+            if self.file_sys.is_fixed_root_dir(idx) {
+                self.current_cluster_idx = None;
+                return Some(FatEntry::from(idx));
+            }
 
-            if fat_entry == FatEntry::END_OF_CHAIN {
+            // Corrupt entries can point anywhere; don't go reading whatever
+            // sector an out of range cluster's entry would be in.
+            if let Err(OutOfRange(c)) = self.file_sys.cluster_to_table_pos(idx) {
                 self.current_cluster_idx = None;
-                self.hit_end = Some(idx);
-            } else {
-                self.current_cluster_idx = Some(next);
+                self.error = Some(TraceError::OutOfRange(c));
+                return None;
+            }
+
+            // Get the next cluster index:
+            let fat_entry = match self.file_sys.read_fat_entry(self.storage, idx) {
+                Ok(entry) => entry,
+                Err(_) => {
+                    self.current_cluster_idx = None;
+                    self.error = Some(TraceError::Io);
+                    return None;
+                }
+            };
+
+            // Anything from 0x..FF8 up marks the end of a chain; formatters
+            // tend to use 0x..FFF rather than the 0x..FF8 we write.
+            match fat_entry.classify(self.file_sys.fat_type) {
+                FatEntryKind::Next(next) => self.current_cluster_idx = Some(next),
+                FatEntryKind::EndOfChain => {
+                    self.current_cluster_idx = None;
+                    self.hit_end = Some(idx);
+                }
+                FatEntryKind::Free | FatEntryKind::Reserved | FatEntryKind::Bad => {
+                    self.current_cluster_idx = None;
+                    self.error = Some(TraceError::BrokenChain);
+                }
             }
 
             Some(FatEntry::from(idx))
@@ -301,3 +632,133 @@ where
 //         }
 //     }
 // }
+
+/// Yields `(first cluster, length)` for each run of consecutive free clusters
+/// in the FAT, lowest clusters first. See `FatFs::free_runs`.
+///
+/// The FAT is read a sector at a time (one cache lookup per FAT sector rather
+/// than one per entry). If reading the FAT fails the iterator stops early;
+/// `error` says whether that happened.
+#[derive(Debug)]
+pub struct FreeRuns<'f, 's, S, CS, Ev>
+where
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
+    CS: ArrayLength<super::cache::CacheEntry>,
+    CS: ArrayLength<super::cache::IndexLink>,
+    CS: crate::util::BitMapLen,
+    Ev: EvictionPolicy,
+{
+    pub file_sys: &'f mut FatFs<S, CS, Ev>,
+    pub storage: &'s mut S,
+
+    next_cluster: u32,
+
+    /// A copy of the FAT sector `loaded` (if it's `Some`).
+    sector: GenericArray<u8, S::SECTOR_SIZE>,
+    loaded: Option<SectorIdx>,
+
+    error: Option<FatError>,
+}
+
+impl<'f, 's, S, CS, Ev> FreeRuns<'f, 's, S, CS, Ev>
+where
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
+    CS: ArrayLength<super::cache::CacheEntry>,
+    CS: ArrayLength<super::cache::IndexLink>,
+    CS: crate::util::BitMapLen,
+    Ev: EvictionPolicy,
+{
+    pub fn new(fs: &'f mut FatFs<S, CS, Ev>, storage: &'s mut S) -> Self {
+        Self {
+            file_sys: fs,
+            storage,
+
+            next_cluster: 2,
+
+            sector: GenericArray::default(),
+            loaded: None,
+
+            error: None,
+        }
+    }
+
+    /// Errors are sticky: once there's been one the iterator stays stopped.
+    pub fn error(&self) -> Option<FatError> {
+        self.error
+    }
+
+    fn load(&mut self, sector: SectorIdx) -> Result<(), FatError> {
+        if self.loaded != Some(sector) {
+            self.file_sys.range_chk(sector, 0, 1)?;
+
+            let cache = self.file_sys.cache.upgrade(&mut *self.storage);
+            self.sector.copy_from_slice(&cache.try_get(sector)?);
+            self.loaded = Some(sector);
+        }
+
+        Ok(())
+    }
+
+    fn entry(&mut self, idx: ClusterIdx) -> Result<FatEntry, FatError> {
+        let fat_type = self.file_sys.fat_type;
+        let span = fat_type.entry_span_in_bytes();
+
+        let (sector, offset) = self.file_sys.cluster_to_table_pos(idx)?;
+        let offset = u16::from(offset) as usize;
+        let in_this_sector = core::cmp::min(span, self.sector.len() - offset);
+
+        let mut bytes = [0u8; 4];
+        self.load(sector)?;
+        bytes[..in_this_sector].copy_from_slice(&self.sector[offset..(offset + in_this_sector)]);
+
+        // FAT12 entries can straddle two sectors.
+        if in_this_sector < span {
+            self.load(sector.checked_add(1).ok_or(FatError::OutOfRange)?)?;
+            bytes[in_this_sector..span].copy_from_slice(&self.sector[..(span - in_this_sector)]);
+        }
+
+        Ok(fat_type.decode(idx, bytes))
+    }
+}
+
+impl<'f, 's, S, CS, Ev> Iterator for FreeRuns<'f, 's, S, CS, Ev>
+where
+    S: Storage<Word = u8>,
+    CS: ArrayLength<RefCell<GenericArray<u8, S::SECTOR_SIZE>>>,
+    CS: ArrayLength<super::cache::CacheEntry>,
+    CS: ArrayLength<super::cache::IndexLink>,
+    CS: crate::util::BitMapLen,
+    Ev: EvictionPolicy,
+{
+    type Item = (ClusterIdx, u32);
+
+    fn next(&mut self) -> Option<(ClusterIdx, u32)> {
+        if self.error.is_some() { return None; }
+
+        let fat_type = self.file_sys.fat_type;
+        let end = self.file_sys.num_clusters();
+        let mut start: Option<u32> = None;
+
+        while self.next_cluster < end {
+            let free = match self.entry(ClusterIdx::new(self.next_cluster)) {
+                Ok(entry) => entry.classify(fat_type) == FatEntryKind::Free,
+                Err(err) => {
+                    self.error = Some(err);
+                    return None;
+                }
+            };
+
+            match (free, start) {
+                (true, None) => start = Some(self.next_cluster),
+                (false, Some(_)) => break,
+                _ => {},
+            }
+
+            self.next_cluster += 1;
+        }
+
+        start.map(|start| (ClusterIdx::new(start), self.next_cluster - start))
+    }
+}
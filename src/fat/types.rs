@@ -5,7 +5,7 @@ use core::iter::Iterator;
 use core::ops::Range;
 
 macro_rules! newtype {
-    ([$m:ident] $name:tt: $inner:ty $(where constructor = $c:ident)?) => {
+    ($(#[$attr:meta])* [$m:ident] $name:tt: $inner:ty $(where constructor = $c:ident)?) => {
         #[doc(hidden)]
         #[allow(non_snake_case)]
         pub mod $m {
@@ -38,6 +38,7 @@ macro_rules! newtype {
             }
         }
 
+        $(#[$attr])*
         pub type $name = $m::Newtype<$inner>;
 
         impl $name {
@@ -61,6 +62,25 @@ impl SectorIdx {
     pub fn idx(&self) -> usize {
         self.0.try_into().unwrap()
     }
+
+    /// `None` if this would go past `u64::MAX`.
+    pub fn checked_add(self, n: u64) -> Option<SectorIdx> {
+        self.0.checked_add(n).map(SectorIdx::new)
+    }
+
+    /// The sector and offset within it that's `bytes` bytes past the start of
+    /// this sector.
+    ///
+    /// Saturates at sector `u64::MAX` (which no volume reaches) rather than
+    /// wrapping so that bounds checks on the result still fail.
+    pub fn offset_in(self, bytes: u64, sector_size: u16) -> (SectorIdx, u16) {
+        let sector_size = sector_size as u64;
+
+        (
+            SectorIdx::new(self.0.saturating_add(bytes / sector_size)),
+            (bytes % sector_size) as u16,
+        )
+    }
 }
 
 impl ClusterIdx {
@@ -69,8 +89,85 @@ impl ClusterIdx {
     }
 }
 
+newtype! {
+    /// The offset of a byte within a sector.
+    ///
+    /// This isn't interchangeable with a `ClusterOffset`:
+    ///
+    /// ```compile_fail
+    /// # use fs::fat::types::{ByteOffset, ClusterOffset};
+    /// fn within_a_cluster(_: ClusterOffset) { }
+    ///
+    /// within_a_cluster(ByteOffset::new(4));
+    /// ```
+    [_bo] ByteOffset: u16 where constructor = new
+}
+
+newtype! {
+    /// The offset of a byte from the start of a cluster; this can be more than
+    /// a sector's worth of bytes.
+    ///
+    /// This isn't interchangeable with a `ByteOffset`:
+    ///
+    /// ```compile_fail
+    /// # use fs::fat::types::{ByteOffset, ClusterOffset};
+    /// fn within_a_sector(_: ByteOffset) { }
+    ///
+    /// within_a_sector(ClusterOffset::new(4));
+    /// ```
+    ///
+    /// And neither is a bare integer:
+    ///
+    /// ```compile_fail
+    /// # use fs::fat::types::ClusterOffset;
+    /// fn within_a_cluster(_: ClusterOffset) { }
+    ///
+    /// within_a_cluster(4u32);
+    /// ```
+    [_co] ClusterOffset: u32 where constructor = new
+}
+
+impl ClusterOffset {
+    /// Splits this into the number of whole sectors it skips and the offset
+    /// into the sector after those.
+    pub fn split(self, sector_size: u16) -> (u32, ByteOffset) {
+        let sector_size = sector_size as u32;
+
+        (self.0 / sector_size, ByteOffset::new((self.0 % sector_size) as u16))
+    }
+
+    /// The inverse of `split`.
+    pub fn from_sectors(sectors: u32, offset: ByteOffset, sector_size: u16) -> Self {
+        ClusterOffset::new(sectors * (sector_size as u32) + (*offset.inner() as u32))
+    }
+
+    /// `None` if this would go past `u32::MAX`.
+    pub fn checked_add(self, n: u32) -> Option<ClusterOffset> {
+        self.0.checked_add(n).map(ClusterOffset::new)
+    }
+}
+
+impl From<ByteOffset> for u16 {
+    fn from(offset: ByteOffset) -> u16 { offset.0 }
+}
+
+impl From<ClusterOffset> for u32 {
+    fn from(offset: ClusterOffset) -> u32 { offset.0 }
+}
+
 newtype! { [_sr] SectorRange: Range<SectorIdx> where constructor = new }
 
+impl SectorRange {
+    /// Shrinks this range so that it only covers sectors that are also in
+    /// `bounds`. Ranges that don't overlap `bounds` at all come out empty.
+    pub fn clamp(self, bounds: &Range<SectorIdx>) -> SectorRange {
+        let start = core::cmp::max(self.0.start, bounds.start);
+        let end = core::cmp::max(core::cmp::min(self.0.end, bounds.end), start);
+
+        SectorRange::new(start..end)
+    }
+}
+
 impl Iterator for SectorRange {
     type Item = SectorIdx;
 
@@ -89,3 +186,54 @@ impl Iterator for SectorRange {
         }
     }
 }
+
+#[cfg(test)]
+mod sector_idx {
+    use super::*;
+    use assert_eq as eq;
+
+    #[test]
+    fn checked_add() {
+        eq!(SectorIdx::new(5).checked_add(3), Some(SectorIdx::new(8)));
+        eq!(SectorIdx::new(u64::max_value() - 1).checked_add(1), Some(SectorIdx::new(u64::max_value())));
+        eq!(SectorIdx::new(u64::max_value() - 1).checked_add(2), None);
+        eq!(SectorIdx::new(u64::max_value()).checked_add(0), Some(SectorIdx::new(u64::max_value())));
+    }
+
+    #[test]
+    fn offset_in() {
+        let s = SectorIdx::new(10);
+
+        eq!(s.offset_in(0, 512), (SectorIdx::new(10), 0));
+        eq!(s.offset_in(511, 512), (SectorIdx::new(10), 511));
+
+        // Exactly on sector boundaries:
+        eq!(s.offset_in(512, 512), (SectorIdx::new(11), 0));
+        eq!(s.offset_in(3 * 4096, 4096), (SectorIdx::new(13), 0));
+        eq!(s.offset_in(3 * 4096 + 1, 4096), (SectorIdx::new(13), 1));
+
+        // Saturates instead of wrapping:
+        let near_the_end = SectorIdx::new(u64::max_value() - 1);
+        eq!(near_the_end.offset_in(512, 512), (SectorIdx::new(u64::max_value()), 0));
+        eq!(near_the_end.offset_in(5 * 512 + 7, 512), (SectorIdx::new(u64::max_value()), 7));
+        eq!(SectorIdx::new(1).offset_in(u64::max_value(), 1), (SectorIdx::new(u64::max_value()), 0));
+    }
+}
+
+#[cfg(test)]
+mod cluster_offset {
+    use super::*;
+    use assert_eq as eq;
+
+    #[test]
+    fn split() {
+        eq!(ClusterOffset::new(0).split(512), (0, ByteOffset::new(0)));
+        eq!(ClusterOffset::new(520).split(512), (1, ByteOffset::new(8)));
+        eq!(ClusterOffset::new(4096).split(4096), (1, ByteOffset::new(0)));
+
+        for &offset in [0, 1, 511, 512, 513, 70_000].iter() {
+            let (sectors, in_sector) = ClusterOffset::new(offset).split(512);
+            eq!(ClusterOffset::from_sectors(sectors, in_sector, 512), ClusterOffset::new(offset));
+        }
+    }
+}
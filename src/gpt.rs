@@ -4,8 +4,6 @@
 //! all the fields/functionality in GPT. Right now we pretty much just have
 //! exactly what we need for single partition disks.
 
-// Another TODO: relax the 512B sector size restriction in this file.
-
 // TODO: switch from pub(crate) to pub, I think
 
 // TODO: use the newtypes here (i.e. SectorIdx)
@@ -14,10 +12,12 @@ use super::Storage;
 
 use storage_traits::errors::WriteError;
 use generic_array::GenericArray;
-use typenum::consts::U512;
+use typenum::marker_traits::Unsigned;
 
-use core::fmt::{self, Debug};
+use core::char::{decode_utf16, REPLACEMENT_CHARACTER};
+use core::fmt::{self, Debug, Display};
 use core::convert::TryInto;
+use core::str::FromStr;
 
 pub const GPT_SIGNATURE: [u8; 8] = *b"EFI PART";
 
@@ -102,7 +102,84 @@ impl Debug for Guid {
     }
 }
 
+impl Display for Guid {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Debug::fmt(self, fmt)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuidParseError {
+    /// Canonical GUIDs are exactly 36 characters long.
+    WrongLength,
+    /// Holds the position of a character that should have been a hyphen or a
+    /// hex digit but wasn't.
+    InvalidCharacter(usize),
+}
+
+/// Parses the canonical `XXXXXXXX-XXXX-XXXX-XXXX-XXXXXXXXXXXX` form (as printed
+/// by the `Debug` and `Display` impls). Hex digits can be either case.
+impl FromStr for Guid {
+    type Err = GuidParseError;
+
+    fn from_str(s: &str) -> Result<Self, GuidParseError> {
+        let s = s.as_bytes();
+
+        if s.len() != 36 {
+            return Err(GuidParseError::WrongLength);
+        }
+
+        let mut u = 0u128;
+        for (idx, c) in s.iter().enumerate() {
+            match idx {
+                8 | 13 | 18 | 23 => if *c != b'-' {
+                    return Err(GuidParseError::InvalidCharacter(idx));
+                },
+                _ => {
+                    let digit = (*c as char).to_digit(16)
+                        .ok_or(GuidParseError::InvalidCharacter(idx))?;
+
+                    u = (u << 4) | (digit as u128);
+                },
+            }
+        }
+
+        // The text has the same layout as the literals `from_mixed_u128` takes.
+        Ok(Guid::from_mixed_u128(u))
+    }
+}
+
+/// GUIDs are (de)serialized in their canonical text form.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Guid {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Guid {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct GuidVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for GuidVisitor {
+            type Value = Guid;
+
+            fn expecting(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(fmt, "a GUID (XXXXXXXX-XXXX-XXXX-XXXX-XXXXXXXXXXXX)")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, s: &str) -> Result<Guid, E> {
+                s.parse().map_err(|e| E::custom(format_args!("invalid GUID ({:?})", e)))
+            }
+        }
+
+        deserializer.deserialize_str(GuidVisitor)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 /// Descriptions sourced from [here](https://en.wikipedia.org/wiki/GUID_Partition_Table#Partition_entries_(LBA_2%E2%80%9333)).
 pub struct Gpt {
     pub(crate) revision: u32,
@@ -129,6 +206,7 @@ pub struct Gpt {
 }
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PartitionEntry {
     pub(crate) partition_type: Guid,
     pub(crate) unique_guid: Guid,
@@ -139,6 +217,7 @@ pub struct PartitionEntry {
     // bit 60 denotes read only
     pub(crate) attribute_flags: u64,
     // UTF-16 LE.
+    #[cfg_attr(feature = "serde", serde(with = "partition_name"))]
     pub(crate) name: [u16; 36],
 }
 
@@ -150,31 +229,101 @@ impl Debug for PartitionEntry {
             .field("first_lba", &self.first_lba)
             .field("last_lba", &self.last_lba)
             .field("attribute_flags", &self.attribute_flags)
-            .field("name", &"Name") // TODO: parse name into a String on std
+            .field("name", &DecodedName(&self.name))
             .finish()
     }
 }
 
+// So we can print the name without allocating.
+struct DecodedName<'a>(&'a [u16; 36]);
+
+impl Debug for DecodedName<'_> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "\"")?;
+        for c in decode_name(self.0) {
+            write!(fmt, "{}", c.escape_debug())?;
+        }
+        write!(fmt, "\"")
+    }
+}
+
+impl Display for DecodedName<'_> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        decode_name(self.0).try_for_each(|c| write!(fmt, "{}", c))
+    }
+}
+
+/// Partition names are (de)serialized as text; see `PartitionEntry::fat`.
+#[cfg(feature = "serde")]
+mod partition_name {
+    use serde::de::{self, Deserializer, Visitor};
+    use serde::ser::Serializer;
+
+    use core::fmt;
+
+    pub fn serialize<S: Serializer>(name: &[u16; 36], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&super::DecodedName(name))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u16; 36], D::Error> {
+        struct NameVisitor;
+
+        impl<'de> Visitor<'de> for NameVisitor {
+            type Value = [u16; 36];
+
+            fn expecting(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(fmt, "a partition name")
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<[u16; 36], E> {
+                Ok(super::encode_name(s))
+            }
+        }
+
+        deserializer.deserialize_str(NameVisitor)
+    }
+}
+
+fn decode_name(name: &[u16; 36]) -> impl Iterator<Item = char> + '_ {
+    decode_utf16(name.iter().cloned().take_while(|c| *c != 0))
+        .map(|c| c.unwrap_or(REPLACEMENT_CHARACTER))
+}
+
+/// Names longer than 36 UTF-16 code units are truncated.
+fn encode_name(name: &str) -> [u16; 36] {
+    let mut buf = [0u16; 36];
+
+    for (b, c) in buf.iter_mut().zip(name.encode_utf16()) {
+        *b = c;
+    }
+
+    buf
+}
+
 impl PartitionEntry {
-    pub fn fat(beginning: u64, end: u64) -> Self {
+    /// Names longer than 36 UTF-16 code units are truncated.
+    pub fn fat(name: &str, beginning: u64, end: u64) -> Self {
         Self {
             partition_type: Guid::microsoft_basic_data(),
             unique_guid: Guid::from_mixed_u128(0x1234567890ABCDEF1234567890ABCDEFu128),
             first_lba: beginning,
             last_lba: end,
             attribute_flags: 0,
-            name: {
-                let name = "RTOS"; // TODO: not this.
-                let mut iter = name.encode_utf16();
-                let mut buf = [0u16; 36];
+            name: encode_name(name),
+        }
+    }
 
-                buf[0] = iter.next().unwrap();
-                buf[1] = iter.next().unwrap();
-                buf[2] = iter.next().unwrap();
-                buf[3] = iter.next().unwrap();
+    /// The partition's name, up to the first NUL. Invalid UTF-16 comes out as
+    /// `REPLACEMENT_CHARACTER`s.
+    pub fn name_chars(&self) -> impl Iterator<Item = char> + '_ {
+        decode_name(&self.name)
+    }
+}
 
-                buf
-            }
+using_std! {
+    impl PartitionEntry {
+        pub fn name_lossy(&self) -> String {
+            self.name_chars().collect()
         }
     }
 }
@@ -183,7 +332,7 @@ impl PartitionEntry {
 
 impl Gpt {
     #[inline(always)]
-    pub fn read_gpt<S: Storage<Word = u8, SECTOR_SIZE = U512>>(storage: &mut S) -> Result<Gpt, ()> {
+    pub fn read_gpt<S: Storage<Word = u8>>(storage: &mut S) -> Result<Gpt, ()> {
         let mut sector = GenericArray::default();
         storage.read_sector(1, &mut sector).unwrap(); // TODO: don't unwrap.
 
@@ -209,13 +358,30 @@ impl Gpt {
         })
     }
 
-    pub fn get_partition_entry<S: Storage<Word = u8, SECTOR_SIZE = U512>>(&self, storage: &mut S, idx: u32) -> Result<PartitionEntry, ()> {
-        if idx != 0 { unimplemented!() /* TODO!! Err on out of range, etc. */ }
+    pub fn num_partition_entries(&self) -> u32 {
+        self.num_partition_entries
+    }
+
+    /// Note that unused entries are still returned; they have a partition type
+    /// of all zeros.
+    pub fn get_partition_entry<S: Storage<Word = u8>>(&self, storage: &mut S, idx: u32) -> Result<PartitionEntry, ()> {
+        if idx >= self.num_partition_entries {
+            return Err(());
+        }
+
+        // Entries are at least 128 bytes and sectors are a power of two that's
+        // at least 512 bytes so entries never straddle sectors.
+        let sector_size = S::SECTOR_SIZE::to_u64();
+        let offset = (idx as u64) * (self.partition_entry_size as u64);
 
         let mut sector = GenericArray::default();
-        storage.read_sector(self.partition_entries_starting_lba as usize, &mut sector).unwrap(); // TODO: don't unwrap.
+        storage.read_sector(
+            (self.partition_entries_starting_lba + offset / sector_size) as usize,
+            &mut sector,
+        ).map_err(|_| ())?;
 
-        let entry = &sector.as_slice()[0..(self.partition_entry_size as usize)];
+        let offset = (offset % sector_size) as usize;
+        let entry = &sector.as_slice()[offset..(offset + self.partition_entry_size as usize)];
 
         Ok(PartitionEntry {
             partition_type: Guid::from_bytes(entry[0..16].try_into().unwrap()),
@@ -227,7 +393,7 @@ impl Gpt {
                 let mut buf = [0u16; 36];
 
                 for i in 0..36 {
-                    buf[i] = ((entry[48 + 2 * i + 1] as u16) << 8) | (entry[48 + 2 * i] as u16);
+                    buf[i] = ((entry[56 + 2 * i + 1] as u16) << 8) | (entry[56 + 2 * i] as u16);
                 }
 
                 buf
@@ -269,4 +435,58 @@ mod gpt_tests {
 
         trip(0xC12A7328_F81F_11D2_BA4B_00A0C93EC93Bu128);
     }
+
+    #[test]
+    fn partition_names() {
+        let p = PartitionEntry::fat("RTOS", 34, 1000);
+        assert_eq!(p.name_lossy(), "RTOS");
+        assert!(format!("{:?}", p).contains("name: \"RTOS\""));
+
+        assert_eq!(PartitionEntry::fat("", 34, 1000).name_lossy(), "");
+        assert_eq!(PartitionEntry::fat("données ✓", 34, 1000).name_lossy(), "données ✓");
+
+        let long = "a partition name that is longer than 36 units";
+        assert_eq!(PartitionEntry::fat(long, 34, 1000).name_lossy(), &long[..36]);
+
+        // An unpaired surrogate:
+        let mut p = PartitionEntry::fat("ab", 34, 1000);
+        p.name[1] = 0xD800;
+        assert_eq!(p.name_chars().collect::<String>(), "a\u{FFFD}");
+    }
+
+    #[test]
+    fn string_roundtrip() {
+        fn trip(s: &str, g: Guid) {
+            assert_eq!(s.parse::<Guid>(), Ok(g));
+            assert_eq!(format!("{}", g), s);
+        }
+
+        trip("EBD0A0A2-B9E5-4433-87C0-68B6B72699C7", Guid::microsoft_basic_data());
+        trip(
+            "C12A7328-F81F-11D2-BA4B-00A0C93EC93B",
+            Guid::from_bytes([0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b]),
+        );
+
+        assert_eq!(
+            "ebd0a0a2-b9e5-4433-87c0-68b6b72699c7".parse::<Guid>(),
+            Ok(Guid::microsoft_basic_data()),
+        );
+    }
+
+    #[test]
+    fn malformed_strings() {
+        assert_eq!("".parse::<Guid>(), Err(GuidParseError::WrongLength));
+        assert_eq!(
+            "EBD0A0A2-B9E5-4433-87C0-68B6B72699C".parse::<Guid>(),
+            Err(GuidParseError::WrongLength),
+        );
+        assert_eq!(
+            "EBD0A0A2_B9E5-4433-87C0-68B6B72699C7".parse::<Guid>(),
+            Err(GuidParseError::InvalidCharacter(8)),
+        );
+        assert_eq!(
+            "EBD0A0A2-B9E5-4433-87C0-68B6B72699CG".parse::<Guid>(),
+            Err(GuidParseError::InvalidCharacter(35)),
+        );
+    }
 }
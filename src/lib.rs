@@ -24,7 +24,16 @@ pub mod mutex;
 
 use storage_traits::Storage;
 
+pub mod storage;
+pub mod storage_adapters;
+
+#[cfg(feature = "embedded-sdmmc")]
+pub mod sdmmc;
+
 pub mod gpt;
 pub mod fat;
 
 pub mod util;
+
+#[cfg(feature = "test-util")]
+pub mod test_util;
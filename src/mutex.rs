@@ -6,12 +6,42 @@
 //! (We are okay with this because, as configured, we can't recover from panics
 //! on embedded anyways — our panic handler just spins forever.)
 
+/// The interface this crate expects of a Mutex.
+///
+/// This is public so that you can bring your own Mutex: implement this trait
+/// for your type and use it to wrap whatever you want to share (i.e. a global
+/// `FatFs`). To have the crate's own globals (the bindings' `STORAGE` and `FS`)
+/// use it too, enable the `custom_mutex` feature and register it with
+/// `register_mutex!` (see `custom::Mutex`). Enable the `stub_mutex` feature if
+/// none of the built-in Mutexes (`mutex::Mutex`) work on your platform.
+///
+/// Implementors must be `Sync`: the whole point is to let multiple threads of
+/// execution (or a thread and an interrupt handler) share the inner value. In
+/// practice this means an `unsafe impl<T: Send> Sync for YourMutex<T> {}`
+/// backed by whatever guarantees your critical sections give you.
+///
+/// Note that `new` can't be a `const fn` in a trait; to put your Mutex in a
+/// `static` give it an inherent `const fn` constructor as well (as the Mutexes
+/// in this module do).
 pub trait MutexInterface<T>: Sync {
     fn new(inner: T) -> Self;
 
     // Run a function n a critical section:
     fn cs<F: FnOnce(&mut T) -> R, R>(&self, func: F) -> R;
 
+    // Like `cs` but returns `None` instead of blocking if the Mutex is already
+    // locked. Useful in interrupt handlers which can't wait on the code they
+    // interrupted.
+    //
+    // This was added after the rest of the trait; the default impl is there
+    // so that existing impls keep building. It can't know whether `cs` would
+    // block so it never runs `func` and always returns `None` (which is never
+    // wrong, just unhelpful); override it if your Mutex can do better.
+    fn try_cs<F: FnOnce(&mut T) -> R, R>(&self, func: F) -> Option<R> {
+        let _ = func;
+        None
+    }
+
     // Get mutable access to the inner data *using a mutable reference*.
     // Since Rust can statically prove that we have exclusive access in this
     // case, no locking occurs.
@@ -31,6 +61,7 @@ pub mod from_std {
     use super::MutexInterface;
 
     pub use std::sync::Mutex;
+    use std::sync::TryLockError;
 
     impl<T: Send> MutexInterface<T> for Mutex<T> {
         fn new(inner: T) -> Self {
@@ -44,6 +75,17 @@ pub mod from_std {
             func(&mut *inner)
         }
 
+        #[inline]
+        fn try_cs<F: FnOnce(&mut T) -> R, R>(&self, func: F) -> Option<R> {
+            let mut inner = match self.try_lock() {
+                Ok(inner) => inner,
+                Err(TryLockError::Poisoned(p)) => p.into_inner(),
+                Err(TryLockError::WouldBlock) => return None,
+            };
+
+            Some(func(&mut *inner))
+        }
+
         #[inline]
         fn get_mut(&mut self) -> &mut T {
             self.get_mut().unwrap()
@@ -70,6 +112,8 @@ pub mod external_mutex {
     extern "C" {
         pub fn semaphore_init(s: *mut Semaphore, locked: u8);
         pub fn semaphore_wait(s: *mut Semaphore);
+        /// Returns non-zero if the semaphore was acquired.
+        pub fn semaphore_try_wait(s: *mut Semaphore) -> u8;
         pub fn semaphore_signal(s: *mut Semaphore);
     }
 
@@ -116,6 +160,19 @@ pub mod external_mutex {
             res
         }
 
+        #[inline]
+        fn try_cs<F: FnOnce(&mut T) -> R, R>(&self, func: F) -> Option<R> {
+            if unsafe { semaphore_try_wait(self.semaphore.as_ptr()) } == 0 {
+                return None;
+            }
+
+            let res = func(unsafe { &mut *self.inner.as_ptr() });
+
+            unsafe { semaphore_signal(self.semaphore.as_ptr()); }
+
+            Some(res)
+        }
+
         #[inline]
         fn get_mut(&mut self) -> &mut T {
             self.inner.get_mut()
@@ -126,6 +183,191 @@ pub mod external_mutex {
     unsafe impl<T> Sync for Mutex<T> where T: Send { }
 }
 
+/// A Mutex for single core systems where there's nothing to contend with.
+///
+/// `cs` just runs the closure; we assume that the caller has arranged for
+/// nothing else to run in the meantime (i.e. by disabling interrupts). If a
+/// critical section is entered while another is still running (i.e. from an
+/// interrupt handler) there's no one to wait for so we panic instead.
+///
+/// This exists so that users who bring their own `MutexInterface` impl don't
+/// have to satisfy one of the other built-in Mutexes; enable the `stub_mutex`
+/// feature to use it.
+#[cfg(feature = "stub_mutex")]
+pub mod stub {
+    use super::MutexInterface;
+
+    use core::cell::{Cell, UnsafeCell};
+
+    pub struct Mutex<T> {
+        locked: Cell<bool>,
+        inner: UnsafeCell<T>,
+    }
+
+    impl<T> Mutex<T> {
+        pub const fn new(inner: T) -> Self {
+            Self {
+                locked: Cell::new(false),
+                inner: UnsafeCell::new(inner),
+            }
+        }
+    }
+
+    impl<T: Send> MutexInterface<T> for Mutex<T> {
+        fn new(inner: T) -> Self {
+            Mutex::new(inner)
+        }
+
+        #[inline]
+        fn cs<F: FnOnce(&mut T) -> R, R>(&self, func: F) -> R {
+            if self.locked.replace(true) {
+                panic!("Entered a critical section on a stub Mutex that was already locked!");
+            }
+
+            let res = func(unsafe { &mut *self.inner.get() });
+
+            self.locked.set(false);
+
+            res
+        }
+
+        #[inline]
+        fn try_cs<F: FnOnce(&mut T) -> R, R>(&self, func: F) -> Option<R> {
+            if self.locked.replace(true) {
+                return None;
+            }
+
+            let res = func(unsafe { &mut *self.inner.get() });
+
+            self.locked.set(false);
+
+            Some(res)
+        }
+
+        #[inline]
+        fn get_mut(&mut self) -> &mut T {
+            self.inner.get_mut()
+        }
+    }
+
+    // Not really; see above. Users of this Mutex promise that there's only one
+    // thread of execution.
+    unsafe impl<T> Sync for Mutex<T> where T: Send { }
+}
+
+/// A Mutex whose locking is done by a `MutexInterface<()>` impl from outside
+/// this crate; enable the `custom_mutex` feature to use it as `mutex::Mutex`.
+///
+/// Register your lock (a `static`) with `register_mutex!`:
+///
+/// ```ignore
+/// static LOCK: MyMutex<()> = MyMutex::new(());
+/// fs::register_mutex!(LOCK);
+/// ```
+///
+/// Every `custom::Mutex` shares that one lock, but only for long enough to
+/// flip its own `locked` flag; `func` runs outside of it. So nesting
+/// critical sections on different `custom::Mutex`es (as the bindings do) is
+/// fine, and `cs` on a `custom::Mutex` that's already locked spins until it's
+/// unlocked.
+#[cfg(feature = "custom_mutex")]
+pub mod custom {
+    use super::MutexInterface;
+
+    use core::cell::{Cell, UnsafeCell};
+
+    extern "Rust" {
+        // Defined by `register_mutex!`.
+        fn __fatfs_custom_mutex_cs(func: &mut dyn FnMut());
+    }
+
+    /// Runs `func` in the registered lock's critical section.
+    fn registered_cs<R>(func: impl FnOnce() -> R) -> R {
+        let mut func = Some(func);
+        let mut res = None;
+
+        unsafe { __fatfs_custom_mutex_cs(&mut || res = func.take().map(|f| f())) }
+
+        res.expect("the registered Mutex to run the critical section")
+    }
+
+    pub struct Mutex<T> {
+        locked: Cell<bool>,
+        inner: UnsafeCell<T>,
+    }
+
+    impl<T> Mutex<T> {
+        pub const fn new(inner: T) -> Self {
+            Self {
+                locked: Cell::new(false),
+                inner: UnsafeCell::new(inner),
+            }
+        }
+
+        fn try_lock(&self) -> bool {
+            registered_cs(|| !self.locked.replace(true))
+        }
+
+        fn unlock(&self) {
+            registered_cs(|| self.locked.set(false))
+        }
+    }
+
+    impl<T: Send> MutexInterface<T> for Mutex<T> {
+        fn new(inner: T) -> Self {
+            Mutex::new(inner)
+        }
+
+        #[inline]
+        fn cs<F: FnOnce(&mut T) -> R, R>(&self, func: F) -> R {
+            while !self.try_lock() {
+                core::hint::spin_loop();
+            }
+
+            let res = func(unsafe { &mut *self.inner.get() });
+
+            self.unlock();
+
+            res
+        }
+
+        #[inline]
+        fn try_cs<F: FnOnce(&mut T) -> R, R>(&self, func: F) -> Option<R> {
+            if !self.try_lock() {
+                return None;
+            }
+
+            let res = func(unsafe { &mut *self.inner.get() });
+
+            self.unlock();
+
+            Some(res)
+        }
+
+        #[inline]
+        fn get_mut(&mut self) -> &mut T {
+            self.inner.get_mut()
+        }
+    }
+
+    // `locked` is only touched in the registered lock's critical section and
+    // `inner` only while `locked` is held.
+    unsafe impl<T> Sync for Mutex<T> where T: Send { }
+}
+
+/// Makes `$lock` (a `static` whose type implements `MutexInterface<()>`) the
+/// lock behind `custom::Mutex`. Use this exactly once, in the final binary.
+#[cfg(feature = "custom_mutex")]
+#[macro_export]
+macro_rules! register_mutex {
+    ($lock:path) => {
+        #[no_mangle]
+        fn __fatfs_custom_mutex_cs(func: &mut dyn FnMut()) {
+            $crate::mutex::MutexInterface::cs(&$lock, |_: &mut ()| func())
+        }
+    };
+}
+
 // We exclude this when external is enabled so that non-cortex M ARM users can
 // still build this crate: cortex_m should compile for them but it will not
 // actually provide the functions that we use below.
@@ -138,9 +380,8 @@ pub mod external_mutex {
 // A current consequence of the feature configuration is that even if you intend
 // to use your own Mutex implemented in Rust that implements the MutexInterface
 // trait, you must satisfy at least one of the built-in Mutexes (std,
-// bare-metal cortex-m, or external). If there's a need, in the future this
-// shortcoming can be remedied by having a default stub Mutex that just crashes
-// (TODO).
+// bare-metal cortex-m, or external) unless you enable the `custom_mutex` (see
+// `custom::Mutex`) or `stub_mutex` (see `stub::Mutex`) features.
 #[cfg(all(target_arch = "arm"))]
 pub mod bare_metal {
     use super::MutexInterface;
@@ -186,6 +427,12 @@ pub mod bare_metal {
             })
         }
 
+        // With interrupts off, there's nothing to contend with.
+        #[inline]
+        fn try_cs<F: FnOnce(&mut T) -> R, R>(&self, func: F) -> Option<R> {
+            Some(self.cs(func))
+        }
+
         #[inline]
         fn get_mut(&mut self) -> &mut T {
             self.inner.get_mut()
@@ -204,17 +451,25 @@ pub mod bare_metal {
 // Other |    std |    bindings | → default mutex = std, or external (on feat)
 // Other | no_std | no bindings | → default mutex = error, external (on feat)
 // Other | no_std |    bindings | → default mutex = error, external (on feat)
+//
+// The `stub_mutex` feature overrides all of the defaults above (but not
+// `external_mutex`). The `custom_mutex` feature overrides everything, including
+// `external_mutex`.
 
 cfg_if::cfg_if! {
-    if #[cfg(feature = "external_mutex")] {
+    if #[cfg(feature = "custom_mutex")] {
+        pub use custom::Mutex;
+    } else if #[cfg(feature = "external_mutex")] {
         pub use external_mutex::Mutex;
+    } else if #[cfg(feature = "stub_mutex")] {
+        pub use stub::Mutex;
     } else if #[cfg(all(target_arch = "arm", feature = "no_std"))] {
         pub use bare_metal::Mutex;
     } else if #[cfg(not(feature = "no_std"))] {
         pub use from_std::Mutex;
     } else if #[cfg(feature = "no_std")] {
         compile_error!("Please enable the `external-mutex` feature and provide \
-            a Mutex implementation.");
+            a Mutex implementation (or enable the `stub_mutex` feature).");
     } else {
         compile_error!("Unreachable!!");
     }
@@ -0,0 +1,76 @@
+//! Interop with the `embedded-sdmmc` crate (behind the `embedded-sdmmc`
+//! feature).
+
+use super::Storage;
+use super::storage::ReadWriteError;
+
+use embedded_sdmmc::{Block, BlockCount, BlockDevice, BlockIdx};
+use generic_array::GenericArray;
+use typenum::consts::U512;
+
+use core::cell::RefCell;
+use core::convert::TryInto;
+use core::fmt::Debug;
+
+/// Lets `embedded-sdmmc` (or anything else that takes a `BlockDevice`) use
+/// a `Storage` impl with 512 byte sectors; blocks map one-to-one onto sectors.
+///
+/// `BlockDevice` methods only get `&self` so the storage lives in a `RefCell`;
+/// calls that come in while another is still going (i.e. from an interrupt
+/// handler) panic.
+#[derive(Debug)]
+pub struct BlockDeviceAdapter<S: Storage<Word = u8, SECTOR_SIZE = U512>> {
+    inner: RefCell<S>,
+}
+
+impl<S: Storage<Word = u8, SECTOR_SIZE = U512>> BlockDeviceAdapter<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner: RefCell::new(inner) }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner.into_inner()
+    }
+}
+
+impl<S> BlockDevice for BlockDeviceAdapter<S>
+where
+    S: Storage<Word = u8, SECTOR_SIZE = U512>,
+    S::ReadErr: Debug,
+    S::WriteErr: Debug,
+{
+    type Error = ReadWriteError<S::ReadErr, S::WriteErr>;
+
+    fn read(&self, blocks: &mut [Block], start_block_idx: BlockIdx, _reason: &str) -> Result<(), Self::Error> {
+        let mut inner = self.inner.borrow_mut();
+
+        for (idx, block) in blocks.iter_mut().enumerate() {
+            inner.read_sector(
+                (start_block_idx.0 as usize) + idx,
+                GenericArray::from_mut_slice(&mut block.contents),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        let mut inner = self.inner.borrow_mut();
+
+        for (idx, block) in blocks.iter().enumerate() {
+            inner.write_sector(
+                (start_block_idx.0 as usize) + idx,
+                GenericArray::from_slice(&block.contents),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Storage with more than `u32::max_value()` sectors is cut short.
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        let capacity = self.inner.borrow().capacity();
+
+        Ok(BlockCount(capacity.try_into().unwrap_or(u32::max_value())))
+    }
+}
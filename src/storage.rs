@@ -0,0 +1,328 @@
+//! Extensions to the `Storage` trait.
+//!
+//! `Storage` lives in the `storage_traits` crate and only gives us single
+//! sector reads and writes; the things in here are built on top of those.
+//!
+//! These are provided methods on a trait that has to be implemented
+//! explicitly (rather than blanket implemented for every `Storage`) so that
+//! backends that can do better than the defaults (i.e. `EDiskStorage`) can
+//! override them.
+
+use super::Storage;
+
+use storage_traits::errors::{ReadError, WriteError};
+use generic_array::GenericArray;
+use typenum::marker_traits::Unsigned;
+
+use core::cmp::min;
+use core::convert::Infallible;
+use core::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EraseError<W, T = Infallible> {
+    /// Zeroing one of the sectors failed.
+    ErrorInIndividualErase(WriteError<W>),
+    /// Backend specific erase error.
+    Other(T),
+}
+
+impl<W, T> From<WriteError<W>> for EraseError<W, T> {
+    fn from(err: WriteError<W>) -> Self {
+        EraseError::ErrorInIndividualErase(err)
+    }
+}
+
+/// Errors for operations (like read-modify-writes) that both read and write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadWriteError<R, W> {
+    Read(ReadError<R>),
+    Write(WriteError<W>),
+}
+
+impl<R, W> From<ReadError<R>> for ReadWriteError<R, W> {
+    fn from(err: ReadError<R>) -> Self {
+        ReadWriteError::Read(err)
+    }
+}
+
+impl<R, W> From<WriteError<W>> for ReadWriteError<R, W> {
+    fn from(err: WriteError<W>) -> Self {
+        ReadWriteError::Write(err)
+    }
+}
+
+/// What kind of failure a `Storage` error represents, as far as the layers
+/// above are concerned.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StorageKind {
+    /// The operation might succeed if tried again (i.e. the medium wasn't
+    /// ready).
+    Retryable,
+    /// The medium can't be written to; trying again won't help.
+    WriteProtected,
+    /// Anything else.
+    Fatal,
+}
+
+/// Implemented by `Storage` error types so that callers can tell errors that
+/// are worth retrying apart from ones that aren't.
+pub trait ClassifyError {
+    fn kind(&self) -> StorageKind;
+}
+
+impl ClassifyError for () {
+    fn kind(&self) -> StorageKind { StorageKind::Fatal }
+}
+
+impl ClassifyError for Infallible {
+    fn kind(&self) -> StorageKind { match *self { } }
+}
+
+impl<E: ClassifyError> ClassifyError for ReadError<E> {
+    fn kind(&self) -> StorageKind {
+        match self {
+            ReadError::Other(e) => e.kind(),
+            _ => StorageKind::Fatal,
+        }
+    }
+}
+
+impl<E: ClassifyError> ClassifyError for WriteError<E> {
+    fn kind(&self) -> StorageKind {
+        match self {
+            WriteError::Other(e) => e.kind(),
+            _ => StorageKind::Fatal,
+        }
+    }
+}
+
+/// Calls `func` until it succeeds, it fails with an error that isn't
+/// `StorageKind::Retryable`, or it has been retried `max_retries` times.
+///
+/// Returns the result of the last call.
+pub fn retry<T, E: ClassifyError>(max_retries: u8, mut func: impl FnMut() -> Result<T, E>) -> Result<T, E> {
+    let mut retries = 0;
+
+    loop {
+        match func() {
+            Err(ref e) if e.kind() == StorageKind::Retryable && retries < max_retries => {
+                retries += 1;
+            },
+            res => return res,
+        }
+    }
+}
+
+pub trait StorageExt: Storage<Word = u8> {
+    /// Number of sectors in the storage medium.
+    fn sector_count(&self) -> usize {
+        self.capacity()
+    }
+
+    /// Reads `buffers.len()` sectors, starting at sector `start`, into
+    /// `buffers`.
+    ///
+    /// The default impl reads one sector at a time; backends that can read a
+    /// run of sectors in one go should override this.
+    fn read_sectors(
+        &mut self,
+        start: usize,
+        buffers: &mut [GenericArray<u8, Self::SECTOR_SIZE>],
+    ) -> Result<(), ReadError<Self::ReadErr>> {
+        for (idx, buffer) in buffers.iter_mut().enumerate() {
+            self.read_sector(start + idx, buffer)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes `buffers` to the `buffers.len()` sectors starting at sector
+    /// `start`.
+    ///
+    /// As with `read_sectors`, the default impl goes one sector at a time.
+    fn write_sectors(
+        &mut self,
+        start: usize,
+        buffers: &[GenericArray<u8, Self::SECTOR_SIZE>],
+    ) -> Result<(), WriteError<Self::WriteErr>> {
+        for (idx, buffer) in buffers.iter().enumerate() {
+            self.write_sector(start + idx, buffer)?;
+        }
+
+        Ok(())
+    }
+
+    /// Zeros every sector.
+    fn erase(&mut self) -> Result<(), EraseError<Self::WriteErr>> {
+        let count = self.sector_count();
+        self.erase_range(0..count)
+    }
+
+    /// Zeros the sectors in `range`.
+    ///
+    /// The default impl writes one sector at a time; backends that can zero
+    /// a run of sectors in one go should override this.
+    fn erase_range(&mut self, range: Range<usize>) -> Result<(), EraseError<Self::WriteErr>> {
+        let zeros = GenericArray::default();
+
+        for idx in range {
+            self.write_sector(idx, &zeros)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes `buffer` starting at byte `offset`.
+    ///
+    /// Sectors that are only partially covered by `buffer` (i.e. the first
+    /// and last sectors for unaligned writes) are read, modified, and written
+    /// back; sectors that are entirely covered are just written.
+    fn write_bytes(
+        &mut self,
+        offset: usize,
+        buffer: &[u8],
+    ) -> Result<(), ReadWriteError<Self::ReadErr, Self::WriteErr>> {
+        let sector_size = Self::SECTOR_SIZE::to_usize();
+
+        let mut sector_idx = offset / sector_size;
+        let mut offset = offset % sector_size;
+        let mut remaining = buffer;
+
+        let mut sector = GenericArray::default();
+
+        while !remaining.is_empty() {
+            let len = min(sector_size - offset, remaining.len());
+            let (chunk, rest) = remaining.split_at(len);
+
+            if len == sector_size {
+                self.write_sector(sector_idx, GenericArray::from_slice(chunk))?;
+            } else {
+                self.read_sector(sector_idx, &mut sector)?;
+                sector[offset..(offset + len)].copy_from_slice(chunk);
+                self.write_sector(sector_idx, &sector)?;
+            }
+
+            sector_idx += 1;
+            offset = 0;
+            remaining = rest;
+        }
+
+        Ok(())
+    }
+}
+
+using_std! {
+    impl StorageExt for storage_traits::FileBackedStorage { }
+}
+
+#[cfg(feature = "test-util")]
+pub use ram::RamStorage;
+
+#[cfg(feature = "test-util")]
+mod ram {
+    use super::{Storage, StorageExt};
+
+    use storage_traits::errors::{ReadError, WriteError};
+    use generic_array::{ArrayLength, GenericArray};
+    use typenum::consts::U512;
+    use typenum::marker_traits::Unsigned;
+
+    /// `Storage` that lives entirely in memory; handy for tests that want to
+    /// build a volume without touching the filesystem.
+    ///
+    /// `N` is the number of (512 byte) sectors. The sectors live in a fixed
+    /// size array so this works without `std` too.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct RamStorage<N: ArrayLength<GenericArray<u8, U512>>> {
+        sectors: GenericArray<GenericArray<u8, U512>, N>,
+    }
+
+    impl<N: ArrayLength<GenericArray<u8, U512>>> RamStorage<N> {
+        /// Zeroed storage.
+        pub fn new() -> Self {
+            Self { sectors: GenericArray::default() }
+        }
+
+        /// Storage whose first `bytes.len()` bytes are `bytes`; the rest is
+        /// zeroed.
+        ///
+        /// Errors if `bytes` doesn't fit.
+        pub fn from_bytes(bytes: &[u8]) -> Result<Self, ()> {
+            if bytes.len() > N::to_usize() * U512::to_usize() {
+                return Err(());
+            }
+
+            let mut s = Self::new();
+            for (sector, chunk) in s.sectors.iter_mut().zip(bytes.chunks(U512::to_usize())) {
+                sector[..chunk.len()].copy_from_slice(chunk);
+            }
+
+            Ok(s)
+        }
+
+        using_std! {
+            pub fn into_bytes(self) -> Vec<u8> {
+                self.sectors.iter().flat_map(|s| s.iter().cloned()).collect()
+            }
+        }
+
+        pub fn sector(&self, idx: usize) -> &[u8] {
+            &self.sectors[idx]
+        }
+
+        pub fn sector_mut(&mut self, idx: usize) -> &mut [u8] {
+            &mut self.sectors[idx]
+        }
+    }
+
+    impl<N: ArrayLength<GenericArray<u8, U512>>> Default for RamStorage<N> {
+        fn default() -> Self { Self::new() }
+    }
+
+    impl<N: ArrayLength<GenericArray<u8, U512>>> Storage for RamStorage<N> {
+        type Word = u8;
+        type SECTOR_SIZE = U512;
+
+        type ReadErr = ();
+        type WriteErr = ();
+
+        fn capacity(&self) -> usize {
+            N::to_usize()
+        }
+
+        fn read_sector(
+            &mut self,
+            sector_idx: usize,
+            buffer: &mut GenericArray<u8, U512>,
+        ) -> Result<(), ReadError<()>> {
+            if sector_idx >= N::to_usize() {
+                return Err(ReadError::OutOfRange {
+                    requested_offset: sector_idx,
+                    max_offset: N::to_usize(),
+                });
+            }
+
+            buffer.copy_from_slice(&self.sectors[sector_idx]);
+            Ok(())
+        }
+
+        fn write_sector(
+            &mut self,
+            sector_idx: usize,
+            words: &GenericArray<u8, U512>,
+        ) -> Result<(), WriteError<()>> {
+            if sector_idx >= N::to_usize() {
+                return Err(WriteError::OutOfRange {
+                    requested_offset: sector_idx,
+                    max_offset: N::to_usize(),
+                });
+            }
+
+            self.sectors[sector_idx].copy_from_slice(words);
+            Ok(())
+        }
+    }
+
+    impl<N: ArrayLength<GenericArray<u8, U512>>> StorageExt for RamStorage<N> { }
+}
@@ -0,0 +1,240 @@
+//! `Storage` impls that wrap other `Storage` impls to change how they behave.
+
+use super::Storage;
+use super::storage::{self, ClassifyError, StorageExt, StorageKind};
+
+use storage_traits::errors::{ReadError, WriteError};
+use generic_array::{ArrayLength, GenericArray};
+use typenum::{Prod, consts::{U1, U2, U4}, marker_traits::Unsigned};
+
+use core::convert::TryInto;
+use core::ops::Mul;
+
+/// Retries reads and writes that fail with errors that are classified as
+/// retryable (see `storage::StorageKind`), up to `max_retries` times.
+///
+/// Useful for media that fail intermittently (i.e. SD cards over SPI).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryStorage<S: Storage<Word = u8>> {
+    pub inner: S,
+    pub max_retries: u8,
+}
+
+impl<S: Storage<Word = u8>> RetryStorage<S> {
+    pub fn new(inner: S, max_retries: u8) -> Self {
+        Self { inner, max_retries }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S> Storage for RetryStorage<S>
+where
+    S: Storage<Word = u8>,
+    S::ReadErr: ClassifyError,
+    S::WriteErr: ClassifyError,
+{
+    type Word = u8;
+    type SECTOR_SIZE = S::SECTOR_SIZE;
+
+    type ReadErr = S::ReadErr;
+    type WriteErr = S::WriteErr;
+
+    fn capacity(&self) -> usize {
+        self.inner.capacity()
+    }
+
+    fn read_sector(
+        &mut self,
+        sector_idx: usize,
+        buffer: &mut GenericArray<u8, S::SECTOR_SIZE>,
+    ) -> Result<(), ReadError<S::ReadErr>> {
+        let inner = &mut self.inner;
+        storage::retry(self.max_retries, || inner.read_sector(sector_idx, &mut *buffer))
+    }
+
+    fn write_sector(
+        &mut self,
+        sector_idx: usize,
+        words: &GenericArray<u8, S::SECTOR_SIZE>,
+    ) -> Result<(), WriteError<S::WriteErr>> {
+        let inner = &mut self.inner;
+        storage::retry(self.max_retries, || inner.write_sector(sector_idx, words))
+    }
+}
+
+impl<S> StorageExt for RetryStorage<S>
+where
+    S: Storage<Word = u8>,
+    S::ReadErr: ClassifyError,
+    S::WriteErr: ClassifyError,
+{ }
+
+/// The error `ReadOnly` gives for every write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ReadOnlyViolation;
+
+impl ClassifyError for ReadOnlyViolation {
+    fn kind(&self) -> StorageKind { StorageKind::WriteProtected }
+}
+
+/// Passes reads through to `inner` and rejects all writes.
+///
+/// Mounting an untrusted image through this makes accidental modifications
+/// fail (when the modified sectors are written out) instead of changing the
+/// image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadOnly<S: Storage<Word = u8>> {
+    pub inner: S,
+}
+
+impl<S: Storage<Word = u8>> ReadOnly<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: Storage<Word = u8>> Storage for ReadOnly<S> {
+    type Word = u8;
+    type SECTOR_SIZE = S::SECTOR_SIZE;
+
+    type ReadErr = S::ReadErr;
+    type WriteErr = ReadOnlyViolation;
+
+    fn capacity(&self) -> usize {
+        self.inner.capacity()
+    }
+
+    fn read_sector(
+        &mut self,
+        sector_idx: usize,
+        buffer: &mut GenericArray<u8, S::SECTOR_SIZE>,
+    ) -> Result<(), ReadError<S::ReadErr>> {
+        self.inner.read_sector(sector_idx, buffer)
+    }
+
+    fn write_sector(
+        &mut self,
+        _sector_idx: usize,
+        _words: &GenericArray<u8, S::SECTOR_SIZE>,
+    ) -> Result<(), WriteError<ReadOnlyViolation>> {
+        Err(WriteError::Other(ReadOnlyViolation))
+    }
+}
+
+impl<S: Storage<Word = u8>> StorageExt for ReadOnly<S> { }
+
+/// Words (the `Storage::Word` type) that `ByteStorage` knows how to split into
+/// bytes and put back together. Words are little endian.
+pub trait Word: Copy + Default {
+    /// The size of the word in bytes, as a type.
+    type Size: Unsigned;
+
+    /// `bytes` is `Size` bytes long.
+    fn read_le(bytes: &[u8]) -> Self;
+
+    /// `bytes` is `Size` bytes long.
+    fn write_le(self, bytes: &mut [u8]);
+}
+
+macro_rules! word {
+    ($($ty:ty => $size:ty),* $(,)?) => {$(
+        impl Word for $ty {
+            type Size = $size;
+
+            fn read_le(bytes: &[u8]) -> Self {
+                <$ty>::from_le_bytes(bytes.try_into().unwrap())
+            }
+
+            fn write_le(self, bytes: &mut [u8]) {
+                bytes.copy_from_slice(&<$ty>::to_le_bytes(self))
+            }
+        }
+    )*};
+}
+
+word! { u8 => U1, u16 => U2, u32 => U4 }
+
+/// Presents storage with wider words (i.e. flash controllers that can only do
+/// 16 or 32 bit accesses) as the byte oriented storage (`Word = u8`) that the
+/// rest of this crate uses.
+///
+/// This is the only place that converts between words and bytes: everything
+/// else (the `SectorCache` and `FatFs` included) works on bytes. A sector of
+/// `N` words becomes a sector of `N * size_of::<Word>()` bytes, with each word
+/// split up little endian first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ByteStorage<S: Storage> {
+    pub inner: S,
+}
+
+impl<S: Storage> ByteStorage<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S> Storage for ByteStorage<S>
+where
+    S: Storage,
+    S::Word: Word,
+    S::SECTOR_SIZE: Mul<<S::Word as Word>::Size>,
+    Prod<S::SECTOR_SIZE, <S::Word as Word>::Size>: ArrayLength<u8>,
+{
+    type Word = u8;
+    type SECTOR_SIZE = Prod<S::SECTOR_SIZE, <S::Word as Word>::Size>;
+
+    type ReadErr = S::ReadErr;
+    type WriteErr = S::WriteErr;
+
+    fn capacity(&self) -> usize {
+        self.inner.capacity()
+    }
+
+    fn read_sector(
+        &mut self,
+        sector_idx: usize,
+        buffer: &mut GenericArray<u8, Self::SECTOR_SIZE>,
+    ) -> Result<(), ReadError<S::ReadErr>> {
+        let mut words = GenericArray::<S::Word, S::SECTOR_SIZE>::default();
+        self.inner.read_sector(sector_idx, &mut words)?;
+
+        let size = <S::Word as Word>::Size::to_usize();
+        for (word, bytes) in words.iter().zip(buffer.chunks_mut(size)) {
+            word.write_le(bytes);
+        }
+
+        Ok(())
+    }
+
+    fn write_sector(
+        &mut self,
+        sector_idx: usize,
+        words: &GenericArray<u8, Self::SECTOR_SIZE>,
+    ) -> Result<(), WriteError<S::WriteErr>> {
+        let size = <S::Word as Word>::Size::to_usize();
+        let words: GenericArray<S::Word, S::SECTOR_SIZE> = words.chunks(size)
+            .map(<S::Word as Word>::read_le)
+            .collect();
+
+        self.inner.write_sector(sector_idx, &words)
+    }
+}
+
+impl<S> StorageExt for ByteStorage<S>
+where
+    S: Storage,
+    S::Word: Word,
+    S::SECTOR_SIZE: Mul<<S::Word as Word>::Size>,
+    Prod<S::SECTOR_SIZE, <S::Word as Word>::Size>: ArrayLength<u8>,
+{ }
@@ -0,0 +1,49 @@
+//! Things for tests (and examples) that don't want to depend on disk images
+//! or devices being around; behind the `test-util` feature.
+
+use super::fat::{FatFs, cache::eviction_policies::UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED};
+use super::gpt::PartitionEntry;
+use super::storage::RamStorage;
+
+use typenum::consts::{U8, U24};
+
+/// A small (24 sector, 12 KiB) volume with a few files on it.
+///
+/// It's FAT12 rather than FAT32: FAT32 volumes need at least 65525 clusters
+/// which doesn't fit in a few KiB. It's a bare volume (no partition table)
+/// with 512 byte sectors and clusters, two FATs, and a 16 entry root
+/// directory. The volume label is `GOLDEN`.
+pub static GOLDEN_IMAGE: &[u8] = include_bytes!("../assets/golden.img");
+
+/// What `list_dir` should give for the golden volume's root directory, in
+/// order.
+pub const GOLDEN_ROOT_DIR: &[&str] = &["HELLO.TXT", "LOREM.TXT", "DOCS", "EMPTY.TXT"];
+
+/// Every file on the golden volume, with its size and CRC-32.
+///
+/// `DOCS/NOTES.TXT` is fragmented (clusters 7 and 9); `LOREM.TXT` spans three
+/// contiguous clusters.
+pub const GOLDEN_FILES: &[(&str, u32, u32)] = &[
+    ("/HELLO.TXT", 14, 2069210904),
+    ("/LOREM.TXT", 1300, 2738070562),
+    ("/EMPTY.TXT", 0, 0),
+    ("/DOCS/NOTES.TXT", 700, 1740975439),
+    ("/DOCS/TODO.TXT", 19, 2079861948),
+];
+
+pub type GoldenStorage = RamStorage<U24>;
+pub type GoldenFs = FatFs<GoldenStorage, U8>;
+
+/// The golden image (see `GOLDEN_IMAGE`) in `RamStorage`, mounted.
+///
+/// The storage is separate from the `FatFs` (as always); changes only affect
+/// this copy of the image.
+pub fn golden_volume() -> (GoldenStorage, GoldenFs) {
+    let mut s = GoldenStorage::from_bytes(GOLDEN_IMAGE).expect("the golden image fits");
+    let partition = PartitionEntry::fat("GOLDEN", 0, (GOLDEN_IMAGE.len() / 512) as u64 - 1);
+
+    let fs = FatFs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED)
+        .expect("the golden image mounts");
+
+    (s, fs)
+}
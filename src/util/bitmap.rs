@@ -6,7 +6,7 @@ use generic_array::{ArrayLength, GenericArray};
 use typenum::consts::{U8, U1};
 use typenum::marker_traits::Unsigned;
 
-use core::ops::{Add, Div};
+use core::ops::{Add, Div, Range};
 use core::marker::PhantomData;
 
 pub trait BitMapLen
@@ -88,10 +88,30 @@ impl<LEN: BitMapLen> BitMap<LEN> {
     }
 
     pub fn clear_all(&mut self) {
-        // Optimizer, save us.
-        for b in 0..self.length() {
-            let _ = self.set(b, false).unwrap();
+        self.arr.iter_mut().for_each(|b| *b = 0);
+
+        self.num_free_bits = self.length;
+        self.next_free = 0;
+    }
+
+    /// Sets every bit. The padding bits past `length` (in the last byte and in
+    /// the spare byte at the end of `arr`) are left as 0s.
+    pub fn set_all(&mut self) {
+        let full_bytes = self.length / 8;
+        let leftover = self.length % 8;
+
+        for (idx, b) in self.arr.iter_mut().enumerate() {
+            *b = if idx < full_bytes {
+                0xFF
+            } else if idx == full_bytes {
+                ((1u16 << leftover) - 1) as u8
+            } else {
+                0
+            };
         }
+
+        self.num_free_bits = 0;
+        self.next_free = 0;
     }
 
     // Returns `Ok` if in bounds and `Err` otherwise.
@@ -139,6 +159,34 @@ impl<LEN: BitMapLen> BitMap<LEN> {
         })
     }
 
+    /// The indexes of the bits that are set, in order.
+    pub fn iter_set_bits<'a>(&'a self) -> impl Iterator<Item = usize> + 'a {
+        (0..self.length).filter(move |b| self.get(*b) == Ok(true))
+    }
+
+    /// Number of set bits in `range`. The parts of `range` past the end of the
+    /// map are ignored.
+    pub fn count_ones_in(&self, range: Range<usize>) -> usize {
+        let start = range.start;
+        let end = core::cmp::min(range.end, self.length);
+        if start >= end {
+            return 0;
+        }
+
+        let (first, last) = (start / 8, (end - 1) / 8);
+        let (head, tail) = ((start % 8) as u32, ((end - 1) % 8) as u32 + 1);
+
+        if first == last {
+            return self.arr[first].bits(head..tail).count_ones() as usize;
+        }
+
+        let middle: u32 = self.arr[(first + 1)..last].iter().map(|b| b.count_ones()).sum();
+
+        (self.arr[first].bits(head..8).count_ones() +
+            middle +
+            self.arr[last].bits(0..tail).count_ones()) as usize
+    }
+
     // Returns `Err` if there are no empty bits available.
     pub fn next_empty_bit(&mut self) -> Result<usize, ()> {
         // The only way this get can fail is if the length is 0. If this happens
@@ -152,22 +200,51 @@ impl<LEN: BitMapLen> BitMap<LEN> {
                 return Err(());
             }
 
-            for b in (self.next_free..self.length()).chain(0..self.next_free) {
-                if self.get(b).unwrap() == false {
-                    self.next_free = b;
-                    return Ok(b);
-                }
+            let found = self.first_empty_bit_in(self.next_free..self.length())
+                .or_else(|| self.first_empty_bit_in(0..self.next_free));
+
+            if let Some(b) = found {
+                self.next_free = b;
+                Ok(b)
+            } else {
+                Err(())
+            }
+        }
+    }
+
+    // Looks a byte at a time for the first empty bit in `range`.
+    fn first_empty_bit_in(&self, range: Range<usize>) -> Option<usize> {
+        let end = core::cmp::min(range.end, self.length);
+        if range.start >= end {
+            return None;
+        }
+
+        let (first, last) = (range.start / 8, (end - 1) / 8);
+
+        for idx in first..=last {
+            let mut byte = self.arr[idx];
+
+            // Bits outside of `range` count as set.
+            if idx == first {
+                byte |= ((1u16 << (range.start % 8)) - 1) as u8;
+            }
+            if idx == last {
+                byte |= !(((1u16 << ((end - 1) % 8 + 1)) - 1) as u8);
             }
 
-            Err(())
+            if byte != 0xFF {
+                return Some(idx * 8 + (!byte).trailing_zeros() as usize);
+            }
         }
+
+        None
     }
 }
 
 #[cfg(test)]
 mod bitmap {
     use super::*;
-    use typenum::consts::U31;
+    use typenum::consts::{U16384, U31};
 
     use assert_eq as eq;
 
@@ -203,4 +280,107 @@ mod bitmap {
         eq!(b.empty_bits(), 31);
         eq!(b.length(), 31);
     }
+
+    #[test]
+    fn clear_and_set_all() {
+        let mut b = BitMap::<U31>::new();
+
+        for idx in [0, 3, 8, 9, 17, 30].iter() {
+            b.set(*idx, true).unwrap();
+        }
+        eq!(b.empty_bits(), 25);
+
+        b.clear_all();
+        eq!(b.empty_bits(), b.length());
+        eq!(b.next_empty_bit(), Ok(0));
+        assert!((0..31).all(|idx| b.get(idx) == Ok(false)));
+
+        b.set_all();
+        eq!(b.empty_bits(), 0);
+        eq!(b.next_empty_bit(), Err(()));
+        assert!((0..31).all(|idx| b.get(idx) == Ok(true)));
+
+        // The padding is left alone, so this looks just like a map that had
+        // every bit set one at a time:
+        let mut one_at_a_time = BitMap::<U31>::new();
+        for idx in 0..31 { one_at_a_time.set(idx, true).unwrap(); }
+        eq!(b, one_at_a_time);
+
+        // Clearing a bit makes it the next empty one:
+        b.set(12, false).unwrap();
+        eq!(b.next_empty_bit(), Ok(12));
+    }
+
+    #[test]
+    fn set_bits_and_counts() {
+        let mut b = BitMap::<U31>::new();
+        let set = [0, 3, 7, 8, 9, 15, 16, 23, 24, 30];
+
+        for idx in set.iter() {
+            b.set(*idx, true).unwrap();
+        }
+
+        eq!(b.iter_set_bits().collect::<Vec<_>>(), set.to_vec());
+
+        let count = |range: Range<usize>| set.iter().filter(|idx| range.contains(*idx)).count();
+
+        // Whole map, whole bytes, within a byte, and across bytes:
+        eq!(b.count_ones_in(0..31), 10);
+        eq!(b.count_ones_in(8..16), 3);
+        eq!(b.count_ones_in(1..7), 1);
+        eq!(b.count_ones_in(4..4), 0);
+        eq!(b.count_ones_in(5..27), count(5..27));
+        eq!(b.count_ones_in(7..9), 2);
+
+        for start in 0..31 {
+            for end in start..31 {
+                eq!(b.count_ones_in(start..end), count(start..end));
+            }
+        }
+
+        // Past the end is ignored:
+        eq!(b.count_ones_in(20..100), 3);
+        eq!(b.count_ones_in(40..100), 0);
+
+        eq!(BitMap::<U31>::new().iter_set_bits().next(), None);
+    }
+
+    #[test]
+    fn next_empty_bit_in_a_big_full_map() {
+        let mut b = BitMap::<U16384>::new();
+        b.set_all();
+        b.set(16_000, false).unwrap();
+
+        // Make it sweep from the start:
+        b.next_free = 3;
+        eq!(b.next_empty_bit(), Ok(16_000));
+
+        // And wrap around:
+        b.set(5, false).unwrap();
+        b.next_free = 16_001;
+        eq!(b.next_empty_bit(), Ok(5));
+    }
+
+    #[test]
+    fn next_empty_bit_matches_a_bit_at_a_time_sweep() {
+        let mut b = BitMap::<U31>::new();
+        b.set_all();
+
+        let sweep = |b: &BitMap<U31>, from: usize| {
+            (from..31).chain(0..from).find(|idx| b.get(*idx) == Ok(false))
+        };
+
+        for empty in 0..31 {
+            b.set(empty, false).unwrap();
+
+            for from in 0..31 {
+                let mut c = b.clone();
+                c.next_free = from;
+
+                eq!(c.next_empty_bit().ok(), sweep(&b, from));
+            }
+
+            b.set(empty, true).unwrap();
+        }
+    }
 }
@@ -1,23 +1,68 @@
 //! Home of the `Bits` trait.
 
+use core::ops::Range;
+
 // TODO: Grab the full version of this from lc3_isa and make it it's own crate.
-// (add in the set functionality here, unify the Sized + Copy stuff by putting
-// those bounds only on the functions that need them)
+// (unify the Sized + Copy stuff by putting those bounds only on the functions
+// that need them)
 pub trait Bits {
     fn bit(&self, b: u32) -> bool;
     fn b(&self, b: u32) -> bool { self.bit(b) }
 
     fn set_bit(&mut self, b: u32, v: bool);
+
+    /// The bits in `range`, shifted down so that `range.start` is bit 0.
+    ///
+    /// Panics if `range` goes past the end of the type.
+    fn bits(&self, range: Range<u32>) -> Self where Self: Sized;
+
+    /// Replaces the bits in `range` with the low bits of `value`; the rest of
+    /// `value` is ignored.
+    ///
+    /// Panics if `range` goes past the end of the type.
+    fn set_bits(&mut self, range: Range<u32>, value: Self) where Self: Sized;
 }
 
-impl Bits for u8 {
-    fn bit(&self, b: u32) -> bool {
-        ((*self >> b) & 1) == 1
-    }
+macro_rules! bits {
+    ($($ty:ty),* $(,)?) => {$(
+        impl Bits for $ty {
+            fn bit(&self, b: u32) -> bool {
+                ((*self >> b) & 1) == 1
+            }
 
-    fn set_bit(&mut self, b: u32, v: bool) {
-        *self = (*self & !(1 << b)) | (((v as Self) << b) as Self);
-    }
+            fn set_bit(&mut self, b: u32, v: bool) {
+                *self = (*self & !(1 << b)) | (((v as Self) << b) as Self);
+            }
+
+            fn bits(&self, range: Range<u32>) -> Self {
+                const WIDTH: u32 = (core::mem::size_of::<$ty>() * 8) as u32;
+
+                let (start, len) = span(range, WIDTH);
+                if len == 0 { return 0 }
+
+                (*self >> start) & (Self::max_value() >> (WIDTH - len))
+            }
+
+            fn set_bits(&mut self, range: Range<u32>, value: Self) {
+                const WIDTH: u32 = (core::mem::size_of::<$ty>() * 8) as u32;
+
+                let (start, len) = span(range, WIDTH);
+                if len == 0 { return }
+
+                let mask = Self::max_value() >> (WIDTH - len);
+                *self = (*self & !(mask << start)) | ((value & mask) << start);
+            }
+        }
+    )*};
+}
+
+bits!(u8, u16, u32, u64);
+
+/// Checks that `range` fits in `width` bits and returns its start and length.
+fn span(range: Range<u32>, width: u32) -> (u32, u32) {
+    assert!(range.start <= range.end && range.end <= width, "bit range out of bounds");
+
+    (range.start, range.end - range.start)
 }
 
 #[cfg(test)]
@@ -57,4 +102,45 @@ mod bits {
         a.set_bit(0, false);
         eq!(a, 0);
     }
+
+    #[test]
+    fn fat_date_fields() {
+        // 2021-03-14: bits 15-9 are years since 1980, 8-5 the month, 4-0 the day.
+        let date: u16 = (41 << 9) | (3 << 5) | 14;
+
+        eq!(date.bits(5..9), 3);
+        eq!(date.bits(0..5), 14);
+        eq!(date.bits(9..16), 41);
+
+        let mut d = date;
+        d.set_bits(5..9, 12);
+        eq!(d.bits(5..9), 12);
+        eq!((d.bits(9..16), d.bits(0..5)), (41, 14));
+
+        // Extra bits in the value are dropped:
+        d.set_bits(5..9, 0b1_0001);
+        eq!(d, (41 << 9) | (1 << 5) | 14);
+    }
+
+    #[test]
+    fn whole_and_empty_ranges() {
+        eq!(0xDEAD_BEEFu32.bits(0..32), 0xDEAD_BEEF);
+        eq!(0xDEAD_BEEFu32.bits(4..4), 0);
+        eq!(0xDEAD_BEEFu32.bits(32..32), 0);
+        eq!(u64::max_value().bits(60..64), 0xF);
+
+        let mut a: u64 = 0;
+        a.set_bits(0..64, u64::max_value());
+        eq!(a, u64::max_value());
+        a.set_bits(8..8, 0);
+        eq!(a, u64::max_value());
+        a.set_bits(32..48, 0);
+        eq!(a, 0xFFFF_0000_FFFF_FFFF);
+    }
+
+    #[test]
+    #[should_panic]
+    fn range_out_of_bounds() {
+        let _ = 0u16.bits(8..17);
+    }
 }
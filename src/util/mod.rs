@@ -5,3 +5,6 @@ pub use bits::Bits;
 
 mod bitmap;
 pub use bitmap::{BitMap, BitMapLen};
+
+#[cfg(feature = "serde")]
+pub(crate) mod serde_text;
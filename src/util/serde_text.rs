@@ -0,0 +1,84 @@
+//! For use with `#[serde(with = "...")]` on fixed size byte array fields that
+//! hold text (i.e. names and labels) so they show up as strings rather than as
+//! lists of numbers.
+//!
+//! Arrays that aren't valid UTF-8 are serialized as bytes instead. Either form
+//! can be deserialized; text that's shorter than the array is padded with
+//! spaces (like the on-disk fields are) and text that's longer is an error.
+
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::ser::Serializer;
+
+use core::fmt;
+use core::marker::PhantomData;
+
+pub fn serialize<A, S>(arr: &A, serializer: S) -> Result<S::Ok, S::Error>
+where
+    A: AsRef<[u8]>,
+    S: Serializer,
+{
+    match core::str::from_utf8(arr.as_ref()) {
+        Ok(s) => serializer.serialize_str(s),
+        Err(_) => serializer.serialize_bytes(arr.as_ref()),
+    }
+}
+
+pub fn deserialize<'de, A, D>(deserializer: D) -> Result<A, D::Error>
+where
+    A: Default + AsMut<[u8]>,
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_any(TextVisitor(PhantomData))
+}
+
+struct TextVisitor<A>(PhantomData<A>);
+
+impl<A: Default + AsMut<[u8]>> TextVisitor<A> {
+    fn fill<E: de::Error>(bytes: impl Iterator<Item = u8>, len: usize) -> Result<A, E> {
+        let mut arr = A::default();
+        let dest = arr.as_mut();
+
+        if len > dest.len() {
+            return Err(E::invalid_length(len, &"at most as many bytes as the field holds"));
+        }
+
+        dest.iter_mut().for_each(|b| *b = b' ');
+        dest.iter_mut().zip(bytes).for_each(|(d, b)| *d = b);
+
+        Ok(arr)
+    }
+}
+
+impl<'de, A: Default + AsMut<[u8]>> Visitor<'de> for TextVisitor<A> {
+    type Value = A;
+
+    fn expecting(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "a string or bytes")
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<A, E> {
+        Self::fill(s.bytes(), s.len())
+    }
+
+    fn visit_bytes<E: de::Error>(self, b: &[u8]) -> Result<A, E> {
+        Self::fill(b.iter().cloned(), b.len())
+    }
+
+    fn visit_seq<S: SeqAccess<'de>>(self, mut seq: S) -> Result<A, S::Error> {
+        let mut arr = A::default();
+        let dest = arr.as_mut();
+
+        let mut len = 0;
+        while let Some(b) = seq.next_element::<u8>()? {
+            *dest.get_mut(len).ok_or_else(|| {
+                de::Error::invalid_length(len + 1, &"at most as many bytes as the field holds")
+            })? = b;
+
+            len += 1;
+        }
+
+        dest[len..].iter_mut().for_each(|b| *b = b' ');
+
+        Ok(arr)
+    }
+}
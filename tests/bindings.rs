@@ -0,0 +1,317 @@
+// Exercises the C bindings through their `#[no_mangle]` symbols.
+//
+// The bindings talk to the disk through `eDisk_Read` and `eDisk_Write`; this
+// file provides those, backed by an in-memory disk.
+//
+// Requires the `no_std` feature to be disabled (for the std Mutex).
+//
+// Run with --no-default-features --features bindings.
+
+mod common;
+
+use fs::bindings::edisk::{DResult, EDiskStorage, ERASE_CHUNK_SECTORS};
+use fs::fat::dir::{DirEntry, FileExt, FileName};
+use fs::fat::types::ClusterIdx;
+use fs::storage::{self, ClassifyError, StorageExt, StorageKind};
+
+use storage_traits::{Storage, errors::{ReadError, WriteError}};
+use generic_array::GenericArray;
+use typenum::consts::U512;
+
+use std::sync::{Mutex, MutexGuard};
+
+static DISK: Mutex<Option<common::MockStorage>> = Mutex::new(None);
+
+// Number of `eDisk_Write` calls so far.
+static WRITES: Mutex<usize> = Mutex::new(0);
+
+// The bindings have one global filesystem so tests can't run concurrently.
+static SERIAL: Mutex<()> = Mutex::new(());
+
+extern "C" {
+    fn eFile_Mount(drive_num: u8, size_in_sectors: u64);
+    fn eFile_NewFile(path: *const u8, len: u16) -> bool;
+    fn eFile_NewDir(path: *const u8, len: u16) -> bool;
+    fn eFile_Read(path: *const u8, len: u16, offset: u32, buf: *mut u8, buf_len: u32, bytes_read: *mut u32) -> bool;
+    fn eFile_ReadAll(path: *const u8, len: u16, func: extern "C" fn(u8)) -> bool;
+    fn eFile_Append(path: *const u8, len: u16, buf: *const u8, buf_len: u32) -> bool;
+    fn eFile_DirList(path: *const u8, len: u16, func: extern "C" fn(*const u8, *const u8)) -> bool;
+    fn eFile_Flush() -> bool;
+}
+
+#[no_mangle]
+pub extern "C" fn eDisk_Read(_drv: u8, buff: *mut u8, sector: u32, count: u32) -> DResult {
+    let disk = DISK.lock().unwrap();
+    let data = &disk.as_ref().unwrap().data;
+
+    let (start, len) = ((sector as usize) * 512, (count as usize) * 512);
+    let buff = unsafe { std::slice::from_raw_parts_mut(buff, len) };
+    buff.copy_from_slice(&data[start..(start + len)]);
+
+    DResult::ResOk
+}
+
+#[no_mangle]
+pub extern "C" fn eDisk_Write(_drv: u8, buff: *const u8, sector: u32, count: u32) -> DResult {
+    let mut disk = DISK.lock().unwrap();
+    let data = &mut disk.as_mut().unwrap().data;
+
+    let (start, len) = ((sector as usize) * 512, (count as usize) * 512);
+    let buff = unsafe { std::slice::from_raw_parts(buff, len) };
+    data[start..(start + len)].copy_from_slice(buff);
+    *WRITES.lock().unwrap() += 1;
+
+    DResult::ResOk
+}
+
+/// Puts a fresh FAT16 volume on the disk and mounts it.
+fn mount() -> MutexGuard<'static, ()> {
+    mount_with(|_, _| { })
+}
+
+/// Like `mount` but lets `setup` change the volume (its partition starts at
+/// LBA 34) before it's mounted.
+fn mount_with(setup: impl FnOnce(&mut common::MockStorage, &common::Geometry)) -> MutexGuard<'static, ()> {
+    let guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+
+    let (mut s, g) = common::gpt_fat16_volume();
+    setup(&mut s, &g);
+    let sectors = (s.data.len() / 512) as u64;
+    *DISK.lock().unwrap() = Some(s);
+
+    unsafe { eFile_Mount(0, sectors) };
+
+    guard
+}
+
+fn call<R>(path: &[u8], func: unsafe extern "C" fn(*const u8, u16) -> R) -> R {
+    unsafe { func(path.as_ptr(), path.len() as u16) }
+}
+
+static LISTED: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+extern "C" fn record(name: *const u8, ext: *const u8) {
+    let name = unsafe { std::slice::from_raw_parts(name, 8) };
+    let ext = unsafe { std::slice::from_raw_parts(ext, 3) };
+
+    LISTED.lock().unwrap().push((
+        String::from_utf8_lossy(name).trim_end().to_string(),
+        String::from_utf8_lossy(ext).trim_end().to_string(),
+    ));
+}
+
+fn dir_list(path: &[u8]) -> Option<Vec<(String, String)>> {
+    LISTED.lock().unwrap().clear();
+
+    if unsafe { eFile_DirList(path.as_ptr(), path.len() as u16, record) } {
+        Some(LISTED.lock().unwrap().clone())
+    } else {
+        None
+    }
+}
+
+static READ: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+extern "C" fn read_byte(b: u8) {
+    READ.lock().unwrap().push(b);
+}
+
+fn read_all(path: &[u8]) -> Option<Vec<u8>> {
+    READ.lock().unwrap().clear();
+
+    if unsafe { eFile_ReadAll(path.as_ptr(), path.len() as u16, read_byte) } {
+        Some(READ.lock().unwrap().clone())
+    } else {
+        None
+    }
+}
+
+/// `None` if the read fails, otherwise the bytes that were read.
+fn read(path: &[u8], offset: u32, buf_len: u32) -> Option<Vec<u8>> {
+    let mut buf = vec![0xCC; buf_len as usize];
+    let mut bytes_read = u32::max_value();
+
+    let ok = unsafe {
+        eFile_Read(path.as_ptr(), path.len() as u16, offset, buf.as_mut_ptr(), buf_len, &mut bytes_read)
+    };
+
+    if ok {
+        buf.truncate(bytes_read as usize);
+        Some(buf)
+    } else {
+        None
+    }
+}
+
+fn append(path: &[u8], data: &[u8]) -> bool {
+    unsafe { eFile_Append(path.as_ptr(), path.len() as u16, data.as_ptr(), data.len() as u32) }
+}
+
+#[test]
+fn new_file_and_dir() {
+    let _guard = mount();
+
+    assert!(call(b"/HELLO.TXT", eFile_NewFile));
+    assert!(call(b"/SUB", eFile_NewDir));
+    assert!(call(b"/SUB/INNER.BIN", eFile_NewFile));
+
+    // Can't create things twice or in directories that don't exist:
+    assert!(!call(b"/HELLO.TXT", eFile_NewFile));
+    assert!(!call(b"/NOPE/A.TXT", eFile_NewFile));
+
+    assert_eq!(dir_list(b"/").unwrap(), vec![
+        ("HELLO".to_string(), "TXT".to_string()),
+        ("SUB".to_string(), "".to_string()),
+    ]);
+    assert_eq!(dir_list(b"/SUB").unwrap(), vec![
+        ("INNER".to_string(), "BIN".to_string()),
+    ]);
+
+    assert!(unsafe { eFile_Flush() });
+}
+
+#[test]
+fn append_twice() {
+    let _guard = mount();
+
+    assert!(call(b"/LOG.TXT", eFile_NewFile));
+    assert_eq!(read_all(b"/LOG.TXT").unwrap(), b"");
+
+    // The second chunk crosses into a second cluster (clusters are 512 bytes):
+    let second: Vec<u8> = (0..600).map(|i| i as u8).collect();
+    assert!(append(b"/LOG.TXT", b"hello, "));
+    assert!(append(b"/LOG.TXT", &second));
+    assert!(unsafe { eFile_Flush() });
+
+    let mut expected = b"hello, ".to_vec();
+    expected.extend_from_slice(&second);
+    assert_eq!(read_all(b"/LOG.TXT").unwrap(), expected);
+
+    // Not files:
+    assert!(call(b"/DIR", eFile_NewDir));
+    assert!(!append(b"/DIR", b"nope"));
+    assert!(!append(b"/MISSING.TXT", b"nope"));
+
+    assert!(unsafe { eFile_Flush() });
+}
+
+#[test]
+fn append_to_an_empty_file_without_clusters() {
+    // Other implementations leave empty files without a first cluster:
+    let _guard = mount_with(|s, g| {
+        let entry = DirEntry::new_file(FileName(*b"EMPTY   "), FileExt(*b"TXT"), ClusterIdx::new(0));
+        common::put_dir_entry(s, g.root_dir_start(34), 0, &entry);
+    });
+
+    assert_eq!(read_all(b"/EMPTY.TXT").unwrap(), b"");
+    assert!(append(b"/EMPTY.TXT", b"hello"));
+    assert!(call(b"/OTHER.TXT", eFile_NewFile));
+    assert!(unsafe { eFile_Flush() });
+
+    // The root directory (which cluster 0 maps to) wasn't written over:
+    assert_eq!(dir_list(b"/").unwrap(), vec![
+        ("EMPTY".to_string(), "TXT".to_string()),
+        ("OTHER".to_string(), "TXT".to_string()),
+    ]);
+    assert_eq!(read_all(b"/EMPTY.TXT").unwrap(), b"hello");
+    assert_eq!(read_all(b"/OTHER.TXT").unwrap(), b"");
+}
+
+#[test]
+fn read_bounds() {
+    let _guard = mount();
+
+    let contents: Vec<u8> = (0..700).map(|i| (i % 251) as u8).collect();
+    assert!(call(b"/DATA.BIN", eFile_NewFile));
+    assert!(append(b"/DATA.BIN", &contents));
+
+    // Entirely within the file, across a cluster boundary:
+    assert_eq!(read(b"/DATA.BIN", 500, 100).unwrap(), &contents[500..600]);
+
+    // Exactly up to the end of the file:
+    assert_eq!(read(b"/DATA.BIN", 600, 100).unwrap(), &contents[600..]);
+
+    // Spanning the end of the file; this gets cut short:
+    assert_eq!(read(b"/DATA.BIN", 650, 100).unwrap(), &contents[650..]);
+
+    // At the end of the file:
+    assert_eq!(read(b"/DATA.BIN", 700, 10).unwrap(), b"");
+
+    // Past the end (including offsets that'd overflow with `buf_len`):
+    assert_eq!(read(b"/DATA.BIN", 701, 10), None);
+    assert_eq!(read(b"/DATA.BIN", u32::max_value(), 10), None);
+
+    assert!(unsafe { eFile_Flush() });
+}
+
+/// Storage that isn't ready for the first `not_ready_for` reads.
+struct WarmingUp {
+    not_ready_for: usize,
+    reads: usize,
+}
+
+impl Storage for WarmingUp {
+    type Word = u8;
+    type SECTOR_SIZE = U512;
+
+    type ReadErr = DResult;
+    type WriteErr = DResult;
+
+    fn capacity(&self) -> usize { 1 }
+
+    fn read_sector(&mut self, _: usize, buffer: &mut GenericArray<u8, U512>) -> Result<(), ReadError<DResult>> {
+        self.reads += 1;
+
+        if self.reads <= self.not_ready_for {
+            Err(ReadError::Other(DResult::ResNotRdy))
+        } else {
+            buffer[0] = 0x42;
+            Ok(())
+        }
+    }
+
+    fn write_sector(&mut self, _: usize, _: &GenericArray<u8, U512>) -> Result<(), WriteError<DResult>> {
+        Err(WriteError::Other(DResult::ResWrPrt))
+    }
+}
+
+#[test]
+fn dresult_classification() {
+    assert_eq!(DResult::ResNotRdy.kind(), StorageKind::Retryable);
+    assert_eq!(DResult::ResWrPrt.kind(), StorageKind::WriteProtected);
+    assert_eq!(DResult::ResError.kind(), StorageKind::Fatal);
+    assert_eq!(ReadError::Other(DResult::ResNotRdy).kind(), StorageKind::Retryable);
+
+    let mut s = WarmingUp { not_ready_for: 1, reads: 0 };
+    let mut sector = GenericArray::default();
+
+    assert_eq!(storage::retry(3, || s.read_sector(0, &mut sector)), Ok(()));
+    assert_eq!((s.reads, sector[0]), (2, 0x42));
+
+    // Write protection isn't worth retrying:
+    let res = storage::retry(3, || s.write_sector(0, &sector));
+    assert_eq!(res.map_err(|e| e.kind()), Err(StorageKind::WriteProtected));
+}
+
+#[test]
+fn erase_in_chunks() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+
+    // Not a multiple of the chunk size so the last call is a short one.
+    let sectors = 5 * ERASE_CHUNK_SECTORS + 3;
+    let mut disk = common::MockStorage::new(sectors);
+    disk.data.iter_mut().for_each(|b| *b = 0xA5);
+    *DISK.lock().unwrap() = Some(disk);
+    *WRITES.lock().unwrap() = 0;
+
+    let mut s = EDiskStorage { drive_num: 0, size_in_sectors: sectors as u64 };
+    s.erase().unwrap();
+
+    assert_eq!(*WRITES.lock().unwrap(), 6);
+
+    let disk = DISK.lock().unwrap();
+    let disk = disk.as_ref().unwrap();
+    for idx in 0..sectors {
+        assert!(disk.sector(idx).iter().all(|b| *b == 0), "sector {} wasn't erased", idx);
+    }
+}
@@ -0,0 +1,551 @@
+// Requires the `no_std` feature to be disabled so that `File`s implement the
+// `Storage` trait.
+//
+// Run with --no-default-features.
+
+mod common;
+
+use fs::fat::{
+    FatError,
+    types::SectorIdx,
+    cache::{
+        CacheMode, CacheStats, FlushError, InvalidateError, SectorCache, VerifyError,
+        CacheEntry,
+        eviction_policies::{ByKey, Reversed, Then, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED},
+    },
+};
+
+use storage_traits::FileBackedStorage;
+use typenum::consts::{U512, U4, U16};
+
+#[test]
+fn write_through() {
+    let path = common::scratch_file("write-through", 8);
+    let mut s = FileBackedStorage::from_file(&path).unwrap();
+
+    let mut cache = SectorCache::<_, U512, U4>::new(
+        &s,
+        SectorIdx::new(8),
+        UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+    );
+    cache.set_mode(CacheMode::WriteThrough);
+
+    let mut c = cache.upgrade(&mut s);
+    c.get_mut(SectorIdx::new(3))[0..4].copy_from_slice(b"abcd");
+
+    // The next access ends the mutable borrow which should write the sector
+    // out:
+    let _ = c.get(SectorIdx::new(5))[0];
+    assert_eq!(b"abcd", &common::sector_of(&path, 3)[0..4]);
+
+    // Dropping the handle should also write out the last modified sector:
+    c.get_mut(SectorIdx::new(6))[511] = 0xAA;
+    drop(c);
+    assert_eq!(0xAA, common::sector_of(&path, 6)[511]);
+
+    // No explicit flush; this shouldn't panic about dirty entries.
+    drop(cache);
+}
+
+#[test]
+fn stats() {
+    let path = common::scratch_file("stats", 8);
+    let mut s = FileBackedStorage::from_file(&path).unwrap();
+
+    let mut cache = SectorCache::<_, U512, U4>::new(
+        &s,
+        SectorIdx::new(8),
+        UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+    );
+
+    {
+        let c = cache.upgrade(&mut s);
+
+        // 5 misses (the last of which evicts the first sector), then 2 hits:
+        for i in 0..5 { let _ = c.get(SectorIdx::new(i))[0]; }
+        let _ = c.get(SectorIdx::new(4))[0];
+        let _ = c.get(SectorIdx::new(3))[0];
+    }
+
+    assert_eq!(cache.stats(), CacheStats { hits: 2, misses: 5, evictions: 1 });
+    assert_eq!(cache.stats().hit_ratio(), Some(2.0 / 7.0));
+
+    cache.reset_stats();
+    assert_eq!(cache.stats(), CacheStats::default());
+    assert_eq!(cache.stats().hit_ratio(), None);
+}
+
+#[test]
+fn flush_with_failing_sector() {
+    let mut s = common::MockStorage::new(8);
+    s.failing_writes.push(2);
+    s.failing_writes.push(5);
+
+    let mut cache = SectorCache::<_, U512, U4>::new(
+        &s,
+        SectorIdx::new(8),
+        UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+    );
+
+    {
+        let mut c = cache.upgrade(&mut s);
+        for i in [5, 1, 2, 3].iter() { c.get_mut(SectorIdx::new(*i))[0] = *i as u8; }
+    }
+
+    let err: FlushError<U4> = cache.flush(&mut s).unwrap_err();
+    assert_eq!(err.num_failed(), 2);
+    assert_eq!(err.first_failed(), SectorIdx::new(2));
+    assert_eq!(err.failed_sectors().collect::<Vec<_>>(), vec![SectorIdx::new(2), SectorIdx::new(5)]);
+    assert_eq!(err.verify_error, None);
+
+    // The other sectors should still have been written:
+    assert_eq!(s.sector(1)[0], 1);
+    assert_eq!(s.sector(2)[0], 0);
+    assert_eq!(s.sector(3)[0], 3);
+    assert_eq!(s.sector(5)[0], 0);
+
+    // And the failed ones should still be dirty:
+    let mut dirty: Vec<_> = cache.dirty_sectors().collect();
+    dirty.sort();
+    assert_eq!(dirty, err.failed_sectors().collect::<Vec<_>>());
+
+    // Once the storage recovers, a retry should succeed:
+    s.failing_writes.clear();
+    assert_eq!(cache.flush(&mut s), Ok(()));
+    assert_eq!(s.sector(2)[0], 2);
+    assert_eq!(s.sector(5)[0], 5);
+    assert_eq!(cache.dirty_sectors().count(), 0);
+}
+
+#[test]
+fn verify_writes() {
+    let mut s = common::MockStorage::new(8);
+    s.lost_writes.push(3);
+    s.lost_writes.push(5);
+
+    let mut cache = SectorCache::<_, U512, U4>::new(
+        &s,
+        SectorIdx::new(8),
+        UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+    );
+
+    // Off by default; the lost write goes unnoticed:
+    cache.upgrade(&mut s).get_mut(SectorIdx::new(3))[0] = 0xAB;
+    assert_eq!(cache.flush(&mut s), Ok(()));
+    assert_eq!(s.sector(3)[0], 0);
+
+    cache.set_verify_writes(true);
+    assert!(cache.verify_writes());
+
+    {
+        let mut c = cache.upgrade(&mut s);
+        for i in 2..6 { c.get_mut(SectorIdx::new(i))[0] = 0xCD; }
+    }
+
+    let reads = s.reads;
+    let err = cache.flush(&mut s).unwrap_err();
+    assert_eq!(err.num_failed(), 2);
+    assert_eq!(err.failed_sectors().collect::<Vec<_>>(), vec![SectorIdx::new(3), SectorIdx::new(5)]);
+    assert_eq!(err.verify_error, Some(VerifyError { sector: SectorIdx::new(3) }));
+
+    // Every write was read back:
+    assert_eq!(s.reads - reads, 4);
+    let mut dirty: Vec<_> = cache.dirty_sectors().collect();
+    dirty.sort();
+    assert_eq!(dirty, vec![SectorIdx::new(3), SectorIdx::new(5)]);
+
+    // Once the storage behaves the retry goes through:
+    s.lost_writes.clear();
+    assert_eq!(cache.flush(&mut s), Ok(()));
+    assert_eq!(s.sector(3)[0], 0xCD);
+    assert_eq!(s.sector(5)[0], 0xCD);
+}
+
+#[test]
+fn flush_range() {
+    let mut s = common::MockStorage::new(16);
+
+    let mut cache = SectorCache::<_, U512, U4>::new(
+        &s,
+        SectorIdx::new(16),
+        UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+    );
+    assert_eq!(cache.dirty_count(), 0);
+
+    {
+        let mut c = cache.upgrade(&mut s);
+        for i in [1, 2, 9, 10].iter() { c.get_mut(SectorIdx::new(*i))[0] = *i as u8; }
+
+        // More writes to a sector that's already dirty don't count again:
+        c.get_mut(SectorIdx::new(9))[1] = 0xFF;
+    }
+    assert_eq!(cache.dirty_count(), 4);
+
+    assert_eq!(cache.flush_range(&mut s, SectorIdx::new(8)..SectorIdx::new(16)), Ok(()));
+    assert_eq!(cache.dirty_count(), 2);
+    assert_eq!(cache.dirty_sectors().collect::<Vec<_>>(), vec![SectorIdx::new(1), SectorIdx::new(2)]);
+
+    assert_eq!((s.sector(9)[0], s.sector(9)[1], s.sector(10)[0]), (9, 0xFF, 10));
+    assert_eq!((s.sector(1)[0], s.sector(2)[0]), (0, 0));
+
+    // The end of the range is exclusive:
+    assert_eq!(cache.flush_range(&mut s, SectorIdx::new(0)..SectorIdx::new(1)), Ok(()));
+    assert_eq!(cache.dirty_count(), 2);
+
+    assert_eq!(cache.flush(&mut s), Ok(()));
+    assert_eq!(cache.dirty_count(), 0);
+    assert_eq!((s.sector(1)[0], s.sector(2)[0]), (1, 2));
+}
+
+#[test]
+fn flush_some() {
+    let mut s = common::MockStorage::new(16);
+
+    let mut cache = SectorCache::<_, U512, U16>::new(
+        &s,
+        SectorIdx::new(16),
+        UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+    );
+
+    {
+        let mut c = cache.upgrade(&mut s);
+        for i in (0..10).rev() { c.get_mut(SectorIdx::new(i))[0] = 1; }
+    }
+    assert_eq!(cache.dirty_count(), 10);
+
+    // Oldest first; these were loaded from 9 down to 0:
+    assert_eq!(cache.flush_some(&mut s, 4), 4);
+    let mut dirty: Vec<_> = cache.dirty_sectors().map(|s| *s.inner()).collect();
+    dirty.sort();
+    assert_eq!(dirty, (0..6).collect::<Vec<_>>());
+    assert!((6..10).all(|i| s.sector(i)[0] == 1));
+
+    assert_eq!(cache.flush_some(&mut s, 4), 4);
+    assert_eq!(cache.flush_some(&mut s, 4), 2);
+    assert_eq!(cache.dirty_count(), 0);
+    assert!((0..10).all(|i| s.sector(i)[0] == 1));
+
+    assert_eq!(cache.flush_some(&mut s, 4), 0);
+
+    // Sectors that can't be written are left for later:
+    {
+        let mut c = cache.upgrade(&mut s);
+        for i in 0..3 { c.get_mut(SectorIdx::new(i))[0] = 2; }
+    }
+    s.failing_writes.push(1);
+    assert_eq!(cache.flush_some(&mut s, 4), 2);
+    assert_eq!(cache.dirty_sectors().collect::<Vec<_>>(), vec![SectorIdx::new(1)]);
+
+    s.failing_writes.clear();
+    assert_eq!(cache.flush_some(&mut s, 4), 1);
+}
+
+#[test]
+fn get_range() {
+    let mut s = common::MockStorage::new(16);
+    for i in 0..16 { s.sector_mut(i)[0] = i as u8; }
+
+    let mut cache = SectorCache::<_, U512, U4>::new(
+        &s,
+        SectorIdx::new(16),
+        UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+    );
+
+    let mut seen = Vec::new();
+    {
+        let mut c = cache.upgrade(&mut s);
+        let _ = c.get(SectorIdx::new(2))[0];
+
+        c.get_range(SectorIdx::new(0)..SectorIdx::new(6), |idx, data| {
+            seen.push((*idx.inner(), data[0], data.len()))
+        }).unwrap();
+    }
+
+    assert_eq!(seen, (0..6).map(|i| (i, i as u8, 512)).collect::<Vec<_>>());
+
+    // Missing sector 0 reads 1 ahead (stopping at 2 which is already cached),
+    // so 1 and 2 are hits. After that the cache is full so 3, 4, and 5 are
+    // misses without any read ahead.
+    assert_eq!(cache.stats(), CacheStats { hits: 2, misses: 5, evictions: 2 });
+    assert_eq!(s.reads, 6);
+
+    // Past the end of the storage:
+    let mut called = false;
+    assert_eq!(
+        cache.upgrade(&mut s).get_range(SectorIdx::new(14)..SectorIdx::new(17), |_, _| called = true),
+        Err(FatError::OutOfRange),
+    );
+    assert!(!called);
+}
+
+#[test]
+fn prefetch() {
+    let mut s = common::MockStorage::new(16);
+
+    let mut cache = SectorCache::<_, U512, U4>::new(
+        &s,
+        SectorIdx::new(16),
+        UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+    );
+
+    cache.prefetch(&mut s, SectorIdx::new(4)..SectorIdx::new(7));
+    assert_eq!(s.reads, 3);
+    assert_eq!(cache.stats(), CacheStats::default());
+
+    {
+        let c = cache.upgrade(&mut s);
+        for i in 4..7 { let _ = c.get(SectorIdx::new(i))[0]; }
+    }
+    assert_eq!(cache.stats(), CacheStats { hits: 3, misses: 0, evictions: 0 });
+
+    // Clean sectors get evicted to make room:
+    cache.prefetch(&mut s, SectorIdx::new(8)..SectorIdx::new(10));
+    assert_eq!(s.reads, 5);
+    assert_eq!(cache.stats().evictions, 1);
+
+    // But dirty ones don't:
+    {
+        let mut c = cache.upgrade(&mut s);
+        for i in 0..4 { c.get_mut(SectorIdx::new(i))[0] = 1; }
+    }
+    cache.reset_stats();
+    let reads = s.reads;
+
+    cache.prefetch(&mut s, SectorIdx::new(12)..SectorIdx::new(14));
+    assert_eq!(s.reads, reads);
+    assert_eq!(s.writes, 0);
+    assert_eq!(cache.stats(), CacheStats::default());
+
+    cache.flush(&mut s).unwrap();
+}
+
+#[test]
+fn readahead_window() {
+    let mut s = common::MockStorage::new(16);
+
+    let mut cache = SectorCache::<_, U512, U4>::new(
+        &s,
+        SectorIdx::new(16),
+        UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+    );
+    cache.set_readahead_window(1);
+
+    // 0 is a miss that reads 1 ahead, then 2 is a miss that reads 3 ahead:
+    cache.upgrade(&mut s).get_range(SectorIdx::new(0)..SectorIdx::new(4), |_, _| ()).unwrap();
+    assert_eq!(cache.stats(), CacheStats { hits: 2, misses: 2, evictions: 0 });
+
+    cache.set_readahead_window(0);
+    cache.reset_stats();
+
+    cache.upgrade(&mut s).get_range(SectorIdx::new(8)..SectorIdx::new(10), |_, _| ()).unwrap();
+    assert_eq!(cache.stats(), CacheStats { hits: 0, misses: 2, evictions: 2 });
+}
+
+#[test]
+fn with_contiguous() {
+    let mut s = common::MockStorage::new(16);
+    for i in 0..16 {
+        s.sector_mut(i)[0] = i as u8;
+        s.sector_mut(i)[511] = !(i as u8);
+    }
+
+    let mut cache = SectorCache::<_, U512, U4>::new(
+        &s,
+        SectorIdx::new(16),
+        UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+    );
+
+    let mut c = cache.upgrade(&mut s);
+    let mut scratch = [0u8; 512 * 3];
+
+    let range = SectorIdx::new(5)..SectorIdx::new(8);
+    let firsts = c.with_contiguous(range.clone(), &mut scratch, |data| {
+        assert_eq!(data.len(), 512 * 3);
+        assert_eq!(data[511], !5);
+        assert_eq!(data[512 * 3 - 1], !7);
+
+        data.chunks(512).map(|s| s[0]).collect::<Vec<_>>()
+    });
+    assert_eq!(firsts, Some(vec![5, 6, 7]));
+
+    // Not enough room to copy into:
+    assert_eq!(c.with_contiguous(range, &mut scratch[..1024], |_| ()), None);
+
+    // A single sector doesn't need any scratch space:
+    let single = SectorIdx::new(9)..SectorIdx::new(10);
+    assert_eq!(c.with_contiguous(single, &mut [], |d| (d.len(), d[0])), Some((512, 9)));
+
+    // Read errors are `None` rather than panics, even for a single sector:
+    s.failing_reads.push(10);
+    let mut c = cache.upgrade(&mut s);
+    assert_eq!(c.with_contiguous(SectorIdx::new(10)..SectorIdx::new(11), &mut [], |_| ()), None);
+    assert_eq!(c.with_contiguous(SectorIdx::new(9)..SectorIdx::new(11), &mut scratch, |_| ()), None);
+}
+
+#[test]
+fn custom_eviction_policy() {
+    let path = common::scratch_file("custom-eviction", 8);
+    let mut s = FileBackedStorage::from_file(&path).unwrap();
+
+    let sector = |e: &CacheEntry| match e {
+        CacheEntry::Resident { s, .. } | CacheEntry::Dirty { s, .. } => *s.inner(),
+        CacheEntry::Free => unreachable!(),
+    };
+
+    // Sectors 0 and 1 are "metadata" and are kept around over everything
+    // else; the lowest numbered data sector goes first.
+    let policy = Then(
+        ByKey(move |e: &CacheEntry| sector(e) >= 2),
+        Reversed(ByKey(sector)),
+    );
+
+    let mut cache = SectorCache::<_, U512, U4, _>::new(&s, SectorIdx::new(8), policy);
+
+    {
+        let c = cache.upgrade(&mut s);
+
+        // Least recently accessed would pick 0 here:
+        for i in 0..4 { let _ = c.get(SectorIdx::new(i))[0]; }
+        let _ = c.get(SectorIdx::new(5))[0];
+    }
+
+    assert_eq!(cache.stats().misses, 5);
+
+    {
+        let c = cache.upgrade(&mut s);
+
+        for i in [0, 1, 3, 5].iter() { let _ = c.get(SectorIdx::new(*i))[0]; }
+    }
+    assert_eq!(cache.stats(), CacheStats { hits: 4, misses: 5, evictions: 1 });
+
+    // 2 was the one that got evicted:
+    {
+        let c = cache.upgrade(&mut s);
+        let _ = c.get(SectorIdx::new(2))[0];
+    }
+    assert_eq!(cache.stats().misses, 6);
+}
+
+#[test]
+fn can_get_without_eviction() {
+    let mut s = common::MockStorage::new(8);
+
+    let mut cache = SectorCache::<_, U512, U4>::new(
+        &s,
+        SectorIdx::new(8),
+        UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+    );
+
+    let c = cache.upgrade(&mut s);
+    let mut held = Vec::new();
+
+    for i in 0..3 {
+        assert!(c.can_get_without_eviction(SectorIdx::new(i)));
+        held.push(c.get(SectorIdx::new(i)));
+    }
+
+    // One free slot left:
+    assert!(c.can_get_without_eviction(SectorIdx::new(6)));
+    held.push(c.get(SectorIdx::new(3)));
+
+    // Now every slot is borrowed; a new sector would need an eviction (which
+    // would panic) but the resident ones are fine:
+    assert!(!c.can_get_without_eviction(SectorIdx::new(6)));
+    assert!(c.can_get_without_eviction(SectorIdx::new(2)));
+    let _again = c.get(SectorIdx::new(2));
+
+    // Past the end of the storage:
+    assert!(!c.can_get_without_eviction(SectorIdx::new(8)));
+
+    // Letting go of the borrows doesn't free up a slot:
+    drop(_again);
+    held.clear();
+    assert!(!c.can_get_without_eviction(SectorIdx::new(6)));
+}
+
+#[test]
+fn pinned_sectors_stay_cached() {
+    let mut s = common::MockStorage::new(16);
+
+    let mut cache = SectorCache::<_, U512, U4>::new(
+        &s,
+        SectorIdx::new(16),
+        UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+    );
+
+    cache.pin(&mut s, SectorIdx::new(0)).unwrap();
+    cache.pin(&mut s, SectorIdx::new(0)).unwrap();
+    assert_eq!(cache.pinned_count(), 1);
+
+    // Pinned sectors can still be written to:
+    cache.upgrade(&mut s).get_mut(SectorIdx::new(0))[0] = 0xAB;
+
+    // Sector 0 is the oldest (and least recently accessed) entry, so without
+    // the pin it'd be the first to go:
+    for _ in 0..10 {
+        for i in 1..16 {
+            let _ = cache.upgrade(&mut s).get(SectorIdx::new(i))[0];
+        }
+    }
+
+    let reads = s.reads;
+    assert_eq!(cache.upgrade(&mut s).get(SectorIdx::new(0))[0], 0xAB);
+    assert_eq!(s.reads, reads);
+
+    // Every slot but one can be pinned:
+    cache.pin(&mut s, SectorIdx::new(1)).unwrap();
+    cache.pin(&mut s, SectorIdx::new(2)).unwrap();
+    assert_eq!(cache.pin(&mut s, SectorIdx::new(3)), Err(FatError::OutOfSpace));
+    let _ = cache.upgrade(&mut s).get(SectorIdx::new(3))[0];
+    let _ = cache.upgrade(&mut s).get(SectorIdx::new(4))[0];
+
+    // Once unpinned, sectors get evicted like normal:
+    assert!(cache.unpin(SectorIdx::new(0)));
+    assert!(!cache.unpin(SectorIdx::new(0)));
+    assert!(!cache.unpin(SectorIdx::new(9)));
+    assert_eq!(cache.pinned_count(), 2);
+
+    cache.flush(&mut s).unwrap();
+    let _ = cache.upgrade(&mut s).get(SectorIdx::new(5))[0];
+    let reads = s.reads;
+    let _ = cache.upgrade(&mut s).get(SectorIdx::new(0))[0];
+    assert_eq!(s.reads, reads + 1);
+}
+
+#[test]
+fn invalidate_all() {
+    let mut s = common::MockStorage::new(8);
+
+    let mut cache = SectorCache::<_, U512, U4>::new(
+        &s,
+        SectorIdx::new(8),
+        UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+    );
+    assert_eq!(cache.generation(), 0);
+
+    let _ = cache.upgrade(&mut s).get(SectorIdx::new(0))[0];
+    let _ = cache.upgrade(&mut s).get(SectorIdx::new(1))[0];
+    cache.upgrade(&mut s).get_mut(SectorIdx::new(2))[0] = 0xCD;
+
+    // The media changes underneath the cache:
+    s.sector_mut(0)[0] = 0xAB;
+
+    // Modified sectors are kept (and reported) but clean ones are dropped:
+    assert_eq!(cache.invalidate_all(), Err(InvalidateError { dirty_sectors: 1 }));
+    assert_eq!(cache.generation(), 1);
+    assert_eq!(cache.dirty_count(), 1);
+
+    let reads = s.reads;
+    assert_eq!(cache.upgrade(&mut s).get(SectorIdx::new(0))[0], 0xAB);
+    assert_eq!(cache.upgrade(&mut s).get(SectorIdx::new(2))[0], 0xCD);
+    assert_eq!(s.reads, reads + 1);
+
+    cache.flush(&mut s).unwrap();
+    assert_eq!(cache.invalidate_all(), Ok(()));
+    assert_eq!(cache.generation(), 2);
+
+    let reads = s.reads;
+    let _ = cache.upgrade(&mut s).get(SectorIdx::new(0))[0];
+    let _ = cache.upgrade(&mut s).get(SectorIdx::new(2))[0];
+    assert_eq!(s.reads, reads + 2);
+}
@@ -0,0 +1,89 @@
+// Requires the `cache-trace` feature and the `no_std` feature to be disabled
+// (for `FileBackedStorage`).
+//
+// Run with --no-default-features --features cache-trace.
+
+mod common;
+
+use fs::fat::{
+    types::SectorIdx,
+    cache::{SectorCache, eviction_policies::UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED},
+};
+
+use log::{Log, Metadata, Record, LevelFilter};
+use storage_traits::FileBackedStorage;
+use typenum::consts::{U512, U2};
+
+use std::sync::Mutex;
+
+/// Collects every message that's logged.
+struct TestLogger(Mutex<Vec<String>>);
+
+impl Log for TestLogger {
+    fn enabled(&self, _: &Metadata<'_>) -> bool { true }
+
+    fn log(&self, record: &Record<'_>) {
+        self.0.lock().unwrap().push(format!("{}", record.args()));
+    }
+
+    fn flush(&self) { }
+}
+
+static LOGGER: TestLogger = TestLogger(Mutex::new(Vec::new()));
+
+fn take_messages() -> Vec<String> {
+    core::mem::replace(&mut *LOGGER.0.lock().unwrap(), Vec::new())
+}
+
+#[test]
+fn eviction_is_logged() {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Trace);
+
+    let path = common::scratch_file("cache-trace", 8);
+    let mut s = FileBackedStorage::from_file(&path).unwrap();
+
+    let mut cache = SectorCache::<_, U512, U2>::new(
+        &s,
+        SectorIdx::new(8),
+        UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+    );
+
+    {
+        let mut c = cache.upgrade(&mut s);
+        c.get_mut(SectorIdx::new(1))[0] = 0xAB;
+        let _ = c.get(SectorIdx::new(2))[0];
+        let _ = c.get(SectorIdx::new(2))[0];
+    }
+
+    let messages = take_messages();
+    assert!(messages.contains(&"cache: miss for sector 1".to_string()), "{:?}", messages);
+    assert!(messages.contains(&"cache: miss for sector 2".to_string()), "{:?}", messages);
+    assert!(messages.contains(&"cache: hit for sector 2".to_string()), "{:?}", messages);
+
+    // The cache is full; sector 2 is unmodified so it goes first:
+    cache.upgrade(&mut s).get_mut(SectorIdx::new(3))[0] = 0xCD;
+
+    let messages = take_messages();
+    assert!(messages.contains(&"cache: miss for sector 3".to_string()), "{:?}", messages);
+    assert!(
+        messages.iter().any(|m| m.starts_with("cache: evicting sector 2 (dirty: false); policy picked Resident")),
+        "{:?}", messages,
+    );
+
+    // Both entries are dirty now so the one that was accessed first goes:
+    let _ = cache.upgrade(&mut s).get(SectorIdx::new(4))[0];
+
+    let messages = take_messages();
+    assert!(
+        messages.iter().any(|m| m.starts_with("cache: evicting sector 1 (dirty: true); policy picked Dirty")),
+        "{:?}", messages,
+    );
+    assert_eq!(0xAB, common::sector_of(&path, 1)[0]);
+
+    // Just sector 3 is left to write out:
+    cache.flush(&mut s).unwrap();
+
+    let messages = take_messages();
+    assert!(messages.contains(&"cache: flush wrote 1 sectors (0 failed)".to_string()), "{:?}", messages);
+}
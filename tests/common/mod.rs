@@ -0,0 +1,302 @@
+//! Helpers shared between the integration tests.
+
+#![allow(dead_code)]
+
+use std::fs::File;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+
+use fs::gpt::{Guid, PartitionEntry};
+use fs::fat::dir::DirEntry;
+use fs::storage::StorageExt;
+
+use storage_traits::{Storage, errors::{ReadError, WriteError}};
+use generic_array::{ArrayLength, GenericArray};
+use typenum::consts::U512;
+
+/// Makes a zeroed file of `sectors` 512 byte sectors in the temp directory
+/// and returns its path.
+///
+/// `name` should be unique across tests since tests run in parallel.
+pub fn scratch_file(name: &str, sectors: usize) -> PathBuf {
+    let path = std::env::temp_dir()
+        .join(format!("fatfs-{}-{}.img", name, std::process::id()));
+
+    let f = File::create(&path).unwrap();
+    f.set_len((sectors * 512) as u64).unwrap();
+
+    path
+}
+
+/// Like `scratch_file` but every byte of the file is set to `fill`.
+pub fn filled_scratch_file(name: &str, sectors: usize, fill: u8) -> PathBuf {
+    let path = std::env::temp_dir()
+        .join(format!("fatfs-{}-{}.img", name, std::process::id()));
+
+    std::fs::write(&path, vec![fill; sectors * 512]).unwrap();
+
+    path
+}
+
+/// Makes a scratch file whose sector `first_lba` has a boot sector that's just
+/// enough to get `FatFs::mount` to succeed.
+///
+/// Returns a partition spanning `first_lba` to the last sector of the file.
+pub fn bare_volume(name: &str, sectors: usize, first_lba: u64) -> (PathBuf, PartitionEntry) {
+    let path = scratch_file(name, sectors);
+
+    let mut contents = std::fs::read(&path).unwrap();
+    let boot_sector = &mut contents[(first_lba as usize * 512)..][..512];
+
+    bare_boot_sector(boot_sector);
+
+    std::fs::write(&path, contents).unwrap();
+
+    (path, PartitionEntry::fat("RTOS", first_lba, (sectors - 1) as u64))
+}
+
+/// Fills in just enough of a boot sector for `FatFs::mount` to succeed: the
+/// sector size (`sector.len()`), one sector per cluster, and one FAT. There
+/// are no reserved sectors and the FAT is empty.
+pub fn bare_boot_sector(sector: &mut [u8]) {
+    let sector_size = sector.len() as u16;
+
+    sector[0x0B..0x0D].copy_from_slice(&sector_size.to_le_bytes());
+    sector[0x0D] = 1;
+    sector[0x10] = 1;
+}
+
+/// The geometry fields of a BPB; enough to describe the volumes that tests
+/// build by hand.
+///
+/// Volumes with `root_entries == 0` get a FAT32 style BPB.
+#[derive(Debug, Clone)]
+pub struct Geometry {
+    pub sectors_per_cluster: u8,
+    pub reserved_sectors: u16,
+    pub num_fats: u8,
+    pub fat_size: u32,
+    pub root_entries: u16,
+    pub total_sectors: u32,
+}
+
+impl Geometry {
+    /// A FAT16 volume with 512 byte clusters and just enough of them to not be
+    /// FAT12.
+    pub fn fat16() -> Self {
+        Self {
+            sectors_per_cluster: 1,
+            reserved_sectors: 1,
+            num_fats: 2,
+            fat_size: 17,
+            root_entries: 512,
+            total_sectors: 4267,
+        }
+    }
+
+    /// First sector of the FAT, where `FatFs` will look for it.
+    pub fn fat_start(&self, first_lba: u64) -> usize {
+        first_lba as usize + self.reserved_sectors as usize
+    }
+
+    pub fn root_dir_start(&self, first_lba: u64) -> usize {
+        self.fat_start(first_lba) + (self.num_fats as usize) * (self.fat_size as usize)
+    }
+
+    pub fn root_dir_size(&self) -> usize {
+        (self.root_entries as usize * 32 + 511) / 512
+    }
+
+    /// First sector of a data cluster; the first data cluster is cluster 2.
+    pub fn cluster_start(&self, first_lba: u64, idx: u32) -> usize {
+        self.root_dir_start(first_lba) + self.root_dir_size() +
+            ((idx - 2) as usize) * (self.sectors_per_cluster as usize)
+    }
+
+    pub fn write_boot_sector(&self, sector: &mut [u8], first_lba: u64) {
+        let sector_size = sector.len() as u16;
+
+        sector[0x0B..0x0D].copy_from_slice(&sector_size.to_le_bytes());
+        sector[0x0D] = self.sectors_per_cluster;
+        sector[0x0E..0x10].copy_from_slice(&self.reserved_sectors.to_le_bytes());
+        sector[0x10] = self.num_fats;
+        sector[0x11..0x13].copy_from_slice(&self.root_entries.to_le_bytes());
+        sector[0x15] = 0xF8;
+
+        // Hidden sectors: the volume's LBA.
+        sector[0x1C..0x20].copy_from_slice(&(first_lba as u32).to_le_bytes());
+        sector[0x20..0x24].copy_from_slice(&self.total_sectors.to_le_bytes());
+
+        if self.root_entries == 0 {
+            sector[0x24..0x28].copy_from_slice(&self.fat_size.to_le_bytes());
+            sector[0x2C..0x30].copy_from_slice(&2u32.to_le_bytes());
+        } else {
+            sector[0x16..0x18].copy_from_slice(&(self.fat_size as u16).to_le_bytes());
+        }
+
+        sector[0x1FE] = 0x55;
+        sector[0x1FF] = 0xAA;
+    }
+}
+
+/// Makes an empty FAT16 volume (see `Geometry::fat16`) whose boot sector is
+/// at LBA 1.
+pub fn fat16_volume() -> (MockStorage, PartitionEntry, Geometry) {
+    let g = Geometry::fat16();
+    let mut s = MockStorage::new(1 + g.total_sectors as usize);
+
+    g.write_boot_sector(s.sector_mut(1), 1);
+    set_fat16_entry(&mut s, &g, 0, 0xFFF8);
+    set_fat16_entry(&mut s, &g, 1, 0xFFFF);
+
+    (s, PartitionEntry::fat("RTOS", 1, g.total_sectors as u64), g)
+}
+
+/// Makes a disk with a GPT (see `write_gpt`) whose first partition is an empty
+/// FAT16 volume (see `Geometry::fat16`) starting at LBA 34.
+pub fn gpt_fat16_volume() -> (MockStorage, Geometry) {
+    let g = Geometry::fat16();
+    let first_lba = 34;
+    let mut s = MockStorage::new(first_lba as usize + g.total_sectors as usize);
+
+    write_gpt(&mut s, &[
+        (Guid::microsoft_basic_data(), first_lba, first_lba + g.total_sectors as u64 - 1),
+    ]);
+    g.write_boot_sector(s.sector_mut(first_lba as usize), first_lba);
+
+    let fat = g.fat_start(first_lba) * 512;
+    s.data[fat..(fat + 4)].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0xFF]);
+
+    (s, g)
+}
+
+pub fn set_fat16_entry(s: &mut MockStorage, g: &Geometry, idx: u32, val: u16) {
+    let offset = (g.fat_start(1) * 512) + (idx as usize) * 2;
+    s.data[offset..(offset + 2)].copy_from_slice(&val.to_le_bytes());
+}
+
+/// Writes `entry` into the `slot`th entry of the directory that starts at
+/// `sector`.
+pub fn put_dir_entry(s: &mut MockStorage, sector: usize, slot: usize, entry: &DirEntry) {
+    let mut buf = [0u8; 32];
+    entry.into_arr(&mut buf);
+
+    let offset = sector * 512 + slot * 32;
+    s.data[offset..(offset + 32)].copy_from_slice(&buf);
+}
+
+/// Writes a GPT header at LBA 1 and a table of 128 entries at LBA 2-33 with
+/// `partitions` (type, first LBA, last LBA) at the start of the table.
+pub fn write_gpt(s: &mut MockStorage, partitions: &[(Guid, u64, u64)]) {
+    let header = s.sector_mut(1);
+    header[0..8].copy_from_slice(b"EFI PART");
+    header[72..80].copy_from_slice(&2u64.to_le_bytes());
+    header[80..84].copy_from_slice(&128u32.to_le_bytes());
+    header[84..88].copy_from_slice(&128u32.to_le_bytes());
+
+    for (i, (ty, first, last)) in partitions.iter().enumerate() {
+        let offset = 2 * 512 + i * 128;
+        let entry = &mut s.data[offset..(offset + 128)];
+
+        entry[0..16].copy_from_slice(&ty.to_bytes());
+        entry[32..40].copy_from_slice(&first.to_le_bytes());
+        entry[40..48].copy_from_slice(&last.to_le_bytes());
+    }
+}
+
+pub fn sector_of(path: &PathBuf, idx: usize) -> Vec<u8> {
+    std::fs::read(path).unwrap()[(idx * 512)..((idx + 1) * 512)].to_vec()
+}
+
+/// In-memory storage that can be told to fail reads or writes to specific
+/// sectors, or to quietly drop writes to them.
+///
+/// Sectors are 512 bytes unless `N` says otherwise.
+#[derive(Debug, Clone)]
+pub struct MockStorage<N: ArrayLength<u8> = U512> {
+    pub data: Vec<u8>,
+
+    pub failing_reads: Vec<usize>,
+    pub failing_writes: Vec<usize>,
+    /// Writes to these sectors claim to succeed but don't change anything.
+    pub lost_writes: Vec<usize>,
+
+    pub reads: usize,
+    pub writes: usize,
+
+    _n: PhantomData<N>,
+}
+
+impl MockStorage {
+    pub fn new(sectors: usize) -> Self {
+        Self::with_sector_size(sectors)
+    }
+}
+
+impl<N: ArrayLength<u8>> MockStorage<N> {
+    pub fn with_sector_size(sectors: usize) -> Self {
+        Self {
+            data: vec![0; sectors * N::to_usize()],
+            failing_reads: Vec::new(),
+            failing_writes: Vec::new(),
+            lost_writes: Vec::new(),
+            reads: 0,
+            writes: 0,
+            _n: PhantomData,
+        }
+    }
+
+    pub fn sector(&self, idx: usize) -> &[u8] {
+        &self.data[(idx * N::to_usize())..((idx + 1) * N::to_usize())]
+    }
+
+    pub fn sector_mut(&mut self, idx: usize) -> &mut [u8] {
+        &mut self.data[(idx * N::to_usize())..((idx + 1) * N::to_usize())]
+    }
+}
+
+impl<N: ArrayLength<u8>> Storage for MockStorage<N> {
+    type Word = u8;
+    type SECTOR_SIZE = N;
+
+    type ReadErr = ();
+    type WriteErr = ();
+
+    fn capacity(&self) -> usize {
+        self.data.len() / N::to_usize()
+    }
+
+    fn read_sector(
+        &mut self,
+        sector_idx: usize,
+        buffer: &mut GenericArray<u8, N>,
+    ) -> Result<(), ReadError<()>> {
+        self.reads += 1;
+
+        if self.failing_reads.contains(&sector_idx) {
+            return Err(ReadError::Other(()));
+        }
+
+        buffer.copy_from_slice(self.sector(sector_idx));
+        Ok(())
+    }
+
+    fn write_sector(
+        &mut self,
+        sector_idx: usize,
+        words: &GenericArray<u8, N>,
+    ) -> Result<(), WriteError<()>> {
+        self.writes += 1;
+
+        if self.failing_writes.contains(&sector_idx) {
+            return Err(WriteError::Other(()));
+        }
+
+        if !self.lost_writes.contains(&sector_idx) {
+            self.sector_mut(sector_idx).copy_from_slice(words);
+        }
+        Ok(())
+    }
+}
+
+impl<N: ArrayLength<u8>> StorageExt for MockStorage<N> { }
@@ -0,0 +1,93 @@
+// An example of a Mutex from outside this crate being used with it.
+
+mod common;
+
+use fs::mutex::MutexInterface;
+use fs::fat::{
+    FatFs,
+    types::SectorIdx,
+    cache::{DynEvictionPolicy, eviction_policies::UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED},
+};
+
+use typenum::consts::U4;
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A spin lock.
+pub struct SpinMutex<T> {
+    locked: AtomicBool,
+    inner: UnsafeCell<T>,
+}
+
+impl<T> SpinMutex<T> {
+    pub const fn new(inner: T) -> Self {
+        Self { locked: AtomicBool::new(false), inner: UnsafeCell::new(inner) }
+    }
+}
+
+impl<T: Send> MutexInterface<T> for SpinMutex<T> {
+    fn new(inner: T) -> Self {
+        SpinMutex::new(inner)
+    }
+
+    fn cs<F: FnOnce(&mut T) -> R, R>(&self, func: F) -> R {
+        while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            std::hint::spin_loop();
+        }
+
+        let res = func(unsafe { &mut *self.inner.get() });
+        self.locked.store(false, Ordering::Release);
+
+        res
+    }
+
+    fn try_cs<F: FnOnce(&mut T) -> R, R>(&self, func: F) -> Option<R> {
+        if self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            return None;
+        }
+
+        let res = func(unsafe { &mut *self.inner.get() });
+        self.locked.store(false, Ordering::Release);
+
+        Some(res)
+    }
+
+    fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+}
+
+unsafe impl<T: Send> Sync for SpinMutex<T> { }
+
+static STORAGE: SpinMutex<Option<common::MockStorage>> = SpinMutex::new(None);
+static FS: SpinMutex<Option<
+    FatFs<common::MockStorage, U4, DynEvictionPolicy>
+>> = SpinMutex::new(None);
+
+#[test]
+fn custom_mutex_wraps_global_fs() {
+    STORAGE.cs(|s| {
+        let mut storage = common::MockStorage::new(16);
+        common::bare_boot_sector(storage.sector_mut(1));
+        *s = Some(storage);
+
+        let s = s.as_mut().unwrap();
+        let partition = fs::gpt::PartitionEntry::fat("RTOS", 1, 15);
+
+        FS.cs(|f| {
+            *f = Some(FatFs::mount(s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap());
+        })
+    });
+
+    let written = std::thread::spawn(|| STORAGE.cs(|s| FS.cs(|f| {
+        let (s, f) = (s.as_mut().unwrap(), f.as_mut().unwrap());
+
+        f.write(s, SectorIdx::new(5), 0, b"hi").unwrap();
+        f.cache.flush(s).unwrap();
+
+        s.sector(5)[..2].to_vec()
+    }))).join().unwrap();
+
+    assert_eq!(written, b"hi");
+}
@@ -0,0 +1,893 @@
+// Run with --no-default-features.
+
+mod common;
+
+use fs::fat::{
+    FatError,
+    FatFs,
+    LookupError,
+    OpenError,
+    ROOT_DIR_ENTRY_OFFSET,
+    types::{ClusterIdx, ClusterOffset},
+    table::FatEntryTracer,
+    dir::{Attribute, AttributeSet, Date, DateTime, DirEntry, DirEntryBuilder, DirEntryRef, DirIter, FileName, FileExt, State},
+    cache::eviction_policies::UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+};
+
+use typenum::consts::U4;
+
+type Fs = FatFs<common::MockStorage, U4>;
+
+/// Builds this tree on a FAT16 volume:
+///
+/// ```text
+/// /HELLO.TXT
+/// /README.TXT
+/// /SUB/A.TXT
+/// /SUB/NESTED/DEEP.TXT
+/// ```
+fn tree() -> (common::MockStorage, Fs) {
+    let (mut s, partition, g) = common::fat16_volume();
+
+    let file = |name: &[u8; 8], ext: &[u8; 3], cluster, size| {
+        let mut d = DirEntry::new_file(FileName(*name), FileExt(*ext), ClusterIdx::new(cluster));
+        d.file_size = size;
+        d
+    };
+    let dir = |name: &[u8; 8], cluster| DirEntry::new_dir(FileName(*name), ClusterIdx::new(cluster));
+
+    let mut hello = file(b"HELLO   ", b"TXT", 5, 12);
+    // 2020-04-17 13:45:30, accessed 2020-05-01:
+    hello.last_modif_date = (40 << 9) | (4 << 5) | 17;
+    hello.last_modif_time = (13 << 11) | (45 << 5) | 15;
+    hello.last_access_date = (40 << 9) | (5 << 5) | 1;
+
+    let root = g.root_dir_start(1);
+    common::put_dir_entry(&mut s, root, 0, &hello);
+    common::put_dir_entry(&mut s, root, 1, &dir(b"SUB     ", 3));
+    common::put_dir_entry(&mut s, root, 2, &file(b"README  ", b"TXT", 8, 3));
+
+    // `..` is 0 when the parent is the root directory.
+    let dots = |s: &mut common::MockStorage, sector, this, parent| {
+        let mut dot = dir(b".       ", this);
+        dot.file_ext = FileExt(*b"   ");
+        let mut dot_dot = dir(b"..      ", parent);
+        dot_dot.file_ext = FileExt(*b"   ");
+
+        common::put_dir_entry(s, sector, 0, &dot);
+        common::put_dir_entry(s, sector, 1, &dot_dot);
+    };
+
+    let sub = g.cluster_start(1, 3);
+    dots(&mut s, sub, 3, 0);
+    common::put_dir_entry(&mut s, sub, 2, &dir(b"NESTED  ", 4));
+    common::put_dir_entry(&mut s, sub, 3, &file(b"A       ", b"TXT", 6, 1));
+
+    let nested = g.cluster_start(1, 4);
+    dots(&mut s, nested, 4, 3);
+    common::put_dir_entry(&mut s, nested, 2, &file(b"DEEP    ", b"TXT", 7, 5));
+
+    for c in 3..=8 {
+        common::set_fat16_entry(&mut s, &g, c, 0xFFF8);
+    }
+
+    let fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    (s, fs)
+}
+
+#[test]
+fn lookup_nested_path() {
+    let (mut s, mut fs) = tree();
+
+    let (_, entry) = fs.lookup_path(&mut s, b"/SUB/NESTED/DEEP.TXT").unwrap();
+    assert_eq!(entry.file_name, FileName(*b"DEEP    "));
+    assert_eq!(entry.cluster_idx(), ClusterIdx::new(7));
+    assert_eq!(entry.file_size, 5);
+
+    let (_, entry) = fs.lookup_path(&mut s, b"/SUB/NESTED/").unwrap();
+    assert!(entry.attributes.is_dir());
+
+    let (_, entry) = fs.lookup_path(&mut s, b"/").unwrap();
+    assert_eq!(entry.cluster_idx(), fs.root_dir_cluster_num);
+}
+
+#[test]
+fn the_root_dir_location_cant_be_written() {
+    let (mut s, mut fs) = tree();
+
+    for path in [&b"/"[..], b"/.", b"/SUB/.."].iter() {
+        let (loc, entry) = fs.lookup_path(&mut s, path).unwrap();
+        assert_eq!(loc, (fs.root_dir_cluster_num, ROOT_DIR_ENTRY_OFFSET));
+        assert_eq!(fs.write_dir_entry(&mut s, loc, &entry), Err(FatError::InvalidArgument));
+    }
+
+    // The first entry in the root directory is untouched:
+    let (loc, entry) = fs.lookup_path(&mut s, b"/HELLO.TXT").unwrap();
+    assert_eq!(loc, (fs.root_dir_cluster_num, 0));
+    assert_eq!(entry.file_size, 12);
+}
+
+#[test]
+fn metadata_and_exists() {
+    let (mut s, mut fs) = tree();
+
+    let m = fs.metadata(&mut s, b"/HELLO.TXT").unwrap();
+    assert!(m.is_file && !m.is_dir);
+    assert_eq!(m.len, 12);
+    assert_eq!(m.modified, DateTime {
+        date: Date { year: 2020, month: 4, day: 17 },
+        hour: 13,
+        minute: 45,
+        second: 30,
+    });
+    assert_eq!(m.accessed, Date { year: 2020, month: 5, day: 1 });
+    assert!(fs.exists(&mut s, b"/HELLO.TXT"));
+
+    let m = fs.metadata(&mut s, b"/SUB/NESTED").unwrap();
+    assert!(m.is_dir && !m.is_file);
+    assert_eq!(m.len, 0);
+    assert!(fs.exists(&mut s, b"/SUB/NESTED"));
+
+    assert_eq!(fs.metadata(&mut s, b"/SUB/NOPE.TXT").unwrap_err(), LookupError::NotFound(b"NOPE.TXT"));
+    assert!(!fs.exists(&mut s, b"/SUB/NOPE.TXT"));
+    assert!(!fs.exists(&mut s, b""));
+}
+
+#[test]
+fn lookup_missing_middle_component() {
+    let (mut s, mut fs) = tree();
+
+    assert_eq!(
+        fs.lookup_path(&mut s, b"/SUB/NOPE/DEEP.TXT").unwrap_err(),
+        LookupError::NotFound(b"NOPE"),
+    );
+}
+
+#[test]
+fn lookup_through_a_file() {
+    let (mut s, mut fs) = tree();
+
+    assert_eq!(
+        fs.lookup_path(&mut s, b"/HELLO.TXT/").unwrap_err(),
+        LookupError::NotADirectory,
+    );
+    assert_eq!(
+        fs.lookup_path(&mut s, b"/SUB/A.TXT/DEEP.TXT").unwrap_err(),
+        LookupError::NotADirectory,
+    );
+    assert_eq!(fs.lookup_path(&mut s, b"").unwrap_err(), LookupError::Empty);
+}
+
+#[test]
+fn lookup_ignores_case() {
+    let (mut s, mut fs) = tree();
+
+    for path in [&b"/readme.txt"[..], b"/README.TXT", b"/ReadMe.Txt"].iter() {
+        let (_, entry) = fs.lookup_path(&mut s, path).unwrap();
+        assert_eq!(entry.cluster_idx(), ClusterIdx::new(8));
+    }
+
+    let (_, entry) = fs.lookup_path(&mut s, b"/sub/Nested/deep.TXT").unwrap();
+    assert_eq!(entry.cluster_idx(), ClusterIdx::new(7));
+}
+
+#[test]
+fn create_nested_dirs() {
+    let (mut s, partition, _) = common::fat16_volume();
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    // The parent has to exist for `create_dir`:
+    assert!(fs.create_dir(&mut s, b"/a/b").is_err());
+
+    fs.create_dir_all(&mut s, b"/a/b/c").unwrap();
+    assert!(fs.create_dir(&mut s, b"/a/b/c").is_err());
+
+    fs.cache.flush(&mut s).unwrap();
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    let (_, b) = fs.lookup_path(&mut s, b"/a/b").unwrap();
+
+    // `.` and `..` are skipped unless asked for:
+    let names: Vec<_> = b.into_dir_iter(&mut fs, &mut s).unwrap()
+        .filter(|(_, d)| d.state() == State::Exists)
+        .map(|(_, d)| d.file_name.0)
+        .collect();
+    assert_eq!(names, vec![*b"C       "]);
+
+    let entries: Vec<_> = b.into_dir_iter(&mut fs, &mut s).unwrap()
+        .with_dot_entries(true)
+        .filter(|(_, d)| d.state() == State::Exists)
+        .map(|(_, d)| d)
+        .collect();
+
+    let names: Vec<_> = entries.iter().map(|d| d.file_name.0).collect();
+    assert_eq!(names, vec![*b".       ", *b"..      ", *b"C       "]);
+
+    // `.` is b itself and `..` is a:
+    let (_, a) = fs.lookup_path(&mut s, b"/a").unwrap();
+    assert!(entries[0].is_dot() && entries[1].is_dotdot());
+    assert_eq!(entries[0].cluster_idx(), b.cluster_idx());
+    assert_eq!(entries[1].cluster_idx(), a.cluster_idx());
+    assert!(entries[2].attributes.is_dir());
+
+    // `..` in a top level directory refers to the root as 0:
+    let (_, dot_dot) = a.into_dir_iter(&mut fs, &mut s).unwrap().with_dot_entries(true).nth(1).unwrap();
+    assert_eq!(dot_dot.file_name.0, *b"..      ");
+    assert_eq!(dot_dot.cluster_idx(), ClusterIdx::new(0));
+}
+
+#[test]
+fn lookup_dot_components() {
+    let (mut s, partition, _) = common::fat16_volume();
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    fs.create_dir_all(&mut s, b"/a/b").unwrap();
+    fs.create_file(&mut s, b"/a/f.txt").unwrap();
+
+    let (_, a) = fs.lookup_path(&mut s, b"/a").unwrap();
+    let (_, f) = fs.lookup_path(&mut s, b"/a/f.txt").unwrap();
+
+    assert_eq!(fs.lookup_path(&mut s, b"/a/./b/../f.txt").unwrap().1, f);
+    assert_eq!(fs.lookup_path(&mut s, b"/a/b/..").unwrap().1.cluster_idx(), a.cluster_idx());
+
+    // `..` from a top level directory (and from the root) is the root:
+    let root = fs.root_dir_cluster_num;
+    assert_eq!(fs.lookup_path(&mut s, b"/a/..").unwrap().1.cluster_idx(), root);
+    assert_eq!(fs.lookup_path(&mut s, b"/../a/../..").unwrap().1.cluster_idx(), root);
+
+    assert_eq!(fs.lookup_path(&mut s, b"/a/f.txt/..").unwrap_err(), LookupError::NotADirectory);
+
+    fs.cache.flush(&mut s).unwrap();
+}
+
+#[test]
+fn rename_within_a_directory() {
+    let (mut s, mut fs) = tree();
+
+    fs.rename(&mut s, b"/HELLO.TXT", b"/greet.txt").unwrap();
+
+    assert_eq!(
+        fs.lookup_path(&mut s, b"/HELLO.TXT").unwrap_err(),
+        LookupError::NotFound(b"HELLO.TXT"),
+    );
+
+    let (_, entry) = fs.lookup_path(&mut s, b"/GREET.TXT").unwrap();
+    assert_eq!(entry.cluster_idx(), ClusterIdx::new(5));
+    assert_eq!(entry.file_size, 12);
+
+    // Can't clobber something that exists:
+    assert!(fs.rename(&mut s, b"/GREET.TXT", b"/README.TXT").is_err());
+    assert!(fs.lookup_path(&mut s, b"/GREET.TXT").is_ok());
+
+    fs.cache.flush(&mut s).unwrap();
+}
+
+#[test]
+fn move_into_a_subdirectory() {
+    let (mut s, mut fs) = tree();
+
+    fs.rename(&mut s, b"/README.TXT", b"/SUB/NESTED/README.MD").unwrap();
+    assert!(fs.lookup_path(&mut s, b"/README.TXT").is_err());
+
+    let (_, entry) = fs.lookup_path(&mut s, b"/SUB/NESTED/README.MD").unwrap();
+    assert_eq!(entry.cluster_idx(), ClusterIdx::new(8));
+    assert_eq!(entry.file_size, 3);
+
+    // Directories bring their contents along:
+    fs.create_dir(&mut s, b"/OTHER").unwrap();
+    fs.rename(&mut s, b"/SUB/NESTED", b"/OTHER/NESTED").unwrap();
+
+    let (_, deep) = fs.lookup_path(&mut s, b"/OTHER/NESTED/DEEP.TXT").unwrap();
+    assert_eq!(deep.cluster_idx(), ClusterIdx::new(7));
+    assert!(fs.lookup_path(&mut s, b"/SUB/NESTED").is_err());
+
+    // And have their `..` updated:
+    fs.create_dir(&mut s, b"/OTHER/INNER").unwrap();
+    fs.rename(&mut s, b"/OTHER/INNER", b"/SUB/INNER").unwrap();
+
+    let (_, sub) = fs.lookup_path(&mut s, b"/SUB").unwrap();
+    let (_, inner) = fs.lookup_path(&mut s, b"/SUB/INNER").unwrap();
+    let (_, dot_dot) = inner.into_dir_iter(&mut fs, &mut s).unwrap().with_dot_entries(true).nth(1).unwrap();
+    assert_eq!(dot_dot.cluster_idx(), sub.cluster_idx());
+
+    fs.cache.flush(&mut s).unwrap();
+}
+
+#[test]
+fn rename_removes_long_name_entries() {
+    let (mut s, partition, g) = common::fat16_volume();
+
+    let root = g.root_dir_start(1);
+    let mut lfn = [0u8; 32];
+    lfn[0] = 0x41;
+    lfn[11] = 0x0F;
+    s.sector_mut(root)[0..32].copy_from_slice(&lfn);
+
+    let file = DirEntry::new_file(FileName(*b"LONGFI~1"), FileExt(*b"TXT"), ClusterIdx::new(3));
+    common::put_dir_entry(&mut s, root, 1, &file);
+    common::set_fat16_entry(&mut s, &g, 3, 0xFFF8);
+
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    fs.rename(&mut s, b"/LONGFI~1.TXT", b"/SHORT.TXT").unwrap();
+    fs.cache.flush(&mut s).unwrap();
+
+    assert_eq!(s.sector(root)[0], 0xE5);
+    assert_eq!(s.sector(root)[32], 0xE5);
+    assert_eq!(&s.sector(root)[64..72], b"SHORT   ");
+}
+
+#[test]
+fn walk_tree() {
+    let (mut s, mut fs) = tree();
+
+    let mut paths = Vec::new();
+    let root = fs.root_dir_cluster_num;
+    fs.walk(&mut s, root, |p, _| paths.push(String::from_utf8(p.to_vec()).unwrap())).unwrap();
+
+    assert_eq!(paths, vec![
+        "/HELLO.TXT",
+        "/SUB",
+        "/SUB/NESTED",
+        "/SUB/NESTED/DEEP.TXT",
+        "/SUB/A.TXT",
+        "/README.TXT",
+    ]);
+
+    // Paths are relative to where the walk starts:
+    let mut paths = Vec::new();
+    fs.walk(&mut s, ClusterIdx::new(3), |p, d| {
+        paths.push((String::from_utf8(p.to_vec()).unwrap(), d.attributes.is_dir()))
+    }).unwrap();
+
+    assert_eq!(paths, vec![
+        ("/NESTED".to_string(), true),
+        ("/NESTED/DEEP.TXT".to_string(), false),
+        ("/A.TXT".to_string(), false),
+    ]);
+}
+
+#[test]
+fn walk_skips_dot_entries() {
+    let (mut s, partition, _) = common::fat16_volume();
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    fs.create_dir_all(&mut s, b"/a/b").unwrap();
+    fs.create_file(&mut s, b"/a/b/f.txt").unwrap();
+
+    let mut paths = Vec::new();
+    let root = fs.root_dir_cluster_num;
+    fs.walk(&mut s, root, |p, _| paths.push(String::from_utf8(p.to_vec()).unwrap())).unwrap();
+
+    assert_eq!(paths, vec!["/A", "/A/B", "/A/B/F.TXT"]);
+
+    fs.cache.flush(&mut s).unwrap();
+}
+
+#[test]
+fn list_dir() {
+    let (mut s, mut fs) = tree();
+
+    let mut out = vec![DirEntry::empty(); 4];
+    assert_eq!(fs.list_dir(&mut s, b"/", &mut out), Ok(3));
+    assert_eq!(out[0].file_name, FileName(*b"HELLO   "));
+    assert_eq!(out[1].file_name, FileName(*b"SUB     "));
+    assert_eq!(out[2].file_name, FileName(*b"README  "));
+
+    // Too small:
+    let mut out = vec![DirEntry::empty(); 2];
+    assert_eq!(fs.list_dir(&mut s, b"/", &mut out), Err(FatError::OutOfSpace));
+    assert_eq!(out[1].file_name, FileName(*b"SUB     "));
+
+    assert_eq!(fs.list_dir(&mut s, b"/README.TXT", &mut out), Err(FatError::NotADirectory));
+    assert_eq!(fs.list_dir(&mut s, b"/NOPE", &mut out), Err(FatError::NotFound));
+
+    // Deleted entries are skipped:
+    let hello = fs.lookup_path(&mut s, b"/HELLO.TXT").unwrap();
+    let root = fs.root_dir_cluster_num;
+    DirIter::from_cluster(root, &mut fs, &mut s).delete(hello).unwrap();
+
+    let names: Vec<_> = fs.list_dir_vec(&mut s, b"/").unwrap().into_iter().map(|(n, _)| n).collect();
+    assert_eq!(names, vec!["SUB", "README.TXT"]);
+
+    let names: Vec<_> = fs.list_dir_vec(&mut s, b"/SUB/").unwrap().into_iter().map(|(n, _)| n).collect();
+    assert_eq!(names, vec!["NESTED", "A.TXT"]);
+
+    fs.cache.flush(&mut s).unwrap();
+}
+
+#[test]
+fn volume_label_roundtrip() {
+    let (mut s, partition, g) = common::fat16_volume();
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    assert_eq!(fs.volume_label(&mut s), Ok(None));
+
+    // Read errors are passed along:
+    fs.cache.invalidate_all().unwrap();
+    s.failing_reads.push(1);
+    assert_eq!(fs.volume_label_bytes(&mut s), Err(FatError::Io));
+    s.failing_reads.clear();
+
+    assert!(fs.set_volume_label(&mut s, "").is_err());
+    assert!(fs.set_volume_label(&mut s, "much too long").is_err());
+    assert!(fs.set_volume_label(&mut s, "a/b").is_err());
+
+    fs.set_volume_label(&mut s, "my disk").unwrap();
+    fs.cache.flush(&mut s).unwrap();
+
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    assert_eq!(fs.volume_label(&mut s), Ok(Some("MY DISK".to_string())));
+
+    // It's in the boot sector too:
+    assert_eq!(s.sector(1)[0x26], 0x29);
+    assert_eq!(&s.sector(1)[0x2B..0x36], b"MY DISK    ");
+
+    // Setting it again replaces the entry rather than adding another one:
+    fs.set_volume_label(&mut s, "RTOS").unwrap();
+    fs.cache.flush(&mut s).unwrap();
+
+    let root = g.root_dir_start(1);
+    assert_eq!(&s.sector(root)[0..11], b"RTOS       ");
+    assert_eq!(s.sector(root)[32], 0);
+
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    assert_eq!(fs.volume_label(&mut s), Ok(Some("RTOS".to_string())));
+    assert_eq!(fs.volume_label_bytes(&mut s), Ok(Some(*b"RTOS       ")));
+
+    // The label entry isn't a file:
+    let mut paths = Vec::new();
+    let root = fs.root_dir_cluster_num;
+    fs.walk(&mut s, root, |p, _| paths.push(p.to_vec())).unwrap();
+    assert!(paths.is_empty());
+}
+
+#[test]
+fn write_dir_entry_persists() {
+    let (mut s, partition, _) = common::fat16_volume();
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    fs.create_dir(&mut s, b"/sub").unwrap();
+    fs.create_file(&mut s, b"/sub/log.txt").unwrap();
+
+    let (loc, mut entry) = fs.lookup_path(&mut s, b"/sub/log.txt").unwrap();
+    assert_eq!(entry.file_size, 0);
+
+    entry.file_size = 1234;
+    entry.last_modif_date = (40 << 9) | (1 << 5) | 2;
+    fs.write_dir_entry(&mut s, loc, &entry).unwrap();
+
+    fs.cache.flush(&mut s).unwrap();
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    let (new_loc, found) = fs.lookup_path(&mut s, b"/sub/log.txt").unwrap();
+    assert_eq!(new_loc, loc);
+    assert_eq!(found, entry);
+    assert_eq!(fs.metadata(&mut s, b"/sub/log.txt").unwrap().len, 1234);
+    assert_eq!(found.modified().date, Date { year: 2020, month: 1, day: 2 });
+}
+
+#[test]
+fn deleted_slots_are_reused() {
+    let (mut s, partition, _) = common::fat16_volume();
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    // One 512 byte cluster: room for `.`, `..`, and 14 other entries.
+    fs.create_dir(&mut s, b"/churn").unwrap();
+    fs.create_file(&mut s, b"/churn/keep.txt").unwrap();
+    let (_, dir) = fs.lookup_path(&mut s, b"/churn").unwrap();
+    let dir = dir.cluster_idx();
+
+    for i in 0..50 {
+        let path = format!("/churn/f{}.txt", i);
+        fs.create_file(&mut s, path.as_bytes()).unwrap();
+
+        let found = fs.lookup_path(&mut s, path.as_bytes()).unwrap();
+        if i == 0 {
+            // Right after `keep.txt`.
+            assert_eq!(found.0, (dir, 3 * 32));
+        }
+        DirIter::from_cluster(dir, &mut fs, &mut s).delete(found).unwrap();
+    }
+
+    // The slot the first file had keeps getting reused:
+    let entries: Vec<_> = DirIter::from_cluster(dir, &mut fs, &mut s)
+        .map(|((_, offset), entry)| (offset, entry.state()))
+        .collect();
+    assert_eq!(entries, vec![(2 * 32, State::Exists), (3 * 32, State::Deleted), (4 * 32, State::End)]);
+
+    assert_eq!(FatEntryTracer::starting_at(&mut fs, &mut s, dir).analyze().unwrap().cluster_count, 1);
+    assert!(fs.exists(&mut s, b"/churn/keep.txt"));
+
+    fs.cache.flush(&mut s).unwrap();
+}
+
+#[test]
+fn attributes() {
+    let (mut s, partition, g) = common::fat16_volume();
+    let root = g.root_dir_start(1);
+
+    // No `Archive` bit:
+    let mut read_only = DirEntry::new_file(FileName(*b"RO      "), FileExt(*b"TXT"), ClusterIdx::new(3));
+    read_only.attributes = AttributeSet::new().with(Attribute::ReadOnly);
+    common::put_dir_entry(&mut s, root, 0, &read_only);
+
+    let mut hidden = DirEntry::new_dir(FileName(*b"SECRET  "), ClusterIdx::new(4));
+    hidden.attributes = hidden.attributes.with(Attribute::Hidden);
+    common::put_dir_entry(&mut s, root, 1, &hidden);
+
+    common::put_dir_entry(&mut s, root, 2, &DirEntry::new_volume_label(*b"MY VOLUME  "));
+
+    common::set_fat16_entry(&mut s, &g, 3, 0xFFF8);
+    common::set_fat16_entry(&mut s, &g, 4, 0xFFF8);
+
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    let (_, ro) = fs.lookup_path(&mut s, b"/RO.TXT").unwrap();
+    assert!(ro.attributes.is_file());
+    assert!(!ro.attributes.is_dir());
+    assert!(ro.attributes.contains(Attribute::ReadOnly));
+    assert!(!ro.attributes.contains(Attribute::Archive));
+    assert!(fs.metadata(&mut s, b"/RO.TXT").unwrap().is_file);
+    assert!(ro.clone().into_file().is_ok());
+    assert!(ro.into_dir_iter(&mut fs, &mut s).is_none());
+
+    let (_, secret) = fs.lookup_path(&mut s, b"/SECRET").unwrap();
+    assert!(secret.attributes.is_dir());
+    assert!(!secret.attributes.is_file());
+    assert!(secret.attributes.contains(Attribute::Hidden));
+    assert!(!secret.attributes.contains(Attribute::System));
+    assert_eq!(secret.attributes.without(Attribute::Hidden), AttributeSet::new().with(Attribute::Directory));
+    assert!(secret.clone().into_file().is_err());
+    assert!(secret.into_dir_iter(&mut fs, &mut s).is_some());
+
+    let label = DirIter::from_cluster(fs.root_dir_cluster_num, &mut fs, &mut s)
+        .map(|(_, e)| e)
+        .find(|e| e.attributes.is_volume_id())
+        .unwrap();
+    assert!(!label.attributes.is_file());
+    assert!(!label.attributes.is_dir());
+    assert!(label.into_file().is_err());
+
+    assert!(!AttributeSet::LFN.is_file());
+}
+
+#[test]
+fn dir_entry_builder() {
+    let created = DateTime {
+        date: Date { year: 2021, month: 3, day: 14 },
+        hour: 15,
+        minute: 9,
+        second: 27,
+    };
+    let modified = DateTime {
+        date: Date { year: 2022, month: 12, day: 31 },
+        hour: 23,
+        minute: 59,
+        second: 58,
+    };
+
+    let entry = DirEntryBuilder::new(FileName(*b"NOTES   "), FileExt(*b"TXT"))
+        .attribute(Attribute::ReadOnly)
+        .attribute(Attribute::Archive)
+        .cluster(ClusterIdx::new(0x0012_3456))
+        .size(4321)
+        .created(created)
+        .modified(modified)
+        .accessed(modified)
+        .build();
+
+    let mut arr = [0; 32];
+    entry.into_arr(&mut arr);
+    let entry = DirEntry::from_arr(arr);
+
+    assert_eq!(entry.file_name, FileName(*b"NOTES   "));
+    assert_eq!(entry.file_ext, FileExt(*b"TXT"));
+    assert_eq!(entry.attributes, AttributeSet::new().with(Attribute::ReadOnly).with(Attribute::Archive));
+    assert!(entry.attributes.is_file());
+    assert_eq!(entry.cluster_idx(), ClusterIdx::new(0x0012_3456));
+    assert_eq!(entry.file_size, 4321);
+
+    // Odd seconds survive in the creation time (but not the others):
+    assert_eq!(entry.created(), created);
+    assert_eq!(entry.modified(), modified);
+    assert_eq!(entry.accessed(), modified.date);
+
+    // Nothing set means zeros, like `new_file`:
+    let bare = DirEntryBuilder::new(FileName(*b"A       "), FileExt(*b"   ")).build();
+    assert_eq!(bare.creation_date, 0);
+    assert_eq!(bare.last_modif_time, 0);
+    assert_eq!(bare.last_access_date, 0);
+}
+
+#[test]
+fn borrowed_entries() {
+    let (mut s, mut fs) = tree();
+
+    let (_, sub) = fs.lookup_path(&mut s, b"/SUB").unwrap();
+
+    let owned: Vec<_> = DirIter::from_cluster(sub.cluster_idx(), &mut fs, &mut s)
+        .with_dot_entries(true)
+        .collect();
+
+    let mut borrowed = Vec::new();
+    let mut d = DirIter::from_cluster(sub.cluster_idx(), &mut fs, &mut s).with_dot_entries(true);
+    while let Some(e) = d.next_with(|pos, e: DirEntryRef<'_>| (pos, e.to_entry())) {
+        borrowed.push(e);
+    }
+    assert_eq!(owned, borrowed);
+
+    // The lazily decoded fields match the owned entry's:
+    let mut d = DirIter::from_cluster(sub.cluster_idx(), &mut fs, &mut s);
+    let (name, cluster, size, is_dir) = d.next_with(|_, e| {
+        (e.file_name(), e.cluster_idx(), e.file_size(), e.attributes().is_dir())
+    }).unwrap();
+    assert_eq!(name, FileName(*b"NESTED  "));
+    assert_eq!(cluster, ClusterIdx::new(4));
+    assert_eq!(size, 0);
+    assert!(is_dir);
+
+    let a = d.next_with(|_, e| e.to_entry()).unwrap();
+    assert_eq!(a.file_name, FileName(*b"A       "));
+    assert_eq!(a.file_size, 1);
+
+    // Then the terminator, and that's it:
+    assert_eq!(d.next_with(|_, e| e.state()), Some(State::End));
+    assert_eq!(d.next_with(|_, e| e.state()), None);
+}
+
+#[test]
+fn open_and_read() {
+    let (mut s, mut fs) = tree();
+
+    let (sector, _) = fs.cluster_to_sector(ClusterIdx::new(5), ClusterOffset::new(0));
+    fs.write(&mut s, sector, 0, b"hello, world").unwrap();
+
+    let file = fs.open(&mut s, b"/HELLO.TXT").unwrap();
+    let mut buf = vec![0xFF; fs.bytes_in_a_cluster() as usize];
+    file.upgrade(&mut fs, &mut s).read_at(0, &mut buf).unwrap();
+
+    assert_eq!(&buf[..12], b"hello, world");
+    assert!(buf[12..].iter().all(|b| *b == 0));
+
+    assert!(fs.open(&mut s, b"/SUB/NESTED/DEEP.TXT").is_ok());
+    assert_eq!(fs.open(&mut s, b"/SUB").unwrap_err(), OpenError::IsADirectory);
+    assert_eq!(fs.open(&mut s, b"/SUB/NESTED/").unwrap_err(), OpenError::IsADirectory);
+    assert_eq!(fs.open(&mut s, b"/SUB/NOPE.TXT").unwrap_err(), OpenError::NotFound(b"NOPE.TXT"));
+    assert_eq!(fs.open(&mut s, b"/HELLO.TXT/A.TXT").unwrap_err(), OpenError::NotADirectory);
+    assert_eq!(fs.open(&mut s, b"").unwrap_err(), OpenError::Empty);
+
+    fs.cache.flush(&mut s).unwrap();
+}
+
+#[test]
+fn display_short_names() {
+    let file = |name: &[u8; 8], ext: &[u8; 3]| DirEntry::new_file(FileName(*name), FileExt(*ext), ClusterIdx::new(3));
+
+    assert_eq!(file(b"README  ", b"TXT").to_string(), "README.TXT");
+    assert_eq!(file(b"MAKEFILE", b"   ").to_string(), "MAKEFILE");
+    assert_eq!(file(b"LONGNAME", b"C  ").to_string(), "LONGNAME.C");
+    assert_eq!(DirEntry::new_dir(FileName(*b"SUB     "), ClusterIdx::new(3)).to_string(), "SUB");
+}
+
+#[test]
+fn names_compare_without_padding() {
+    // Space padded, NUL padded, and exact length:
+    assert_eq!(FileName::new("A"), FileName(*b"A       "));
+    assert_eq!(FileName::new("A"), FileName(*b"A\0\0\0\0\0\0\0"));
+    assert_eq!(FileName(*b"A       "), FileName(*b"A\0\0\0\0\0\0\0"));
+    assert_eq!(FileName::new("LONGNAME"), FileName(*b"LONGNAME"));
+    assert_eq!(FileName::new("TOOLONGNAME"), FileName(*b"TOOLONGN"));
+    assert_ne!(FileName::new("A"), FileName::new("AB"));
+    assert_ne!(FileName::new("A"), FileName(*b" A      "));
+
+    assert_eq!(FileExt::new("C"), FileExt(*b"C\0\0"));
+    assert_eq!(FileExt::new(""), FileExt(*b"\0\0\0"));
+    assert_eq!(FileExt::new("TXT"), FileExt(*b"TXT"));
+    assert_ne!(FileExt::new("TX"), FileExt::new("TXT"));
+
+    assert!(FileName(*b"README\0\0").matches(b"readme"));
+    assert!(!FileName(*b"README  ").matches(b"read"));
+    assert!(FileExt(*b"TXT").matches(b"Txt"));
+    assert!(FileExt(*b"   ").matches(b""));
+
+    // Lookups go by the whole name, not a prefix of it:
+    let (mut s, mut fs) = tree();
+    assert!(fs.exists(&mut s, b"/HELLO.TXT"));
+    assert!(!fs.exists(&mut s, b"/HELLO"));
+    assert!(!fs.exists(&mut s, b"/HELL.TXT"));
+    assert!(!fs.exists(&mut s, b"/HELLO.T"));
+    assert!(!fs.exists(&mut s, b"/HELLOTHERE.TXT"));
+
+    // Including names padded with NULs:
+    let (_, sub) = fs.lookup_path(&mut s, b"/SUB").unwrap();
+    let mut d = DirIter::from_cluster(sub.cluster_idx(), &mut fs, &mut s);
+    while d.next().is_some() { }
+    d.add_entry(DirEntry::new_file(FileName(*b"NUL\0\0\0\0\0"), FileExt(*b"C\0\0"), ClusterIdx::new(9))).unwrap();
+
+    let (_, nul) = fs.lookup_path(&mut s, b"/sub/nul.c").unwrap();
+    assert_eq!(nul.cluster_idx(), ClusterIdx::new(9));
+
+    fs.cache.flush(&mut s).unwrap();
+}
+
+#[test]
+fn find_entry_stops_at_the_first_match() {
+    let (mut s, partition, g) = common::fat16_volume();
+
+    // /BIG is a directory that spans 3 clusters (16 entries each): 5 -> 6 -> 7.
+    let big = DirEntry::new_dir(FileName(*b"BIG     "), ClusterIdx::new(5));
+    common::put_dir_entry(&mut s, g.root_dir_start(1), 0, &big);
+
+    common::set_fat16_entry(&mut s, &g, 5, 6);
+    common::set_fat16_entry(&mut s, &g, 6, 7);
+    common::set_fat16_entry(&mut s, &g, 7, 0xFFF8);
+
+    for i in 0..40usize {
+        let mut name = *b"F       ";
+        name[1] = b'0' + (i / 10) as u8;
+        name[2] = b'0' + (i % 10) as u8;
+
+        let cluster = 5 + i / 16;
+        let file = DirEntry::new_file(FileName(name), FileExt(*b"BIN"), ClusterIdx::new(100 + i as u32));
+        common::put_dir_entry(&mut s, g.cluster_start(1, cluster), i % 16, &file);
+    }
+
+    // Something in the first cluster only needs that cluster read:
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    let reads = s.reads;
+
+    let mut iter = DirIter::from_cluster(ClusterIdx::new(5), &mut fs, &mut s);
+    let (pos, entry) = iter.find_entry(&FileName(*b"f01     "), &FileExt(*b"bin")).unwrap();
+    assert_eq!(pos, (ClusterIdx::new(5), 32));
+    assert_eq!(entry.cluster_idx(), ClusterIdx::new(101));
+    drop(iter);
+
+    assert_eq!(s.reads - reads, 1);
+
+    // Whereas going through the whole directory reads all three (and the FAT
+    // to get from one to the next):
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    let reads = s.reads;
+
+    let mut iter = DirIter::from_cluster(ClusterIdx::new(5), &mut fs, &mut s);
+    assert_eq!(iter.find_entry(&FileName(*b"NOPE    "), &FileExt(*b"BIN")), None);
+    drop(iter);
+
+    assert!(s.reads - reads >= 3);
+
+    // The search picks up where the iterator left off:
+    let mut iter = DirIter::from_cluster(ClusterIdx::new(5), &mut fs, &mut s);
+    assert!(iter.find_entry(&FileName(*b"F20     "), &FileExt(*b"BIN")).is_some());
+    assert_eq!(iter.find_entry(&FileName(*b"F01     "), &FileExt(*b"BIN")), None);
+    drop(iter);
+
+    // And `lookup_path` uses it:
+    let (_, entry) = fs.lookup_path(&mut s, b"/big/f39.bin").unwrap();
+    assert_eq!(entry.cluster_idx(), ClusterIdx::new(139));
+    assert_eq!(fs.lookup_path(&mut s, b"/BIG/F39TOOLONG.BIN"), Err(LookupError::NotFound(b"F39TOOLONG.BIN")));
+}
+
+#[test]
+fn full_directories_grow() {
+    let (mut s, partition, _) = common::fat16_volume();
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    // Clusters are a sector (16 entries) here and `.` and `..` take up two of
+    // the first cluster's slots; 14 files fill it exactly (with no room for a
+    // terminator) and the rest need two more clusters.
+    fs.create_dir(&mut s, b"/SUB").unwrap();
+    let names: Vec<String> = (0..40).map(|i| format!("F{}.TXT", i)).collect();
+    for name in names.iter() {
+        fs.create_file(&mut s, format!("/SUB/{}", name).as_bytes()).unwrap();
+    }
+
+    let listed: Vec<_> = fs.list_dir_vec(&mut s, b"/SUB").unwrap().into_iter().map(|(n, _)| n).collect();
+    assert_eq!(listed, names);
+
+    let (_, sub) = fs.lookup_path(&mut s, b"/SUB").unwrap();
+    let info = FatEntryTracer::starting_at(&mut fs, &mut s, sub.cluster_idx()).analyze().unwrap();
+    assert_eq!(info.cluster_count, 3);
+
+    // The FAT16 root directory can't grow; it has 512 slots and `SUB` is in
+    // one of them:
+    for i in 0..511 {
+        fs.create_file(&mut s, format!("/R{}", i).as_bytes()).unwrap();
+    }
+
+    // Failing to add the entry doesn't leak the new file's cluster:
+    let free = fs.free_cluster_count(&mut s).unwrap();
+    assert_eq!(fs.create_file(&mut s, b"/ONE.MOR").map(|_| ()), Err(FatError::OutOfSpace));
+    assert_eq!(fs.create_dir(&mut s, b"/ONE"), Err(FatError::OutOfSpace));
+    assert_eq!(fs.free_cluster_count(&mut s).unwrap(), free);
+    assert_eq!(fs.list_dir_vec(&mut s, b"/").unwrap().len(), 512);
+
+    fs.cache.flush(&mut s).unwrap();
+}
+
+#[test]
+fn rename_rejects_dot_entries_and_the_root() {
+    let (mut s, mut fs) = tree();
+
+    for from in [&b"/"[..], b"/.", b"/SUB/..", b"/SUB/NESTED/..", b"/SUB/./A.TXT"].iter() {
+        assert_eq!(fs.rename(&mut s, from, b"/MOVED"), Err(FatError::InvalidArgument));
+    }
+
+    // Nothing moved or got deleted:
+    assert!(!fs.exists(&mut s, b"/MOVED"));
+    let names: Vec<_> = fs.list_dir_vec(&mut s, b"/").unwrap().into_iter().map(|(n, _)| n).collect();
+    assert_eq!(names, vec!["HELLO.TXT", "SUB", "README.TXT"]);
+    let names: Vec<_> = fs.list_dir_vec(&mut s, b"/SUB").unwrap().into_iter().map(|(n, _)| n).collect();
+    assert_eq!(names, vec!["NESTED", "A.TXT"]);
+
+    fs.cache.flush(&mut s).unwrap();
+}
+
+#[test]
+fn directories_cant_move_into_themselves() {
+    let (mut s, mut fs) = tree();
+
+    assert_eq!(fs.rename(&mut s, b"/SUB", b"/SUB/NESTED/SUB"), Err(FatError::InvalidArgument));
+    assert_eq!(fs.rename(&mut s, b"/SUB", b"/SUB/OTHER"), Err(FatError::InvalidArgument));
+    assert_eq!(fs.rename(&mut s, b"/SUB/NESTED", b"/SUB/NESTED/X"), Err(FatError::InvalidArgument));
+    assert!(fs.exists(&mut s, b"/SUB/NESTED/DEEP.TXT"));
+
+    // Moving up or sideways is fine:
+    fs.rename(&mut s, b"/SUB/NESTED", b"/NESTED").unwrap();
+    fs.rename(&mut s, b"/SUB", b"/NESTED/SUB").unwrap();
+    assert!(fs.exists(&mut s, b"/NESTED/SUB/A.TXT"));
+    assert!(fs.exists(&mut s, b"/NESTED/DEEP.TXT"));
+
+    fs.cache.flush(&mut s).unwrap();
+}
+
+#[test]
+fn rename_removes_long_name_entries_in_the_previous_cluster() {
+    let (mut s, partition, g) = common::fat16_volume();
+
+    // `/SUB` is clusters 3 and then 9 (a sector, so 16 entries, each); the
+    // file's long name entries are at the end of cluster 3 and its short
+    // entry is at the start of cluster 9.
+    let root = g.root_dir_start(1);
+    common::put_dir_entry(&mut s, root, 0, &DirEntry::new_dir(FileName(*b"SUB     "), ClusterIdx::new(3)));
+
+    let sub = g.cluster_start(1, 3);
+    let mut dot = DirEntry::new_dir(FileName(*b".       "), ClusterIdx::new(3));
+    dot.file_ext = FileExt(*b"   ");
+    let mut dot_dot = DirEntry::new_dir(FileName(*b"..      "), ClusterIdx::new(0));
+    dot_dot.file_ext = FileExt(*b"   ");
+    common::put_dir_entry(&mut s, sub, 0, &dot);
+    common::put_dir_entry(&mut s, sub, 1, &dot_dot);
+
+    let mut deleted = DirEntry::new_file(FileName(*b"GONE    "), FileExt(*b"TXT"), ClusterIdx::new(0));
+    deleted.file_name.0[0] = 0xE5;
+    for slot in 2..14 {
+        common::put_dir_entry(&mut s, sub, slot, &deleted);
+    }
+
+    let mut lfn = [0u8; 32];
+    lfn[11] = 0x0F;
+    for (slot, ord) in [(14, 0x42), (15, 0x01)].iter() {
+        lfn[0] = *ord;
+        s.sector_mut(sub)[(slot * 32)..((slot + 1) * 32)].copy_from_slice(&lfn);
+    }
+
+    let next = g.cluster_start(1, 9);
+    let file = DirEntry::new_file(FileName(*b"LONGFI~1"), FileExt(*b"TXT"), ClusterIdx::new(10));
+    common::put_dir_entry(&mut s, next, 0, &file);
+
+    common::set_fat16_entry(&mut s, &g, 3, 9);
+    common::set_fat16_entry(&mut s, &g, 9, 0xFFF8);
+    common::set_fat16_entry(&mut s, &g, 10, 0xFFF8);
+
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    fs.rename(&mut s, b"/SUB/LONGFI~1.TXT", b"/SUB/SHORT.TXT").unwrap();
+    fs.cache.flush(&mut s).unwrap();
+
+    assert_eq!(s.sector(sub)[14 * 32], 0xE5);
+    assert_eq!(s.sector(sub)[15 * 32], 0xE5);
+    assert_eq!(s.sector(next)[0], 0xE5);
+
+    // The new entry went in the first deleted slot:
+    assert_eq!(&s.sector(sub)[64..72], b"SHORT   ");
+}
@@ -0,0 +1,1360 @@
+// Requires the `no_std` feature to be disabled so that `File`s implement the
+// `Storage` trait.
+//
+// Run with --no-default-features.
+
+mod common;
+
+use fs::gpt::{Gpt, Guid};
+use fs::fat::{
+    AllocPolicy,
+    FatError,
+    FatFs,
+    MountError,
+    OutOfRange,
+    PartitionMountError,
+    types::{ByteOffset, ClusterIdx, ClusterOffset, SectorIdx, SectorRange},
+    boot_sector::FsInfo,
+    table::{ChainInfo, FatEntry, FatEntryKind, FatEntryTracer, FatType, TraceError},
+    dir::{DirEntry, DirIter, FileName, FileExt, State},
+    cache::eviction_policies::UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+};
+
+use fs::storage::StorageExt;
+
+use storage_traits::{FileBackedStorage, Storage, errors::{ReadError, WriteError}};
+use generic_array::GenericArray;
+use typenum::consts::{U4, U16, U512, U4096};
+
+#[test]
+fn mount_guard_flushes_on_drop() {
+    let (path, partition) = common::bare_volume("mount-guard", 64, 1);
+    let mut s = FileBackedStorage::from_file(&path).unwrap();
+
+    {
+        let mut guard = FatFs::<_, U4, _>::mount_guarded(
+            &mut s,
+            &partition,
+            UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+        ).unwrap();
+
+        let (fs, s) = guard.split();
+        fs.write(s, SectorIdx::new(10), 3, b"hello").unwrap();
+
+        // Deref gets us at the filesystem:
+        assert_eq!(guard.sector_size_in_bytes, 512);
+    }
+
+    assert_eq!(b"hello", &common::sector_of(&path, 10)[3..8]);
+}
+
+#[test]
+fn mount_with_4k_sectors() {
+    let mut s = common::MockStorage::<U4096>::with_sector_size(16);
+    common::bare_boot_sector(s.sector_mut(1));
+
+    let partition = fs::gpt::PartitionEntry::fat("RTOS", 1, 15);
+    let mut fs = FatFs::<_, U4, _>::mount(
+        &mut s,
+        &partition,
+        UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+    ).unwrap();
+
+    assert_eq!(fs.sector_size_in_bytes, 4096);
+
+    // Straddle the boundary between sectors 10 and 11:
+    fs.write(&mut s, SectorIdx::new(10), 4094, b"abcd").unwrap();
+    fs.cache.flush(&mut s).unwrap();
+
+    assert_eq!(b"ab", &s.sector(10)[4094..]);
+    assert_eq!(b"cd", &s.sector(11)[..2]);
+}
+
+#[test]
+fn mount_rejects_mismatched_sector_size() {
+    let mut s = common::MockStorage::<U4096>::with_sector_size(16);
+    s.sector_mut(1)[0x0B..0x0D].copy_from_slice(&512u16.to_le_bytes());
+
+    let partition = fs::gpt::PartitionEntry::fat("RTOS", 1, 15);
+    assert_eq!(FatFs::<_, U4, _>::mount(
+        &mut s,
+        &partition,
+        UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+    ).unwrap_err(), MountError::UnsupportedSectorSize);
+}
+
+#[test]
+fn fat16_chain() {
+    let g = common::Geometry {
+        sectors_per_cluster: 1,
+        reserved_sectors: 1,
+        num_fats: 2,
+        fat_size: 17,
+        root_entries: 512,
+        total_sectors: 4267,
+    };
+
+    let mut s = common::MockStorage::new(1 + 4267);
+    g.write_boot_sector(s.sector_mut(1), 1);
+
+    // 5 -> 6 -> 9 -> end
+    let fat = s.sector_mut(g.fat_start(1));
+    fat[0..4].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0xFF]);
+    fat[10..12].copy_from_slice(&6u16.to_le_bytes());
+    fat[12..14].copy_from_slice(&9u16.to_le_bytes());
+    fat[18..20].copy_from_slice(&0xFFF8u16.to_le_bytes());
+
+    let root = s.sector_mut(g.root_dir_start(1));
+    root[0..11].copy_from_slice(b"HELLO   TXT");
+    root[11] = 0x20;
+
+    let partition = fs::gpt::PartitionEntry::fat("RTOS", 1, 4267);
+    let mut fs = FatFs::<_, U4, _>::mount(
+        &mut s,
+        &partition,
+        UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+    ).unwrap();
+
+    assert_eq!(fs.fat_type, FatType::Fat16);
+    assert_eq!(fs.root_dir_size_in_sectors, 32);
+
+    let mut tracer = FatEntryTracer::starting_at(&mut fs, &mut s, ClusterIdx::new(5));
+    let chain: Vec<u32> = tracer.by_ref().map(|e| *e.next.inner()).collect();
+    assert_eq!(chain, vec![5, 6, 9]);
+
+    // Growing the chain should write 16-bit entries:
+    tracer.grow_file().unwrap();
+    fs.cache.flush(&mut s).unwrap();
+
+    let fat = s.sector(g.fat_start(1));
+    assert_eq!(&fat[18..20], &2u16.to_le_bytes());
+    assert_eq!(&fat[4..6], &0xFFF8u16.to_le_bytes());
+
+    // The root directory is the fixed region after the FATs:
+    let names: Vec<_> = DirIter::from_cluster(fs.root_dir_cluster_num, &mut fs, &mut s)
+        .filter(|(_, d)| d.state() == State::Exists)
+        .map(|(_, d)| d.file_name.0)
+        .collect();
+    assert_eq!(names, vec![*b"HELLO   "]);
+}
+
+#[test]
+fn fat12_chain() {
+    let g = common::Geometry {
+        sectors_per_cluster: 1,
+        reserved_sectors: 1,
+        num_fats: 1,
+        fat_size: 2,
+        root_entries: 16,
+        total_sectors: 100,
+    };
+
+    let mut s = common::MockStorage::new(1 + 100);
+    g.write_boot_sector(s.sector_mut(1), 1);
+
+    // 2 -> 3 -> 4 -> end; entries are packed two to every three bytes.
+    let fat = s.sector_mut(g.fat_start(1));
+    fat[0..9].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0x03, 0x40, 0x00, 0xF8, 0x0F, 0x00]);
+
+    let partition = fs::gpt::PartitionEntry::fat("RTOS", 1, 100);
+    let mut fs = FatFs::<_, U4, _>::mount(
+        &mut s,
+        &partition,
+        UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+    ).unwrap();
+
+    assert_eq!(fs.fat_type, FatType::Fat12);
+
+    let mut tracer = FatEntryTracer::starting_at(&mut fs, &mut s, ClusterIdx::new(2));
+    let chain: Vec<u32> = tracer.by_ref().map(|e| *e.next.inner()).collect();
+    assert_eq!(chain, vec![2, 3, 4]);
+
+    // 4 -> 5 -> end, without disturbing the neighbouring entries:
+    tracer.grow_file().unwrap();
+    fs.cache.flush(&mut s).unwrap();
+
+    assert_eq!(
+        &s.sector(g.fat_start(1))[0..9],
+        &[0xF8, 0xFF, 0xFF, 0x03, 0x40, 0x00, 0x05, 0x80, 0xFF],
+    );
+}
+
+#[test]
+fn mount_second_partition() {
+    let g = common::Geometry::fat16();
+    let esp = Guid::from_mixed_u128(0xC12A7328_F81F_11D2_BA4B_00A0C93EC93B);
+
+    let mut s = common::MockStorage::new(40 + g.total_sectors as usize);
+    common::write_gpt(&mut s, &[
+        (esp, 34, 39),
+        (Guid::microsoft_basic_data(), 40, 40 + g.total_sectors as u64 - 1),
+    ]);
+
+    g.write_boot_sector(s.sector_mut(40), 40);
+    let marker = DirEntry::new_file(FileName(*b"SECOND  "), FileExt(*b"TXT"), ClusterIdx::new(3));
+    common::put_dir_entry(&mut s, g.root_dir_start(40), 0, &marker);
+
+    // Partition names are UTF-16 at offset 56 of each entry:
+    for (i, c) in "DATA".encode_utf16().enumerate() {
+        let offset = 2 * 512 + 128 + 56 + 2 * i;
+        s.data[offset..(offset + 2)].copy_from_slice(&c.to_le_bytes());
+    }
+
+    let gpt = Gpt::read_gpt(&mut s).unwrap();
+    assert_eq!(gpt.get_partition_entry(&mut s, 1).unwrap().name_lossy(), "DATA");
+    assert_eq!(gpt.get_partition_entry(&mut s, 0).unwrap().name_lossy(), "");
+
+    type Fs = FatFs<common::MockStorage, U4>;
+
+    let mut fs = Fs::mount_nth(&mut s, &gpt, 1, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    assert!(fs.lookup_path(&mut s, b"/SECOND.TXT").is_ok());
+
+    let mut fs = Fs::mount_first_of_type(&mut s, &gpt, Guid::microsoft_basic_data(), UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    assert!(fs.lookup_path(&mut s, b"/SECOND.TXT").is_ok());
+
+    // Not a FAT partition:
+    assert_eq!(
+        Fs::mount_nth(&mut s, &gpt, 0, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap_err(),
+        PartitionMountError::MountFailed(MountError::WrongPartitionType),
+    );
+
+    // Unused and out of range entries:
+    for n in [2, 128].iter() {
+        assert_eq!(
+            Fs::mount_nth(&mut s, &gpt, *n, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap_err(),
+            PartitionMountError::NoSuchPartition,
+        );
+    }
+
+    let linux = Guid::from_mixed_u128(0x0FC63DAF_8483_4772_8E79_3D69D8477DE4);
+    assert_eq!(
+        Fs::mount_first_of_type(&mut s, &gpt, linux, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap_err(),
+        PartitionMountError::NoSuchPartition,
+    );
+}
+
+#[test]
+fn analyze_contiguous_chain() {
+    let (mut s, partition, g) = common::fat16_volume();
+
+    // 4 -> 5 -> 6 -> 7 -> end
+    for c in 4..7 {
+        common::set_fat16_entry(&mut s, &g, c, c as u16 + 1);
+    }
+    common::set_fat16_entry(&mut s, &g, 7, 0xFFF8);
+
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    assert_eq!(
+        FatEntryTracer::starting_at(&mut fs, &mut s, ClusterIdx::new(4)).analyze().unwrap(),
+        ChainInfo {
+            cluster_count: 4,
+            is_contiguous: true,
+            first_cluster: ClusterIdx::new(4),
+            last_cluster: ClusterIdx::new(7),
+        },
+    );
+
+    // A partially consumed tracer only looks at what's left:
+    let mut tracer = FatEntryTracer::starting_at(&mut fs, &mut s, ClusterIdx::new(4));
+    tracer.next();
+    let info = tracer.analyze().unwrap();
+    assert_eq!((info.cluster_count, info.first_cluster), (3, ClusterIdx::new(5)));
+}
+
+#[test]
+fn analyze_fragmented_chain() {
+    let (mut s, partition, g) = common::fat16_volume();
+
+    // 4 -> 5 -> 9 -> 10 -> 3 -> end
+    common::set_fat16_entry(&mut s, &g, 4, 5);
+    common::set_fat16_entry(&mut s, &g, 5, 9);
+    common::set_fat16_entry(&mut s, &g, 9, 10);
+    common::set_fat16_entry(&mut s, &g, 10, 3);
+    common::set_fat16_entry(&mut s, &g, 3, 0xFFF8);
+
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    assert_eq!(
+        FatEntryTracer::starting_at(&mut fs, &mut s, ClusterIdx::new(4)).analyze().unwrap(),
+        ChainInfo {
+            cluster_count: 5,
+            is_contiguous: false,
+            first_cluster: ClusterIdx::new(4),
+            last_cluster: ClusterIdx::new(3),
+        },
+    );
+
+    // A single cluster is trivially contiguous:
+    let info = FatEntryTracer::starting_at(&mut fs, &mut s, ClusterIdx::new(3)).analyze().unwrap();
+    assert!(info.is_contiguous);
+    assert_eq!(info.cluster_count, 1);
+}
+
+#[test]
+fn read_matches_the_storage() {
+    let (mut s, partition, _) = common::fat16_volume();
+    for (i, b) in s.data[(100 * 512)..(110 * 512)].iter_mut().enumerate() {
+        *b = (i * 31 + i / 512) as u8;
+    }
+
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    for &offset in [0u16, 1, 255, 511].iter() {
+        for &len in [0usize, 1, 2, 511, 512, 513, 1024, 1500, 3 * 512 + 7].iter() {
+            let mut buf = vec![0; len];
+            fs.read(&mut s, SectorIdx::new(101), offset, &mut buf).unwrap();
+
+            // Straight from the storage:
+            let start = 101 * 512 + offset as usize;
+            assert_eq!(&buf[..], &s.data[start..(start + len)], "offset {}, len {}", offset, len);
+        }
+    }
+
+    // Bounds checks are unchanged (4267 is the partition's last sector):
+    assert!(fs.read(&mut s, SectorIdx::new(101), 512, &mut [0; 1]).is_err());
+    assert!(fs.read(&mut s, SectorIdx::new(4266), 0, &mut [0; 2]).is_ok());
+    assert!(fs.read(&mut s, SectorIdx::new(4266), 0, &mut [0; 1025]).is_err());
+}
+
+#[test]
+fn tracer_stops_on_cycles() {
+    let (mut s, partition, g) = common::fat16_volume();
+
+    // 4 -> 4 -> ...
+    common::set_fat16_entry(&mut s, &g, 4, 4);
+    // 6 -> 7 -> 8 -> 6 -> ...
+    common::set_fat16_entry(&mut s, &g, 6, 7);
+    common::set_fat16_entry(&mut s, &g, 7, 8);
+    common::set_fat16_entry(&mut s, &g, 8, 6);
+    common::set_fat16_entry(&mut s, &g, 9, 0xFFF8);
+
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    let max = fs.num_fat_entries() as usize;
+
+    for start in [4, 6].iter() {
+        let mut tracer = FatEntryTracer::starting_at(&mut fs, &mut s, ClusterIdx::new(*start));
+        assert_eq!(tracer.by_ref().count(), max);
+        assert!(tracer.took_too_long());
+
+        // It stays stopped and can't be grown:
+        assert!(tracer.next().is_none());
+        assert!(tracer.grow_file().is_err());
+    }
+
+    // A chain that ends properly doesn't trip it:
+    let mut tracer = FatEntryTracer::starting_at(&mut fs, &mut s, ClusterIdx::new(9));
+    assert_eq!(tracer.by_ref().count(), 1);
+    assert!(!tracer.took_too_long());
+}
+
+#[test]
+fn tracer_reports_read_errors() {
+    let (mut s, partition, g) = common::fat16_volume();
+
+    // 4 -> 5 -> 300 -> 301 -> end; 300's entry is in the FAT's second sector.
+    common::set_fat16_entry(&mut s, &g, 4, 5);
+    common::set_fat16_entry(&mut s, &g, 5, 300);
+    common::set_fat16_entry(&mut s, &g, 300, 301);
+    common::set_fat16_entry(&mut s, &g, 301, 0xFFF8);
+    s.failing_reads.push(g.fat_start(1) + 1);
+
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    let mut tracer = FatEntryTracer::starting_at(&mut fs, &mut s, ClusterIdx::new(4));
+    let chain: Vec<u32> = tracer.by_ref().map(|e| *e.next.inner()).collect();
+    assert_eq!(chain, vec![4, 5]);
+    assert_eq!(tracer.error(), Some(TraceError::Io));
+    assert!(!tracer.took_too_long());
+    assert!(tracer.grow_file().is_err());
+
+    assert_eq!(
+        FatEntryTracer::starting_at(&mut fs, &mut s, ClusterIdx::new(4)).analyze(),
+        Err(TraceError::Io),
+    );
+
+    // Once the storage comes back so does tracing:
+    s.failing_reads.clear();
+    let info = FatEntryTracer::starting_at(&mut fs, &mut s, ClusterIdx::new(4)).analyze().unwrap();
+    assert_eq!(info.cluster_count, 4);
+
+    // Directories that can't be read stop their iterators with an error too:
+    s.failing_reads.push(g.root_dir_start(1));
+    let root = fs.root_dir_cluster_num;
+
+    let mut iter = DirIter::from_cluster(root, &mut fs, &mut s);
+    assert!(iter.next().is_none());
+    assert_eq!(iter.error(), Some(TraceError::Io));
+
+    assert!(fs.walk(&mut s, root, |_, _| ()).is_err());
+}
+
+#[test]
+fn first_allocation_skips_used_and_reserved_clusters() {
+    let (mut s, partition, g) = common::fat16_volume();
+
+    // 2 and 3 are taken:
+    common::set_fat16_entry(&mut s, &g, 2, 3);
+    common::set_fat16_entry(&mut s, &g, 3, 0xFFF8);
+
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    assert_eq!(fs.next_known_free_cluster, ClusterIdx::new(4));
+    assert_eq!(fs.next_free_cluster(&mut s), Ok(ClusterIdx::new(4)));
+
+    // Even if pointed at them, the reserved clusters aren't handed out:
+    fs.write_fat_entry(&mut s, ClusterIdx::new(1), &FatEntry::FREE).unwrap();
+    fs.next_known_free_cluster = ClusterIdx::new(1);
+    assert_eq!(fs.next_free_cluster(&mut s), Ok(ClusterIdx::new(5)));
+
+    fs.cache.flush(&mut s).unwrap();
+}
+
+/// A (small as FAT32 volumes go) FAT32 volume starting at sector 1 with an
+/// FSInfo sector (the volume's second sector) whose next free cluster hint is
+/// `hint`.
+fn fat32_volume(hint: u32) -> (common::MockStorage, fs::gpt::PartitionEntry, common::Geometry) {
+    let g = common::Geometry {
+        sectors_per_cluster: 1,
+        reserved_sectors: 8,
+        num_fats: 1,
+        fat_size: 517,
+        root_entries: 0,
+        total_sectors: 66600,
+    };
+
+    let mut s = common::MockStorage::new(1 + 66600);
+    g.write_boot_sector(s.sector_mut(1), 1);
+
+    s.sector_mut(1)[0x30..0x32].copy_from_slice(&1u16.to_le_bytes());
+    {
+        let info = s.sector_mut(2);
+        info[0x000..0x004].copy_from_slice(b"RRaA");
+        info[0x1E4..0x1E8].copy_from_slice(b"rrAa");
+        info[0x1E8..0x1EC].copy_from_slice(&0xFFFF_FFFFu32.to_le_bytes());
+        info[0x1EC..0x1F0].copy_from_slice(&hint.to_le_bytes());
+        info[0x1FC..0x200].copy_from_slice(&0xAA55_0000u32.to_le_bytes());
+    }
+
+    // Reserved entries and the root directory (cluster 2):
+    let fat = s.sector_mut(g.fat_start(1));
+    fat[0..12].copy_from_slice(&[
+        0xF8, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F, 0xF8, 0xFF, 0xFF, 0xFF,
+    ]);
+
+    (s, fs::gpt::PartitionEntry::fat("RTOS", 1, 66600), g)
+}
+
+#[test]
+fn fat32_free_cluster_hint() {
+    let (mut s, partition, _) = fat32_volume(100);
+    let mount = |s: &mut common::MockStorage| {
+        FatFs::<_, U4, _>::mount(s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap()
+    };
+
+    let mut fs = mount(&mut s);
+    assert_eq!(fs.fat_type, FatType::Fat32);
+    assert_eq!(fs.next_known_free_cluster, ClusterIdx::new(100));
+    assert_eq!(fs.next_free_cluster(&mut s), Ok(ClusterIdx::new(100)));
+    fs.cache.flush(&mut s).unwrap();
+
+    // Hints that are out of range are ignored; we look for ourselves:
+    s.sector_mut(2)[0x1EC..0x1F0].copy_from_slice(&0xFFFF_FFFFu32.to_le_bytes());
+    assert_eq!(mount(&mut s).next_known_free_cluster, ClusterIdx::new(3));
+
+    s.sector_mut(2)[0x1EC..0x1F0].copy_from_slice(&1u32.to_le_bytes());
+    assert_eq!(mount(&mut s).next_known_free_cluster, ClusterIdx::new(3));
+
+    // As are FSInfo sectors without their signatures:
+    s.sector_mut(2)[0x1EC..0x1F0].copy_from_slice(&100u32.to_le_bytes());
+    s.sector_mut(2)[0] = 0;
+    assert_eq!(mount(&mut s).next_known_free_cluster, ClusterIdx::new(3));
+}
+
+#[test]
+fn fat32_fs_info_read_errors_fail_the_mount() {
+    let (mut s, partition, _) = fat32_volume(100);
+    s.failing_reads.push(2);
+
+    let res = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED);
+    assert_eq!(res.map(|_| ()), Err(MountError::Io));
+}
+
+#[test]
+fn unmount_writes_fs_info_and_backup_boot_sector() {
+    let (mut s, partition, _) = fat32_volume(FsInfo::UNKNOWN);
+
+    // Backup boot sector at the volume's 7th sector:
+    s.sector_mut(1)[0x32..0x34].copy_from_slice(&6u16.to_le_bytes());
+
+    let mount = |s: &mut common::MockStorage| {
+        FatFs::<_, U4, _>::mount(s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap()
+    };
+
+    let mut fs = mount(&mut s);
+    let free_at_first = fs.free_cluster_count(&mut s).unwrap();
+    assert_eq!(free_at_first, fs.num_clusters() - 3);
+
+    for expected in 3..8 {
+        assert_eq!(fs.next_free_cluster(&mut s), Ok(ClusterIdx::new(expected)));
+    }
+    fs.unmount(&mut s).unwrap();
+
+    let info = FsInfo::read(GenericArray::<u8, U512>::from_slice(s.sector(2))).unwrap();
+    assert_eq!(info, FsInfo { free_cluster_count: free_at_first - 5, next_free_cluster: 8 });
+    assert_eq!(s.sector(7), s.sector(1));
+
+    // A fresh mount agrees:
+    let mut fs = mount(&mut s);
+    assert_eq!(fs.next_known_free_cluster, ClusterIdx::new(8));
+    assert_eq!(fs.free_cluster_count(&mut s), Ok(info.free_cluster_count));
+    assert_eq!(
+        FatEntryTracer::starting_at(&mut fs, &mut s, ClusterIdx::new(7)).analyze().unwrap().cluster_count,
+        1,
+    );
+    fs.unmount(&mut s).unwrap();
+}
+
+#[test]
+fn mount_falls_back_to_the_backup_boot_sector() {
+    let (mut s, partition, _) = fat32_volume(FsInfo::UNKNOWN);
+    s.sector_mut(1)[0x32..0x34].copy_from_slice(&6u16.to_le_bytes());
+
+    let good = s.sector(1).to_vec();
+    s.sector_mut(1).iter_mut().for_each(|b| *b = 0);
+
+    let mount = |s: &mut common::MockStorage| {
+        FatFs::<_, U4, _>::mount(s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED)
+    };
+
+    // No backup (and garbage where it'd be):
+    s.sector_mut(7)[0x0B..0x0D].copy_from_slice(&512u16.to_le_bytes());
+    assert_eq!(mount(&mut s).map(|_| ()), Err(MountError::UnsupportedSectorSize));
+
+    // A backup at the volume's 7th sector:
+    s.sector_mut(7).copy_from_slice(&good);
+    let mut fs = mount(&mut s).unwrap();
+    assert_eq!(fs.boot_sector, SectorIdx::new(7));
+    assert_eq!(fs.fat_type, FatType::Fat32);
+    assert_eq!(fs.root_dir_cluster_num, ClusterIdx::new(2));
+    assert_eq!(fs.get_boot_sect(&mut s).unwrap().bpb.bytes_per_logical_sector, 512);
+
+    // Unmounting leaves the bad boot sector alone:
+    fs.unmount(&mut s).unwrap();
+    assert!(s.sector(1).iter().all(|b| *b == 0));
+    assert_eq!(s.sector(7), &good[..]);
+}
+
+#[test]
+fn fat32_root_dir_on_a_partitioned_disk() {
+    let g = common::Geometry {
+        sectors_per_cluster: 1,
+        reserved_sectors: 32,
+        num_fats: 2,
+        fat_size: 517,
+        root_entries: 0,
+        total_sectors: 66600,
+    };
+    let first_lba = 2048;
+
+    let mut s = common::MockStorage::new(first_lba as usize + g.total_sectors as usize);
+    common::write_gpt(&mut s, &[
+        (Guid::microsoft_basic_data(), first_lba, first_lba + g.total_sectors as u64 - 1),
+    ]);
+    g.write_boot_sector(s.sector_mut(first_lba as usize), first_lba);
+
+    // Formatters often leave the hidden sectors field as 0; that shouldn't
+    // matter.
+    s.sector_mut(first_lba as usize)[0x1C..0x20].copy_from_slice(&0u32.to_le_bytes());
+
+    // Reserved entries, the root directory (2), /SUB (3), and /HELLO.TXT (4):
+    let fat = s.sector_mut(g.fat_start(first_lba));
+    fat[0..8].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F]);
+    for c in 2..5 {
+        fat[(c * 4)..(c * 4 + 4)].copy_from_slice(&0x0FFF_FFF8u32.to_le_bytes());
+    }
+
+    let mut hello = DirEntry::new_file(FileName(*b"HELLO   "), FileExt(*b"TXT"), ClusterIdx::new(4));
+    hello.file_size = 5;
+    let root = g.cluster_start(first_lba, 2);
+    common::put_dir_entry(&mut s, root, 0, &hello);
+    common::put_dir_entry(&mut s, root, 1, &DirEntry::new_dir(FileName(*b"SUB     "), ClusterIdx::new(3)));
+
+    let inner = DirEntry::new_file(FileName(*b"INNER   "), FileExt(*b"TXT"), ClusterIdx::new(0));
+    common::put_dir_entry(&mut s, g.cluster_start(first_lba, 3), 0, &inner);
+
+    let data = g.cluster_start(first_lba, 4) * 512;
+    s.data[data..(data + 5)].copy_from_slice(b"hello");
+
+    let gpt = Gpt::read_gpt(&mut s).unwrap();
+    let mut fs = FatFs::<_, U4, _>::mount_nth(&mut s, &gpt, 0, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    assert_eq!(fs.fat_type, FatType::Fat32);
+    assert_eq!(fs.fat_starting_sector, SectorIdx::new(first_lba + 32));
+
+    // Cluster 2 is the first data cluster, which is the root directory here:
+    assert_eq!(fs.root_dir_cluster_num, ClusterIdx::new(2));
+    assert_eq!(fs.cluster_to_sector(ClusterIdx::new(2), ClusterOffset::new(0)), (SectorIdx::new(root as u64), ByteOffset::new(0)));
+    assert_eq!(fs.cluster_to_sector(ClusterIdx::new(3), ClusterOffset::new(520)), (SectorIdx::new(root as u64 + 2), ByteOffset::new(8)));
+
+    let mut buf = [0; 8];
+    assert_eq!(fs.read_file(&mut s, b"/HELLO.TXT", 0, &mut buf), Ok(5));
+    assert_eq!(&buf[..5], b"hello");
+    assert!(fs.metadata(&mut s, b"/SUB").unwrap().is_dir);
+    assert!(fs.exists(&mut s, b"/SUB/INNER.TXT"));
+}
+
+#[test]
+fn fat32_root_dir_spanning_clusters() {
+    let (mut s, partition, g) = fat32_volume(FsInfo::UNKNOWN);
+
+    // The root directory is clusters 2 -> 5 -> 9; with 512 byte clusters the
+    // first two hold 16 entries each.
+    let fat = s.sector_mut(g.fat_start(1));
+    for (c, next) in [(2, 5), (5, 9), (9, 0x0FFF_FFF8)].iter() {
+        fat[(c * 4)..(c * 4 + 4)].copy_from_slice(&(*next as u32).to_le_bytes());
+    }
+
+    let names: Vec<[u8; 8]> = (0..40).map(|i| {
+        let mut name = [0; 8];
+        name.copy_from_slice(format!("F{:07}", i).as_bytes());
+        name
+    }).collect();
+
+    for (i, name) in names.iter().enumerate() {
+        let cluster = [2, 5, 9][i / 16];
+        let entry = DirEntry::new_file(FileName(*name), FileExt(*b"TXT"), ClusterIdx::new(0));
+        common::put_dir_entry(&mut s, g.cluster_start(1, cluster), i % 16, &entry);
+    }
+
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    let root = fs.root_dir_cluster_num;
+
+    let mut iter = DirIter::from_cluster(root, &mut fs, &mut s);
+    let found: Vec<_> = iter.by_ref()
+        .map(|((c, offset), entry)| (*c.inner(), offset, entry.file_name.0))
+        .collect();
+    assert_eq!(iter.error(), None);
+
+    let expected: Vec<_> = names.iter().enumerate()
+        .map(|(i, name)| ([2, 5, 9][i / 16], (i as u32 % 16) * 32, *name))
+        .collect();
+    assert_eq!(found, expected);
+
+    // Cluster 0 (as in `..` entries) is the root directory too:
+    assert_eq!(DirIter::from_cluster(ClusterIdx::new(0), &mut fs, &mut s).count(), 40);
+
+    assert!(fs.exists(&mut s, b"/F0000039.TXT"));
+    assert!(!fs.exists(&mut s, b"/F0000040.TXT"));
+
+    let mut walked = 0;
+    fs.walk(&mut s, root, |_, _| walked += 1).unwrap();
+    assert_eq!(walked, 40);
+}
+
+#[test]
+fn boot_sector_fields() {
+    // The start of a boot sector as `mkfs.fat -F 16 -i 1234ABCD` writes it:
+    let (mut s, p, _) = common::fat16_volume();
+    {
+        let b = s.sector_mut(1);
+        b[0x00..0x03].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+        b[0x03..0x0B].copy_from_slice(b"mkfs.fat");
+        b[0x24] = 0x80;
+        b[0x26] = 0x29;
+        b[0x27..0x2B].copy_from_slice(&0x1234_ABCDu32.to_le_bytes());
+        b[0x2B..0x36].copy_from_slice(b"NO NAME    ");
+        b[0x36..0x3E].copy_from_slice(b"FAT16   ");
+    }
+
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &p, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    assert_eq!(&fs.oem_name(&mut s).unwrap(), b"mkfs.fat");
+    assert_eq!(fs.media_descriptor(&mut s), Ok(0xF8));
+    assert_eq!(fs.volume_id(&mut s), Ok(Some(0x1234_ABCD)));
+
+    // Read errors are passed along:
+    fs.cache.invalidate_all().unwrap();
+    s.failing_reads.push(1);
+    assert_eq!(fs.oem_name(&mut s), Err(FatError::Io));
+    assert_eq!(fs.media_descriptor(&mut s), Err(FatError::Io));
+    assert_eq!(fs.volume_id(&mut s), Err(FatError::Io));
+    drop(fs);
+
+    // FAT32 keeps the volume id further in:
+    let (mut s, p, _) = fat32_volume(3);
+    s.sector_mut(1)[0x42] = 0x29;
+    s.sector_mut(1)[0x43..0x47].copy_from_slice(&0xC0FF_EE00u32.to_le_bytes());
+
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &p, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    assert_eq!(fs.volume_id(&mut s), Ok(Some(0xC0FF_EE00)));
+
+    // No extended boot signature, no volume id:
+    let (mut s, p, _) = common::fat16_volume();
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &p, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    assert_eq!(fs.volume_id(&mut s), Ok(None));
+}
+
+#[test]
+fn allocate_chain() {
+    let (mut s, partition, g) = common::fat16_volume();
+
+    // Clusters 3 and 6 are taken so the first run of 8 starts at 7:
+    common::set_fat16_entry(&mut s, &g, 3, 0xFFF8);
+    common::set_fat16_entry(&mut s, &g, 6, 0xFFF8);
+
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    let free = fs.free_cluster_count(&mut s).unwrap();
+
+    let head = fs.allocate_chain(&mut s, 8).unwrap();
+    assert_eq!(head, ClusterIdx::new(7));
+    assert_eq!(
+        FatEntryTracer::starting_at(&mut fs, &mut s, head).analyze(),
+        Ok(ChainInfo {
+            cluster_count: 8,
+            is_contiguous: true,
+            first_cluster: ClusterIdx::new(7),
+            last_cluster: ClusterIdx::new(14),
+        }),
+    );
+    assert_eq!(fs.free_cluster_count(&mut s).unwrap(), free - 8);
+
+    // Short runs fit in the gaps:
+    assert_eq!(fs.allocate_chain(&mut s, 2).unwrap(), ClusterIdx::new(4));
+    assert!(fs.allocate_chain(&mut s, 0).is_err());
+
+    fs.cache.flush(&mut s).unwrap();
+}
+
+#[test]
+fn allocate_chain_without_a_long_enough_run() {
+    let (mut s, partition, g) = common::fat16_volume();
+
+    // Every other cluster is taken:
+    let clusters = (g.total_sectors - g.root_dir_start(0) as u32 - g.root_dir_size() as u32) + 2;
+    for c in (2..clusters).step_by(2) {
+        common::set_fat16_entry(&mut s, &g, c, 0xFFF8);
+    }
+
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    let free = fs.free_cluster_count(&mut s).unwrap();
+
+    let head = fs.allocate_chain(&mut s, 3).unwrap();
+    let chain: Vec<_> = FatEntryTracer::starting_at(&mut fs, &mut s, head).map(|e| e.next).collect();
+    assert_eq!(chain.len(), 3);
+    assert!(chain.windows(2).all(|w| w[1].inner() - w[0].inner() >= 2));
+    assert_eq!(fs.free_cluster_count(&mut s).unwrap(), free - 3);
+
+    // Asking for more than there is doesn't allocate anything:
+    assert!(fs.allocate_chain(&mut s, free).is_err());
+    assert_eq!(fs.free_cluster_count(&mut s).unwrap(), free - 3);
+
+    fs.cache.flush(&mut s).unwrap();
+}
+
+#[test]
+fn out_of_range_clusters() {
+    let (mut s, partition, g) = common::fat16_volume();
+
+    // 3 -> 4 -> 0x8000, which is past the end of the volume (but would still
+    // be somewhere in the volume if we went looking for its FAT entry):
+    common::set_fat16_entry(&mut s, &g, 3, 4);
+    common::set_fat16_entry(&mut s, &g, 4, 0x8000);
+
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    let last = ClusterIdx::new(fs.num_clusters() - 1);
+    let past = ClusterIdx::new(fs.num_clusters());
+
+    assert!(fs.cluster_to_table_pos(last).is_ok());
+    assert_eq!(fs.cluster_to_table_pos(past), Err(OutOfRange(past)));
+    assert_eq!(
+        fs.cluster_to_table_pos(ClusterIdx::new(0x8000)),
+        Err(OutOfRange(ClusterIdx::new(0x8000))),
+    );
+
+    let reads = s.reads;
+    assert!(fs.read_fat_entry(&mut s, ClusterIdx::new(0x8000)).is_err());
+    assert!(fs.write_fat_entry(&mut s, past, &FatEntry::FREE).is_err());
+    assert_eq!(s.reads, reads);
+
+    let mut tracer = FatEntryTracer::starting_at(&mut fs, &mut s, ClusterIdx::new(3));
+    let chain: Vec<u32> = tracer.by_ref().map(|e| *e.next.inner()).collect();
+    assert_eq!(chain, vec![3, 4]);
+    assert_eq!(tracer.error(), Some(TraceError::OutOfRange(ClusterIdx::new(0x8000))));
+}
+
+#[test]
+fn classify_fat_entries() {
+    let e = |v| FatEntry::from(ClusterIdx::new(v));
+
+    for (ty, top) in [(FatType::Fat12, 0xFFF), (FatType::Fat16, 0xFFFF), (FatType::Fat32, 0x0FFF_FFFF)].iter() {
+        let ty = *ty;
+
+        assert_eq!(e(0).classify(ty), FatEntryKind::Free);
+        assert_eq!(e(1).classify(ty), FatEntryKind::Reserved);
+        assert_eq!(e(2).classify(ty), FatEntryKind::Next(ClusterIdx::new(2)));
+        assert_eq!(e(top - 16).classify(ty), FatEntryKind::Next(ClusterIdx::new(top - 16)));
+        assert_eq!(e(top - 15).classify(ty), FatEntryKind::Reserved);
+        assert_eq!(e(top - 9).classify(ty), FatEntryKind::Reserved);
+        assert_eq!(e(top - 8).classify(ty), FatEntryKind::Bad);
+        assert_eq!(e(top - 7).classify(ty), FatEntryKind::EndOfChain);
+        assert_eq!(e(top).classify(ty), FatEntryKind::EndOfChain);
+
+        assert_eq!(ty.bad_cluster().classify(ty), FatEntryKind::Bad);
+        assert_eq!(ty.end_of_chain().classify(ty), FatEntryKind::EndOfChain);
+    }
+
+    // The top 4 bits of FAT32 entries don't count:
+    assert_eq!(e(0xF000_0000).classify(FatType::Fat32), FatEntryKind::Free);
+    assert_eq!(e(0xFFFF_FFF7).classify(FatType::Fat32), FatEntryKind::Bad);
+    assert_eq!(e(0x1000_0005).classify(FatType::Fat32), FatEntryKind::Next(ClusterIdx::new(5)));
+    assert_eq!(FatEntry::BAD_CLUSTER.classify(FatType::Fat32), FatEntryKind::Bad);
+}
+
+#[test]
+fn bad_clusters_are_never_allocated() {
+    let (mut s, partition, g) = common::fat16_volume();
+    common::set_fat16_entry(&mut s, &g, 3, 0xFFF8);
+
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    let free = fs.free_cluster_count(&mut s).unwrap();
+
+    let bad = [2, 4, 100];
+    for c in bad.iter() {
+        fs.mark_bad_cluster(&mut s, ClusterIdx::new(*c)).unwrap();
+    }
+    assert_eq!(fs.read_fat_entry(&mut s, ClusterIdx::new(2)), Ok(FatEntry::from(ClusterIdx::new(0xFFF7))));
+    assert_eq!(fs.free_cluster_count(&mut s).unwrap(), free - 3);
+
+    // Clusters that are in use (or reserved) can't be marked:
+    assert!(fs.mark_bad_cluster(&mut s, ClusterIdx::new(3)).is_err());
+    assert!(fs.mark_bad_cluster(&mut s, ClusterIdx::new(1)).is_err());
+
+    // Contiguous runs go around them:
+    let head = fs.allocate_chain(&mut s, 3).unwrap();
+    assert_eq!(head, ClusterIdx::new(5));
+
+    // Use up the rest of the volume:
+    let mut allocated = 3;
+    while let Ok(c) = fs.next_free_cluster(&mut s) {
+        assert!(!bad.contains(c.inner()), "handed out bad cluster {:?}", c);
+        allocated += 1;
+    }
+    assert_eq!(allocated, free - 3);
+
+    for c in bad.iter() {
+        assert_eq!(
+            fs.read_fat_entry(&mut s, ClusterIdx::new(*c)).unwrap().classify(FatType::Fat16),
+            FatEntryKind::Bad,
+        );
+    }
+
+    fs.cache.flush(&mut s).unwrap();
+}
+
+#[test]
+fn fat32_reserved_bits_are_kept() {
+    let (mut s, partition, g) = fat32_volume(3);
+
+    // Cluster 5's slot has its reserved bits set but is otherwise free:
+    let fat = g.fat_start(1);
+    s.sector_mut(fat)[20..24].copy_from_slice(&0xA000_0000u32.to_le_bytes());
+
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    // Reads ignore them:
+    assert_eq!(fs.read_fat_entry(&mut s, ClusterIdx::new(5)), Ok(FatEntry::FREE));
+
+    // Writes leave them be:
+    fs.write_fat_entry(&mut s, ClusterIdx::new(5), &FatEntry::from(ClusterIdx::new(0x0123_4567))).unwrap();
+    assert_eq!(fs.read_fat_entry(&mut s, ClusterIdx::new(5)), Ok(FatEntry::from(ClusterIdx::new(0x0123_4567))));
+
+    fs.write_fat_entry(&mut s, ClusterIdx::new(5), &FatEntry::END_OF_CHAIN).unwrap();
+    fs.cache.flush(&mut s).unwrap();
+
+    assert_eq!(&s.sector(fat)[20..24], &0xAFFF_FFF8u32.to_le_bytes());
+}
+
+#[test]
+fn any_end_of_chain_value_ends_a_chain() {
+    // FAT32, ended the way `mkfs.fat` does it: 3 -> 4 -> 0x0FFFFFFF.
+    let (mut s, partition, g) = fat32_volume(3);
+    let fat = g.fat_start(1);
+    s.sector_mut(fat)[12..16].copy_from_slice(&4u32.to_le_bytes());
+    s.sector_mut(fat)[16..20].copy_from_slice(&0x0FFF_FFFFu32.to_le_bytes());
+
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    let mut tracer = FatEntryTracer::starting_at(&mut fs, &mut s, ClusterIdx::new(3));
+    let chain: Vec<u32> = tracer.by_ref().map(|e| *e.next.inner()).collect();
+    assert_eq!(chain, vec![3, 4]);
+    assert_eq!(tracer.error(), None);
+
+    // And it can be grown from there:
+    tracer.grow_file().unwrap();
+    assert!(tracer.next().is_some());
+    drop(tracer);
+    fs.cache.flush(&mut s).unwrap();
+
+    // FAT16: 3 -> 0xFFFF, 4 -> 0xFFFA (also an end of chain), and 5 -> 0 (free;
+    // a broken chain).
+    let (mut s, partition, g) = common::fat16_volume();
+    common::set_fat16_entry(&mut s, &g, 3, 0xFFFF);
+    common::set_fat16_entry(&mut s, &g, 4, 0xFFFA);
+    common::set_fat16_entry(&mut s, &g, 6, 5);
+
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    for c in [3, 4].iter() {
+        let info = FatEntryTracer::starting_at(&mut fs, &mut s, ClusterIdx::new(*c)).analyze().unwrap();
+        assert_eq!(info.cluster_count, 1);
+    }
+
+    assert_eq!(
+        FatEntryTracer::starting_at(&mut fs, &mut s, ClusterIdx::new(6)).analyze(),
+        Err(TraceError::BrokenChain),
+    );
+}
+
+#[test]
+fn clamp_to_the_partition() {
+    let (mut s, partition, _) = common::fat16_volume();
+    let fs = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    let first = partition.first_lba;
+    let last = partition.last_lba;
+
+    let valid = fs.valid_sector_range();
+    assert_eq!(valid.clone().next(), Some(SectorIdx::new(first)));
+    assert_eq!(valid.clone().last(), Some(SectorIdx::new(last)));
+
+    // Straddles the end of the partition:
+    let sectors: Vec<_> = SectorRange::new(SectorIdx::new(last - 2)..SectorIdx::new(last + 5))
+        .clamp(&valid)
+        .collect();
+    assert_eq!(sectors, [last - 2, last - 1, last].iter().map(|s| SectorIdx::new(*s)).collect::<Vec<_>>());
+
+    // And the start:
+    let sectors: Vec<_> = SectorRange::new(SectorIdx::new(0)..SectorIdx::new(first + 2))
+        .clamp(&valid)
+        .collect();
+    assert_eq!(sectors, [SectorIdx::new(first), SectorIdx::new(first + 1)]);
+
+    // Entirely outside of it:
+    assert_eq!(SectorRange::new(SectorIdx::new(last + 1)..SectorIdx::new(last + 9)).clamp(&valid).count(), 0);
+    assert_eq!(SectorRange::new(SectorIdx::new(0)..SectorIdx::new(first)).clamp(&valid).count(), 0);
+}
+
+#[test]
+fn storage_errors_are_returned() {
+    let (mut s, partition, g) = common::fat16_volume();
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    let sector = g.cluster_start(1, 10);
+    s.failing_reads.push(sector);
+
+    let mut buf = [0; 16];
+    assert_eq!(fs.read(&mut s, SectorIdx::new(sector as u64), 0, &mut buf), Err(FatError::Io));
+    // Writes have to read the sector in first:
+    assert_eq!(fs.write(&mut s, SectorIdx::new(sector as u64), 0, b"hi"), Err(FatError::Io));
+
+    s.failing_reads.clear();
+    assert_eq!(fs.read(&mut s, SectorIdx::new(sector as u64), 0, &mut buf), Ok(()));
+
+    // Fill the cache with dirty sectors; the first one is the one that gets
+    // evicted next, but it can't be written out:
+    let dirty: Vec<usize> = (20..24).map(|c| g.cluster_start(1, c)).collect();
+    for d in dirty.iter() {
+        fs.write(&mut s, SectorIdx::new(*d as u64), 0, &[0xAB]).unwrap();
+    }
+    s.failing_writes.push(dirty[0]);
+
+    assert_eq!(fs.read(&mut s, SectorIdx::new(sector as u64), 0, &mut buf), Err(FatError::Io));
+    assert_eq!(fs.cache.dirty_count(), 4);
+
+    s.failing_writes.clear();
+    assert_eq!(fs.read(&mut s, SectorIdx::new(sector as u64), 0, &mut buf), Ok(()));
+    assert_eq!(s.sector(dirty[0])[0], 0xAB);
+
+    fs.cache.flush(&mut s).unwrap();
+}
+
+#[test]
+fn reads_carry_on_into_the_next_cluster() {
+    let (mut s, partition, g) = common::fat16_volume();
+
+    // 10 -> 12 -> 11; each sector is filled with its cluster number.
+    common::set_fat16_entry(&mut s, &g, 10, 12);
+    common::set_fat16_entry(&mut s, &g, 12, 11);
+    common::set_fat16_entry(&mut s, &g, 11, 0xFFF8);
+    for c in 10..=12 {
+        for b in s.sector_mut(g.cluster_start(1, c)).iter_mut() { *b = c as u8; }
+    }
+
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    let first = FatEntry::from(ClusterIdx::new(10));
+
+    // Exactly the whole cluster:
+    let mut buf = [0; 512];
+    first.upgrade(&mut fs, &mut s).read(ClusterOffset::new(0), &mut buf).unwrap();
+    assert!(buf.iter().all(|b| *b == 10));
+
+    // The last half of the first cluster and then into the next one (12, not
+    // 11):
+    let mut buf = [0; 512];
+    first.upgrade(&mut fs, &mut s).read(ClusterOffset::new(256), &mut buf).unwrap();
+    assert!(buf[..256].iter().all(|b| *b == 10));
+    assert!(buf[256..].iter().all(|b| *b == 12));
+
+    // All the way through the chain:
+    let mut buf = [0; 1536];
+    first.upgrade(&mut fs, &mut s).read(ClusterOffset::new(0), &mut buf).unwrap();
+    assert!(buf[1024..].iter().all(|b| *b == 11));
+
+    // But not past its end:
+    let mut buf = [0; 1537];
+    assert_eq!(first.upgrade(&mut fs, &mut s).read(ClusterOffset::new(0), &mut buf), Err(FatError::OutOfRange));
+    let mut buf = [0; 1];
+    assert_eq!(first.upgrade(&mut fs, &mut s).read(ClusterOffset::new(513), &mut buf), Err(FatError::OutOfRange));
+
+    // And not through a broken chain:
+    common::set_fat16_entry(&mut s, &g, 12, 0);
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    let mut buf = [0; 1536];
+    assert_eq!(first.upgrade(&mut fs, &mut s).read(ClusterOffset::new(0), &mut buf), Err(FatError::Corrupt));
+}
+
+#[test]
+fn ranges_can_end_right_on_a_boundary() {
+    let (mut s, partition, _) = common::fat16_volume();
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    // Clusters are 512 bytes:
+    let cluster = FatEntry::from(ClusterIdx::new(10));
+    let data = [0x5A; 513];
+    assert_eq!(cluster.upgrade(&mut fs, &mut s).write(ClusterOffset::new(0), data[..512].iter().cloned()), Ok(()));
+    assert_eq!(cluster.upgrade(&mut fs, &mut s).write(ClusterOffset::new(0), data.iter().cloned()), Err(FatError::OutOfRange));
+    assert_eq!(cluster.upgrade(&mut fs, &mut s).write(ClusterOffset::new(480), data[..32].iter().cloned()), Ok(()));
+    assert_eq!(cluster.upgrade(&mut fs, &mut s).write(ClusterOffset::new(481), data[..32].iter().cloned()), Err(FatError::OutOfRange));
+
+    // Offsets so big that adding the length overflows are out of range too:
+    assert_eq!(cluster.upgrade(&mut fs, &mut s).write(ClusterOffset::new(u32::MAX), data[..2].iter().cloned()), Err(FatError::OutOfRange));
+
+    let mut buf = [0; 512];
+    cluster.upgrade(&mut fs, &mut s).read(ClusterOffset::new(0), &mut buf).unwrap();
+    assert!(buf.iter().all(|b| *b == 0x5A));
+
+    // The last two sectors of the partition:
+    let last = partition.last_lba;
+    let mut buf = [0; 1024];
+    assert_eq!(fs.read(&mut s, SectorIdx::new(last - 1), 0, &mut buf), Ok(()));
+    assert_eq!(fs.read(&mut s, SectorIdx::new(last - 1), 0, &mut [0; 1025]), Err(FatError::OutOfRange));
+    assert_eq!(fs.read(&mut s, SectorIdx::new(last), 511, &mut [0; 1]), Ok(()));
+    assert_eq!(fs.read(&mut s, SectorIdx::new(last), 511, &mut [0; 2]), Err(FatError::OutOfRange));
+
+    assert_eq!(fs.write(&mut s, SectorIdx::new(last - 1), 0, &[0xC3; 1024]), Ok(()));
+    assert_eq!(fs.write(&mut s, SectorIdx::new(last - 1), 0, &[0xC3; 1025]), Err(FatError::OutOfRange));
+
+    fs.cache.flush(&mut s).unwrap();
+    assert!(s.sector(last as usize).iter().all(|b| *b == 0xC3));
+}
+
+#[test]
+fn read_and_write_whole_clusters() {
+    // 2 KiB clusters:
+    let g = common::Geometry { sectors_per_cluster: 4, ..common::Geometry::fat16() };
+    let mut s = common::MockStorage::new(1 + g.total_sectors as usize);
+    g.write_boot_sector(s.sector_mut(1), 1);
+    s.sector_mut(g.fat_start(1))[0..3].copy_from_slice(&[0xF8, 0xFF, 0xFF]);
+    let partition = fs::gpt::PartitionEntry::fat("RTOS", 1, g.total_sectors as u64);
+
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    assert_eq!(fs.bytes_in_a_cluster(), 2048);
+
+    // Not actually random, but close enough:
+    let mut x: u32 = 0x1234_5678;
+    let data: Vec<u8> = (0..2048).map(|_| {
+        x ^= x << 13; x ^= x >> 17; x ^= x << 5;
+        x as u8
+    }).collect();
+
+    fs.write_cluster(&mut s, ClusterIdx::new(7), &data).unwrap();
+
+    let mut buf = vec![0; 2048];
+    fs.read_cluster(&mut s, ClusterIdx::new(7), &mut buf).unwrap();
+    assert_eq!(buf, data);
+
+    // The neighbours are untouched:
+    fs.read_cluster(&mut s, ClusterIdx::new(8), &mut buf).unwrap();
+    assert!(buf.iter().all(|b| *b == 0));
+
+    fs.cache.flush(&mut s).unwrap();
+    let first = g.cluster_start(1, 7);
+    let on_disk: Vec<u8> = (first..(first + 4)).flat_map(|i| s.sector(i).to_vec()).collect();
+    assert_eq!(on_disk, data);
+
+    // The buffer has to be exactly a cluster:
+    assert_eq!(fs.read_cluster(&mut s, ClusterIdx::new(7), &mut buf[..2047]), Err(FatError::InvalidArgument));
+    assert_eq!(fs.write_cluster(&mut s, ClusterIdx::new(7), &[0; 4096]), Err(FatError::InvalidArgument));
+
+    // And only data clusters (and the root directory) can be used:
+    assert_eq!(fs.read_cluster(&mut s, ClusterIdx::new(1), &mut buf), Err(FatError::OutOfRange));
+    let past = ClusterIdx::new(fs.num_clusters());
+    assert_eq!(fs.read_cluster(&mut s, past, &mut buf), Err(FatError::OutOfRange));
+
+    let root = fs.root_dir_cluster_num;
+    let mut root_dir = vec![0; fs.bytes_in_cluster(root) as usize];
+    fs.read_cluster(&mut s, root, &mut root_dir).unwrap();
+    assert_eq!(root_dir.len(), 512 * 32);
+}
+
+#[test]
+fn aligned_allocation() {
+    let (mut s, partition, g) = common::fat16_volume();
+
+    // Cluster 2 starts at sector 68 so the first cluster on an 8 sector
+    // boundary is 6 (sector 72); make that one taken so 14 (sector 80) is next.
+    assert_eq!(g.cluster_start(1, 6), 72);
+    common::set_fat16_entry(&mut s, &g, 6, 0xFFF8);
+
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    assert_eq!(fs.alloc_policy, AllocPolicy::FirstFit);
+
+    fs.create_file(&mut s, b"/FIRST.BIN").unwrap();
+    let (_, first) = fs.lookup_path(&mut s, b"/FIRST.BIN").unwrap();
+    assert_eq!(first.cluster_idx(), ClusterIdx::new(2));
+
+    fs.alloc_policy = AllocPolicy::AlignedTo(8);
+    for name in [&b"/A.BIN"[..], b"/B.BIN"].iter() {
+        fs.create_file(&mut s, name).unwrap();
+
+        let (_, entry) = fs.lookup_path(&mut s, name).unwrap();
+        let (sector, _) = fs.cluster_to_sector(entry.cluster_idx(), ClusterOffset::new(0));
+        assert_eq!(sector.inner() % 8, 0);
+    }
+
+    let (_, a) = fs.lookup_path(&mut s, b"/A.BIN").unwrap();
+    let (_, b) = fs.lookup_path(&mut s, b"/B.BIN").unwrap();
+    assert_eq!((a.cluster_idx(), b.cluster_idx()), (ClusterIdx::new(14), ClusterIdx::new(22)));
+    assert_eq!(fs.read_fat_entry(&mut s, ClusterIdx::new(14)).unwrap().classify(FatType::Fat16), FatEntryKind::EndOfChain);
+
+    // The clusters that were skipped are still free for everything else:
+    fs.alloc_policy = AllocPolicy::FirstFit;
+    fs.create_file(&mut s, b"/C.BIN").unwrap();
+    let (_, c) = fs.lookup_path(&mut s, b"/C.BIN").unwrap();
+    assert_eq!(c.cluster_idx(), ClusterIdx::new(3));
+
+    fs.cache.flush(&mut s).unwrap();
+}
+
+#[test]
+fn mount_pins_the_fat_and_root_dir() {
+    let (mut s, partition, g) = common::fat16_volume();
+
+    // Small caches don't pin anything:
+    let fs = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    assert_eq!(fs.cache.pinned_count(), 0);
+
+    // Bigger ones pin the first FAT sector and then the start of the root
+    // directory, up to a quarter of the cache:
+    let mut fs = FatFs::<_, U16, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    assert_eq!(fs.cache.pinned_count(), 3);
+
+    let reads = s.reads;
+    for sector in 100..200 {
+        let _ = fs.cache.upgrade(&mut s).get(SectorIdx::new(sector))[0];
+    }
+    assert_eq!(s.reads, reads + 100);
+
+    let mut c = fs.cache.upgrade(&mut s);
+    let _ = c.get(SectorIdx::new(g.fat_start(1) as u64))[0];
+    let _ = c.get(SectorIdx::new(g.root_dir_start(1) as u64))[0];
+    let _ = c.get(SectorIdx::new(g.root_dir_start(1) as u64 + 1))[0];
+    drop(c);
+    assert_eq!(s.reads, reads + 100);
+}
+
+#[test]
+fn format_only_touches_the_metadata() {
+    let mut s = common::MockStorage::new(1 + 4096);
+    s.data.iter_mut().for_each(|b| *b = 0xA5);
+
+    let partition = fs::gpt::PartitionEntry::fat("RTOS", 1, 4096);
+    let mut fs = FatFs::<_, U4, _>::format(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    // 4096 sectors with 1 sector clusters: 1 reserved sector, two 12 sector
+    // FATs, and a 32 sector root directory.
+    assert_eq!(fs.fat_type, FatType::Fat12);
+    assert_eq!(fs.fat_starting_sector, SectorIdx::new(2));
+    assert_eq!(fs.num_clusters(), 2 + 4039);
+    let data_start = 1 + 1 + 24 + 32;
+
+    // Only the metadata gets written, one sector at a time with the default
+    // `erase_range`, plus the boot sector and the first sector of each FAT:
+    assert_eq!(s.writes, (data_start - 1) + 1 + 2);
+
+    assert_eq!(&s.sector(1)[0x1FE..], &[0x55, 0xAA]);
+    for fat in &[2, 14] {
+        assert_eq!(&s.sector(*fat)[..4], &[0xF8, 0xFF, 0xFF, 0x00]);
+        assert!(s.sector(*fat)[3..].iter().all(|b| *b == 0));
+    }
+    for sector in 3..14 {
+        assert!(s.sector(sector).iter().all(|b| *b == 0));
+    }
+    for sector in 26..data_start {
+        assert!(s.sector(sector).iter().all(|b| *b == 0));
+    }
+
+    // Everything else (including the sector before the partition) is left
+    // alone:
+    for sector in (0..1).chain(data_start..=4096) {
+        assert!(s.sector(sector).iter().all(|b| *b == 0xA5), "sector {}", sector);
+    }
+
+    let mut out = vec![DirEntry::empty(); 1];
+    assert_eq!(fs.list_dir(&mut s, b"/", &mut out), Ok(0));
+    assert_eq!(fs.free_cluster_count(&mut s), Ok(4039));
+
+    // Too small to hold a volume:
+    let mut tiny = common::MockStorage::new(16);
+    let partition = fs::gpt::PartitionEntry::fat("RTOS", 1, 15);
+    assert!(FatFs::<_, U4, _>::format(&mut tiny, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).is_err());
+}
+
+/// Storage that only keeps the sectors that have been written to (the rest
+/// read as zeros); FAT32 volumes are too big to keep in a `MockStorage`.
+struct SparseStorage {
+    sectors: std::collections::HashMap<usize, [u8; 512]>,
+    capacity: usize,
+}
+
+impl Storage for SparseStorage {
+    type Word = u8;
+    type SECTOR_SIZE = U512;
+
+    type ReadErr = ();
+    type WriteErr = ();
+
+    fn capacity(&self) -> usize { self.capacity }
+
+    fn read_sector(&mut self, sector_idx: usize, buffer: &mut GenericArray<u8, U512>) -> Result<(), ReadError<()>> {
+        match self.sectors.get(&sector_idx) {
+            Some(sector) => buffer.copy_from_slice(sector),
+            None => buffer.iter_mut().for_each(|b| *b = 0),
+        }
+
+        Ok(())
+    }
+
+    fn write_sector(&mut self, sector_idx: usize, words: &GenericArray<u8, U512>) -> Result<(), WriteError<()>> {
+        let mut sector = [0; 512];
+        sector.copy_from_slice(words);
+        self.sectors.insert(sector_idx, sector);
+
+        Ok(())
+    }
+}
+
+impl StorageExt for SparseStorage { }
+
+#[test]
+fn format_fat32_writes_the_backup_fs_info_sector() {
+    // 300 MiB:
+    let mut s = SparseStorage { sectors: Default::default(), capacity: 1 + 300 * 2048 };
+    let partition = fs::gpt::PartitionEntry::fat("RTOS", 1, 300 * 2048);
+
+    let fs = FatFs::<_, U4, _>::format(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    assert_eq!(fs.fat_type, FatType::Fat32);
+
+    let boot_sect = &s.sectors[&1];
+    let fs_info_at = u16::from_le_bytes([boot_sect[0x30], boot_sect[0x31]]) as usize;
+    let backup_at = u16::from_le_bytes([boot_sect[0x32], boot_sect[0x33]]) as usize;
+    assert_eq!((fs_info_at, backup_at), (1, 6));
+
+    // The backups sit together, in the same order as the originals:
+    assert_eq!(s.sectors[&(1 + backup_at)][..], s.sectors[&1][..]);
+    assert_eq!(s.sectors[&(1 + backup_at + 1)][..], s.sectors[&(1 + fs_info_at)][..]);
+    assert_eq!(&s.sectors[&(1 + backup_at + 1)][..4], b"RRaA");
+
+    fs.unmount(&mut s).unwrap();
+}
+
+#[test]
+fn format_keeps_why_the_remount_failed() {
+    let partition = fs::gpt::PartitionEntry::fat("RTOS", 1, 4096);
+
+    // The boot sector never makes it to the storage:
+    let mut s = common::MockStorage::new(1 + 4096);
+    s.lost_writes.push(1);
+    assert_eq!(
+        FatFs::<_, U4, _>::format(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap_err(),
+        FatError::Mount(MountError::NotFat),
+    );
+
+    // Or can't be read back:
+    let mut s = common::MockStorage::new(1 + 4096);
+    s.failing_reads.push(1);
+    assert_eq!(
+        FatFs::<_, U4, _>::format(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap_err(),
+        FatError::Mount(MountError::Io),
+    );
+}
+
+#[test]
+fn free_runs() {
+    let (mut s, partition, g) = common::fat16_volume();
+
+    // Used: 2-4, 8-9, and the last cluster.
+    for c in (2..5).chain(8..10).chain(4201..4202) {
+        common::set_fat16_entry(&mut s, &g, c, 0xFFF8);
+    }
+
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    assert_eq!(fs.num_clusters(), 4202);
+
+    fs.cache.reset_stats();
+    let mut runs = fs.free_runs(&mut s);
+    assert_eq!(runs.by_ref().collect::<Vec<_>>(), vec![
+        (ClusterIdx::new(5), 3),
+        (ClusterIdx::new(10), 4191),
+    ]);
+    assert_eq!(runs.error(), None);
+
+    // One lookup per FAT sector (the 4202 entries span 17 sectors):
+    let stats = fs.cache.stats();
+    assert_eq!(stats.hits + stats.misses, 17);
+}
+
+#[test]
+fn writes_look_each_sector_up_once() {
+    let (mut s, partition, _) = common::fat16_volume();
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    // Bytes 500..1700 of sector 100 onwards: the tail of the first sector, two
+    // whole sectors, and the start of a fourth.
+    let data: Vec<u8> = (0..1200u32).map(|i| (i % 251) as u8).collect();
+
+    fs.cache.reset_stats();
+    fs.write(&mut s, SectorIdx::new(100), 500, &data).unwrap();
+    let stats = fs.cache.stats();
+    assert_eq!(stats.hits + stats.misses, 4);
+
+    // Same thing with an iterator that doesn't know how long it is:
+    fs.cache.reset_stats();
+    fs.write_iter(&mut s, SectorIdx::new(200), 500, data.iter().cloned().filter(|_| true)).unwrap();
+    let stats = fs.cache.stats();
+    assert_eq!(stats.hits + stats.misses, 4);
+
+    // An empty write doesn't touch anything:
+    fs.cache.reset_stats();
+    fs.write_iter(&mut s, SectorIdx::new(300), 0, core::iter::empty()).unwrap();
+    let stats = fs.cache.stats();
+    assert_eq!(stats.hits + stats.misses, 0);
+
+    fs.unmount(&mut s).unwrap();
+
+    for start in [100, 200].iter() {
+        let mut written = s.sector(*start)[500..].to_vec();
+        for i in 1..4 {
+            written.extend_from_slice(s.sector(start + i));
+        }
+        assert_eq!(&written[..1200], &data[..]);
+        assert!(written[1200..].iter().all(|b| *b == 0));
+    }
+}
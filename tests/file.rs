@@ -0,0 +1,512 @@
+// Run with --no-default-features.
+
+mod common;
+
+use fs::fat::{
+    FatError,
+    FatFs,
+    types::{ClusterIdx, ClusterOffset, SectorIdx},
+    table::{FatEntry, FatEntryTracer},
+    dir::{DirEntry, FileName, FileExt},
+    cache::eviction_policies::UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+};
+
+use typenum::consts::{U4, U4096};
+
+type Fs = FatFs<common::MockStorage, U4>;
+
+#[test]
+fn create_write_remount_read() {
+    let (mut s, partition, _) = common::fat16_volume();
+
+    // Spans the first two (512 byte) clusters.
+    let data: Vec<u8> = (0..700u32).map(|i| (i * 7) as u8).collect();
+
+    {
+        let mut guard = Fs::mount_guarded(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+        let (fs, s) = guard.split();
+
+        let file = fs.create_file(s, b"/notes.txt").unwrap();
+        file.upgrade(fs, s).write_at(100, &data).unwrap();
+
+        // Can't create it twice:
+        assert!(fs.create_file(s, b"/NOTES.TXT").is_err());
+        // Or in a directory that doesn't exist:
+        assert!(fs.create_file(s, b"/nope/notes.txt").is_err());
+    }
+
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    let (_, entry) = fs.lookup_path(&mut s, b"/notes.txt").unwrap();
+    let file = entry.into_file().unwrap();
+
+    let mut buf = vec![0; 700];
+    file.upgrade(&mut fs, &mut s).read_at(100, &mut buf).unwrap();
+    assert_eq!(buf, data);
+
+    // The chain only has two clusters:
+    assert!(file.upgrade(&mut fs, &mut s).read_at(1000, &mut buf[..100]).is_err());
+}
+
+#[test]
+fn read_contiguous_and_fragmented() {
+    let (mut s, partition, g) = common::fat16_volume();
+    let root = g.root_dir_start(1);
+
+    // /A.BIN is clusters 3 -> 4 -> 5, /B.BIN is 6 -> 9 -> 7.
+    let files = [(b"A       ", [3, 4, 5]), (b"B       ", [6, 9, 7])];
+
+    for (slot, (name, chain)) in files.iter().enumerate() {
+        let entry = DirEntry::new_file(FileName(**name), FileExt(*b"BIN"), ClusterIdx::new(chain[0]));
+        common::put_dir_entry(&mut s, root, slot, &entry);
+
+        for (i, c) in chain.iter().enumerate() {
+            let next = chain.get(i + 1).map_or(0xFFF8, |n| *n as u16);
+            common::set_fat16_entry(&mut s, &g, *c, next);
+
+            // Fill each cluster with its position in the file:
+            let start = g.cluster_start(1, *c) * 512;
+            s.data[start..(start + 512)].iter_mut().for_each(|b| *b = i as u8 + 1);
+        }
+    }
+
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    for path in [&b"/A.BIN"[..], &b"/B.BIN"[..]].iter() {
+        let (_, entry) = fs.lookup_path(&mut s, path).unwrap();
+        let file = entry.into_file().unwrap();
+
+        let mut buf = vec![0; 1000];
+        file.upgrade(&mut fs, &mut s).read_at(300, &mut buf).unwrap();
+
+        let expected: Vec<u8> = (300..1300).map(|i| (i / 512) as u8 + 1).collect();
+        assert_eq!(buf, expected);
+
+        // Right up to the end of the chain is fine but no further:
+        assert!(file.upgrade(&mut fs, &mut s).read_at(1536 - 10, &mut buf[..10]).is_ok());
+        assert!(file.upgrade(&mut fs, &mut s).read_at(1536 - 10, &mut buf[..11]).is_err());
+    }
+}
+
+#[test]
+fn reads_only_follow_the_chain_as_far_as_they_go() {
+    let (mut s, partition, g) = common::fat16_volume();
+    let root = g.root_dir_start(1);
+
+    // /LOOP.BIN is 3 -> 4 -> 4 -> ...; tracing the whole chain fails.
+    let entry = DirEntry::new_file(FileName(*b"LOOP    "), FileExt(*b"BIN"), ClusterIdx::new(3));
+    common::put_dir_entry(&mut s, root, 0, &entry);
+    common::set_fat16_entry(&mut s, &g, 3, 4);
+    common::set_fat16_entry(&mut s, &g, 4, 4);
+
+    for (c, fill) in [(3, 0x33), (4, 0x44)].iter() {
+        let start = g.cluster_start(1, *c) * 512;
+        s.data[start..(start + 512)].iter_mut().for_each(|b| *b = *fill);
+    }
+
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    let file = fs.lookup_path(&mut s, b"/LOOP.BIN").unwrap().1.into_file().unwrap();
+
+    let mut buf = vec![0; 600];
+    file.upgrade(&mut fs, &mut s).read_at(0, &mut buf).unwrap();
+    assert!(buf[..512].iter().all(|b| *b == 0x33));
+    assert!(buf[512..].iter().all(|b| *b == 0x44));
+}
+
+#[test]
+fn read_and_write_by_path() {
+    let (mut s, partition, g) = common::fat16_volume();
+
+    // Leftovers in the free clusters shouldn't show up in the file.
+    let data_start = g.cluster_start(1, 2) * 512;
+    for b in s.data[data_start..(data_start + 16 * 512)].iter_mut() { *b = 0xAA; }
+
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    fs.create_file(&mut s, b"/data.bin").unwrap();
+
+    // Past the end of the (one cluster, empty) file:
+    fs.write_file(&mut s, b"/data.bin", 1000, b"hello").unwrap();
+    assert_eq!(fs.metadata(&mut s, b"/data.bin").unwrap().len, 1005);
+
+    // Overwriting in the middle doesn't change the size:
+    fs.write_file(&mut s, b"/data.bin", 10, b"hi").unwrap();
+    assert_eq!(fs.metadata(&mut s, b"/data.bin").unwrap().len, 1005);
+
+    fs.cache.flush(&mut s).unwrap();
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    let mut expected = vec![0u8; 1005];
+    expected[10..12].copy_from_slice(b"hi");
+    expected[1000..].copy_from_slice(b"hello");
+
+    let mut buf = vec![0xFF; 2000];
+    assert_eq!(fs.read_file(&mut s, b"/data.bin", 0, &mut buf), Ok(1005));
+    assert_eq!(&buf[..1005], &expected[..]);
+
+    // Reads are clamped to the end of the file:
+    assert_eq!(fs.read_file(&mut s, b"/data.bin", 998, &mut buf), Ok(7));
+    assert_eq!(&buf[..7], b"\0\0hello");
+    assert_eq!(fs.read_file(&mut s, b"/data.bin", 1005, &mut buf), Ok(0));
+    assert!(fs.read_file(&mut s, b"/data.bin", 1006, &mut buf).is_err());
+
+    // Directories and missing files aren't files:
+    fs.create_dir(&mut s, b"/dir").unwrap();
+    assert!(fs.read_file(&mut s, b"/dir", 0, &mut buf).is_err());
+    assert!(fs.write_file(&mut s, b"/dir", 0, b"x").is_err());
+    assert!(fs.write_file(&mut s, b"/nope.bin", 0, b"x").is_err());
+
+    fs.cache.flush(&mut s).unwrap();
+}
+
+#[test]
+fn append_handle() {
+    let (mut s, partition, _) = common::fat16_volume();
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    // Two clusters to start with:
+    let mut expected: Vec<u8> = (0..600u32).map(|i| i as u8).collect();
+    fs.create_file(&mut s, b"/log.txt").unwrap();
+    fs.write_file(&mut s, b"/log.txt", 0, &expected).unwrap();
+
+    let (loc, entry) = fs.lookup_path(&mut s, b"/log.txt").unwrap();
+    let first = entry.cluster_idx();
+    let file = entry.into_file().unwrap();
+
+    let mut handle = file.upgrade(&mut fs, &mut s).open_append().unwrap();
+
+    // Point the file's first cluster at itself; anything that goes through
+    // the chain from the start from here on out will fail.
+    let second = handle.fs.read_fat_entry(handle.storage, first).unwrap();
+    handle.fs.write_fat_entry(handle.storage, first, &FatEntry::from(first)).unwrap();
+
+    for i in 0..1000u32 {
+        let record = format!("{:06}\n", i);
+        handle.append(record.as_bytes()).unwrap();
+        expected.extend_from_slice(record.as_bytes());
+    }
+    assert_eq!(handle.entry().file_size as usize, expected.len());
+
+    handle.fs.write_fat_entry(handle.storage, first, &second).unwrap();
+    let entry = handle.entry().clone();
+    fs.write_dir_entry(&mut s, loc, &entry).unwrap();
+
+    let mut buf = vec![0; expected.len()];
+    assert_eq!(fs.read_file(&mut s, b"/log.txt", 0, &mut buf), Ok(expected.len()));
+    assert_eq!(buf, expected);
+
+    // 7600 bytes; 15 clusters.
+    let chain = fs::fat::table::FatEntryTracer::starting_at(&mut fs, &mut s, first).analyze().unwrap();
+    assert_eq!(chain.cluster_count, 15);
+
+    fs.cache.flush(&mut s).unwrap();
+}
+
+#[test]
+fn reads_stop_at_the_end_of_the_file() {
+    let (mut s, partition, g) = common::fat16_volume();
+
+    // /TAIL.BIN is 700 bytes in clusters 3 -> 4; the rest of cluster 4 is
+    // garbage.
+    let mut entry = DirEntry::new_file(FileName(*b"TAIL    "), FileExt(*b"BIN"), ClusterIdx::new(3));
+    entry.file_size = 700;
+    common::put_dir_entry(&mut s, g.root_dir_start(1), 0, &entry);
+    common::set_fat16_entry(&mut s, &g, 3, 4);
+    common::set_fat16_entry(&mut s, &g, 4, 0xFFF8);
+
+    let start = g.cluster_start(1, 3) * 512;
+    s.data[start..(start + 1024)].iter_mut().for_each(|b| *b = 0xEE);
+    s.data[start..(start + 700)].iter_mut().for_each(|b| *b = 0x11);
+
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    let file = fs.open(&mut s, b"/TAIL.BIN").unwrap();
+
+    let mut buf = vec![0; 1024];
+    assert_eq!(file.upgrade(&mut fs, &mut s).read(0, &mut buf), Ok(700));
+    assert!(buf[..700].iter().all(|b| *b == 0x11));
+    assert!(buf[700..].iter().all(|b| *b == 0));
+
+    assert_eq!(file.upgrade(&mut fs, &mut s).read(650, &mut buf), Ok(50));
+    assert_eq!(file.upgrade(&mut fs, &mut s).read(700, &mut buf), Ok(0));
+    assert!(file.upgrade(&mut fs, &mut s).read(701, &mut buf).is_err());
+
+    // Going a cluster at a time, the last one is cut short at
+    // `700 % 512 = 188` bytes:
+    let mut buf = vec![0; 511];
+    let last = FatEntry::from(ClusterIdx::new(4));
+    assert_eq!(last.upgrade(&mut fs, &mut s).read_clamped(ClusterOffset::new(0), &mut buf, ClusterOffset::new(700 % 512)), Ok(188));
+    assert!(buf[..188].iter().all(|b| *b == 0x11));
+    assert!(buf[188..].iter().all(|b| *b == 0));
+
+    assert_eq!(last.upgrade(&mut fs, &mut s).read_clamped(ClusterOffset::new(100), &mut buf, ClusterOffset::new(188)), Ok(88));
+    assert_eq!(last.upgrade(&mut fs, &mut s).read_clamped(ClusterOffset::new(188), &mut buf, ClusterOffset::new(188)), Ok(0));
+}
+
+#[test]
+fn defragment_file() {
+    let (mut s, partition, g) = common::fat16_volume();
+
+    // /FRAG.BIN is 1300 bytes in clusters 3 -> 9 -> 5:
+    let chain = [3, 9, 5];
+    let mut entry = DirEntry::new_file(FileName(*b"FRAG    "), FileExt(*b"BIN"), ClusterIdx::new(3));
+    entry.file_size = 1300;
+    common::put_dir_entry(&mut s, g.root_dir_start(1), 0, &entry);
+
+    let data: Vec<u8> = (0..1536u32).map(|i| (i * 13 + i / 512) as u8).collect();
+    for (i, c) in chain.iter().enumerate() {
+        let next = chain.get(i + 1).map_or(0xFFF8, |n| *n as u16);
+        common::set_fat16_entry(&mut s, &g, *c, next);
+
+        let start = g.cluster_start(1, *c) * 512;
+        s.data[start..(start + 512)].copy_from_slice(&data[(i * 512)..((i + 1) * 512)]);
+    }
+
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+    let free = fs.free_cluster_count(&mut s).unwrap();
+
+    fs.defragment_file(&mut s, b"/FRAG.BIN").unwrap();
+
+    // 6, 7, 8 is the first free run that's long enough:
+    let (_, entry) = fs.lookup_path(&mut s, b"/FRAG.BIN").unwrap();
+    assert_eq!(entry.cluster_idx(), ClusterIdx::new(6));
+    assert_eq!(entry.file_size, 1300);
+
+    let info = FatEntryTracer::starting_at(&mut fs, &mut s, entry.cluster_idx()).analyze().unwrap();
+    assert!(info.is_contiguous);
+    assert_eq!(info.cluster_count, 3);
+
+    let mut buf = vec![0; 1536];
+    entry.clone().into_file().unwrap().upgrade(&mut fs, &mut s).read_at(0, &mut buf).unwrap();
+    assert_eq!(buf, data);
+
+    // The old clusters are free again:
+    for c in chain.iter() {
+        assert_eq!(fs.read_fat_entry(&mut s, ClusterIdx::new(*c)), Ok(FatEntry::FREE));
+    }
+    assert_eq!(fs.free_cluster_count(&mut s).unwrap(), free);
+
+    // Already contiguous; nothing to do:
+    fs.defragment_file(&mut s, b"/FRAG.BIN").unwrap();
+    assert_eq!(fs.lookup_path(&mut s, b"/FRAG.BIN").unwrap().1.cluster_idx(), ClusterIdx::new(6));
+
+    fs.cache.flush(&mut s).unwrap();
+}
+
+#[test]
+fn copy_file() {
+    let (mut s, partition, g) = common::fat16_volume();
+
+    // /SRC.BIN is 1300 bytes in clusters 3 -> 9 -> 5:
+    let chain = [3, 9, 5];
+    let mut entry = DirEntry::new_file(FileName(*b"SRC     "), FileExt(*b"BIN"), ClusterIdx::new(3));
+    entry.file_size = 1300;
+    common::put_dir_entry(&mut s, g.root_dir_start(1), 0, &entry);
+
+    let data: Vec<u8> = (0..1536u32).map(|i| (i * 11 + i / 512) as u8).collect();
+    for (i, c) in chain.iter().enumerate() {
+        let next = chain.get(i + 1).map_or(0xFFF8, |n| *n as u16);
+        common::set_fat16_entry(&mut s, &g, *c, next);
+
+        let start = g.cluster_start(1, *c) * 512;
+        s.data[start..(start + 512)].copy_from_slice(&data[(i * 512)..((i + 1) * 512)]);
+    }
+
+    // And /SHORT.BIN is 5 bytes in cluster 20:
+    let mut entry = DirEntry::new_file(FileName(*b"SHORT   "), FileExt(*b"BIN"), ClusterIdx::new(20));
+    entry.file_size = 5;
+    common::put_dir_entry(&mut s, g.root_dir_start(1), 1, &entry);
+    common::set_fat16_entry(&mut s, &g, 20, 0xFFFF);
+    let start = g.cluster_start(1, 20) * 512;
+    s.data[start..(start + 5)].copy_from_slice(b"short");
+
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    assert_eq!(fs.copy_file(&mut s, b"/SRC.BIN", b"/DEST.BIN"), Ok(1300));
+
+    let read = |fs: &mut Fs, s: &mut common::MockStorage, path: &[u8]| {
+        let (_, entry) = fs.lookup_path(s, path).unwrap();
+        let mut buf = vec![0; entry.file_size as usize];
+        let len = entry.into_file().unwrap().upgrade(fs, s).read(0, &mut buf).unwrap();
+        buf.truncate(len);
+        buf
+    };
+
+    assert_eq!(read(&mut fs, &mut s, b"/DEST.BIN"), &data[..1300]);
+    let (_, dest) = fs.lookup_path(&mut s, b"/DEST.BIN").unwrap();
+    let info = FatEntryTracer::starting_at(&mut fs, &mut s, dest.cluster_idx()).analyze().unwrap();
+    assert_eq!(info.cluster_count, 3);
+
+    // The destination already exists now:
+    assert_eq!(fs.copy_file(&mut s, b"/SRC.BIN", b"/DEST.BIN"), Err(FatError::AlreadyExists));
+
+    // Overwriting a longer file with a shorter one gives back the extra
+    // clusters:
+    let free = fs.free_cluster_count(&mut s).unwrap();
+
+    assert_eq!(fs.copy_file_overwrite(&mut s, b"/SHORT.BIN", b"/DEST.BIN"), Ok(5));
+    assert_eq!(read(&mut fs, &mut s, b"/DEST.BIN"), b"short");
+    assert_eq!(fs.free_cluster_count(&mut s).unwrap(), free + 2);
+
+    // Can't copy a file over itself or copy something that isn't there:
+    assert_eq!(fs.copy_file_overwrite(&mut s, b"/SRC.BIN", b"/SRC.BIN"), Err(FatError::InvalidArgument));
+    assert_eq!(fs.copy_file(&mut s, b"/NOPE.BIN", b"/OTHER.BIN"), Err(FatError::NotFound));
+
+    // Sizes right up against 4 GiB don't overflow while counting clusters
+    // (the chain is far too short for the size so the copy stops partway):
+    let (loc, mut short) = fs.lookup_path(&mut s, b"/SHORT.BIN").unwrap();
+    short.file_size = u32::MAX;
+    fs.write_dir_entry(&mut s, loc, &short).unwrap();
+    assert_eq!(fs.copy_file(&mut s, b"/SHORT.BIN", b"/HUGE.BIN"), Err(FatError::Corrupt));
+
+    fs.cache.flush(&mut s).unwrap();
+}
+
+#[test]
+fn copy_file_with_4k_sectors() {
+    let mut s = common::MockStorage::<U4096>::with_sector_size(64);
+    let partition = fs::gpt::PartitionEntry::fat("RTOS", 1, 63);
+    let mut fs = FatFs::<_, U4, _>::format(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    // A few clusters' worth:
+    let data: Vec<u8> = (0..10_000u32).map(|i| (i * 13) as u8).collect();
+    fs.create_file(&mut s, b"/SRC.BIN").unwrap();
+    fs.write_file(&mut s, b"/SRC.BIN", 0, &data).unwrap();
+
+    assert_eq!(fs.copy_file(&mut s, b"/SRC.BIN", b"/DEST.BIN"), Ok(10_000));
+
+    let mut buf = vec![0; data.len()];
+    assert_eq!(fs.read_file(&mut s, b"/DEST.BIN", 0, &mut buf), Ok(data.len()));
+    assert_eq!(buf, data);
+
+    fs.unmount(&mut s).unwrap();
+}
+
+#[test]
+fn create_file_truncate() {
+    let (mut s, partition, _) = common::fat16_volume();
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    // Fresh files are just created:
+    let file = fs.create_file_truncate(&mut s, b"/BIG.BIN").unwrap();
+    let first = fs.lookup_path(&mut s, b"/BIG.BIN").unwrap().1.cluster_idx();
+
+    let data = vec![0xA5; 10 * 1024];
+    file.upgrade(&mut fs, &mut s).write_at(0, &data).unwrap();
+
+    let (loc, mut entry) = fs.lookup_path(&mut s, b"/BIG.BIN").unwrap();
+    entry.file_size = data.len() as u32;
+    entry.last_modif_date = 0x4E21;
+    fs.write_dir_entry(&mut s, loc, &entry).unwrap();
+
+    let free = fs.free_cluster_count(&mut s).unwrap();
+
+    // Existing ones are truncated in place:
+    fs.create_file_truncate(&mut s, b"/BIG.BIN").unwrap();
+
+    let (new_loc, entry) = fs.lookup_path(&mut s, b"/BIG.BIN").unwrap();
+    assert_eq!(new_loc, loc);
+    assert_eq!(entry.cluster_idx(), first);
+    assert_eq!(entry.file_size, 0);
+    assert_eq!(entry.last_modif_date, 0);
+
+    let info = FatEntryTracer::starting_at(&mut fs, &mut s, first).analyze().unwrap();
+    assert_eq!(info.cluster_count, 1);
+    assert_eq!(fs.free_cluster_count(&mut s).unwrap(), free + 19);
+
+    // Directories aren't truncated:
+    fs.create_dir(&mut s, b"/SUB").unwrap();
+    assert!(fs.create_file_truncate(&mut s, b"/SUB").is_err());
+
+    fs.cache.flush(&mut s).unwrap();
+}
+
+#[test]
+fn errors_say_what_went_wrong() {
+    let (mut s, partition, _) = common::fat16_volume();
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    fs.create_dir(&mut s, b"/DIR").unwrap();
+    fs.create_file(&mut s, b"/FILE.TXT").unwrap();
+
+    let mut buf = [0; 4];
+
+    // Not found:
+    assert_eq!(fs.read_file(&mut s, b"/NOPE.TXT", 0, &mut buf), Err(FatError::NotFound));
+    assert_eq!(fs.write_file(&mut s, b"/DIR/NOPE.TXT", 0, b"x"), Err(FatError::NotFound));
+    assert_eq!(fs.create_file(&mut s, b"/NOPE/NEW.TXT"), Err(FatError::NotFound));
+    assert_eq!(fs.defragment_file(&mut s, b"/NOPE.TXT"), Err(FatError::NotFound));
+
+    // Things that aren't what they're used as:
+    assert_eq!(fs.read_file(&mut s, b"/DIR", 0, &mut buf), Err(FatError::IsADirectory));
+    assert_eq!(fs.create_file(&mut s, b"/FILE.TXT/NEW.TXT"), Err(FatError::NotADirectory));
+    assert_eq!(fs.create_dir(&mut s, b"/DIR"), Err(FatError::AlreadyExists));
+    assert_eq!(fs.create_file(&mut s, b"/TOOLONGNAME.TXT"), Err(FatError::InvalidArgument));
+
+    // Reads past the end of the file:
+    assert_eq!(fs.read_file(&mut s, b"/FILE.TXT", 1, &mut buf), Err(FatError::OutOfRange));
+
+    // Out of space: take every cluster that's left.
+    let free = fs.free_cluster_count(&mut s).unwrap();
+    fs.allocate_chain(&mut s, free).unwrap();
+
+    assert_eq!(fs.next_free_cluster(&mut s), Err(FatError::OutOfSpace));
+    assert_eq!(fs.allocate_chain(&mut s, 1), Err(FatError::OutOfSpace));
+    assert_eq!(fs.create_dir(&mut s, b"/FULL"), Err(FatError::OutOfSpace));
+    assert_eq!(fs.write_file(&mut s, b"/FILE.TXT", 0, &[0xAA; 1024]), Err(FatError::OutOfSpace));
+
+    fs.cache.flush(&mut s).unwrap();
+}
+
+#[test]
+fn data_extents() {
+    let (mut s, partition, g) = common::fat16_volume();
+    let root = g.root_dir_start(1);
+
+    // /A.BIN is clusters 3 -> 4 -> 5, /B.BIN is 6 -> 9 -> 7 -> 8, /C.BIN is
+    // empty.
+    let files: [(&[u8; 8], &[u32]); 3] = [
+        (b"A       ", &[3, 4, 5]),
+        (b"B       ", &[6, 9, 7, 8]),
+        (b"C       ", &[]),
+    ];
+
+    for (slot, (name, chain)) in files.iter().enumerate() {
+        let first = chain.first().cloned().unwrap_or(0);
+        let entry = DirEntry::new_file(FileName(**name), FileExt(*b"BIN"), ClusterIdx::new(first));
+        common::put_dir_entry(&mut s, root, slot, &entry);
+
+        for (i, c) in chain.iter().enumerate() {
+            let next = chain.get(i + 1).map_or(0xFFF8, |n| *n as u16);
+            common::set_fat16_entry(&mut s, &g, *c, next);
+        }
+    }
+
+    let mut fs = Fs::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    // Clusters are one sector each.
+    let sectors = |first: u32, last: u32| {
+        SectorIdx::new(g.cluster_start(1, first) as u64)..SectorIdx::new(g.cluster_start(1, last) as u64 + 1)
+    };
+    let extents = |fs: &mut Fs, s: &mut common::MockStorage, path: &[u8]| {
+        let file = fs.lookup_path(s, path).unwrap().1.into_file().unwrap();
+        let mut file = file.upgrade(fs, s);
+        let mut extents = file.data_extents();
+
+        let ranges: Vec<_> = extents.by_ref().collect();
+        assert_eq!(extents.error(), None);
+        ranges
+    };
+
+    assert_eq!(extents(&mut fs, &mut s, b"/A.BIN"), vec![sectors(3, 5)]);
+    assert_eq!(
+        extents(&mut fs, &mut s, b"/B.BIN"),
+        vec![sectors(6, 6), sectors(9, 9), sectors(7, 8)],
+    );
+    assert_eq!(extents(&mut fs, &mut s, b"/C.BIN"), vec![]);
+
+    // Our empty files have a cluster, which counts:
+    fs.create_file(&mut s, b"/D.BIN").unwrap();
+    let first = *fs.lookup_path(&mut s, b"/D.BIN").unwrap().1.cluster_idx().inner();
+    assert_eq!(extents(&mut fs, &mut s, b"/D.BIN"), vec![sectors(first, first)]);
+
+    fs.cache.flush(&mut s).unwrap();
+}
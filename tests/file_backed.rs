@@ -1,19 +1,32 @@
 // Requires the `no_std` feature to be disabled so that `File`s implement the
-// `Storage` trait.
+// `Storage` trait and the `test-util` feature (for the golden image, which
+// these tests copy into a file).
 //
-// Run with --no-default-features.
+// Run with --no-default-features --features test-util.
+
+mod common;
+
+use fs::gpt::PartitionEntry;
+use fs::fat::{FatFs, cache::eviction_policies::UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED};
+use fs::test_util::{GOLDEN_FILES, GOLDEN_IMAGE};
 
 use storage_traits::{FileBackedStorage, Storage};
 use generic_array::GenericArray;
-use typenum::consts::U512;
-
-const FILE_PATH: &'static str = "assets/gpt.img";
+use typenum::consts::{U512, U64};
 
 const SD_CARD_PATH: &'static str = "/dev/mmcblk0";
 // const SD_CARD_SIZE: usize = (16 * 1024 * 1024 * 1024) / 512;
 const SD_CARD_SIZE: usize = 31_449_088;
 
-fn read_sector_one(mut storage: FileBackedStorage) {
+/// A file with the golden image in it.
+fn golden_file(name: &str) -> FileBackedStorage {
+    let path = common::scratch_file(name, GOLDEN_IMAGE.len() / 512);
+    std::fs::write(&path, GOLDEN_IMAGE).unwrap();
+
+    FileBackedStorage::from_file(&path).unwrap()
+}
+
+fn read_sector_zero(storage: &mut FileBackedStorage) -> GenericArray<u8, U512> {
     eprintln!("size in:\n  - sectors: {:#X}\n  - words: {:#X}\n  - bytes: {:#X} ({}) (2 ** {})",
         storage.capacity(),
         storage.capacity_in_words(),
@@ -25,25 +38,50 @@ fn read_sector_one(mut storage: FileBackedStorage) {
     let mut sector = GenericArray::default();
     storage.read_sector(0, &mut sector).unwrap();
 
-    let mut checksum: u64 = 0;
-
-    // TODO: use an actual checksum here?
-    // or, don't bother; just for testing.
-    for byte in sector.as_slice() {
-        checksum = checksum.wrapping_add(*byte as u64);
-    }
-
-    // // Should fail!
-    assert_eq!(1774, checksum);
+    sector
 }
 
 #[test]
 fn file() {
-    read_sector_one(FileBackedStorage::from_file(FILE_PATH).unwrap())
+    let mut s = golden_file("file-backed-sector");
+
+    assert_eq!(s.capacity(), GOLDEN_IMAGE.len() / 512);
+    assert_eq!(read_sector_zero(&mut s).as_slice(), &GOLDEN_IMAGE[..512]);
 }
 
 #[test]
 #[ignore]
 fn card() {
-    read_sector_one(FileBackedStorage::from_file_with_explicit_size(SD_CARD_PATH, SD_CARD_SIZE).unwrap())
+    let mut s = FileBackedStorage::from_file_with_explicit_size(SD_CARD_PATH, SD_CARD_SIZE).unwrap();
+
+    // Whatever's on the card, the read should work:
+    read_sector_zero(&mut s);
+}
+
+#[test]
+fn read_whole_files() {
+    let mut s = golden_file("file-backed-files");
+    let partition = PartitionEntry::fat("GOLDEN", 0, s.capacity() as u64 - 1);
+
+    let mut fs = FatFs::<_, U64, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    for (path, size, crc) in GOLDEN_FILES.iter() {
+        let (_, entry) = fs.lookup_path(&mut s, path.as_bytes()).unwrap();
+        let file = entry.clone().into_file().unwrap();
+
+        let data = file.upgrade(&mut fs, &mut s).read_to_end().unwrap();
+        assert_eq!(data.len(), *size as usize, "{}", path);
+        assert_eq!(crc::crc32::checksum_ieee(&data), *crc, "{}", path);
+
+        // Same thing, into a buffer that's bigger than it needs to be:
+        let mut buf = vec![0; data.len() + 100];
+        assert_eq!(file.upgrade(&mut fs, &mut s).read_to_slice(&mut buf), Ok(data.len()));
+        assert_eq!(&buf[..data.len()], &data[..]);
+
+        // And one that's too small:
+        if !data.is_empty() {
+            let mut buf = vec![0; data.len() - 1];
+            assert!(file.upgrade(&mut fs, &mut s).read_to_slice(&mut buf).is_err());
+        }
+    }
 }
@@ -0,0 +1,184 @@
+// Requires the `test-util` feature (for the golden image) and the `no_std`
+// feature to be disabled.
+//
+// Run with --no-default-features --features test-util.
+
+use fs::fat::FatError;
+use fs::fat::table::FatType;
+use fs::fat::types::ClusterIdx;
+use fs::test_util::{golden_volume, GOLDEN_FILES, GOLDEN_IMAGE, GOLDEN_ROOT_DIR};
+
+#[test]
+fn root_dir() {
+    let (mut s, mut fs) = golden_volume();
+    assert_eq!(fs.fat_type, FatType::Fat12);
+
+    let names: Vec<_> = fs.list_dir_vec(&mut s, b"/").unwrap()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(names, GOLDEN_ROOT_DIR);
+}
+
+#[test]
+fn read_whole_files() {
+    let (mut s, mut fs) = golden_volume();
+
+    for (path, size, crc) in GOLDEN_FILES.iter() {
+        let (_, entry) = fs.lookup_path(&mut s, path.as_bytes()).unwrap();
+        assert_eq!(entry.file_size, *size, "{}", path);
+
+        let data = entry.into_file().unwrap().upgrade(&mut fs, &mut s).read_to_end().unwrap();
+        assert_eq!(data.len(), *size as usize, "{}", path);
+        assert_eq!(crc::crc32::checksum_ieee(&data), *crc, "{}", path);
+    }
+}
+
+#[test]
+fn unmount_only_writes_what_changed() {
+    let (mut s, mut fs) = golden_volume();
+
+    // The same length, so the directory entry doesn't change either:
+    fs.write_file(&mut s, b"/HELLO.TXT", 0, b"HOWDY").unwrap();
+    fs.unmount(&mut s).unwrap();
+
+    // The bytes where FAT32 keeps the backup boot sector's location are part
+    // of the volume label on FAT12; nothing should be written there.
+    let changed: Vec<_> = s.into_bytes()
+        .chunks(512)
+        .zip(GOLDEN_IMAGE.chunks(512))
+        .enumerate()
+        .filter(|(_, (new, old))| new != old)
+        .map(|(idx, (new, _))| (idx, new.to_vec()))
+        .collect();
+
+    assert_eq!(changed.len(), 1);
+    assert!(changed[0].1.starts_with(b"HOWDY"));
+}
+
+#[test]
+fn files_without_clusters_arent_written_through_cluster_0() {
+    let (mut s, mut fs) = golden_volume();
+
+    let (_, entry) = fs.lookup_path(&mut s, b"/EMPTY.TXT").unwrap();
+    assert_eq!(*entry.cluster_idx().inner(), 0);
+
+    let file = entry.into_file().unwrap();
+    assert_eq!(file.upgrade(&mut fs, &mut s).write_at(0, b"oops"), Err(FatError::InvalidArgument));
+
+    let names: Vec<_> = fs.list_dir_vec(&mut s, b"/").unwrap()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(names, GOLDEN_ROOT_DIR);
+
+    fs.unmount(&mut s).unwrap();
+    assert_eq!(s.into_bytes(), GOLDEN_IMAGE);
+}
+
+#[test]
+fn writing_files_without_clusters_gives_them_one() {
+    let (mut s, mut fs) = golden_volume();
+    let free = fs.free_cluster_count(&mut s).unwrap();
+
+    fs.write_file(&mut s, b"/EMPTY.TXT", 10, b"hi").unwrap();
+
+    let (_, entry) = fs.lookup_path(&mut s, b"/EMPTY.TXT").unwrap();
+    assert!(*entry.cluster_idx().inner() >= 2);
+    assert_eq!(entry.file_size, 12);
+    assert_eq!(fs.free_cluster_count(&mut s).unwrap(), free - 1);
+
+    let mut buf = [0xFF; 12];
+    assert_eq!(fs.read_file(&mut s, b"/EMPTY.TXT", 0, &mut buf), Ok(12));
+    assert_eq!(&buf, b"\0\0\0\0\0\0\0\0\0\0hi");
+
+    // The root directory is intact:
+    let names: Vec<_> = fs.list_dir_vec(&mut s, b"/").unwrap()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(names, GOLDEN_ROOT_DIR);
+
+    for (path, size, crc) in GOLDEN_FILES.iter().filter(|(p, _, _)| *p != "/EMPTY.TXT") {
+        let mut data = vec![0; *size as usize];
+        assert_eq!(fs.read_file(&mut s, path.as_bytes(), 0, &mut data), Ok(*size as usize));
+        assert_eq!(crc::crc32::checksum_ieee(&data), *crc, "{}", path);
+    }
+
+    fs.unmount(&mut s).unwrap();
+}
+
+#[test]
+fn appending_to_files_without_clusters() {
+    let (mut s, mut fs) = golden_volume();
+
+    let (loc, entry) = fs.lookup_path(&mut s, b"/EMPTY.TXT").unwrap();
+    let file = entry.into_file().unwrap();
+
+    let mut handle = file.upgrade(&mut fs, &mut s).open_append().unwrap();
+    handle.append(b"first ").unwrap();
+    handle.append(b"second").unwrap();
+
+    let entry = handle.entry().clone();
+    assert!(*entry.cluster_idx().inner() >= 2);
+    fs.write_dir_entry(&mut s, loc, &entry).unwrap();
+
+    let mut buf = [0; 12];
+    assert_eq!(fs.read_file(&mut s, b"/EMPTY.TXT", 0, &mut buf), Ok(12));
+    assert_eq!(&buf, b"first second");
+
+    let names: Vec<_> = fs.list_dir_vec(&mut s, b"/").unwrap()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(names, GOLDEN_ROOT_DIR);
+
+    fs.unmount(&mut s).unwrap();
+}
+
+#[test]
+fn truncating_files_without_clusters() {
+    let (mut s, mut fs) = golden_volume();
+    let reserved = fs.read_fat_entry(&mut s, ClusterIdx::new(0)).unwrap();
+
+    let file = fs.create_file_truncate(&mut s, b"/EMPTY.TXT").unwrap();
+    file.upgrade(&mut fs, &mut s).write_at(0, b"data").unwrap();
+
+    // FAT[0] (which has the media descriptor in it) is left alone:
+    assert_eq!(fs.read_fat_entry(&mut s, ClusterIdx::new(0)).unwrap(), reserved);
+
+    let (_, entry) = fs.lookup_path(&mut s, b"/EMPTY.TXT").unwrap();
+    assert!(*entry.cluster_idx().inner() >= 2);
+    assert_eq!(entry.file_size, 0);
+
+    let names: Vec<_> = fs.list_dir_vec(&mut s, b"/").unwrap()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(names, GOLDEN_ROOT_DIR);
+
+    fs.unmount(&mut s).unwrap();
+}
+
+#[test]
+fn copying_over_files_without_clusters() {
+    let (mut s, mut fs) = golden_volume();
+    let reserved = fs.read_fat_entry(&mut s, ClusterIdx::new(0)).unwrap();
+
+    assert_eq!(fs.copy_file_overwrite(&mut s, b"/HELLO.TXT", b"/EMPTY.TXT"), Ok(14));
+    assert_eq!(fs.read_fat_entry(&mut s, ClusterIdx::new(0)).unwrap(), reserved);
+
+    let mut hello = [0; 14];
+    let mut copy = [0; 14];
+    assert_eq!(fs.read_file(&mut s, b"/HELLO.TXT", 0, &mut hello), Ok(14));
+    assert_eq!(fs.read_file(&mut s, b"/EMPTY.TXT", 0, &mut copy), Ok(14));
+    assert_eq!(hello, copy);
+
+    let names: Vec<_> = fs.list_dir_vec(&mut s, b"/").unwrap()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(names, GOLDEN_ROOT_DIR);
+
+    fs.unmount(&mut s).unwrap();
+}
@@ -0,0 +1,43 @@
+// Requires the `no_std` feature to be disabled so that the std Mutex is
+// available.
+//
+// Run with --no-default-features.
+
+use fs::mutex::{MutexInterface, from_std::Mutex};
+
+#[test]
+fn try_cs_on_held_std_mutex() {
+    let m = <Mutex<u8> as MutexInterface<u8>>::new(7);
+    assert_eq!(m.try_cs(|v| *v), Some(7));
+
+    let guard = m.lock().unwrap();
+    assert_eq!(m.try_cs(|v| *v), None);
+    drop(guard);
+
+    // And from inside a critical section:
+    assert_eq!(m.cs(|_| m.try_cs(|v| *v)), None);
+}
+
+/// A Mutex written before `try_cs` existed.
+struct OldMutex<T>(std::sync::Mutex<T>);
+
+impl<T: Send> MutexInterface<T> for OldMutex<T> {
+    fn new(inner: T) -> Self {
+        OldMutex(std::sync::Mutex::new(inner))
+    }
+
+    fn cs<F: FnOnce(&mut T) -> R, R>(&self, func: F) -> R {
+        func(&mut *self.0.lock().unwrap())
+    }
+
+    fn get_mut(&mut self) -> &mut T {
+        self.0.get_mut().unwrap()
+    }
+}
+
+#[test]
+fn try_cs_defaults_to_not_locking() {
+    let m = <OldMutex<u8> as MutexInterface<u8>>::new(7);
+    assert_eq!(m.try_cs(|v| *v), None);
+    assert_eq!(m.cs(|v| *v), 7);
+}
@@ -0,0 +1,170 @@
+// Requires the `test-util` feature (for `RamStorage`) and the `no_std`
+// feature to be disabled.
+//
+// Run with --no-default-features --features test-util.
+
+mod common;
+
+use fs::fat::{
+    FatError,
+    FatFs,
+    MountError,
+    types::{ClusterIdx, SectorIdx},
+    cache::eviction_policies::UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+};
+use fs::gpt::{Gpt, Guid, PartitionEntry};
+use fs::storage::{RamStorage, StorageExt};
+
+use typenum::consts::{U4, U64};
+
+#[test]
+fn format_and_mount() {
+    // `FatFs::format` isn't implemented yet so we "format" the volume by hand:
+    // erase everything and then write a boot sector that's just enough to get
+    // `FatFs::mount` to succeed.
+    let mut s = RamStorage::<U64>::from_bytes(&[0xA5; 64 * 512]).unwrap();
+    s.erase().unwrap();
+    assert!(s.sector(10).iter().all(|b| *b == 0));
+
+    s.write_bytes(512 + 0x0B, &512u16.to_le_bytes()).unwrap();
+    s.write_bytes(512 + 0x0D, &[1]).unwrap(); // sectors per cluster
+    s.write_bytes(512 + 0x10, &[1]).unwrap(); // FATs
+
+    let partition = PartitionEntry::fat("RTOS", 1, 63);
+    {
+        let mut fs = FatFs::<_, U4, _>::mount(
+            &mut s,
+            &partition,
+            UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+        ).unwrap();
+
+        fs.write(&mut s, SectorIdx::new(10), 3, b"hello").unwrap();
+        fs.cache.flush(&mut s).unwrap();
+    }
+
+    let bytes = s.into_bytes();
+    assert_eq!(bytes.len(), 64 * 512);
+    assert_eq!(b"hello", &bytes[(10 * 512 + 3)..(10 * 512 + 8)]);
+}
+
+#[test]
+fn from_bytes_too_big() {
+    assert!(RamStorage::<U4>::from_bytes(&[0; 4 * 512 + 1]).is_err());
+    assert!(RamStorage::<U4>::from_bytes(&[1; 3]).unwrap().sector(0)[..4] == [1, 1, 1, 0]);
+}
+
+#[test]
+fn allocating_on_a_full_volume_fails() {
+    // A tiny FAT12 volume: 60 one sector clusters.
+    let g = common::Geometry {
+        sectors_per_cluster: 1,
+        reserved_sectors: 1,
+        num_fats: 1,
+        fat_size: 1,
+        root_entries: 16,
+        total_sectors: 63,
+    };
+
+    let mut s = RamStorage::<U64>::new();
+    g.write_boot_sector(s.sector_mut(1), 1);
+    s.sector_mut(g.fat_start(1))[0..3].copy_from_slice(&[0xF8, 0xFF, 0xFF]);
+
+    let partition = PartitionEntry::fat("RTOS", 1, 63);
+    let mut fs = FatFs::<_, U4, _>::mount(
+        &mut s,
+        &partition,
+        UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED,
+    ).unwrap();
+
+    assert_eq!(fs.cluster_count, 60);
+
+    let mut given = Vec::new();
+    while let Ok(c) = fs.next_free_cluster(&mut s) {
+        given.push(*c.inner());
+        assert!(given.len() <= 60);
+    }
+
+    // Every cluster, once:
+    given.sort();
+    assert_eq!(given, (2..62).collect::<Vec<_>>());
+
+    // And it stays full:
+    assert_eq!(fs.next_free_cluster(&mut s), Err(FatError::OutOfSpace));
+    assert_eq!(fs.create_file(&mut s, b"/full.txt"), Err(FatError::OutOfSpace));
+
+    // Until something is freed:
+    fs.write_fat_entry(&mut s, ClusterIdx::new(30), &fs::fat::table::FatEntry::FREE).unwrap();
+    assert_eq!(fs.next_free_cluster(&mut s), Ok(ClusterIdx::new(30)));
+
+    fs.cache.flush(&mut s).unwrap();
+}
+
+#[test]
+fn mount_rejects_what_it_cant_handle() {
+    // A tiny FAT12 volume at LBA 34, after a GPT; it mounts as is.
+    let g = common::Geometry {
+        sectors_per_cluster: 1,
+        reserved_sectors: 1,
+        num_fats: 1,
+        fat_size: 1,
+        root_entries: 16,
+        total_sectors: 30,
+    };
+
+    let esp = Guid::from_mixed_u128(0xC12A7328_F81F_11D2_BA4B_00A0C93EC93B);
+    let mut disk = common::MockStorage::new(64);
+    common::write_gpt(&mut disk, &[
+        (Guid::microsoft_basic_data(), 34, 63),
+        (esp, 34, 63),
+    ]);
+    g.write_boot_sector(disk.sector_mut(34), 34);
+
+    let base = RamStorage::<U64>::from_bytes(&disk.data).unwrap();
+    let mount = |s: &mut RamStorage<U64>, n| {
+        let gpt = Gpt::read_gpt(s).unwrap();
+        let partition = gpt.get_partition_entry(s, n).unwrap();
+
+        FatFs::<_, U4, _>::mount(s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).map(|_| ())
+    };
+    let tweaked = |offset: usize, bytes: &[u8]| {
+        let mut s = base.clone();
+        s.write_bytes(34 * 512 + offset, bytes).unwrap();
+        s
+    };
+
+    assert_eq!(mount(&mut base.clone(), 0), Ok(()));
+
+    // Same volume, but the partition says it's an EFI system partition:
+    assert_eq!(mount(&mut base.clone(), 1), Err(MountError::WrongPartitionType));
+
+    assert_eq!(
+        mount(&mut tweaked(0x0B, &1024u16.to_le_bytes()), 0),
+        Err(MountError::UnsupportedSectorSize),
+    );
+
+    // exFAT:
+    let mut exfat = tweaked(0x03, b"EXFAT   ");
+    exfat.write_bytes(34 * 512 + 0x0B, &[0; 0x40 - 0x0B]).unwrap();
+    exfat.write_bytes(34 * 512 + 0x0B, &512u16.to_le_bytes()).unwrap();
+    assert_eq!(mount(&mut exfat, 0), Err(MountError::NotFat));
+
+    // A file system type that isn't FAT:
+    let mut other = tweaked(0x26, &[0x29]);
+    other.write_bytes(34 * 512 + 0x36, b"HPFS    ").unwrap();
+    assert_eq!(mount(&mut other, 0), Err(MountError::NotFat));
+
+    // But FAT ones are fine:
+    let mut fat12 = tweaked(0x26, &[0x29]);
+    fat12.write_bytes(34 * 512 + 0x36, b"FAT12   ").unwrap();
+    assert_eq!(mount(&mut fat12, 0), Ok(()));
+
+    // Nonsense geometry:
+    assert_eq!(mount(&mut tweaked(0x0D, &[3]), 0), Err(MountError::NotFat));
+    assert_eq!(mount(&mut tweaked(0x10, &[0]), 0), Err(MountError::NotFat));
+
+    // Enough clusters (on paper) for FAT32, but version 1.0:
+    let mut fat32 = tweaked(0x20, &66600u32.to_le_bytes());
+    fat32.write_bytes(34 * 512 + 0x11, &[0; 2]).unwrap(); // no fixed root directory
+    fat32.write_bytes(34 * 512 + 0x2A, &[0, 1]).unwrap();
+    assert_eq!(mount(&mut fat32, 0), Err(MountError::UnsupportedVersion));
+}
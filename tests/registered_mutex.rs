@@ -0,0 +1,109 @@
+// A Mutex from outside this crate plugged in as `mutex::Mutex` (which the
+// bindings' globals use) with the `custom_mutex` feature.
+//
+// Run with --no-default-features --features custom_mutex.
+
+mod common;
+
+use fs::mutex::{Mutex, MutexInterface};
+use fs::fat::{
+    FatFs,
+    types::SectorIdx,
+    cache::{DynEvictionPolicy, eviction_policies::UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED},
+};
+
+use typenum::consts::U4;
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// A spin lock that counts how many critical sections it's run.
+pub struct CountingLock {
+    locked: AtomicBool,
+    uses: AtomicUsize,
+    unit: (),
+}
+
+impl CountingLock {
+    pub const fn new() -> Self {
+        Self { locked: AtomicBool::new(false), uses: AtomicUsize::new(0), unit: () }
+    }
+}
+
+impl MutexInterface<()> for CountingLock {
+    fn new(_: ()) -> Self {
+        CountingLock::new()
+    }
+
+    fn cs<F: FnOnce(&mut ()) -> R, R>(&self, func: F) -> R {
+        while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            std::hint::spin_loop();
+        }
+        self.uses.fetch_add(1, Ordering::Relaxed);
+
+        let res = func(&mut ());
+        self.locked.store(false, Ordering::Release);
+
+        res
+    }
+
+    fn try_cs<F: FnOnce(&mut ()) -> R, R>(&self, func: F) -> Option<R> {
+        if self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            return None;
+        }
+        self.uses.fetch_add(1, Ordering::Relaxed);
+
+        let res = func(&mut ());
+        self.locked.store(false, Ordering::Release);
+
+        Some(res)
+    }
+
+    fn get_mut(&mut self) -> &mut () {
+        &mut self.unit
+    }
+}
+
+static LOCK: CountingLock = CountingLock::new();
+fs::register_mutex!(LOCK);
+
+static STORAGE: Mutex<Option<common::MockStorage>> = Mutex::new(None);
+static FS: Mutex<Option<
+    FatFs<common::MockStorage, U4, DynEvictionPolicy>
+>> = Mutex::new(None);
+
+#[test]
+fn registered_mutex_wraps_global_fs() {
+    let before = LOCK.uses.load(Ordering::Relaxed);
+
+    STORAGE.cs(|s| {
+        let mut storage = common::MockStorage::new(16);
+        common::bare_boot_sector(storage.sector_mut(1));
+        *s = Some(storage);
+
+        let s = s.as_mut().unwrap();
+        let partition = fs::gpt::PartitionEntry::fat("RTOS", 1, 15);
+
+        // Nesting critical sections on different Mutexes is fine:
+        FS.cs(|f| {
+            *f = Some(FatFs::mount(s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap());
+        })
+    });
+
+    let written = std::thread::spawn(|| STORAGE.cs(|s| FS.cs(|f| {
+        let (s, f) = (s.as_mut().unwrap(), f.as_mut().unwrap());
+
+        f.write(s, SectorIdx::new(5), 0, b"hi").unwrap();
+        f.cache.flush(s).unwrap();
+
+        s.sector(5)[..2].to_vec()
+    }))).join().unwrap();
+
+    assert_eq!(written, b"hi");
+
+    // Locking and unlocking each goes through the registered lock:
+    assert!(LOCK.uses.load(Ordering::Relaxed) >= before + 8);
+
+    // A held Mutex can't be locked again without blocking:
+    assert_eq!(FS.cs(|_| FS.try_cs(|_| ())), None);
+    assert_eq!(FS.try_cs(|f| f.is_some()), Some(true));
+}
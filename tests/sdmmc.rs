@@ -0,0 +1,33 @@
+// Requires the `embedded-sdmmc` and `test-util` features (and the `no_std`
+// feature to be disabled).
+//
+// Run with --no-default-features --features "embedded-sdmmc test-util".
+
+use fs::sdmmc::BlockDeviceAdapter;
+use fs::storage::RamStorage;
+
+use embedded_sdmmc::{Block, BlockDevice, BlockIdx};
+use typenum::consts::U8;
+
+#[test]
+fn blocks_are_sectors() {
+    let mut bytes = [0u8; 8 * 512];
+    bytes[3 * 512..4 * 512].iter_mut().for_each(|b| *b = 0x33);
+
+    let dev = BlockDeviceAdapter::new(RamStorage::<U8>::from_bytes(&bytes).unwrap());
+    assert_eq!(dev.num_blocks().unwrap().0, 8);
+
+    let mut blocks = [Block::new(), Block::new()];
+    dev.read(&mut blocks, BlockIdx(2), "test").unwrap();
+    assert!(blocks[0].contents.iter().all(|b| *b == 0));
+    assert!(blocks[1].contents.iter().all(|b| *b == 0x33));
+
+    blocks[0].contents[0..5].copy_from_slice(b"hello");
+    dev.write(&blocks[..1], BlockIdx(6)).unwrap();
+
+    // Past the end:
+    assert!(dev.read(&mut blocks, BlockIdx(7), "test").is_err());
+
+    let s = dev.into_inner();
+    assert_eq!(&s.sector(6)[0..5], b"hello");
+}
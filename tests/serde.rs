@@ -0,0 +1,85 @@
+// Requires the `serde` feature and the `no_std` feature to be disabled.
+//
+// Run with --no-default-features --features serde.
+
+mod common;
+
+use fs::fat::{
+    boot_sector::BootSector,
+    types::ClusterIdx,
+    dir::{DirEntry, FileName, FileExt},
+};
+use fs::gpt::{Gpt, Guid, PartitionEntry};
+
+use generic_array::GenericArray;
+use typenum::consts::U512;
+
+#[test]
+fn gpt_roundtrip() {
+    let mut s = common::MockStorage::new(64);
+    common::write_gpt(&mut s, &[
+        (Guid::microsoft_basic_data(), 34, 47),
+        (Guid::microsoft_basic_data(), 48, 63),
+    ]);
+    s.sector_mut(1)[56..72].copy_from_slice(&Guid::from_mixed_u128(0x0123_4567_89AB_CDEF_0123_4567_89AB_CDEF).to_bytes());
+
+    let gpt = Gpt::read_gpt(&mut s).unwrap();
+
+    let json = serde_json::to_string(&gpt).unwrap();
+    assert!(json.contains(r#""disk_guid":"01234567-89AB-CDEF-0123-456789ABCDEF""#));
+    assert!(json.contains(r#""num_partition_entries":128"#));
+
+    let back: Gpt = serde_json::from_str(&json).unwrap();
+    assert_eq!(back, gpt);
+}
+
+#[test]
+fn partition_entry_roundtrip() {
+    let p = PartitionEntry::fat("RTOS ✓", 34, 63);
+
+    let json = serde_json::to_string(&p).unwrap();
+    assert!(json.contains(r#""partition_type":"EBD0A0A2-B9E5-4433-87C0-68B6B72699C7""#));
+    assert!(json.contains(r#""name":"RTOS ✓""#));
+
+    let back: PartitionEntry = serde_json::from_str(&json).unwrap();
+    assert_eq!(format!("{:?}", back), format!("{:?}", p));
+
+    assert!(serde_json::from_str::<PartitionEntry>(&json.replace("EBD0A0A2", "EBD0A0AZ")).is_err());
+}
+
+#[test]
+fn dir_entry_roundtrip() {
+    let mut entry = DirEntry::new_file(FileName(*b"README  "), FileExt(*b"TXT"), ClusterIdx::new(0x12345));
+    entry.file_size = 77;
+
+    let json = serde_json::to_string(&entry).unwrap();
+    assert!(json.contains(r#""file_name":"README  ""#));
+    assert!(json.contains(r#""file_ext":"TXT""#));
+
+    let back: DirEntry = serde_json::from_str(&json).unwrap();
+    assert_eq!(back, entry);
+
+    // Short text is padded:
+    let short = json.replace(r#""README  ""#, r#""README""#);
+    assert_eq!(serde_json::from_str::<DirEntry>(&short).unwrap(), entry);
+
+    // Names that aren't text (deleted entries start with 0xE5) still make it
+    // through:
+    entry.file_name.0[0] = 0xE5;
+    let json = serde_json::to_string(&entry).unwrap();
+    assert_eq!(serde_json::from_str::<DirEntry>(&json).unwrap(), entry);
+}
+
+#[test]
+fn boot_sector_roundtrip() {
+    let (mut s, _, _) = common::fat16_volume();
+    s.sector_mut(1)[0x03..0x0B].copy_from_slice(b"mkfs.fat");
+
+    let boot_sect = BootSector::read(&GenericArray::<u8, U512>::clone_from_slice(s.sector(1)));
+
+    let json = serde_json::to_string(&boot_sect).unwrap();
+    assert!(json.contains(r#""oem_name":"mkfs.fat""#));
+
+    let back: BootSector = serde_json::from_str(&json).unwrap();
+    assert_eq!(back, boot_sect);
+}
@@ -0,0 +1,95 @@
+// Requires the `no_std` feature to be disabled so that `File`s implement the
+// `Storage` trait.
+//
+// Run with --no-default-features.
+
+mod common;
+
+use fs::storage::StorageExt;
+
+use storage_traits::{FileBackedStorage, Storage};
+use generic_array::GenericArray;
+
+#[test]
+fn erase() {
+    let path = common::filled_scratch_file("erase", 16, 0xA5);
+    let mut s = FileBackedStorage::from_file(&path).unwrap();
+
+    s.erase().unwrap();
+
+    let mut sector = GenericArray::default();
+    for idx in [0, 1, 7, 15].iter() {
+        s.read_sector(*idx, &mut sector).unwrap();
+        assert!(sector.iter().all(|b| *b == 0), "sector {} wasn't erased", idx);
+    }
+}
+
+#[test]
+fn unaligned_write_bytes() {
+    let path = common::filled_scratch_file("write-bytes", 4, 0x11);
+    let mut s = FileBackedStorage::from_file(&path).unwrap();
+
+    // Straddles sectors 1 and 2:
+    s.write_bytes(512 + 500, &[0xEE; 20]).unwrap();
+
+    let mut sector = GenericArray::default();
+
+    s.read_sector(1, &mut sector).unwrap();
+    assert!(sector[..500].iter().all(|b| *b == 0x11));
+    assert!(sector[500..].iter().all(|b| *b == 0xEE));
+
+    s.read_sector(2, &mut sector).unwrap();
+    assert!(sector[..8].iter().all(|b| *b == 0xEE));
+    assert!(sector[8..].iter().all(|b| *b == 0x11));
+
+    // Untouched:
+    s.read_sector(0, &mut sector).unwrap();
+    assert!(sector.iter().all(|b| *b == 0x11));
+    s.read_sector(3, &mut sector).unwrap();
+    assert!(sector.iter().all(|b| *b == 0x11));
+}
+
+#[test]
+fn write_bytes_covering_whole_sectors() {
+    let path = common::filled_scratch_file("write-bytes-whole", 4, 0x11);
+    let mut s = FileBackedStorage::from_file(&path).unwrap();
+
+    let data: Vec<u8> = (0..(512 * 2 + 2)).map(|i| i as u8).collect();
+    s.write_bytes(511, &data).unwrap();
+
+    let contents = std::fs::read(&path).unwrap();
+    assert_eq!(contents[510], 0x11);
+    assert_eq!(&contents[511..(511 + data.len())], &data[..]);
+    assert_eq!(contents[511 + data.len()], 0x11);
+}
+
+#[test]
+fn read_and_write_sectors_match_single_sector_ops() {
+    let path = common::scratch_file("multi-sector", 8);
+    let mut s = FileBackedStorage::from_file(&path).unwrap();
+
+    let mut sectors = vec![GenericArray::default(); 3];
+    for (i, sector) in sectors.iter_mut().enumerate() {
+        sector.iter_mut().enumerate().for_each(|(j, b)| *b = (i * 7 + j) as u8);
+    }
+
+    s.write_sectors(2, &sectors).unwrap();
+
+    // Each sector should be where single sector reads expect it:
+    let mut sector = GenericArray::default();
+    for (i, expected) in sectors.iter().enumerate() {
+        s.read_sector(2 + i, &mut sector).unwrap();
+        assert_eq!(&sector, expected);
+    }
+
+    // And reading them back as a run should match too:
+    let mut read = vec![GenericArray::default(); 4];
+    s.read_sectors(1, &mut read).unwrap();
+
+    s.read_sector(1, &mut sector).unwrap();
+    assert_eq!(read[0], sector);
+    assert_eq!(&read[1..], &sectors[..]);
+
+    // Runs that go off the end fail:
+    assert!(s.read_sectors(6, &mut read).is_err());
+}
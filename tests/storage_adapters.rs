@@ -0,0 +1,183 @@
+mod common;
+
+use fs::fat::{
+    FatFs,
+    types::{ClusterIdx, SectorIdx},
+    cache::{FlushError, eviction_policies::UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED},
+    dir::{DirEntry, FileExt, FileName},
+};
+use fs::gpt::PartitionEntry;
+use fs::storage::{ClassifyError, StorageExt, StorageKind};
+use fs::storage_adapters::{ByteStorage, ReadOnly, ReadOnlyViolation, RetryStorage};
+
+use storage_traits::{Storage, errors::{ReadError, WriteError}};
+use generic_array::GenericArray;
+use typenum::consts::{U4, U256, U512};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FlakyError { Timeout, Broken }
+
+impl ClassifyError for FlakyError {
+    fn kind(&self) -> StorageKind {
+        match self {
+            FlakyError::Timeout => StorageKind::Retryable,
+            FlakyError::Broken => StorageKind::Fatal,
+        }
+    }
+}
+
+/// Storage whose operations fail the first `failures` times they're tried.
+struct Flaky {
+    failures: usize,
+    error: FlakyError,
+    attempts: usize,
+}
+
+impl Flaky {
+    fn new(failures: usize, error: FlakyError) -> Self {
+        Self { failures, error, attempts: 0 }
+    }
+
+    fn attempt(&mut self) -> Result<(), FlakyError> {
+        self.attempts += 1;
+
+        if self.attempts <= self.failures { Err(self.error) } else { Ok(()) }
+    }
+}
+
+impl Storage for Flaky {
+    type Word = u8;
+    type SECTOR_SIZE = U512;
+
+    type ReadErr = FlakyError;
+    type WriteErr = FlakyError;
+
+    fn capacity(&self) -> usize { 8 }
+
+    fn read_sector(&mut self, _: usize, _: &mut GenericArray<u8, U512>) -> Result<(), ReadError<FlakyError>> {
+        self.attempt().map_err(ReadError::Other)
+    }
+
+    fn write_sector(&mut self, _: usize, _: &GenericArray<u8, U512>) -> Result<(), WriteError<FlakyError>> {
+        self.attempt().map_err(WriteError::Other)
+    }
+}
+
+#[test]
+fn retry_succeeds_on_third_attempt() {
+    let mut s = RetryStorage::new(Flaky::new(2, FlakyError::Timeout), 3);
+    let mut sector = GenericArray::default();
+
+    assert_eq!(s.read_sector(0, &mut sector), Ok(()));
+    assert_eq!(s.inner.attempts, 3);
+
+    s.inner = Flaky::new(2, FlakyError::Timeout);
+    assert_eq!(s.write_sector(0, &sector), Ok(()));
+    assert_eq!(s.inner.attempts, 3);
+}
+
+#[test]
+fn retry_gives_up() {
+    // Out of retries:
+    let mut s = RetryStorage::new(Flaky::new(5, FlakyError::Timeout), 3);
+    let mut sector = GenericArray::default();
+
+    assert_eq!(s.read_sector(0, &mut sector), Err(ReadError::Other(FlakyError::Timeout)));
+    assert_eq!(s.inner.attempts, 4);
+
+    // Errors that aren't retryable aren't retried:
+    let mut s = RetryStorage::new(Flaky::new(1, FlakyError::Broken), 3);
+    assert_eq!(s.read_sector(0, &mut sector), Err(ReadError::Other(FlakyError::Broken)));
+    assert_eq!(s.inner.attempts, 1);
+}
+
+#[test]
+fn read_only_rejects_writes() {
+    let mut inner = common::MockStorage::new(16);
+    common::bare_boot_sector(inner.sector_mut(1));
+    inner.sector_mut(7)[0] = 0x77;
+
+    let mut s = ReadOnly::new(inner);
+
+    let mut sector = GenericArray::default();
+    s.read_sector(7, &mut sector).unwrap();
+    assert_eq!(sector[0], 0x77);
+
+    assert_eq!(s.write_sector(7, &sector), Err(WriteError::Other(ReadOnlyViolation)));
+    assert!(s.write_bytes(3, b"hi").is_err());
+    assert_eq!(ReadOnlyViolation.kind(), StorageKind::WriteProtected);
+
+    // Mounting works and so does reading:
+    let partition = PartitionEntry::fat("RTOS", 1, 15);
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    let mut buf = [0u8; 1];
+    fs.read(&mut s, SectorIdx::new(7), 0, &mut buf).unwrap();
+    assert_eq!(buf, [0x77]);
+
+    // With nothing modified, flushing is a no-op:
+    assert_eq!(fs.cache.flush(&mut s), Ok(()));
+
+    // But modifications can't be written out:
+    fs.write(&mut s, SectorIdx::new(7), 0, b"x").unwrap();
+    let err: FlushError<U4> = fs.cache.flush(&mut s).unwrap_err();
+    assert_eq!(err.failed_sectors().collect::<Vec<_>>(), vec![SectorIdx::new(7)]);
+    assert_eq!(err.verify_error, None);
+    assert_eq!(s.inner.sector(7)[0], 0x77);
+    assert_eq!(s.inner.writes, 0);
+
+    // The cache still has the dirty sector (and would panic when dropped).
+    std::mem::forget(fs);
+}
+
+/// Storage that's made up of 256 16-bit words per sector.
+struct Words(Vec<u16>);
+
+impl Storage for Words {
+    type Word = u16;
+    type SECTOR_SIZE = U256;
+
+    type ReadErr = ();
+    type WriteErr = ();
+
+    fn capacity(&self) -> usize {
+        self.0.len() / 256
+    }
+
+    fn read_sector(&mut self, idx: usize, buffer: &mut GenericArray<u16, U256>) -> Result<(), ReadError<()>> {
+        buffer.copy_from_slice(&self.0[(idx * 256)..((idx + 1) * 256)]);
+        Ok(())
+    }
+
+    fn write_sector(&mut self, idx: usize, words: &GenericArray<u16, U256>) -> Result<(), WriteError<()>> {
+        self.0[(idx * 256)..((idx + 1) * 256)].copy_from_slice(words);
+        Ok(())
+    }
+}
+
+#[test]
+fn byte_storage_over_16_bit_words() {
+    let (mut bytes, partition, g) = common::fat16_volume();
+
+    let mut hello = DirEntry::new_file(FileName(*b"HELLO   "), FileExt(*b"TXT"), ClusterIdx::new(5));
+    hello.file_size = 5;
+    common::put_dir_entry(&mut bytes, g.root_dir_start(1), 0, &hello);
+    common::set_fat16_entry(&mut bytes, &g, 5, 0xFFF8);
+    bytes.sector_mut(g.cluster_start(1, 5))[0..5].copy_from_slice(b"hello");
+
+    let words = bytes.data.chunks(2).map(|w| u16::from_le_bytes([w[0], w[1]])).collect();
+    let mut s = ByteStorage::new(Words(words));
+
+    let mut fs = FatFs::<_, U4, _>::mount(&mut s, &partition, UNMODIFIED_THEN_LEAST_RECENTLY_ACCESSED).unwrap();
+
+    let (_, entry) = fs.lookup_path(&mut s, b"/HELLO.TXT").unwrap();
+    let data = entry.into_file().unwrap().upgrade(&mut fs, &mut s).read_to_end().unwrap();
+    assert_eq!(data, b"hello");
+
+    // Writes are put back together into words, low byte first:
+    let sector = g.cluster_start(1, 5);
+    fs.write(&mut s, SectorIdx::new(sector as u64), 0, b"HE").unwrap();
+    fs.cache.flush(&mut s).unwrap();
+    assert_eq!(s.inner.0[sector * 256], u16::from_le_bytes(*b"HE"));
+    assert_eq!(s.inner.0[sector * 256 + 1], u16::from_le_bytes(*b"ll"));
+}
@@ -0,0 +1,32 @@
+// Checks that the crate builds with `no_std` and the stub Mutex (and that the
+// stub Mutex behaves).
+//
+// Run with --no-default-features --features no_std,stub_mutex.
+
+use fs::mutex::{Mutex, MutexInterface};
+
+static COUNTER: Mutex<u32> = Mutex::new(0);
+
+#[test]
+fn stub_mutex_runs_closures() {
+    assert_eq!(COUNTER.cs(|c| { *c += 1; *c }), 1);
+    assert_eq!(COUNTER.cs(|c| *c), 1);
+
+    let mut m = <Mutex<u8> as MutexInterface<u8>>::new(3);
+    *m.get_mut() = 4;
+    assert_eq!(m.cs(|v| *v), 4);
+}
+
+#[test]
+fn stub_mutex_try_cs() {
+    let m = Mutex::new(1u8);
+    assert_eq!(m.try_cs(|v| *v), Some(1));
+    assert_eq!(m.cs(|_| m.try_cs(|v| *v)), None);
+}
+
+#[test]
+#[should_panic]
+fn stub_mutex_panics_when_reentered() {
+    let m = Mutex::new(());
+    m.cs(|_| m.cs(|_| ()));
+}